@@ -0,0 +1,76 @@
+/// Telling which commits descend from which, for many pairs of them at once
+use std::collections::{HashMap, HashSet};
+
+/// The commits `git rev-list --parents` listed for some tips, leaving out those
+/// reachable from some bases, along with their parents.  A walk from a tip
+/// leaves the listing wherever it reaches a commit which one of the bases can
+/// reach, so a base which is an ancestor of a tip is usually found right where
+/// the walk leaves, without asking git about that pair alone.
+///
+/// ```
+/// # use git_sync::RevGraph;
+/// // c has parents b and x, b has parent a, and a and x are the bases
+/// let graph = RevGraph::parse("c b x\nb a\n");
+/// assert_eq!(graph.descends("a", "c"), Some(true));
+/// assert_eq!(graph.descends("x", "b"), None);
+/// assert_eq!(graph.descends("c", "c"), Some(true));
+/// // Nothing reachable from d is reachable from any base
+/// let graph = RevGraph::parse("d\n");
+/// assert_eq!(graph.descends("a", "d"), Some(false));
+/// // A tip which a base can reach isn't listed at all
+/// assert_eq!(graph.descends("a", "b"), None);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RevGraph {
+    parents: HashMap<String, Vec<String>>,
+}
+
+impl RevGraph {
+    /// Read what `git rev-list --parents` wrote: a line for each commit, with
+    /// its parents after it
+    pub fn parse(listing: &str) -> RevGraph {
+        let parents = listing
+            .lines()
+            .filter_map(|line| {
+                let mut ids = line.split_whitespace().map(str::to_string);
+                Some((ids.next()?, ids.collect()))
+            })
+            .collect();
+        RevGraph { parents }
+    }
+
+    /// Whether `old` is an ancestor of `new`, where `old` was one of the bases
+    /// and `new` one of the tips, or `None` if the listing can't tell, because
+    /// the walk from `new` reached other bases' history as well
+    pub fn descends(&self, old: &str, new: &str) -> Option<bool> {
+        if old == new {
+            return Some(true);
+        }
+        if !self.parents.contains_key(new) {
+            return None;
+        }
+        let mut seen = HashSet::new();
+        let mut exits = HashSet::new();
+        let mut walk = vec![new];
+        while let Some(commit) = walk.pop() {
+            if !seen.insert(commit) {
+                continue;
+            }
+            match self.parents.get(commit) {
+                Some(parents) => walk.extend(parents.iter().map(String::as_str)),
+                None => {
+                    exits.insert(commit);
+                }
+            }
+        }
+        // Every path from `new` to `old` leaves the listing, since `old` isn't in
+        // it, and the first commit it reaches outside is where it leaves
+        if exits.contains(old) {
+            Some(true)
+        } else if exits.is_empty() {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,112 @@
+/// Keeping a record of every sync, of who pushed what where, for those who must
+/// be able to account for it
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use super::{committer_ident, json_object, Error, Json};
+
+/// A file of JSON lines to which an entry is appended for every sync.  It is
+/// opened for appending only, and each entry is written whole and flushed to
+/// disk before the next, so that entries already written are never touched and
+/// syncs running at once don't interleave theirs.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl AuditLog {
+    /// Open the audit log at `path`, creating it if need be, so that a sync
+    /// which couldn't be recorded needn't be started
+    pub async fn open(path: &Path) -> Result<AuditLog, Error> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .await
+            .map_err(|err| {
+                Error::Config(format!(
+                    "Cannot open the audit log {}: {}",
+                    path.display(),
+                    err
+                ))
+            })?;
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Where the audit log is
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add an entry to the end of the log
+    pub async fn append(&mut self, entry: &Json) -> Result<(), Error> {
+        let line = format!("{}\n", entry);
+        self.file.write_all(line.as_bytes()).await?;
+        self.file.sync_data().await?;
+        Ok(())
+    }
+}
+
+/// Who a sync was done by: the user running it, on which host, and as whom git
+/// knows them
+///
+/// ```
+/// # use git_sync::Operator;
+/// let operator = Operator {
+///     user: Some("mirror".to_string()),
+///     host: None,
+///     ident: Some("Mirror Bot <mirror@example.com>".to_string()),
+/// };
+/// assert_eq!(
+///     operator.to_json().to_string(),
+///     r#"{"user":"mirror","host":null,"ident":"Mirror Bot <mirror@example.com>"}"#
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Operator {
+    /// The login name of the user, from `$USER` or `$LOGNAME`
+    pub user: Option<String>,
+    /// The name of the machine the sync ran on
+    pub host: Option<String>,
+    /// The committer identity git would use, without its timestamp
+    pub ident: Option<String>,
+}
+
+impl Operator {
+    /// Whoever is running this process.  Anything which can't be found out is
+    /// left out, rather than preventing the sync.
+    pub async fn current() -> Operator {
+        let user = ["USER", "LOGNAME"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|user| !user.is_empty());
+        let host = tokio::fs::read_to_string("/proc/sys/kernel/hostname")
+            .await
+            .ok()
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty());
+        let ident = committer_ident()
+            .await
+            .ok()
+            .map(|ident| match ident.rfind('>') {
+                Some(end) => ident[..=end].to_string(),
+                None => ident,
+            });
+        Operator { user, host, ident }
+    }
+
+    /// The operator as a JSON object
+    pub fn to_json(&self) -> Json {
+        json_object(vec![
+            ("user", Json::from(self.user.clone())),
+            ("host", Json::from(self.host.clone())),
+            ("ident", Json::from(self.ident.clone())),
+        ])
+    }
+}
@@ -0,0 +1,150 @@
+/// A git remote helper reaching repositories through git-sync's transports
+///
+/// With this on the `PATH`, a remote URL of the form `sync::<url>` (for example
+/// `sync::wss://gateway/repo.git`) makes git run `git-remote-sync <name> <url>`.
+/// We offer git the `connect` capability, so for each fetch or push git asks us
+/// for a connection to upload-pack or receive-pack and then speaks the protocol
+/// itself over our stdio, which we relay to the remote service.
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+
+use git_sync::{ConnectOptions, Proxy, RemoteUrl, SshOptions, SshProgram};
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let url = match args.as_slice() {
+        [_, _name, url] => url.clone(),
+        _ => return Err(io::Error::other("Usage: git-remote-sync <remote> <url>")),
+    };
+    let url = url.strip_prefix("sync::").unwrap_or(&url);
+    let url: RemoteUrl = url.parse().map_err(io::Error::other)?;
+    serve(&url, BufReader::new(io::stdin()), io::stdout()).await
+}
+
+/// Answer git's remote helper commands from `stdin`, relaying a connection to
+/// `url` once git asks for one
+async fn serve<R, W>(url: &RemoteUrl, mut stdin: R, mut stdout: W) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stdin.read_line(&mut line).await? == 0 {
+            // git has nothing more for us
+            return Ok(());
+        }
+        let command = line.trim_end();
+        if command == "capabilities" {
+            stdout.write_all(b"connect\n\n").await?;
+            stdout.flush().await?;
+        } else if let Some(service) = command.strip_prefix("connect ") {
+            let proxy = match url.host() {
+                Some(host) => Proxy::from_env(host).map_err(io::Error::other)?,
+                None => None,
+            };
+            let opts = ConnectOptions {
+                proxy,
+                ssh: SshOptions {
+                    program: SshProgram::from_env(),
+                    ..SshOptions::default()
+                },
+                ..ConnectOptions::default()
+            };
+            let mut transport = url.connect(service, &opts).await?;
+            // An empty line tells git the connection is established, after which
+            // it's just a matter of relaying bytes until both sides are done
+            stdout.write_all(b"\n").await?;
+            stdout.flush().await?;
+            {
+                let (reader, writer) = transport.streams();
+                let outgoing = async { io::copy(&mut stdin, writer).await.map(|_| ()) };
+                // Our stdout buffers until flushed, so io::copy won't do here
+                let incoming = async {
+                    let mut buf = vec![0; 65536];
+                    loop {
+                        let len = reader.read(&mut buf).await?;
+                        if len == 0 {
+                            return Ok(());
+                        }
+                        stdout.write_all(&buf[..len]).await?;
+                        stdout.flush().await?;
+                    }
+                };
+                tokio::try_join!(outgoing, incoming)?;
+            }
+            return Ok(transport.shutdown().await?);
+        } else if command.is_empty() {
+            return Ok(());
+        } else {
+            return Err(io::Error::other(format!(
+                "Unsupported remote helper command: {}",
+                command
+            )));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    /// Run the helper with `input` from git, returning what it says back
+    async fn helper(url: &RemoteUrl, input: &str) -> io::Result<String> {
+        let mut output = Vec::new();
+        serve(url, input.as_bytes(), &mut output).await?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    /// A repository with a single commit on `main`, which the test should remove
+    fn repository(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("git-sync-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&path).unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=Sync", "-c", "user.email=sync@example.com"])
+                .arg("-C")
+                .arg(&path)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q", "-b", "main"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "Start"]);
+        path
+    }
+
+    #[tokio::test]
+    async fn offers_to_connect() {
+        let url = RemoteUrl::Local(PathBuf::from("/nonexistent"));
+        assert_eq!(
+            helper(&url, "capabilities\n\n").await.unwrap(),
+            "connect\n\n"
+        );
+        // git may simply hang up
+        assert_eq!(helper(&url, "capabilities\n").await.unwrap(), "connect\n\n");
+        let err = helper(&url, "list\n").await.unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported remote helper command: list");
+    }
+
+    #[tokio::test]
+    async fn relays_the_service() {
+        let path = repository("remote-helper");
+        let url = RemoteUrl::Local(path.clone());
+        // Asking for nothing once the refs are advertised ends the fetch
+        let output = helper(&url, "capabilities\nconnect git-upload-pack\n0000")
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&path).unwrap();
+        let advertisement = output.strip_prefix("connect\n\n\n").unwrap();
+        assert!(advertisement.contains(" refs/heads/main\n"), "{}", output);
+        assert!(advertisement.ends_with("0000"), "{}", output);
+    }
+}
@@ -0,0 +1,979 @@
+/// Git bundles, files holding a pack along with the refs it was made for, which
+/// can be synced from and into as though they were repositories
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+use super::{
+    duplex_transport, open_decompressed, Codec, CompressedFile, Decompressed, DuplexTransport,
+    Error, PackChecksum, ProtocolLine, RefUpdate, DEFAULT_AGENT, EMPTY_PACK, NULLSHA,
+};
+
+/// The first line of a version 2 bundle
+pub const BUNDLE_V2_SIGNATURE: &str = "# v2 git bundle";
+
+/// The first line of a version 3 bundle, which may name capabilities
+pub const BUNDLE_V3_SIGNATURE: &str = "# v3 git bundle";
+
+/// The most pack data a side-band-64k packet can carry
+const BAND_DATA_LEN: usize = 65515;
+
+/// How much is buffered between a bundle's upload-pack and the sync reading it
+const BUNDLE_BUF_SIZE: usize = 128 * 1024;
+
+/// A bundle, as written by `git bundle create`: a header listing the objects the
+/// pack needs but doesn't have (`-<object>` lines) and the refs it was made for
+/// (`<object> <ref>` lines), followed after a blank line by the pack.  A bundle
+/// may be compressed whole with gzip or zstd, as a `.bundle.gz` or `.bundle.zst`
+/// file, which is decompressed as it's read.
+///
+/// Served as though by upload-pack, a bundle advertises its refs, and sends its
+/// whole pack when any of them are wanted.  The target must already have any
+/// objects the bundle needs.  Pushed into as though by receive-pack, a new bundle
+/// is written with the refs pushed.
+///
+/// ```
+/// # use git_sync::{Bundle, ProtocolLine, RefAdvertisement, Transport, EMPTY_PACK};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let path = std::env::temp_dir().join(format!("source-{}.bundle", std::process::id()));
+/// let mut contents = b"# v2 git bundle\n\
+///     -1111111111111111111111111111111111111111 two\n\
+///     2222222222222222222222222222222222222222 HEAD\n\
+///     2222222222222222222222222222222222222222 refs/heads/main\n\n"
+///     .to_vec();
+/// contents.extend_from_slice(EMPTY_PACK);
+/// std::fs::write(&path, &contents).unwrap();
+///
+/// let bundle = Bundle::open(&path).await.unwrap();
+/// assert_eq!(bundle.prerequisites(), ["1111111111111111111111111111111111111111"]);
+/// assert_eq!(bundle.refs().len(), 2);
+/// assert_eq!(bundle.head(), Some("refs/heads/main"));
+///
+/// let mut transport = bundle.serve();
+/// let advert = RefAdvertisement::read_from(transport.reader()).await.unwrap();
+/// assert_eq!(
+///     advert.refs()["refs/heads/main"],
+///     "2222222222222222222222222222222222222222"
+/// );
+/// assert_eq!(advert.symrefs()["HEAD"], "refs/heads/main");
+/// ProtocolLine::Flush.write_to(transport.writer()).await.unwrap();
+/// Box::new(transport).shutdown().await.unwrap();
+///
+/// std::fs::write(&path, b"# v2 git bundle\n\n").unwrap();
+/// assert!(Bundle::open(&path).await.is_err());
+/// # std::fs::remove_file(&path).unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    path: PathBuf,
+    object_format: String,
+    prerequisites: Vec<String>,
+    /// The refs, by name, with the objects they name, in the bundle's order
+    refs: Vec<(String, String)>,
+    /// Where the pack starts in the file
+    offset: u64,
+}
+
+impl Bundle {
+    /// Read the header of the bundle at `path`
+    pub async fn open(path: &Path) -> Result<Bundle, Error> {
+        let cannot = |why: String| {
+            Error::Config(format!(
+                "Cannot sync from the bundle {}: {}",
+                path.display(),
+                why
+            ))
+        };
+        let file = open_decompressed(path, 0)
+            .await
+            .map_err(|err| cannot(err.to_string()))?;
+        let mut file = BufReader::new(file);
+        let mut bundle = Bundle {
+            path: path.to_path_buf(),
+            object_format: "sha1".to_string(),
+            prerequisites: Vec::new(),
+            refs: Vec::new(),
+            offset: 0,
+        };
+        let mut version = None;
+        loop {
+            let mut line = Vec::new();
+            let read = file.read_until(b'\n', &mut line).await?;
+            if read == 0 {
+                return Err(cannot("it ends before the pack".to_string()));
+            }
+            bundle.offset += read as u64;
+            let line =
+                String::from_utf8(line).map_err(|_| cannot("it isn't a bundle".to_string()))?;
+            let line = line.trim_end_matches('\n');
+            if version.is_none() {
+                version = match line {
+                    BUNDLE_V2_SIGNATURE => Some(2),
+                    BUNDLE_V3_SIGNATURE => Some(3),
+                    _ => return Err(cannot("it isn't a bundle".to_string())),
+                };
+                continue;
+            }
+            if line.is_empty() {
+                break;
+            }
+            if let (Some(3), Some(capability)) = (version, line.strip_prefix('@')) {
+                match capability.strip_prefix("object-format=") {
+                    Some(format) => bundle.object_format = format.to_string(),
+                    None => {
+                        return Err(cannot(format!(
+                            "it needs the capability {:?}, which git-sync lacks",
+                            capability
+                        )))
+                    }
+                }
+                continue;
+            }
+            // Prerequisites may be followed by a comment, usually a commit's subject
+            if let Some(prerequisite) = line.strip_prefix('-') {
+                match prerequisite.split(' ').next() {
+                    Some(object) if is_object_id(object) => {
+                        bundle.prerequisites.push(object.to_string())
+                    }
+                    _ => return Err(cannot(format!("it has a bad line {:?}", line))),
+                }
+                continue;
+            }
+            match line.split_once(' ') {
+                Some((object, refname)) if is_object_id(object) && !refname.is_empty() => {
+                    bundle.refs.push((refname.to_string(), object.to_string()))
+                }
+                _ => return Err(cannot(format!("it has a bad line {:?}", line))),
+            }
+        }
+        if bundle.refs.is_empty() {
+            return Err(cannot("it has no refs".to_string()));
+        }
+        Ok(bundle)
+    }
+
+    /// Where the bundle is
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The objects the bundle's pack needs but doesn't have
+    pub fn prerequisites(&self) -> &[String] {
+        &self.prerequisites
+    }
+
+    /// The refs the bundle has, each with the object it names
+    pub fn refs(&self) -> &[(String, String)] {
+        &self.refs
+    }
+
+    /// The branch `HEAD` is taken to be, as with `git clone`: the first one the
+    /// bundle has naming the same object, if the bundle has a `HEAD` at all
+    pub fn head(&self) -> Option<&str> {
+        let head = self
+            .refs
+            .iter()
+            .find(|(refname, _)| refname == "HEAD")
+            .map(|(_, object)| object)?;
+        self.refs
+            .iter()
+            .find(|(refname, object)| refname.starts_with("refs/heads/") && object == head)
+            .map(|(refname, _)| refname.as_str())
+    }
+
+    /// Open the bundle's pack to read it from the start
+    pub async fn open_pack(&self) -> Result<Decompressed, Error> {
+        Ok(open_decompressed(&self.path, self.offset).await?)
+    }
+
+    /// Take a push as though by receive-pack, over an in-memory transport, and
+    /// write what's pushed as a bundle at `path`.  It has the refs of `basis` (by
+    /// name, with the objects they name) as changed by the push, and needs the
+    /// commits they name, which the pack may be based on (see
+    /// [`read_bundle_basis`] for tags).  Nothing is written
+    /// if nothing is pushed, and the bundle replaces what was at `path` only once
+    /// it's whole.  It's compressed if `path` ends `.gz` (gzip) or `.zst` (zstd).
+    ///
+    /// ```
+    /// # use git_sync::{Bundle, ProtocolLine, RefAdvertisement, ReportStatus, Transport, EMPTY_PACK};
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let path = std::env::temp_dir().join(format!("target-{}.bundle", std::process::id()));
+    /// let old = "1111111111111111111111111111111111111111";
+    /// let basis = vec![("refs/heads/main".to_string(), old.to_string())];
+    /// let mut transport = Bundle::receive(&path, basis);
+    /// let advert = RefAdvertisement::read_from(transport.reader()).await.unwrap();
+    /// assert_eq!(advert.refs()["refs/heads/main"], old);
+    ///
+    /// let command = format!("{} {} refs/tags/v1\0report-status", git_sync::NULLSHA, old);
+    /// ProtocolLine::write_str(transport.writer(), command).await.unwrap();
+    /// ProtocolLine::Flush.write_to(transport.writer()).await.unwrap();
+    /// tokio::io::AsyncWriteExt::write_all(transport.writer(), EMPTY_PACK).await.unwrap();
+    /// let report = ReportStatus::read_from(transport.reader()).await.unwrap();
+    /// assert_eq!(report.unpack, Ok(()));
+    /// Box::new(transport).shutdown().await.unwrap();
+    ///
+    /// let bundle = Bundle::open(&path).await.unwrap();
+    /// assert_eq!(bundle.prerequisites(), [old]);
+    /// assert_eq!(bundle.refs().len(), 2);
+    /// # std::fs::remove_file(&path).unwrap();
+    /// # }
+    /// ```
+    pub fn receive(path: &Path, basis: Vec<(String, String)>) -> DuplexTransport {
+        let (transport, mut stream) = duplex_transport(BUNDLE_BUF_SIZE);
+        let path = path.to_path_buf();
+        tokio::spawn(async move {
+            if let Err(err) = receive_pack(&path, &basis, &mut stream).await {
+                tracing::warn!(
+                    service = "git-receive-pack",
+                    "Unable to write the bundle {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        });
+        transport
+    }
+
+    /// Serve the bundle as though by upload-pack, over an in-memory transport
+    pub fn serve(self) -> DuplexTransport {
+        let (transport, mut stream) = duplex_transport(BUNDLE_BUF_SIZE);
+        tokio::spawn(async move {
+            if let Err(err) = upload_pack(&self, &mut stream).await {
+                tracing::debug!(
+                    service = "git-upload-pack",
+                    "Stopped serving the bundle {}: {}",
+                    self.path.display(),
+                    err
+                );
+            }
+        });
+        transport
+    }
+}
+
+/// Play the part of upload-pack for a bundle: advertise its refs and, should any
+/// of them be wanted, send its pack
+async fn upload_pack(bundle: &Bundle, stream: &mut DuplexStream) -> Result<(), Error> {
+    let mut caps = format!(
+        "side-band-64k ofs-delta thin-pack object-format={} agent={}",
+        bundle.object_format, DEFAULT_AGENT
+    );
+    if let Some(head) = bundle.head() {
+        caps.push_str(&format!(" symref=HEAD:{}", head));
+    }
+    for (idx, (refname, object)) in bundle.refs.iter().enumerate() {
+        let line = match idx {
+            0 => format!("{} {}\0{}\n", object, refname, caps),
+            _ => format!("{} {}\n", object, refname),
+        };
+        ProtocolLine::write_str(stream, line).await?;
+    }
+    ProtocolLine::Flush.write_to(stream).await?;
+
+    // The wants come first, then after a flush the haves and `done`.  There's
+    // only the one pack to send, so the haves make no difference.
+    let mut wants = Vec::new();
+    loop {
+        match ProtocolLine::read_from(stream, true).await? {
+            ProtocolLine::Flush => break,
+            ProtocolLine::Data(line) if line.starts_with(b"want ") => {
+                let want = line[5..].split(|b| *b == b' ').next().unwrap_or_default();
+                wants.push(String::from_utf8_lossy(want).into_owned());
+            }
+            line => return Err(Error::Protocol(format!("Expected a want, got {:?}", line))),
+        }
+    }
+    if wants.is_empty() {
+        return Ok(());
+    }
+    loop {
+        match ProtocolLine::read_from(stream, true).await? {
+            ProtocolLine::Data(line) if line.as_ref() == b"done" => break,
+            ProtocolLine::Data(line) if line.starts_with(b"have ") => {}
+            line => return Err(Error::Protocol(format!("Expected a have, got {:?}", line))),
+        }
+    }
+    let ours = |want: &String| bundle.refs.iter().any(|(_, object)| object == want);
+    if let Some(want) = wants.iter().find(|want| !ours(want)) {
+        let err = format!("ERR upload-pack: not our ref {}\n", want);
+        return ProtocolLine::write_str(stream, err).await;
+    }
+    ProtocolLine::write_str(stream, "NAK\n").await?;
+
+    let mut pack = bundle.open_pack().await?;
+    let mut buf = vec![0; BAND_DATA_LEN + 1];
+    buf[0] = 1;
+    loop {
+        let read = pack.read(&mut buf[1..]).await?;
+        if read == 0 {
+            break;
+        }
+        ProtocolLine::Data(Cow::from(&buf[..=read]))
+            .write_to(stream)
+            .await?;
+    }
+    ProtocolLine::Flush.write_to(stream).await
+}
+
+/// Read what a bundle to be written is based on, from a bundle or from a list
+/// of refs with an `<object> <ref>` line for each, as `git show-ref` and
+/// `git ls-remote` print them.  Only the refs under `refs/` count, along with
+/// the lines giving the objects tags peel to, such as `refs/tags/v1^{}`.
+pub async fn read_bundle_basis(path: &Path) -> Result<Vec<(String, String)>, Error> {
+    let cannot = |why: String| {
+        Error::Config(format!(
+            "Cannot base a bundle on {}: {}",
+            path.display(),
+            why
+        ))
+    };
+    let mut contents = Vec::new();
+    let read = match open_decompressed(path, 0).await {
+        Ok(mut file) => file.read_to_end(&mut contents).await,
+        Err(err) => Err(err),
+    };
+    read.map_err(|err| cannot(err.to_string()))?;
+    let refs = if contents.starts_with(BUNDLE_V2_SIGNATURE.as_bytes())
+        || contents.starts_with(BUNDLE_V3_SIGNATURE.as_bytes())
+    {
+        Bundle::open(path).await?.refs
+    } else {
+        let contents = String::from_utf8(contents)
+            .map_err(|_| cannot("it isn't a bundle or a list of refs".to_string()))?;
+        let mut refs = Vec::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            match line.split_once(&[' ', '\t'][..]) {
+                Some((object, refname)) if is_object_id(object) => {
+                    refs.push((refname.trim().to_string(), object.to_string()))
+                }
+                _ => return Err(cannot(format!("it has a bad line {:?}", line))),
+            }
+        }
+        refs
+    };
+    Ok(refs
+        .into_iter()
+        .filter(|(refname, _)| refname.starts_with("refs/"))
+        .collect())
+}
+
+/// Play the part of receive-pack for a bundle to be written at `path`: advertise
+/// the refs of its basis and write what's pushed
+async fn receive_pack(
+    path: &Path,
+    basis: &[(String, String)],
+    stream: &mut DuplexStream,
+) -> Result<(), Error> {
+    let (peeled, basis): (Vec<_>, Vec<_>) = basis
+        .iter()
+        .cloned()
+        .partition(|(refname, _)| refname.ends_with("^{}"));
+    let peeled: BTreeMap<_, _> = peeled.into_iter().collect();
+    let caps = format!(
+        "report-status delete-refs side-band-64k quiet atomic ofs-delta object-format=sha1 agent={}",
+        DEFAULT_AGENT
+    );
+    if basis.is_empty() {
+        let line = format!("{} capabilities^{{}}\0{}\n", NULLSHA, caps);
+        ProtocolLine::write_str(stream, line).await?;
+    }
+    for (idx, (refname, object)) in basis.iter().enumerate() {
+        let line = match idx {
+            0 => format!("{} {}\0{}\n", object, refname, caps),
+            _ => format!("{} {}\n", object, refname),
+        };
+        ProtocolLine::write_str(stream, line).await?;
+    }
+    ProtocolLine::Flush.write_to(stream).await?;
+
+    let mut updates = Vec::new();
+    let mut band = false;
+    loop {
+        let line = match ProtocolLine::read_from(stream, true).await? {
+            ProtocolLine::Flush => break,
+            ProtocolLine::Data(line) => line,
+            line => {
+                return Err(Error::Protocol(format!(
+                    "Expected a command, got {:?}",
+                    line
+                )))
+            }
+        };
+        let mut parts = line.split(|b| *b == 0);
+        let command = String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned();
+        if let Some(caps) = parts.next() {
+            band = caps
+                .split(|b| *b == b' ')
+                .any(|cap| cap == b"side-band-64k");
+        }
+        match command.splitn(3, ' ').collect::<Vec<_>>()[..] {
+            [old, new, refname] if old.len() == NULLSHA.len() && new.len() == NULLSHA.len() => {
+                updates.push(RefUpdate {
+                    refname: refname.to_string(),
+                    oldsha: old.to_string(),
+                    newsha: new.to_string(),
+                })
+            }
+            _ => return Err(Error::Protocol(format!("Bad command {:?}", command))),
+        }
+    }
+    if updates.is_empty() {
+        return Ok(());
+    }
+
+    // The bundle has the basis's refs as the push leaves them, and needs whatever
+    // the basis named.  Git insists on needing only commits, so a tag is left out
+    // of what's needed unless the basis says what it peels to.
+    let mut refs: BTreeMap<_, _> = basis.iter().cloned().collect();
+    for update in &updates {
+        if update.is_delete() {
+            refs.remove(&update.refname);
+        } else {
+            refs.insert(update.refname.clone(), update.newsha.clone());
+        }
+    }
+    let mut header = format!("{}\n", BUNDLE_V2_SIGNATURE);
+    let needed = basis.iter().filter_map(|(refname, object)| {
+        if refname.starts_with("refs/tags/") {
+            peeled.get(&format!("{}^{{}}", refname))
+        } else {
+            Some(object)
+        }
+    });
+    for object in needed.collect::<BTreeSet<_>>() {
+        header.push_str(&format!("-{}\n", object));
+    }
+    for (refname, object) in &refs {
+        header.push_str(&format!("{} {}\n", object, refname));
+    }
+    header.push('\n');
+
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let mut file = match CompressedFile::create(&partial, Codec::for_path(path)).await {
+        Ok(mut file) => file.write_all(header.as_bytes()).await.map(|()| file),
+        Err(err) => Err(err),
+    };
+    let sending = !updates.iter().all(RefUpdate::is_delete);
+    if sending {
+        // Nothing marks the end of the pack but the checksum it ends with.  It's
+        // read to the end even if it can't be written, so that it can be said why.
+        let mut checksum = PackChecksum::new();
+        let mut buf = vec![0; BAND_DATA_LEN];
+        while !checksum.is_whole() {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(Error::Protocol("The pack ended early".to_string()));
+            }
+            checksum.feed(&buf[..read]);
+            if let Ok(writing) = &mut file {
+                if let Err(err) = writing.write_all(&buf[..read]).await {
+                    file = Err(err);
+                }
+            }
+        }
+    }
+    let written = async {
+        let mut file = file?;
+        if !sending {
+            // Nothing is sent with deletions, but a bundle must have a pack
+            file.write_all(EMPTY_PACK).await?;
+        }
+        if refs.is_empty() {
+            return Err(Error::Config("a bundle must have refs".to_string()));
+        }
+        file.finish().await?.sync_data().await?;
+        tokio::fs::rename(&partial, path).await?;
+        Ok::<_, Error>(())
+    };
+
+    let mut report = Vec::new();
+    let status = match written.await {
+        Ok(()) => {
+            tracing::info!(
+                service = "git-receive-pack",
+                refs = refs.len(),
+                "Wrote the bundle {}",
+                path.display()
+            );
+            Ok(())
+        }
+        Err(err) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            Err(err.to_string())
+        }
+    };
+    match &status {
+        Ok(()) => ProtocolLine::write_str(&mut report, "unpack ok\n").await?,
+        Err(err) => ProtocolLine::write_str(&mut report, format!("unpack {}\n", err)).await?,
+    }
+    for update in &updates {
+        let line = match &status {
+            Ok(()) => format!("ok {}\n", update.refname),
+            Err(_) => format!("ng {} unpacker error\n", update.refname),
+        };
+        ProtocolLine::write_str(&mut report, line).await?;
+    }
+    ProtocolLine::Flush.write_to(&mut report).await?;
+    if band {
+        for chunk in report.chunks(BAND_DATA_LEN) {
+            let mut data = vec![1];
+            data.extend_from_slice(chunk);
+            ProtocolLine::Data(Cow::from(data)).write_to(stream).await?;
+        }
+        ProtocolLine::Flush.write_to(stream).await?;
+    } else {
+        stream.write_all(&report).await?;
+    }
+    Ok(())
+}
+
+/// Whether `name` is an object id, SHA-1 or SHA-256, as git shows them
+fn is_object_id(name: &str) -> bool {
+    matches!(name.len(), 40 | 64) && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{Capability, RefAdvertisement, RefStatus, ReportParser, ReportStatus, Transport};
+
+    const ONE: &str = "1111111111111111111111111111111111111111";
+    const TWO: &str = "2222222222222222222222222222222222222222";
+    const THREE: &str = "3333333333333333333333333333333333333333";
+
+    /// Somewhere for a test's bundle, which it should remove when it's done
+    fn bundle_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("git-sync-{}-{}", std::process::id(), name))
+    }
+
+    /// Write a bundle with the given header lines (after the signature) and an
+    /// empty pack, compressed as its name says
+    async fn write_bundle(name: &str, signature: &str, lines: &[String]) -> PathBuf {
+        let path = bundle_path(name);
+        let mut file = CompressedFile::create(&path, Codec::for_path(&path))
+            .await
+            .unwrap();
+        let mut header = format!("{}\n", signature);
+        for line in lines {
+            header.push_str(line);
+            header.push('\n');
+        }
+        header.push('\n');
+        file.write_all(header.as_bytes()).await.unwrap();
+        file.write_all(EMPTY_PACK).await.unwrap();
+        file.finish().await.unwrap();
+        path
+    }
+
+    /// Why the bundle at `path` can't be synced from, removing it
+    async fn refusal(path: PathBuf) -> String {
+        let err = Bundle::open(&path).await.unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        let prefix = format!("Cannot sync from the bundle {}: ", path.display());
+        err.strip_prefix(&prefix).unwrap_or(&err).to_string()
+    }
+
+    #[tokio::test]
+    async fn reads_headers() {
+        let path = write_bundle(
+            "v3.bundle.gz",
+            BUNDLE_V3_SIGNATURE,
+            &[
+                "@object-format=sha1".to_string(),
+                format!("-{} Subject of the commit", ONE),
+                format!("{} refs/heads/b", TWO),
+                format!("{} refs/heads/a", TWO),
+            ],
+        )
+        .await;
+        let bundle = Bundle::open(&path).await.unwrap();
+        assert_eq!(bundle.prerequisites(), [ONE]);
+        // The bundle's order is kept
+        assert_eq!(
+            bundle.refs(),
+            [
+                ("refs/heads/b".to_string(), TWO.to_string()),
+                ("refs/heads/a".to_string(), TWO.to_string())
+            ]
+        );
+        // Without a HEAD, there's no branch to take for it
+        assert_eq!(bundle.head(), None);
+        let mut pack = Vec::new();
+        bundle
+            .open_pack()
+            .await
+            .unwrap()
+            .read_to_end(&mut pack)
+            .await
+            .unwrap();
+        assert_eq!(pack, EMPTY_PACK);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_what_is_not_a_bundle() {
+        let refuse = |name: &str, signature: &str, lines: &[String]| {
+            let (name, signature, lines) =
+                (name.to_string(), signature.to_string(), lines.to_vec());
+            async move { refusal(write_bundle(&name, &signature, &lines).await).await }
+        };
+        let refs = [format!("{} refs/heads/main", ONE)];
+        assert_eq!(
+            refuse("v1.bundle", "# v1 git bundle", &refs).await,
+            "it isn't a bundle"
+        );
+        assert_eq!(
+            refuse(
+                "filter.bundle",
+                BUNDLE_V3_SIGNATURE,
+                &["@filter=blob:none".to_string()]
+            )
+            .await,
+            "it needs the capability \"filter=blob:none\", which git-sync lacks"
+        );
+        // Capabilities only come with version 3
+        assert_eq!(
+            refuse(
+                "v2caps.bundle",
+                BUNDLE_V2_SIGNATURE,
+                &["@object-format=sha1".to_string()]
+            )
+            .await,
+            "it has a bad line \"@object-format=sha1\""
+        );
+        assert_eq!(
+            refuse(
+                "short.bundle",
+                BUNDLE_V2_SIGNATURE,
+                &["-1234 bad".to_string()]
+            )
+            .await,
+            "it has a bad line \"-1234 bad\""
+        );
+        assert_eq!(
+            refuse("noref.bundle", BUNDLE_V2_SIGNATURE, &[ONE.to_string()]).await,
+            format!("it has a bad line {:?}", ONE)
+        );
+        assert_eq!(
+            refuse("empty.bundle", BUNDLE_V2_SIGNATURE, &[]).await,
+            "it has no refs"
+        );
+        let path = bundle_path("cut.bundle");
+        std::fs::write(&path, format!("{}\n{} HEAD\n", BUNDLE_V2_SIGNATURE, ONE)).unwrap();
+        assert_eq!(refusal(path).await, "it ends before the pack");
+    }
+
+    #[tokio::test]
+    async fn serves_its_pack_when_wanted() {
+        let path = write_bundle(
+            "serve.bundle.zst",
+            BUNDLE_V2_SIGNATURE,
+            &[
+                format!("{} HEAD", TWO),
+                format!("{} refs/heads/main", TWO),
+                format!("{} refs/tags/v1", ONE),
+            ],
+        )
+        .await;
+        let mut transport = Bundle::open(&path).await.unwrap().serve();
+        let advert = RefAdvertisement::read_from(transport.reader())
+            .await
+            .unwrap();
+        assert_eq!(advert.refs().len(), 3);
+        assert_eq!(advert.symrefs()["HEAD"], "refs/heads/main");
+        assert!(advert.caps().contains_key(&Capability::SideBand64K));
+
+        let want = format!("want {} side-band-64k ofs-delta\n", TWO);
+        ProtocolLine::write_str(transport.writer(), want)
+            .await
+            .unwrap();
+        ProtocolLine::Flush
+            .write_to(transport.writer())
+            .await
+            .unwrap();
+        let have = format!("have {}\n", ONE);
+        ProtocolLine::write_str(transport.writer(), have)
+            .await
+            .unwrap();
+        ProtocolLine::write_str(transport.writer(), "done\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            ProtocolLine::read_from(transport.reader(), true)
+                .await
+                .unwrap(),
+            ProtocolLine::Data(Cow::from(&b"NAK"[..]))
+        );
+        let mut pack = Vec::new();
+        loop {
+            match ProtocolLine::read_from(transport.reader(), false)
+                .await
+                .unwrap()
+            {
+                ProtocolLine::Data(data) => {
+                    assert_eq!(data[0], 1);
+                    pack.extend_from_slice(&data[1..]);
+                }
+                ProtocolLine::Flush => break,
+                line => panic!("Unexpected {:?}", line),
+            }
+        }
+        assert_eq!(pack, EMPTY_PACK);
+        Box::new(transport).shutdown().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_only_its_own_refs() {
+        let path = write_bundle(
+            "theirs.bundle",
+            BUNDLE_V2_SIGNATURE,
+            &[format!("{} refs/heads/main", TWO)],
+        )
+        .await;
+        let mut transport = Bundle::open(&path).await.unwrap().serve();
+        RefAdvertisement::read_from(transport.reader())
+            .await
+            .unwrap();
+        ProtocolLine::write_str(transport.writer(), format!("want {}\n", ONE))
+            .await
+            .unwrap();
+        ProtocolLine::Flush
+            .write_to(transport.writer())
+            .await
+            .unwrap();
+        ProtocolLine::write_str(transport.writer(), "done\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            ProtocolLine::read_from(transport.reader(), true)
+                .await
+                .unwrap(),
+            ProtocolLine::Data(Cow::from(
+                format!("ERR upload-pack: not our ref {}", ONE).into_bytes()
+            ))
+        );
+        Box::new(transport).shutdown().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Push `commands` into a bundle at `path` based on `basis`, sending `pack`,
+    /// and return the report, read from the side-band if `band`
+    async fn push(
+        path: &Path,
+        basis: &[(&str, &str)],
+        commands: &[String],
+        pack: &[u8],
+        band: bool,
+    ) -> ReportStatus {
+        let basis = basis
+            .iter()
+            .map(|(refname, object)| (refname.to_string(), object.to_string()))
+            .collect();
+        let mut transport = Bundle::receive(path, basis);
+        RefAdvertisement::read_from(transport.reader())
+            .await
+            .unwrap();
+        let caps = if band {
+            "report-status side-band-64k"
+        } else {
+            "report-status"
+        };
+        for (idx, command) in commands.iter().enumerate() {
+            let line = match idx {
+                0 => format!("{}\0{}", command, caps),
+                _ => command.clone(),
+            };
+            ProtocolLine::write_str(transport.writer(), line)
+                .await
+                .unwrap();
+        }
+        ProtocolLine::Flush
+            .write_to(transport.writer())
+            .await
+            .unwrap();
+        transport.writer().write_all(pack).await.unwrap();
+        let report = if band {
+            let mut parser = ReportParser::new();
+            loop {
+                match ProtocolLine::read_from(transport.reader(), false)
+                    .await
+                    .unwrap()
+                {
+                    ProtocolLine::Data(data) => {
+                        assert_eq!(data[0], 1);
+                        parser.feed(&data[1..]).unwrap();
+                    }
+                    ProtocolLine::Flush => break,
+                    line => panic!("Unexpected {:?}", line),
+                }
+            }
+            parser.finish().unwrap()
+        } else {
+            ReportStatus::read_from(transport.reader()).await.unwrap()
+        };
+        Box::new(transport).shutdown().await.unwrap();
+        report
+    }
+
+    fn partial(path: &Path) -> PathBuf {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        PathBuf::from(partial)
+    }
+
+    #[tokio::test]
+    async fn writes_what_is_pushed() {
+        let path = bundle_path("pushed.bundle.gz");
+        let report = push(
+            &path,
+            &[
+                ("refs/heads/main", ONE),
+                ("refs/tags/v1", THREE),
+                ("refs/tags/v1^{}", ONE),
+                ("refs/tags/light", ONE),
+            ],
+            &[
+                format!("{} {} refs/heads/main", ONE, TWO),
+                format!("{} {} refs/heads/new", NULLSHA, TWO),
+            ],
+            EMPTY_PACK,
+            true,
+        )
+        .await;
+        assert_eq!(report.unpack, Ok(()));
+        assert_eq!(report.refs["refs/heads/main"], RefStatus::Ok);
+        assert_eq!(report.refs["refs/heads/new"], RefStatus::Ok);
+        assert!(!partial(&path).exists());
+
+        let bundle = Bundle::open(&path).await.unwrap();
+        // A tag is needed as what it peels to, and a tag without a peeled line
+        // isn't needed at all
+        assert_eq!(bundle.prerequisites(), [ONE]);
+        let refs: Vec<_> = bundle
+            .refs()
+            .iter()
+            .map(|(refname, object)| (refname.as_str(), object.as_str()))
+            .collect();
+        assert_eq!(
+            refs,
+            [
+                ("refs/heads/main", TWO),
+                ("refs/heads/new", TWO),
+                ("refs/tags/light", ONE),
+                ("refs/tags/v1", THREE),
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_an_empty_pack_for_deletions() {
+        let path = bundle_path("deleted.bundle");
+        let basis = [("refs/heads/main", ONE), ("refs/heads/old", TWO)];
+        let delete = format!("{} {} refs/heads/old", TWO, NULLSHA);
+        let report = push(&path, &basis, &[delete], b"", false).await;
+        assert_eq!(report.refs["refs/heads/old"], RefStatus::Ok);
+        let bundle = Bundle::open(&path).await.unwrap();
+        assert_eq!(
+            bundle.refs(),
+            [("refs/heads/main".to_string(), ONE.to_string())]
+        );
+        assert_eq!(bundle.prerequisites(), [ONE, TWO]);
+        let mut pack = Vec::new();
+        bundle
+            .open_pack()
+            .await
+            .unwrap()
+            .read_to_end(&mut pack)
+            .await
+            .unwrap();
+        assert_eq!(pack, EMPTY_PACK);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_to_delete_every_ref() {
+        let path = bundle_path("nothing.bundle");
+        let delete = format!("{} {} refs/heads/main", ONE, NULLSHA);
+        let report = push(&path, &[("refs/heads/main", ONE)], &[delete], b"", false).await;
+        assert_eq!(report.unpack, Err("a bundle must have refs".to_string()));
+        assert_eq!(
+            report.refs["refs/heads/main"],
+            RefStatus::Rejected("unpacker error".into())
+        );
+        assert!(!path.exists());
+        assert!(!partial(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn writes_nothing_of_a_pack_cut_short() {
+        let path = bundle_path("cut-short.bundle");
+        let mut transport = Bundle::receive(&path, Vec::new());
+        RefAdvertisement::read_from(transport.reader())
+            .await
+            .unwrap();
+        let create = format!("{} {} refs/heads/main\0report-status", NULLSHA, ONE);
+        ProtocolLine::write_str(transport.writer(), create)
+            .await
+            .unwrap();
+        ProtocolLine::Flush
+            .write_to(transport.writer())
+            .await
+            .unwrap();
+        transport
+            .writer()
+            .write_all(&EMPTY_PACK[..EMPTY_PACK.len() - 1])
+            .await
+            .unwrap();
+        transport.writer().shutdown().await.unwrap();
+        // The receiving end goes without a report once it's given up
+        let mut rest = Vec::new();
+        transport.reader().read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        assert!(!path.exists());
+        assert!(!partial(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn reads_bases_from_lists_of_refs() {
+        let path = bundle_path("basis.txt");
+        std::fs::write(
+            &path,
+            format!(
+                "{one} HEAD\n{one}\trefs/heads/main\n\n{two} refs/tags/v1\n{one} refs/tags/v1^{{}}\n",
+                one = ONE,
+                two = TWO
+            ),
+        )
+        .unwrap();
+        let basis = read_bundle_basis(&path).await.unwrap();
+        assert_eq!(
+            basis,
+            [
+                ("refs/heads/main".to_string(), ONE.to_string()),
+                ("refs/tags/v1".to_string(), TWO.to_string()),
+                ("refs/tags/v1^{}".to_string(), ONE.to_string()),
+            ]
+        );
+        std::fs::write(&path, "refs/heads/main\n").unwrap();
+        let err = read_bundle_basis(&path).await.unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "Cannot base a bundle on {}: it has a bad line \"refs/heads/main\"",
+                path.display()
+            )
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
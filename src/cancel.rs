@@ -0,0 +1,64 @@
+/// Cooperative cancellation of long-running operations such as a sync
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// A token which can be cloned and handed to anything which should stop when asked.
+/// Cancelling any clone cancels them all.
+///
+/// ```
+/// # use git_sync::CancellationToken;
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let token = CancellationToken::new();
+/// let waiter = token.clone();
+/// let task = tokio::spawn(async move { waiter.cancelled().await });
+/// assert!(!token.is_cancelled());
+/// token.cancel();
+/// task.await.unwrap();
+/// assert!(token.is_cancelled());
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        let (sender, receiver) = watch::channel(false);
+        CancellationToken {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    /// Ask everything holding this token to stop
+    pub fn cancel(&self) {
+        // We hold a receiver ourselves, so this can't fail
+        let _ = self.sender.send(true);
+    }
+
+    /// Whether cancellation has been requested
+    pub fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Wait until cancellation is requested
+    pub async fn cancelled(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                // The sender is gone, so cancellation can never happen
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
@@ -0,0 +1,151 @@
+/// Signed push certificates
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use super::{Error, RefUpdate};
+
+/// The means by which a push certificate is signed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Signer {
+    /// Sign with `gpg` using the given key id
+    Gpg(String),
+    /// Sign with `ssh-keygen -Y sign` using the given key file
+    Ssh(PathBuf),
+}
+
+impl FromStr for Signer {
+    type Err = String;
+
+    /// Parse a signer of the form `gpg:<keyid>` or `ssh:<keyfile>`, where a bare
+    /// value is taken to be a GPG key id
+    ///
+    /// ```
+    /// # use git_sync::Signer;
+    /// # use std::path::PathBuf;
+    /// assert_eq!("gpg:ABCD1234".parse(), Ok(Signer::Gpg("ABCD1234".into())));
+    /// assert_eq!(
+    ///     "ssh:/home/me/.ssh/id_ed25519".parse(),
+    ///     Ok(Signer::Ssh(PathBuf::from("/home/me/.ssh/id_ed25519")))
+    /// );
+    /// assert_eq!("ABCD1234".parse(), Ok(Signer::Gpg("ABCD1234".into())));
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(key) = s.strip_prefix("gpg:") {
+            Ok(Signer::Gpg(key.to_string()))
+        } else if let Some(keyfile) = s.strip_prefix("ssh:") {
+            Ok(Signer::Ssh(PathBuf::from(keyfile)))
+        } else if s.is_empty() {
+            Err("Empty signing key".to_string())
+        } else {
+            Ok(Signer::Gpg(s.to_string()))
+        }
+    }
+}
+
+impl Signer {
+    /// Produce an armored detached signature of the given payload
+    pub async fn sign(&self, payload: &[u8]) -> Result<String, Error> {
+        let mut cmd = match self {
+            Signer::Gpg(key) => {
+                let mut cmd = Command::new("gpg");
+                cmd.arg("-bsau").arg(key);
+                cmd
+            }
+            Signer::Ssh(keyfile) => {
+                let mut cmd = Command::new("ssh-keygen");
+                cmd.args(["-Y", "sign", "-n", "git", "-f"]).arg(keyfile);
+                cmd
+            }
+        };
+        let mut child = cmd
+            .kill_on_drop(true)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        {
+            let mut stdin = child.stdin.take().expect("Did not get a stdin handle?");
+            stdin.write_all(payload).await?;
+        }
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(Error::ChildFailed {
+                command: "Signing the push certificate".to_string(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        if output.stdout.is_empty() {
+            return Err(Error::Protocol(
+                "The signer produced no signature".to_string(),
+            ));
+        }
+        String::from_utf8(output.stdout)
+            .map_err(|_| Error::Protocol("Signature was not valid UTF-8".to_string()))
+    }
+}
+
+/// The content of a push certificate, prior to signing
+pub struct PushCert<'a> {
+    /// The identity of the pusher, as `Name <email> timestamp tz`
+    pub pusher: &'a str,
+    /// The repository being pushed to, if known
+    pub pushee: Option<&'a str>,
+    /// The nonce offered by receive-pack in its `push-cert` capability
+    pub nonce: &'a str,
+    /// The updates being certified
+    pub updates: &'a [RefUpdate],
+}
+
+impl PushCert<'_> {
+    /// Render the unsigned certificate text
+    pub fn render(&self) -> String {
+        let mut cert = String::from("certificate version 0.1\n");
+        cert.push_str(&format!("pusher {}\n", self.pusher));
+        if let Some(pushee) = self.pushee {
+            cert.push_str(&format!("pushee {}\n", pushee));
+        }
+        cert.push_str(&format!("nonce {}\n", self.nonce));
+        cert.push('\n');
+        for update in self.updates {
+            cert.push_str(&update.command());
+            cert.push('\n');
+        }
+        cert
+    }
+
+    /// Render the certificate and append a signature from the given signer
+    pub async fn sign(&self, signer: &Signer) -> Result<String, Error> {
+        let mut cert = self.render();
+        let signature = signer.sign(cert.as_bytes()).await?;
+        cert.push_str(&signature);
+        if !cert.ends_with('\n') {
+            cert.push('\n');
+        }
+        Ok(cert)
+    }
+}
+
+/// Retrieve the local committer identity (with timestamp) for use as a pusher
+pub async fn committer_ident() -> Result<String, Error> {
+    let output = Command::new("git")
+        .args(["var", "GIT_COMMITTER_IDENT"])
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(Error::ChildFailed {
+            command: "Determining the committer identity".to_string(),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end()
+        .to_string())
+}
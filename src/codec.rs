@@ -0,0 +1,307 @@
+/// Compressing the files syncs write, and decompressing those they read
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use async_compression::tokio_03::bufread::{GzipDecoder, ZstdDecoder};
+use async_compression::tokio_03::write::{GzipEncoder, ZstdEncoder};
+use tokio::fs::File;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite};
+use tokio::io::{AsyncWriteExt, BufReader};
+
+use super::Error;
+
+/// What a gzip stream starts with
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// What a zstd frame starts with
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// How a file is compressed, if it is
+///
+/// ```
+/// # use git_sync::Codec;
+/// # use std::path::Path;
+/// assert_eq!("zstd".parse::<Codec>().unwrap(), Codec::Zstd);
+/// assert!("lzma".parse::<Codec>().is_err());
+/// assert_eq!(Codec::for_path(Path::new("backup.bundle.gz")), Codec::Gzip);
+/// assert_eq!(Codec::for_path(Path::new("backup.bundle")), Codec::None);
+/// assert_eq!(Codec::detect(b"# v2 git bundle\n"), Codec::None);
+/// assert_eq!(Codec::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0]), Codec::Zstd);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    /// The codec a file's name asks for: gzip for `.gz`, zstd for `.zst`, and
+    /// otherwise none
+    pub fn for_path(path: &Path) -> Codec {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Codec::Gzip,
+            Some("zst") => Codec::Zstd,
+            _ => Codec::None,
+        }
+    }
+
+    /// The codec a file starting with `start` was compressed with
+    pub fn detect(start: &[u8]) -> Codec {
+        if start.starts_with(GZIP_MAGIC) {
+            Codec::Gzip
+        } else if start.starts_with(ZSTD_MAGIC) {
+            Codec::Zstd
+        } else {
+            Codec::None
+        }
+    }
+
+    /// What's added to the name of a file compressed with the codec
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::None => "",
+            Codec::Gzip => ".gz",
+            Codec::Zstd => ".zst",
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Codec, Error> {
+        match s {
+            "none" => Ok(Codec::None),
+            "gzip" => Ok(Codec::Gzip),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(Error::Config(format!(
+                "Unknown compression {}: expected none, gzip or zstd",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::None => "none",
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        })
+    }
+}
+
+/// A file being written through a codec, compressed as it goes.  It must be
+/// finished for the compressed stream to be whole.
+///
+/// ```
+/// # use git_sync::{open_decompressed, Codec, CompressedFile};
+/// # use tokio::io::{AsyncReadExt, AsyncWriteExt};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let path = std::env::temp_dir().join(format!("compressed-{}.zst", std::process::id()));
+/// let mut file = CompressedFile::create(&path, Codec::Zstd).await.unwrap();
+/// file.write_all(&[b'x'; 10000]).await.unwrap();
+/// file.finish().await.unwrap();
+/// assert!(std::fs::metadata(&path).unwrap().len() < 100);
+///
+/// let mut contents = Vec::new();
+/// open_decompressed(&path, 9990)
+///     .await
+///     .unwrap()
+///     .read_to_end(&mut contents)
+///     .await
+///     .unwrap();
+/// assert_eq!(contents, [b'x'; 10]);
+/// # std::fs::remove_file(&path).unwrap();
+/// # }
+/// ```
+#[derive(Debug)]
+pub enum CompressedFile {
+    None(File),
+    Gzip(GzipEncoder<File>),
+    Zstd(ZstdEncoder<File>),
+}
+
+impl CompressedFile {
+    /// Create the file at `path`, replacing whatever was there, to write through
+    /// `codec`
+    pub async fn create(path: &Path, codec: Codec) -> io::Result<CompressedFile> {
+        Ok(CompressedFile::new(File::create(path).await?, codec))
+    }
+
+    /// Write through `codec` to a file already open
+    pub fn new(file: File, codec: Codec) -> CompressedFile {
+        match codec {
+            Codec::None => CompressedFile::None(file),
+            Codec::Gzip => CompressedFile::Gzip(GzipEncoder::new(file)),
+            Codec::Zstd => CompressedFile::Zstd(ZstdEncoder::new(file)),
+        }
+    }
+
+    /// End the compressed stream and flush it all to the file, returning the file
+    pub async fn finish(mut self) -> io::Result<File> {
+        self.shutdown().await?;
+        Ok(match self {
+            CompressedFile::None(file) => file,
+            CompressedFile::Gzip(encoder) => encoder.into_inner(),
+            CompressedFile::Zstd(encoder) => encoder.into_inner(),
+        })
+    }
+}
+
+impl AsyncWrite for CompressedFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            CompressedFile::None(file) => Pin::new(file).poll_write(cx, buf),
+            CompressedFile::Gzip(encoder) => Pin::new(encoder).poll_write(cx, buf),
+            CompressedFile::Zstd(encoder) => Pin::new(encoder).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            CompressedFile::None(file) => Pin::new(file).poll_flush(cx),
+            CompressedFile::Gzip(encoder) => Pin::new(encoder).poll_flush(cx),
+            CompressedFile::Zstd(encoder) => Pin::new(encoder).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            CompressedFile::None(file) => Pin::new(file).poll_shutdown(cx),
+            CompressedFile::Gzip(encoder) => Pin::new(encoder).poll_shutdown(cx),
+            CompressedFile::Zstd(encoder) => Pin::new(encoder).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A file being read, decompressed if need be
+pub type Decompressed = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Open the file at `path` to read from `offset` bytes into what it holds,
+/// decompressing it if it was compressed with any [`Codec`].  A zstd stream
+/// which was cut short reads as though it ended there, so what's read must be
+/// checked as packs are, by their checksums.
+pub async fn open_decompressed(path: &Path, offset: u64) -> io::Result<Decompressed> {
+    let mut file = File::open(path).await?;
+    let mut start = [0; 4];
+    let mut len = 0;
+    while len < start.len() {
+        match file.read(&mut start[len..]).await? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    let codec = Codec::detect(&start[..len]);
+    // Only a file which isn't compressed can be read from part way through
+    let seek_to = match codec {
+        Codec::None => offset,
+        _ => 0,
+    };
+    file.seek(SeekFrom::Start(seek_to)).await?;
+    let mut reader: Decompressed = match codec {
+        Codec::None => return Ok(Box::new(file)),
+        Codec::Gzip => {
+            let mut decoder = GzipDecoder::new(BufReader::new(file));
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Codec::Zstd => Box::new(ZstdDecoder::new(BufReader::new(file))),
+    };
+    // A compressed stream can only be skipped through
+    let skipped = io::copy(&mut (&mut reader).take(offset), &mut io::sink()).await?;
+    if skipped < offset {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `contents` through `codec` and read them back
+    async fn round_trip(codec: Codec, contents: &[u8], offset: u64) -> io::Result<Vec<u8>> {
+        let path = std::env::temp_dir().join(format!(
+            "git-sync-codec-{}-{}-{}",
+            codec,
+            offset,
+            std::process::id()
+        ));
+        let mut file = CompressedFile::create(&path, codec).await?;
+        // In pieces, as packs arrive
+        for piece in contents.chunks(1000) {
+            file.write_all(piece).await?;
+        }
+        file.finish().await?;
+        let start = std::fs::read(&path)?;
+        assert_eq!(Codec::detect(&start), codec);
+        let mut read = Vec::new();
+        let result = match open_decompressed(&path, offset).await {
+            Ok(mut reader) => reader.read_to_end(&mut read).await.map(|_| read),
+            Err(err) => Err(err),
+        };
+        std::fs::remove_file(&path)?;
+        result
+    }
+
+    #[tokio::test]
+    async fn reads_back_what_it_writes() {
+        let contents: Vec<u8> = (0..100_000_u32).map(|n| (n % 251) as u8).collect();
+        for &codec in &[Codec::None, Codec::Gzip, Codec::Zstd] {
+            assert_eq!(round_trip(codec, &contents, 0).await.unwrap(), contents);
+            assert_eq!(
+                round_trip(codec, &contents, 12345).await.unwrap(),
+                &contents[12345..]
+            );
+            assert_eq!(round_trip(codec, b"", 0).await.unwrap(), b"");
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_offsets_past_the_end() {
+        for &codec in &[Codec::Gzip, Codec::Zstd] {
+            let err = round_trip(codec, b"short", 6).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_no_more_than_is_left_of_truncated_streams() {
+        let contents: Vec<u8> = (0..100_000_u32).map(|n| (n * 7 % 256) as u8).collect();
+        for &codec in &[Codec::Gzip, Codec::Zstd] {
+            let path = std::env::temp_dir().join(format!(
+                "git-sync-codec-cut-{}-{}",
+                codec,
+                std::process::id()
+            ));
+            let mut file = CompressedFile::create(&path, codec).await.unwrap();
+            file.write_all(&contents).await.unwrap();
+            file.finish().await.unwrap();
+            let whole = std::fs::read(&path).unwrap();
+            std::fs::write(&path, &whole[..whole.len() / 2]).unwrap();
+            let mut read = Vec::new();
+            let mut reader = open_decompressed(&path, 0).await.unwrap();
+            let result = reader.read_to_end(&mut read).await;
+            // gzip's trailer is missing, but zstd has nothing to say the stream
+            // didn't end there
+            match codec {
+                Codec::Gzip => assert!(result.is_err()),
+                _ => assert!(read.len() < contents.len() && contents.starts_with(&read)),
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,106 @@
+/// Colouring the messages shown to people, when their terminal can show it
+use std::fmt;
+use std::str::FromStr;
+
+/// When to colour output, as `--color` asks
+///
+/// ```
+/// # use git_sync::ColorChoice;
+/// let choice: ColorChoice = "always".parse().unwrap();
+/// assert!(choice.enabled(false));
+/// assert!(!"never".parse::<ColorChoice>().unwrap().enabled(true));
+/// assert!(!ColorChoice::Auto.enabled(false));
+/// assert!("sometimes".parse::<ColorChoice>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Colour output going to a terminal, unless `NO_COLOR` is set or the
+    /// terminal is dumb
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to colour output going somewhere which is a terminal or not
+    pub fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                is_terminal
+                    && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && std::env::var_os("TERM").is_none_or(|term| term != "dumb")
+            }
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!("Unknown color choice: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ColorChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColorChoice::Auto => "auto",
+            ColorChoice::Always => "always",
+            ColorChoice::Never => "never",
+        })
+    }
+}
+
+/// How a piece of a message is shown, by what it is
+///
+/// ```
+/// # use git_sync::Paint;
+/// assert_eq!(Paint::Stage.paint("[push]", false), "[push]");
+/// assert_eq!(Paint::Error.paint("Error:", true), "\x1b[1;31mError:\x1b[0m");
+/// assert_eq!(Paint::Remote.paint("", true), "");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Paint {
+    /// The stage of a sync a line is about, such as `[fetch]`
+    Stage,
+    /// What a remote said, rather than us
+    Remote,
+    /// An error a remote reported
+    RemoteError,
+    /// Something which was done as asked
+    Success,
+    Warning,
+    /// An error of our own
+    Error,
+}
+
+impl Paint {
+    /// The SGR parameters which select the paint
+    fn sgr(self) -> &'static str {
+        match self {
+            Paint::Stage => "36",
+            Paint::Remote => "2",
+            Paint::RemoteError => "35",
+            Paint::Success => "32",
+            Paint::Warning => "33",
+            Paint::Error => "1;31",
+        }
+    }
+
+    /// `text` in this paint, if colour is enabled, or just as it is if not
+    pub fn paint(self, text: &str, color: bool) -> String {
+        if color && !text.is_empty() {
+            format!("\x1b[{}m{}\x1b[0m", self.sgr(), text)
+        } else {
+            text.to_string()
+        }
+    }
+}
@@ -0,0 +1,47 @@
+/// Compatibility shims for the original free-function API
+///
+/// As the library grows richer types, the functions here keep the signatures which
+/// earlier releases exposed, implemented on top of whatever the current API is, so
+/// that existing users can migrate gradually.
+use std::collections::HashMap;
+
+use tokio::io::{self, AsyncRead, AsyncWrite};
+
+use super::{Capability, RefAdvertisement, SendActivity};
+
+/// Request a pack from upload-pack, as [`request_pack`](crate::request_pack) originally did
+pub async fn request_pack<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    want: impl Iterator<Item = &str>,
+    have: impl Iterator<Item = &str>,
+    caps: impl Iterator<Item = (Capability, Option<&str>)>,
+) -> io::Result<bool>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    Ok(super::request_pack(reader, writer, want, have, caps).await?)
+}
+
+/// Send the ref changes needed to turn `existing` into `target`, as
+/// [`send_refchange`](crate::send_refchange) originally did
+pub async fn send_refchange<W>(
+    writer: &mut W,
+    existing: &HashMap<String, String>,
+    target: &HashMap<String, String>,
+    caps: impl Iterator<Item = (Capability, Option<&str>)>,
+) -> io::Result<SendActivity>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    Ok(super::send_refchange(writer, existing, target, caps).await?)
+}
+
+/// Read a ref advertisement, as [`RefAdvertisement::read_from`] originally did
+pub async fn read_ref_advertisement<R>(reader: &mut R) -> io::Result<RefAdvertisement>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    Ok(RefAdvertisement::read_from(reader).await?)
+}
@@ -0,0 +1,786 @@
+/// Configuration files describing many pairs of repositories to sync
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use super::{Error, Schedule};
+
+/// A value in a configuration file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<ConfigValue>),
+}
+
+impl fmt::Display for ConfigValue {
+    /// Strings and integers are shown bare, as they'd be given on a command line
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigValue::String(s) => f.write_str(s),
+            ConfigValue::Integer(n) => write!(f, "{}", n),
+            ConfigValue::Boolean(b) => write!(f, "{}", b),
+            ConfigValue::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_str("]")
+            }
+        }
+    }
+}
+
+/// The settings in one table of a configuration file, by key
+pub type ConfigTable = BTreeMap<String, ConfigValue>;
+
+/// One pair of repositories to sync, with its settings merged over the global ones
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairConfig {
+    /// The name of the pair, which defaults to its target
+    pub name: String,
+    pub source: String,
+    pub target: String,
+    /// When the daemon should sync the pair, if not at its usual interval
+    pub schedule: Option<Schedule>,
+    /// The remaining settings, which are options for the sync
+    pub settings: ConfigTable,
+}
+
+/// A configuration file listing pairs of repositories to sync, in a subset of
+/// TOML.  Settings at the top of the file apply to every pair, and each
+/// `[[pair]]` table gives a `source` and `target` and optionally a `name` and
+/// settings of its own.  A pair's lists of values, such as patterns, add to the
+/// global ones, unless the pair names them in its `replace` list, and its other
+/// settings replace the global ones.  A `schedule` says when the daemon should
+/// sync the pair, as a [`Schedule`].
+///
+/// Several pairs may sync into one target, each under its own `dest-prefix`, to
+/// gather many sources into one repository.  `{name}` in a `dest-prefix` stands
+/// for the name of the pair, so one global setting such as
+/// `dest-prefix = "refs/mirrors/{name}/"` gives each pair a namespace of its own.
+/// Pairs sharing a target must have prefixes, none of which lies within another,
+/// so that no pair's sync can touch the refs of another.
+///
+/// ```
+/// # use git_sync::{ConfigValue, PairConfig, SyncConfig};
+/// let config: SyncConfig = r#"
+/// ## Settings for every pair
+/// exclude-forge-refs = true
+/// include = ["refs/heads/*", "refs/tags/*"]
+///
+/// [[pair]]
+/// name = "tools"
+/// source = "git://git.example.com/tools.git"
+/// target = "/srv/mirrors/tools.git"
+/// exclude = "refs/heads/wip/*"
+///
+/// [[pair]]
+/// source = "ssh://review.example.com:29418/docs.git"
+/// target = "/srv/mirrors/docs.git"
+/// ssh-identity = '/etc/git-sync/docs_key'
+/// exclude-forge-refs = false
+/// include = ["refs/changes/*"]
+/// refspec = ["refs/meta/config:refs/mirror/config"]
+/// replace = ["include"]
+/// schedule = "*/2 * * * *"
+/// "#
+/// .parse()
+/// .unwrap();
+/// let strings = |values: &[&str]| {
+///     ConfigValue::Array(values.iter().map(|s| ConfigValue::String(s.to_string())).collect())
+/// };
+/// let pairs = config.pairs();
+/// assert_eq!(pairs.len(), 2);
+/// assert_eq!(pairs[0].name, "tools");
+/// assert_eq!(pairs[0].settings["exclude-forge-refs"], ConfigValue::Boolean(true));
+/// assert_eq!(pairs[0].settings["include"], strings(&["refs/heads/*", "refs/tags/*"]));
+/// assert_eq!(pairs[1].name, "/srv/mirrors/docs.git");
+/// assert_eq!(pairs[1].settings["exclude-forge-refs"], ConfigValue::Boolean(false));
+/// assert_eq!(pairs[1].settings["include"], strings(&["refs/changes/*"]));
+/// assert_eq!(pairs[0].schedule, None);
+/// assert_eq!(pairs[1].schedule.as_ref().unwrap().to_string(), "*/2 * * * *");
+///
+/// let err = "[[pair]]\nsource = \"a\"\n".parse::<SyncConfig>().unwrap_err();
+/// assert_eq!(err.to_string(), "Line 1: A pair needs a target");
+///
+/// let backup: SyncConfig = r#"
+/// dest-prefix = "refs/mirrors/{name}/"
+/// [[pair]]
+/// name = "tools"
+/// source = "git://git.example.com/tools.git"
+/// target = "/srv/backup.git"
+/// [[pair]]
+/// name = "docs"
+/// source = "git://git.example.com/docs.git"
+/// target = "/srv/backup.git"
+/// "#
+/// .parse()
+/// .unwrap();
+/// let prefix = |pair: &PairConfig| pair.settings["dest-prefix"].to_string();
+/// assert_eq!(prefix(&backup.pairs()[1]), "refs/mirrors/docs/");
+/// let err = r#"
+/// [[pair]]
+/// name = "tools"
+/// source = "git://git.example.com/tools.git"
+/// target = "/srv/backup.git"
+/// [[pair]]
+/// source = "git://git.example.com/docs.git"
+/// target = "/srv/backup.git"
+/// "#
+/// .parse::<SyncConfig>()
+/// .unwrap_err();
+/// assert_eq!(
+///     err.to_string(),
+///     "Line 6: The pairs tools and /srv/backup.git both sync into /srv/backup.git, so each needs a dest-prefix of its own"
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncConfig {
+    /// The settings which apply to every pair
+    pub defaults: ConfigTable,
+    /// Each pair, with the keys of the global lists it replaces
+    pairs: Vec<(PairConfig, Vec<String>)>,
+}
+
+impl SyncConfig {
+    /// Read a configuration file
+    pub async fn load(path: &Path) -> Result<SyncConfig, Error> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|err| Error::Config(format!("Cannot read {}: {}", path.display(), err)))?;
+        text.parse().map_err(|err| match err {
+            Error::Config(msg) => Error::Config(format!("{}: {}", path.display(), msg)),
+            err => err,
+        })
+    }
+
+    /// The pairs to sync, in the order the file gives them, each with the global
+    /// settings merged in
+    pub fn pairs(&self) -> Vec<PairConfig> {
+        self.pairs
+            .iter()
+            .map(|(pair, replace)| {
+                let mut settings = self.defaults.clone();
+                for (key, value) in &pair.settings {
+                    let value = match (settings.remove(key), value) {
+                        (Some(global), value)
+                            if !replace.contains(key) && (is_array(&global) || is_array(value)) =>
+                        {
+                            let mut values = into_values(global);
+                            values.extend(into_values(value.clone()));
+                            ConfigValue::Array(values)
+                        }
+                        (_, value) => value.clone(),
+                    };
+                    settings.insert(key.clone(), value);
+                }
+                if let Some(ConfigValue::String(prefix)) = settings.get_mut("dest-prefix") {
+                    *prefix = prefix.replace("{name}", &pair.name);
+                }
+                PairConfig {
+                    settings,
+                    ..pair.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Check that pairs which sync into the same target can't touch each other's
+    /// refs, since each only considers those under its own prefix
+    fn check_shared_targets(&self, lines: &[usize]) -> Result<(), Error> {
+        let pairs = self.pairs();
+        let prefix = |pair: &PairConfig| match pair.settings.get("dest-prefix") {
+            Some(ConfigValue::String(prefix)) if prefix.ends_with('/') => Some(prefix.clone()),
+            Some(ConfigValue::String(prefix)) => Some(format!("{}/", prefix)),
+            _ => None,
+        };
+        for (idx, pair) in pairs.iter().enumerate() {
+            let error = |msg: String| Error::Config(format!("Line {}: {}", lines[idx], msg));
+            let uses_name = matches!(
+                self.pairs[idx].0.settings.get("dest-prefix").or(self.defaults.get("dest-prefix")),
+                Some(ConfigValue::String(prefix)) if prefix.contains("{name}")
+            );
+            if uses_name && !is_ref_component(&pair.name) {
+                return Err(error(format!(
+                    "The pair {} needs a name which can be part of a ref name, to use in its dest-prefix",
+                    pair.name
+                )));
+            }
+            for other in pairs[..idx]
+                .iter()
+                .filter(|other| other.target == pair.target)
+            {
+                match (prefix(other), prefix(pair)) {
+                    (Some(a), Some(b)) if !a.starts_with(&b) && !b.starts_with(&a) => {}
+                    (Some(a), Some(b)) => {
+                        return Err(error(format!(
+                            "The pairs {} and {} both sync into {}, under {} and {}, which overlap",
+                            other.name, pair.name, pair.target, a, b
+                        )))
+                    }
+                    _ => {
+                        return Err(error(format!(
+                            "The pairs {} and {} both sync into {}, so each needs a dest-prefix of its own",
+                            other.name, pair.name, pair.target
+                        )))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for SyncConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SyncConfig, Error> {
+        let mut parser = Parser { text: s, pos: 0 };
+        let mut defaults = ConfigTable::new();
+        // Each pair's settings, and the line its table started on
+        let mut tables: Vec<(ConfigTable, usize)> = Vec::new();
+        loop {
+            parser.skip_blank();
+            let line = parser.line();
+            match parser.peek() {
+                None => break,
+                Some('[') => {
+                    let header = parser.rest_of_line();
+                    if strip_comment(header).trim_end() != "[[pair]]" {
+                        return Err(parser.error_at(line, "Only [[pair]] tables are supported"));
+                    }
+                    tables.push((ConfigTable::new(), line));
+                }
+                Some(_) => {
+                    let key = parser.key()?;
+                    parser.expect('=')?;
+                    let value = parser.value()?;
+                    parser.end_of_line()?;
+                    let table = match tables.last_mut() {
+                        Some((table, _)) => table,
+                        None => &mut defaults,
+                    };
+                    if table.insert(key.clone(), value).is_some() {
+                        return Err(parser.error_at(line, &format!("{} is set twice", key)));
+                    }
+                }
+            }
+        }
+
+        let schedule = |text: String, line: Option<usize>| {
+            text.parse::<Schedule>().map_err(|msg| match line {
+                Some(line) => parser.error_at(line, &msg),
+                None => Error::Config(msg),
+            })
+        };
+        let default_schedule = match defaults.remove("schedule") {
+            Some(ConfigValue::String(text)) => Some(schedule(text, None)?),
+            Some(_) => return Err(Error::Config("The schedule must be a string".into())),
+            None => None,
+        };
+        let mut pairs = Vec::new();
+        let lines: Vec<usize> = tables.iter().map(|(_, line)| *line).collect();
+        for (mut settings, line) in tables {
+            let mut take = |key: &str| match settings.remove(key) {
+                Some(ConfigValue::String(s)) => Ok(Some(s)),
+                Some(_) => Err(parser.error_at(line, &format!("The {} must be a string", key))),
+                None => Ok(None),
+            };
+            let source =
+                take("source")?.ok_or_else(|| parser.error_at(line, "A pair needs a source"))?;
+            let target =
+                take("target")?.ok_or_else(|| parser.error_at(line, "A pair needs a target"))?;
+            let name = take("name")?.unwrap_or_else(|| target.clone());
+            let schedule = match take("schedule")? {
+                Some(text) => Some(schedule(text, Some(line))?),
+                None => default_schedule.clone(),
+            };
+            let replace = match settings.remove("replace").map(into_values) {
+                Some(values) => values
+                    .into_iter()
+                    .map(|value| match value {
+                        ConfigValue::String(key) => Ok(key),
+                        _ => Err(parser.error_at(line, "The replace list must be of keys")),
+                    })
+                    .collect::<Result<_, _>>()?,
+                None => vec![],
+            };
+            if pairs
+                .iter()
+                .any(|(pair, _): &(PairConfig, _)| pair.name == name)
+            {
+                return Err(parser.error_at(line, &format!("There are two pairs named {}", name)));
+            }
+            pairs.push((
+                PairConfig {
+                    name,
+                    source,
+                    target,
+                    schedule,
+                    settings,
+                },
+                replace,
+            ));
+        }
+        for key in &["source", "target", "name", "replace"] {
+            if defaults.contains_key(*key) {
+                return Err(Error::Config(format!(
+                    "The {} must be set in a [[pair]]",
+                    key
+                )));
+            }
+        }
+        let config = SyncConfig { defaults, pairs };
+        config.check_shared_targets(&lines)?;
+        Ok(config)
+    }
+}
+
+/// Whether a name can be used as a part of a ref name, such as a namespace
+fn is_ref_component(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(['.', '/', '-'])
+        && !name.ends_with(['.', '/'])
+        && !name.contains("..")
+        && !name.contains("//")
+        && !name.ends_with(".lock")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c))
+}
+
+fn is_array(value: &ConfigValue) -> bool {
+    matches!(value, ConfigValue::Array(_))
+}
+
+/// The values of an array, or a single value as if it were an array of one
+fn into_values(value: ConfigValue) -> Vec<ConfigValue> {
+    match value {
+        ConfigValue::Array(values) => values,
+        value => vec![value],
+    }
+}
+
+/// Drop a comment from the end of a line which has no strings in it
+fn strip_comment(line: &str) -> &str {
+    match line.find('#') {
+        Some(idx) => &line[..idx],
+        None => line,
+    }
+}
+
+/// Reads keys and values from the text of a configuration file
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    /// The line the parser has reached, counting from 1
+    fn line(&self) -> usize {
+        self.text[..self.pos].matches('\n').count() + 1
+    }
+
+    fn error_at(&self, line: usize, msg: &str) -> Error {
+        Error::Config(format!("Line {}: {}", line, msg))
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        self.error_at(self.line(), msg)
+    }
+
+    /// Skip spaces and tabs, and a comment if one follows them
+    fn skip_space(&mut self) {
+        while let Some(' ') | Some('\t') = self.peek() {
+            self.bump();
+        }
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip whitespace, including line breaks, and comments
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_space();
+            match self.peek() {
+                Some('\n') | Some('\r') => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn rest_of_line(&mut self) -> &'a str {
+        let start = self.pos;
+        while !matches!(self.peek(), None | Some('\n')) {
+            self.bump();
+        }
+        &self.text[start..self.pos]
+    }
+
+    fn end_of_line(&mut self) -> Result<(), Error> {
+        self.skip_space();
+        match self.peek() {
+            None | Some('\n') | Some('\r') => Ok(()),
+            Some(c) => Err(self.error(&format!("Unexpected {:?} after value", c))),
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), Error> {
+        self.skip_space();
+        // Report the line the character was missing from, not the next one
+        let line = self.line();
+        match self.bump() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(self.error_at(line, &format!("Expected {:?}", expected))),
+        }
+    }
+
+    /// A bare key, made of letters, digits, `-` and `_`
+    fn key(&mut self) -> Result<String, Error> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        if start == self.pos {
+            return Err(self.error("Expected a key"));
+        }
+        Ok(self.text[start..self.pos].to_string())
+    }
+
+    fn value(&mut self) -> Result<ConfigValue, Error> {
+        self.skip_space();
+        match self.peek() {
+            Some('"') => self.basic_string().map(ConfigValue::String),
+            Some('\'') => self.literal_string().map(ConfigValue::String),
+            Some('[') => self.array(),
+            Some(_) => {
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '_' {
+                        self.bump();
+                    } else {
+                        break;
+                    }
+                }
+                match &self.text[start..self.pos] {
+                    "true" => Ok(ConfigValue::Boolean(true)),
+                    "false" => Ok(ConfigValue::Boolean(false)),
+                    word => word
+                        .replace('_', "")
+                        .parse()
+                        .map(ConfigValue::Integer)
+                        .map_err(|_| {
+                            self.error(&format!("Cannot understand the value {:?}", word))
+                        }),
+                }
+            }
+            None => Err(self.error("Expected a value")),
+        }
+    }
+
+    /// A string in double quotes, with backslash escapes
+    fn basic_string(&mut self) -> Result<String, Error> {
+        let line = self.line();
+        self.bump();
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(match self.bump() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('u') => self.unicode_escape()?,
+                    _ => return Err(self.error("Unknown escape in string")),
+                }),
+                None | Some('\n') => return Err(self.error_at(line, "Unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, Error> {
+        let digits = self.text.get(self.pos..self.pos + 4).unwrap_or("");
+        let c = u32::from_str_radix(digits, 16)
+            .ok()
+            .and_then(std::char::from_u32)
+            .ok_or_else(|| self.error("Bad \\u escape in string"))?;
+        self.pos += 4;
+        Ok(c)
+    }
+
+    /// A string in single quotes, taken literally
+    fn literal_string(&mut self) -> Result<String, Error> {
+        let line = self.line();
+        self.bump();
+        let start = self.pos;
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(self.text[start..self.pos - 1].to_string()),
+                None | Some('\n') => return Err(self.error_at(line, "Unterminated string")),
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// An array of values, which may span several lines
+    fn array(&mut self) -> Result<ConfigValue, Error> {
+        self.bump();
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(ConfigValue::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_blank();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(ConfigValue::Array(values)),
+                _ => return Err(self.error("Expected ',' or ']' in array")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<SyncConfig, String> {
+        text.parse::<SyncConfig>().map_err(|err| err.to_string())
+    }
+
+    /// The settings of the only pair in `text`, after a `[[pair]]` header
+    fn settings(text: &str) -> ConfigTable {
+        let config = parse(&format!(
+            "[[pair]]\nsource = \"a\"\ntarget = \"b\"\n{}",
+            text
+        ))
+        .unwrap();
+        config.pairs().remove(0).settings
+    }
+
+    fn string(s: &str) -> ConfigValue {
+        ConfigValue::String(s.to_string())
+    }
+
+    #[test]
+    fn values() {
+        let settings = settings(
+            r#"
+basic = "tab\there \"quoted\" \\ \u00e9"
+literal = 'C:\repos\no escapes'
+number = -1_000
+yes = true   # and a comment
+list = [
+    "one",  # first
+    'two',
+    [3, false],
+]
+empty = []
+"#,
+        );
+        assert_eq!(settings["basic"], string("tab\there \"quoted\" \\ \u{e9}"));
+        assert_eq!(settings["literal"], string(r"C:\repos\no escapes"));
+        assert_eq!(settings["number"], ConfigValue::Integer(-1000));
+        assert_eq!(settings["yes"], ConfigValue::Boolean(true));
+        assert_eq!(
+            settings["list"],
+            ConfigValue::Array(vec![
+                string("one"),
+                string("two"),
+                ConfigValue::Array(vec![ConfigValue::Integer(3), ConfigValue::Boolean(false)]),
+            ])
+        );
+        assert_eq!(settings["list"].to_string(), "[one, two, [3, false]]");
+        assert_eq!(settings["empty"], ConfigValue::Array(vec![]));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let errors = [
+            ("key = \"open\n", "Line 1: Unterminated string"),
+            ("key = 'open\n", "Line 1: Unterminated string"),
+            ("key = \"\\q\"\n", "Line 1: Unknown escape in string"),
+            ("key = \"\\u12\"\n", "Line 1: Bad \\u escape in string"),
+            (
+                "key = maybe\n",
+                "Line 1: Cannot understand the value \"maybe\"",
+            ),
+            ("key = 1 2\n", "Line 1: Unexpected '2' after value"),
+            ("key\n", "Line 1: Expected '='"),
+            ("= 1\n", "Line 1: Expected a key"),
+            ("key =\n", "Line 1: Cannot understand the value \"\""),
+            ("key = [1 2]\n", "Line 1: Expected ',' or ']' in array"),
+            ("\n[pair]\n", "Line 2: Only [[pair]] tables are supported"),
+            ("a = 1\n\na = 2\n", "Line 3: a is set twice"),
+        ];
+        for (text, error) in &errors {
+            assert_eq!(parse(text).unwrap_err(), *error, "parsing {:?}", text);
+        }
+    }
+
+    #[test]
+    fn pair_errors() {
+        let errors = [
+            ("[[pair]]\ntarget = \"b\"\n", "Line 1: A pair needs a source"),
+            (
+                "[[pair]]\nsource = 1\ntarget = \"b\"\n",
+                "Line 1: The source must be a string",
+            ),
+            (
+                "source = \"a\"\n[[pair]]\nsource = \"a\"\ntarget = \"b\"\n",
+                "The source must be set in a [[pair]]",
+            ),
+            (
+                "[[pair]]\nsource = \"a\"\ntarget = \"b\"\nreplace = [1]\n",
+                "Line 1: The replace list must be of keys",
+            ),
+            (
+                "[[pair]]\nsource = \"a\"\ntarget = \"b\"\n[[pair]]\nsource = \"c\"\ntarget = \"d\"\nname = \"b\"\n",
+                "Line 4: There are two pairs named b",
+            ),
+            ("schedule = 5\n", "The schedule must be a string"),
+            (
+                "[[pair]]\nsource = \"a\"\ntarget = \"b\"\nschedule = \"61 * * * *\"\n",
+                "Line 1: \"61\" is out of range in schedule",
+            ),
+        ];
+        for (text, error) in &errors {
+            assert_eq!(parse(text).unwrap_err(), *error, "parsing {:?}", text);
+        }
+    }
+
+    #[test]
+    fn merges_pairs_over_the_defaults() {
+        let config = parse(
+            r#"
+include = ["refs/heads/*"]
+exclude = ["refs/heads/wip/*"]
+depth = 1
+schedule = "0 * * * *"
+
+[[pair]]
+name = "adds"
+source = "a"
+target = "b"
+include = "refs/tags/*"
+depth = 2
+
+[[pair]]
+name = "replaces"
+source = "c"
+target = "d"
+exclude = "refs/heads/tmp/*"
+replace = ["exclude"]
+schedule = "30 * * * *"
+"#,
+        )
+        .unwrap();
+        let pairs = config.pairs();
+        // A single value is taken as a list of one when merged with a list
+        assert_eq!(
+            pairs[0].settings["include"],
+            ConfigValue::Array(vec![string("refs/heads/*"), string("refs/tags/*")])
+        );
+        assert_eq!(pairs[0].settings["exclude"], config.defaults["exclude"]);
+        assert_eq!(pairs[0].settings["depth"], ConfigValue::Integer(2));
+        assert_eq!(pairs[0].schedule.as_ref().unwrap().to_string(), "0 * * * *");
+        assert_eq!(pairs[1].settings["include"], config.defaults["include"]);
+        assert_eq!(pairs[1].settings["exclude"], string("refs/heads/tmp/*"));
+        assert_eq!(pairs[1].settings["depth"], ConfigValue::Integer(1));
+        assert!(!pairs[1].settings.contains_key("replace"));
+        assert_eq!(
+            pairs[1].schedule.as_ref().unwrap().to_string(),
+            "30 * * * *"
+        );
+    }
+
+    #[test]
+    fn keeps_pairs_sharing_a_target_apart() {
+        let pair = |name: &str, prefix: &str| {
+            format!(
+                "[[pair]]\nname = \"{}\"\nsource = \"{}\"\ntarget = \"shared\"\ndest-prefix = \"{}\"\n",
+                name, name, prefix
+            )
+        };
+        let config = parse(&(pair("a", "refs/a") + &pair("b", "refs/b/"))).unwrap();
+        assert_eq!(config.pairs().len(), 2);
+        // refs/a/ and refs/ab/ are apart, even though one string starts the other
+        parse(&(pair("a", "refs/a") + &pair("ab", "refs/ab"))).unwrap();
+        assert_eq!(
+            parse(&(pair("a", "refs/a/") + &pair("inner", "refs/a/inner/"))).unwrap_err(),
+            "Line 6: The pairs a and inner both sync into shared, under refs/a/ and refs/a/inner/, which overlap"
+        );
+        assert_eq!(
+            parse("dest-prefix = \"refs/{name}/\"\n[[pair]]\nname = \"../up\"\nsource = \"a\"\ntarget = \"b\"\n")
+                .unwrap_err(),
+            "Line 2: The pair ../up needs a name which can be part of a ref name, to use in its dest-prefix"
+        );
+    }
+
+    #[test]
+    fn names_ref_components() {
+        for name in &["tools", "team/tools", "v1.2", "under_score"] {
+            assert!(is_ref_component(name), "{}", name);
+        }
+        for name in &[
+            "",
+            ".hidden",
+            "-flag",
+            "trailing/",
+            "a..b",
+            "a//b",
+            "x.lock",
+            "sp ace",
+            "/abs",
+        ] {
+            assert!(!is_ref_component(name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn names_the_file_in_errors() {
+        let path =
+            std::env::temp_dir().join(format!("git-sync-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "[[pair]]\nsource = \"a\"\n").unwrap();
+        let err = SyncConfig::load(&path).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{}: Line 1: A pair needs a target", path.display())
+        );
+        std::fs::remove_file(&path).unwrap();
+        let err = SyncConfig::load(&path).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Cannot read {}", path.display())));
+    }
+}
@@ -0,0 +1,147 @@
+/// Comparing the refs of two repositories
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use super::RefUpdate;
+
+/// How a ref in the source compares with the ref of the same name in the target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefState {
+    /// Both have the ref, at the same object
+    Same(String),
+    /// Both have the ref, at different objects
+    Differs { source: String, target: String },
+    /// Only the source has the ref
+    Missing(String),
+    /// Only the target has the ref
+    Extra(String),
+}
+
+impl RefState {
+    /// A single word describing the state
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RefState::Same(_) => "same",
+            RefState::Differs { .. } => "differs",
+            RefState::Missing(_) => "missing",
+            RefState::Extra(_) => "extra",
+        }
+    }
+
+    /// The object the ref names in the source, if the source has it
+    pub fn source(&self) -> Option<&str> {
+        match self {
+            RefState::Same(sha) | RefState::Missing(sha) => Some(sha),
+            RefState::Differs { source, .. } => Some(source),
+            RefState::Extra(_) => None,
+        }
+    }
+
+    /// The object the ref names in the target, if the target has it
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            RefState::Same(sha) | RefState::Extra(sha) => Some(sha),
+            RefState::Differs { target, .. } => Some(target),
+            RefState::Missing(_) => None,
+        }
+    }
+}
+
+/// How one ref compares between a source and a target
+///
+/// Each is displayed as a tab-separated line of the state, the ref name, and the
+/// objects it names in the source and the target, with `-` where it's absent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefDiff {
+    pub refname: String,
+    pub state: RefState,
+}
+
+impl fmt::Display for RefDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}",
+            self.state.as_str(),
+            self.refname,
+            self.state.source().unwrap_or("-"),
+            self.state.target().unwrap_or("-")
+        )
+    }
+}
+
+impl From<&RefUpdate> for RefDiff {
+    /// How a ref differs, given the update which would bring the target into line
+    fn from(update: &RefUpdate) -> Self {
+        let state = if update.is_create() {
+            RefState::Missing(update.newsha.clone())
+        } else if update.is_delete() {
+            RefState::Extra(update.oldsha.clone())
+        } else {
+            RefState::Differs {
+                source: update.newsha.clone(),
+                target: update.oldsha.clone(),
+            }
+        };
+        RefDiff {
+            refname: update.refname.clone(),
+            state,
+        }
+    }
+}
+
+/// Compare the refs of a source and a target, in order of ref name.  Only refs
+/// under `refs/` are compared, so `HEAD` and peeled tags are left out.
+///
+/// ```
+/// # use std::collections::HashMap;
+/// # use git_sync::{diff_refs, RefState};
+/// let sha = |c: char| c.to_string().repeat(40);
+/// let source: HashMap<_, _> = vec![
+///     ("refs/heads/main".to_string(), sha('a')),
+///     ("refs/heads/new".to_string(), sha('b')),
+///     ("refs/tags/v1^{}".to_string(), sha('c')),
+/// ]
+/// .into_iter()
+/// .collect();
+/// let target: HashMap<_, _> = vec![
+///     ("refs/heads/main".to_string(), sha('d')),
+///     ("refs/heads/old".to_string(), sha('e')),
+/// ]
+/// .into_iter()
+/// .collect();
+/// let diffs = diff_refs(&source, &target);
+/// assert_eq!(diffs.len(), 3);
+/// assert_eq!(diffs[0].state.as_str(), "differs");
+/// assert_eq!(diffs[1].state, RefState::Missing(sha('b')));
+/// assert_eq!(diffs[2].to_string(), format!("extra\trefs/heads/old\t-\t{}", sha('e')));
+/// ```
+pub fn diff_refs(
+    source: &HashMap<String, String>,
+    target: &HashMap<String, String>,
+) -> Vec<RefDiff> {
+    let names: BTreeSet<&String> = source
+        .keys()
+        .chain(target.keys())
+        .filter(|name| name.starts_with("refs/") && !name.ends_with("^{}"))
+        .collect();
+    names
+        .into_iter()
+        .map(|name| {
+            let state = match (source.get(name), target.get(name)) {
+                (Some(source), Some(target)) if source == target => RefState::Same(source.clone()),
+                (Some(source), Some(target)) => RefState::Differs {
+                    source: source.clone(),
+                    target: target.clone(),
+                },
+                (Some(source), None) => RefState::Missing(source.clone()),
+                (None, Some(target)) => RefState::Extra(target.clone()),
+                (None, None) => unreachable!("Ref {} came from neither side", name),
+            };
+            RefDiff {
+                refname: name.clone(),
+                state,
+            }
+        })
+        .collect()
+}
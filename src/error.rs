@@ -0,0 +1,206 @@
+/// The errors reported by the library
+use std::fmt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+use tokio::io;
+
+use super::{Capability, ExitReason, RefOutcome};
+
+/// Everything which can go wrong while talking to remotes and syncing between them
+///
+/// ```
+/// # use git_sync::{Capability, Error};
+/// let err = Error::MissingCapability(Capability::Atomic);
+/// assert_eq!(err.to_string(), "The remote does not support atomic");
+/// // Errors can be turned back into I/O errors where that's all a caller can return
+/// let err: std::io::Error = Error::Cancelled.into();
+/// assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+/// ```
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to a stream failed
+    Io(io::Error),
+    /// The other end didn't follow the git protocol
+    Protocol(String),
+    /// The remote reported an error of its own, with an `ERR` packet
+    Remote(String),
+    /// The remote doesn't offer a capability which we need
+    MissingCapability(Capability),
+    /// The target rejected some of the ref updates pushed to it
+    RefsRejected(Vec<RefOutcome>),
+    /// We wouldn't do something because a policy forbids it, such as the limit
+    /// on deletions
+    Refused(String),
+    /// A remote couldn't be reached
+    Transport(String),
+    /// A program we ran failed
+    ChildFailed {
+        /// What the program was doing
+        command: String,
+        status: ExitStatus,
+        /// The end of what the program wrote to its stderr
+        stderr: String,
+    },
+    /// The options given can't work together, or with the remotes given
+    Config(String),
+    /// Something took longer than permitted
+    TimedOut(Timeout),
+    /// The operation was cancelled
+    Cancelled,
+    /// Another sync into the same target holds this lock file
+    Locked(PathBuf),
+    /// This many of the target's refs aren't as a sync would leave them
+    Drifted(usize),
+    /// Some of the pairs of a configuration file failed, the first of them for
+    /// the reason given
+    PairsFailed {
+        failed: usize,
+        total: usize,
+        reason: ExitReason,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => err.fmt(f),
+            Error::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            Error::Remote(msg) => write!(f, "The remote reported an error: {}", msg),
+            Error::MissingCapability(cap) => {
+                write!(f, "The remote does not support {}", cap.as_str())
+            }
+            Error::RefsRejected(outcomes) => {
+                write!(f, "{} ref update(s) were rejected", outcomes.len())
+            }
+            Error::Transport(msg) | Error::Refused(msg) | Error::Config(msg) => f.write_str(msg),
+            Error::TimedOut(timeout) => timeout.fmt(f),
+            Error::ChildFailed {
+                command,
+                status,
+                stderr,
+            } => {
+                write!(f, "{} failed: {}", command, status)?;
+                match stderr.trim_end() {
+                    "" => Ok(()),
+                    stderr => write!(f, "\n{}", stderr),
+                }
+            }
+            Error::Cancelled => f.write_str("Cancelled"),
+            Error::Locked(path) => write!(
+                f,
+                "Another sync into the target is in progress, holding {}",
+                path.display()
+            ),
+            Error::Drifted(count) => {
+                write!(f, "{} ref(s) in the target differ from the source", count)
+            }
+            Error::PairsFailed { failed, total, .. } => {
+                write!(f, "{} of {} pair(s) failed", failed, total)
+            }
+        }
+    }
+}
+
+impl Error {
+    /// Whether what failed may well work if it's tried again, as when a remote
+    /// refuses or drops the connection, or ssh fails to connect (exiting 255)
+    ///
+    /// ```
+    /// # use git_sync::Error;
+    /// # use std::os::unix::process::ExitStatusExt;
+    /// # use std::process::ExitStatus;
+    /// assert!(Error::Transport("Unable to connect".to_string()).is_transient());
+    /// let err = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+    /// assert!(Error::Io(err).is_transient());
+    /// let ssh = |code| Error::ChildFailed {
+    ///     command: "ssh".to_string(),
+    ///     status: ExitStatus::from_raw(code << 8),
+    ///     stderr: String::new(),
+    /// };
+    /// assert!(ssh(255).is_transient());
+    /// assert!(!ssh(128).is_transient());
+    /// assert!(!Error::Protocol("Unexpected flush".to_string()).is_transient());
+    /// ```
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(err) => match err.get_ref().and_then(|err| err.downcast_ref::<Error>()) {
+                Some(err) => err.is_transient(),
+                None => ExitReason::for_io_error(err) == ExitReason::Transport,
+            },
+            Error::Transport(_) => true,
+            // Trying again won't give a sync which ran out of time any more of it
+            Error::TimedOut(timeout) => !matches!(timeout, Timeout::Sync),
+            Error::ChildFailed { status, .. } => status.code() == Some(255),
+            _ => false,
+        }
+    }
+}
+
+/// Which of the limits on how long a sync's steps may take was exceeded
+///
+/// ```
+/// # use git_sync::{Error, Timeout};
+/// let err = Error::TimedOut(Timeout::Idle);
+/// assert_eq!(err.to_string(), "Timed out relaying the pack, which stopped coming");
+/// assert!(err.is_transient());
+/// assert!(!Error::TimedOut(Timeout::Sync).is_transient());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Timeout {
+    /// A service didn't start and advertise its refs in time
+    Connect {
+        /// The service, e.g. `git-upload-pack`
+        service: String,
+        /// The repository it was started for
+        repo: String,
+    },
+    /// The source didn't start sending a pack in time after being asked for it
+    Negotiation,
+    /// Pack data stopped coming from the source, or going into the target, for
+    /// too long
+    Idle,
+    /// The whole sync took too long
+    Sync,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timeout::Connect { service, repo } => {
+                write!(f, "Timed out starting {} in {}", service, repo)
+            }
+            Timeout::Negotiation => {
+                f.write_str("Timed out waiting for the source to start sending the pack")
+            }
+            Timeout::Idle => f.write_str("Timed out relaying the pack, which stopped coming"),
+            Timeout::Sync => f.write_str("The sync timed out"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            Error::TimedOut(_) => io::Error::new(io::ErrorKind::TimedOut, err),
+            Error::Cancelled => io::Error::new(io::ErrorKind::Interrupted, err),
+            err => io::Error::other(err),
+        }
+    }
+}
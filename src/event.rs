@@ -0,0 +1,103 @@
+/// Structured reports of a sync's progress, for embedders to display as they wish
+use std::fmt;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use super::{
+    Capability, Maintenance, ProgressUpdate, RefAdvertisement, RefOutcome, RefUpdate, SyncOutcome,
+    SyncPlan,
+};
+
+/// Which side of a sync something happened on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncSide {
+    Source,
+    Target,
+}
+
+impl fmt::Display for SyncSide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SyncSide::Source => "source",
+            SyncSide::Target => "target",
+        })
+    }
+}
+
+/// Something which happened during a sync
+#[derive(Debug, Clone)]
+pub enum SyncEvent {
+    /// A service advertised its refs and capabilities
+    AdvertisementRead(SyncSide, RefAdvertisement),
+    /// We asked a service for these capabilities, when requesting a pack from the
+    /// source or sending ref updates to the target
+    CapabilitiesRequested(SyncSide, Vec<(Capability, Option<String>)>),
+    /// A non-fast-forward update will not be pushed, for the given reason
+    Refused(RefUpdate, String),
+    /// The ref updates to push have been worked out
+    PlanComputed(SyncPlan),
+    /// A batch of updates is about to be pushed
+    BatchStarted {
+        /// Which batch this is, counting from 1
+        batch: usize,
+        /// How many batches there are
+        batches: usize,
+        /// How many updates are in this batch
+        updates: usize,
+    },
+    /// The number of pack bytes relayed from the source to the target so far in
+    /// the current batch
+    PackBytes(u64),
+    /// How many objects the pack being relayed in the current batch has, as its
+    /// header says, once enough of it has arrived to tell
+    PackObjects(u64),
+    /// A progress message from a service
+    RemoteProgress(SyncSide, String),
+    /// A step of a service's progress, parsed from its progress messages, each of
+    /// which is also sent as it was written as [`SyncEvent::RemoteProgress`]
+    Progress(SyncSide, ProgressUpdate),
+    /// An error message from a service
+    RemoteError(SyncSide, String),
+    /// A line a service process wrote to its stderr
+    ServiceStderr(SyncSide, String),
+    /// Something which is worth knowing about, but doesn't stop the sync
+    Warning(String),
+    /// The target didn't exist, so was created as a bare repository
+    TargetCreated {
+        /// The object format it uses, e.g. `sha1`
+        object_format: String,
+        /// The branch its `HEAD` was pointed at, to match the source's
+        head: Option<String>,
+    },
+    /// The target's refs were packed
+    RefsPacked,
+    /// A housekeeping task was done in the target
+    Maintained(Maintenance),
+    /// A symbolic ref (e.g. `HEAD`) in the target was pointed at a ref
+    HeadSet(String, String),
+    /// The target reported what happened to a ref update
+    RefResult(RefOutcome),
+    /// An attempt at the sync failed before any ref updates were sent, in a way
+    /// which may not happen again, so it will be tried again after a delay
+    Retrying {
+        /// Which attempt failed, counting from 1
+        attempt: usize,
+        /// How many times the sync may be tried again in all
+        retries: usize,
+        /// How long until the next attempt
+        delay: Duration,
+        /// Why the attempt failed
+        error: String,
+    },
+    /// The sync is over
+    Completed(SyncOutcome),
+    /// The sync was cancelled before it finished
+    Cancelled,
+}
+
+/// Where a sync sends its events
+pub type SyncEventSender = mpsc::UnboundedSender<SyncEvent>;
+
+/// Where events from a sync are received
+pub type SyncEventReceiver = mpsc::UnboundedReceiver<SyncEvent>;
@@ -0,0 +1,126 @@
+/// Why a command finished as it did, as a status for scripts to act on
+use std::fmt;
+
+use tokio::io;
+
+use super::Error;
+
+/// The reason a command finished, each with its own exit status, so that
+/// scripts can tell a flaky network from a sync which needs a person's attention
+///
+/// ```
+/// # use git_sync::{Error, ExitReason};
+/// let err: std::io::Error = Error::Transport("Unable to connect".to_string()).into();
+/// assert_eq!(ExitReason::for_io_error(&err), ExitReason::Transport);
+/// assert_eq!(ExitReason::Transport.code(), 2);
+///
+/// let err = Error::Refused("Refusing to delete 9 of 10 refs".to_string());
+/// assert_eq!(ExitReason::for_error(&err).code(), 4);
+///
+/// let err = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+/// assert_eq!(ExitReason::for_io_error(&err), ExitReason::Transport);
+///
+/// let err: std::io::Error = Error::Drifted(3).into();
+/// assert_eq!(ExitReason::for_io_error(&err).code(), 8);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExitReason {
+    /// Everything asked for was done
+    Success,
+    /// The target rejected some of the ref updates pushed to it
+    Partial,
+    /// A remote couldn't be reached, or the connection to it failed
+    Transport,
+    /// A remote didn't speak the git protocol as expected, reported an error, or
+    /// lacks a capability which is needed
+    Protocol,
+    /// Something was not done because a policy forbids it, such as the limit on
+    /// deletions or refusing non-fast-forward updates
+    Refused,
+    /// The command line or configuration can't work
+    Config,
+    /// Another sync into the same target is in progress
+    Locked,
+    /// The target's refs aren't as a sync would leave them
+    Drifted,
+    /// Something else went wrong
+    Failed,
+    /// The command was interrupted or terminated
+    Cancelled,
+}
+
+impl ExitReason {
+    /// The status to exit with
+    pub fn code(self) -> i32 {
+        match self {
+            ExitReason::Success => 0,
+            ExitReason::Partial => 1,
+            ExitReason::Transport => 2,
+            ExitReason::Protocol => 3,
+            ExitReason::Refused => 4,
+            ExitReason::Config => 5,
+            ExitReason::Locked => 6,
+            ExitReason::Failed => 7,
+            ExitReason::Drifted => 8,
+            // As shells report a command killed by SIGINT
+            ExitReason::Cancelled => 130,
+        }
+    }
+
+    /// Why a command which failed with `err` did so
+    pub fn for_error(err: &Error) -> ExitReason {
+        match err {
+            Error::Io(err) => ExitReason::for_io_error(err),
+            Error::RefsRejected(_) => ExitReason::Partial,
+            Error::Transport(_) | Error::ChildFailed { .. } | Error::TimedOut(_) => {
+                ExitReason::Transport
+            }
+            Error::Protocol(_) | Error::Remote(_) | Error::MissingCapability(_) => {
+                ExitReason::Protocol
+            }
+            Error::Refused(_) => ExitReason::Refused,
+            Error::Config(_) => ExitReason::Config,
+            Error::Locked(_) => ExitReason::Locked,
+            Error::Drifted(_) => ExitReason::Drifted,
+            Error::Cancelled => ExitReason::Cancelled,
+            Error::PairsFailed { reason, .. } => *reason,
+        }
+    }
+
+    /// Why a command which failed with `err` did so, looking inside it for an
+    /// [`Error`] if there is one, or otherwise going by its kind
+    pub fn for_io_error(err: &io::Error) -> ExitReason {
+        if let Some(err) = err.get_ref().and_then(|err| err.downcast_ref::<Error>()) {
+            return ExitReason::for_error(err);
+        }
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut => ExitReason::Transport,
+            io::ErrorKind::Interrupted => ExitReason::Cancelled,
+            _ => ExitReason::Failed,
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExitReason::Success => "success",
+            ExitReason::Partial => "partial",
+            ExitReason::Transport => "transport",
+            ExitReason::Protocol => "protocol",
+            ExitReason::Refused => "refused",
+            ExitReason::Config => "config",
+            ExitReason::Locked => "locked",
+            ExitReason::Drifted => "drifted",
+            ExitReason::Failed => "failed",
+            ExitReason::Cancelled => "cancelled",
+        })
+    }
+}
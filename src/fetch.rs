@@ -1,7 +1,8 @@
 /// Stuff to do with the fetch protocol
-use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite};
 
 use super::Capability;
+use super::Error;
 use super::ProtocolLine;
 
 pub async fn request_pack<R, W>(
@@ -10,10 +11,10 @@ pub async fn request_pack<R, W>(
     want: impl Iterator<Item = &str>,
     have: impl Iterator<Item = &str>,
     caps: impl Iterator<Item = (Capability, Option<&str>)>,
-) -> io::Result<bool>
+) -> Result<bool, Error>
 where
-    R: AsyncRead + Unpin,
-    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
 {
     // To request a pack from the remote end we need to send wants and haves.  With our first want, we send our capability list.
     // It's an error to have a capability list include the multi-ack or multi-ack-detailed capabilities for now because we do not
@@ -43,11 +44,17 @@ where
         ProtocolLine::write_str(writer, format!("have {}", sha)).await?;
     }
     ProtocolLine::write_str(writer, "done").await?;
-    // Since we deliberately sent no multi-ack, we expect to read a NAK packet now
+    // Since we deliberately sent no multi-ack, we expect to read a single NAK packet now,
+    // or a single ACK if the remote found one of our haves in common
     match ProtocolLine::read_from(reader, true).await? {
-        ProtocolLine::Data(cow) if cow == (b"NAK" as &[u8]) => {}
+        ProtocolLine::Data(cow) if cow == (b"NAK" as &[u8]) || cow.starts_with(b"ACK ") => {}
+        ProtocolLine::Data(cow) if cow.starts_with(b"ERR ") => {
+            return Err(Error::Remote(
+                String::from_utf8_lossy(&cow[4..]).into_owned(),
+            ));
+        }
         _ => {
-            return Err(io::Error::new(io::ErrorKind::Other, "NAK packet not found"));
+            return Err(Error::Protocol("NAK packet not found".to_string()));
         }
     }
     // We're ready now
@@ -0,0 +1,240 @@
+/// Running commands of the user's before and after each sync, much as git runs hooks
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use super::{Error, RefChangeKind, RefUpdate};
+
+/// How long a hook may run before it's killed, so that a stuck hook can't hold
+/// up the sync for ever
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// A shell command run before or after a sync.  Its environment describes the
+/// sync, and the ref updates it's about are written to its stdin one per line,
+/// as `<old> <new> <ref>`, as git gives them to its `pre-receive` and
+/// `post-receive` hooks.
+///
+/// ```
+/// # use git_sync::{Hook, RefUpdate};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let update = RefUpdate {
+///     refname: "refs/heads/main".to_string(),
+///     oldsha: "0000000000000000000000000000000000000000".to_string(),
+///     newsha: "1111111111111111111111111111111111111111".to_string(),
+/// };
+/// let hook = Hook::new(r#"test "$GIT_SYNC_PAIR $GIT_SYNC_CREATE" = "docs 1" && grep -q ' refs/heads/main$'"#)
+///     .env("GIT_SYNC_PAIR", "docs");
+/// hook.run(&Hook::update_counts(&[update.clone()]), &[update]).await.unwrap();
+/// assert!(Hook::new("exit 1").run(&[], &[]).await.is_err());
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    command: String,
+    env: Vec<(String, String)>,
+}
+
+impl Hook {
+    /// A hook which runs `command` with `sh -c`
+    pub fn new(command: impl Into<String>) -> Hook {
+        Hook {
+            command: command.into(),
+            env: Vec::new(),
+        }
+    }
+
+    /// Set `key` to `value` in the environment of every run of the hook, e.g. to
+    /// name the pair of repositories being synced
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Hook {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// The command the hook runs
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Run the hook with `vars` in its environment as well, and `updates` on its
+    /// stdin, failing unless it exits successfully within [`HOOK_TIMEOUT`]
+    pub async fn run(&self, vars: &[(&str, String)], updates: &[RefUpdate]) -> Result<(), Error> {
+        let name = format!("The hook `{}`", self.command);
+        let env: Vec<(&str, &str)> = self
+            .env
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(vars.iter().map(|(key, value)| (*key, value.as_str())))
+            .collect();
+        let lines: String = updates
+            .iter()
+            .map(|update| format!("{} {} {}\n", update.oldsha, update.newsha, update.refname))
+            .collect();
+        let run = run_shell(&name, &self.command, &env, lines.as_bytes());
+        tokio::time::timeout(HOOK_TIMEOUT, run)
+            .await
+            .map_err(|_| Error::Transport(format!("{} took too long", name)))?
+    }
+
+    /// Variables giving how many of `updates` create, update and delete refs:
+    /// `GIT_SYNC_CREATE`, `GIT_SYNC_UPDATE` and `GIT_SYNC_DELETE`
+    pub fn update_counts(updates: &[RefUpdate]) -> Vec<(&'static str, String)> {
+        let count = |kind| {
+            updates
+                .iter()
+                .filter(|update| update.kind() == kind)
+                .count()
+                .to_string()
+        };
+        vec![
+            ("GIT_SYNC_CREATE", count(RefChangeKind::Create)),
+            ("GIT_SYNC_UPDATE", count(RefChangeKind::Update)),
+            ("GIT_SYNC_DELETE", count(RefChangeKind::Delete)),
+        ]
+    }
+}
+
+/// Run `command` with `sh -c`, with `env` in its environment and `input` on its
+/// stdin, failing unless it exits successfully.  `name` says what the command
+/// is in the error if it fails.  The command is killed if the future is dropped,
+/// so a timeout around it stops the command too.
+pub async fn run_shell(
+    name: &str,
+    command: &str,
+    env: &[(&str, &str)],
+    input: &[u8],
+) -> Result<(), Error> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .kill_on_drop(true)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    for (key, value) in env {
+        cmd.env(key, value);
+    }
+    let mut child = cmd.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command which doesn't care for its input may not read it
+        let _ = stdin.write_all(input).await;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(Error::ChildFailed {
+            command: name.to_string(),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::NULLSHA;
+
+    const ONE: &str = "1111111111111111111111111111111111111111";
+    const TWO: &str = "2222222222222222222222222222222222222222";
+
+    fn update(refname: &str, oldsha: &str, newsha: &str) -> RefUpdate {
+        RefUpdate {
+            refname: refname.to_string(),
+            oldsha: oldsha.to_string(),
+            newsha: newsha.to_string(),
+        }
+    }
+
+    /// Somewhere for a hook to write what it's given, which the test should remove
+    fn output_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("git-sync-hook-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn counts_each_kind_of_update() {
+        let updates = [
+            update("refs/heads/new", NULLSHA, ONE),
+            update("refs/heads/also-new", NULLSHA, TWO),
+            update("refs/heads/main", ONE, TWO),
+            update("refs/heads/gone", ONE, NULLSHA),
+        ];
+        assert_eq!(
+            Hook::update_counts(&updates),
+            vec![
+                ("GIT_SYNC_CREATE", "2".to_string()),
+                ("GIT_SYNC_UPDATE", "1".to_string()),
+                ("GIT_SYNC_DELETE", "1".to_string()),
+            ]
+        );
+        assert_eq!(
+            Hook::update_counts(&[]),
+            vec![
+                ("GIT_SYNC_CREATE", "0".to_string()),
+                ("GIT_SYNC_UPDATE", "0".to_string()),
+                ("GIT_SYNC_DELETE", "0".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_updates_on_stdin_and_the_sync_in_its_environment() {
+        let path = output_path("stdin");
+        let hook = Hook::new(r#"{ echo "$GIT_SYNC_PAIR $GIT_SYNC_PHASE"; cat; } > "$OUT""#)
+            .env("GIT_SYNC_PAIR", "docs")
+            .env("OUT", path.to_string_lossy());
+        assert_eq!(
+            hook.command(),
+            r#"{ echo "$GIT_SYNC_PAIR $GIT_SYNC_PHASE"; cat; } > "$OUT""#
+        );
+        let updates = [
+            update("refs/heads/main", ONE, TWO),
+            update("refs/tags/v1", NULLSHA, ONE),
+        ];
+        hook.run(&[("GIT_SYNC_PHASE", "post".to_string())], &updates)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!(
+                "docs post\n{one} {two} refs/heads/main\n{null} {one} refs/tags/v1\n",
+                one = ONE,
+                two = TWO,
+                null = NULLSHA
+            )
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn need_not_read_its_input() {
+        // Far more than a pipe holds, which the hook never reads
+        let updates: Vec<_> = (0..5000)
+            .map(|n| update(&format!("refs/heads/branch-{}", n), ONE, TWO))
+            .collect();
+        Hook::new("true").run(&[], &updates).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_with_what_the_hook_said() {
+        let err = Hook::new("echo no syncing today >&2; exit 3")
+            .run(&[], &[])
+            .await
+            .unwrap_err();
+        match err {
+            Error::ChildFailed {
+                command,
+                status,
+                stderr,
+            } => {
+                assert_eq!(command, "The hook `echo no syncing today >&2; exit 3`");
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(stderr, "no syncing today\n");
+            }
+            err => panic!("Unexpected {:?}", err),
+        }
+    }
+}
@@ -0,0 +1,375 @@
+/// Small HTTP servers and clients, enough to tell monitoring systems how we are
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST};
+use hyper::{Method, Request, Response, StatusCode};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::Error;
+
+/// How long a client may take to send its request and be answered
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long sending a request and reading the response may take, so that a
+/// stuck server can't hold up a sync
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What to send back for a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    /// A successful response
+    pub fn ok(content_type: &'static str, body: impl Into<String>) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    /// A plain text response with the given status, e.g. for errors
+    pub fn text(status: u16, body: impl Into<String>) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+}
+
+/// A tokio stream, as hyper reads and writes it
+struct HyperIo<S>(S);
+
+impl<S: AsyncRead + Unpin> hyper::rt::Read for HyperIo<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        mut buf: hyper::rt::ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut bytes = [0; 8192];
+        let len = buf.remaining().min(bytes.len());
+        let mut read = ReadBuf::new(&mut bytes[..len]);
+        match Pin::new(&mut self.0).poll_read(cx, &mut read) {
+            Poll::Ready(Ok(())) => {
+                buf.put_slice(read.filled());
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> hyper::rt::Write for HyperIo<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Answer `GET` and `HEAD` requests on `listener` until the task is dropped,
+/// calling `handler` with the path of each (without any query string).  Each
+/// connection carries a single request.
+///
+/// ```
+/// # use git_sync::{serve_http, HttpResponse};
+/// # use tokio::io::{AsyncReadExt, AsyncWriteExt};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+/// let addr = listener.local_addr().unwrap();
+/// tokio::spawn(serve_http(listener, |path: &str| match path {
+///     "/healthz" => HttpResponse::ok("text/plain", "ok\n"),
+///     _ => HttpResponse::text(404, "Not found\n"),
+/// }));
+/// let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+/// client.write_all(b"GET /healthz?verbose HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+/// let mut response = String::new();
+/// client.read_to_string(&mut response).await.unwrap();
+/// assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+/// assert!(response.ends_with("\r\n\r\nok\n"));
+/// # }
+/// ```
+pub async fn serve_http<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(&str) -> HttpResponse + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            // Running out of file descriptors and the like shouldn't stop us for good
+            Err(_) => {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |request| {
+                let response = respond(&request, &*handler);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            let connection = hyper::server::conn::http1::Builder::new()
+                .keep_alive(false)
+                .serve_connection(HyperIo(stream), service);
+            // A client which goes away early, or is too slow, is no concern of ours
+            let _ = tokio::time::timeout(REQUEST_TIMEOUT, connection).await;
+        });
+    }
+}
+
+/// The response to a request, which hyper leaves the body off for `HEAD`
+fn respond<F>(request: &Request<Incoming>, handler: &F) -> Response<Full<Bytes>>
+where
+    F: Fn(&str) -> HttpResponse,
+{
+    let response = match *request.method() {
+        Method::GET | Method::HEAD => handler(request.uri().path()),
+        _ => HttpResponse::text(405, "Only GET and HEAD are supported\n"),
+    };
+    let mut builder = Response::builder()
+        .status(response.status)
+        .header(CONTENT_TYPE, response.content_type);
+    if response.status == 405 {
+        builder = builder.header("Allow", "GET, HEAD");
+    }
+    builder
+        .body(Full::new(Bytes::from(response.body)))
+        .unwrap_or_else(|_| {
+            let mut response = Response::new(Full::new(Bytes::new()));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        })
+}
+
+/// Send `body` to an `http://` URL with the given method (e.g. `POST`), failing
+/// unless the server answers with a 2xx status within thirty seconds
+pub async fn http_send(
+    method: &str,
+    url: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(), Error> {
+    tokio::time::timeout(SEND_TIMEOUT, send(method, url, content_type, body))
+        .await
+        .map_err(|_| Error::Transport(format!("{} {} took too long", method, url)))?
+}
+
+async fn send(method: &str, url: &str, content_type: &str, body: &str) -> Result<(), Error> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| Error::Config(format!("Only http:// URLs are supported, not {}", url)))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        // A colon inside brackets is part of an IPv6 address, not before a port
+        Some((host, port)) if !port.contains(']') => {
+            let port = port
+                .parse()
+                .map_err(|_| Error::Config(format!("Bad port in {}", url)))?;
+            (host, port)
+        }
+        _ => (authority, 80),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let stream = TcpStream::connect((host, port))
+        .await
+        .map_err(|err| Error::Transport(format!("Cannot connect to {}: {}", authority, err)))?;
+    let failed =
+        |err: hyper::Error| Error::Transport(format!("{} {} failed: {}", method, url, err));
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(HyperIo(stream))
+        .await
+        .map_err(failed)?;
+    tokio::spawn(connection);
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(HOST, authority)
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, body.len())
+        .header(CONNECTION, "close")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .map_err(|err| Error::Config(format!("Cannot send to {}: {}", url, err)))?;
+    let response = sender.send_request(request).await.map_err(failed)?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let detail = match response.into_body().collect().await {
+        Ok(body) => String::from_utf8_lossy(&body.to_bytes()).trim().to_string(),
+        Err(_) => String::new(),
+    };
+    Err(Error::Transport(format!(
+        "{} {} failed: {} {}",
+        method, url, status, detail
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serve paths which echo themselves, returning where
+    async fn echo_server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_http(listener, |path: &str| match path {
+            "/missing" => HttpResponse::text(404, "Not found\n"),
+            path => HttpResponse::ok("text/plain", format!("{}\n", path)),
+        }));
+        addr
+    }
+
+    /// Send `request` as is, returning the whole response
+    async fn exchange(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_get_with_the_path_alone() {
+        let addr = echo_server().await;
+        let response = exchange(addr, "GET /status?pair=x HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("content-type: text/plain\r\n"));
+        assert!(response.ends_with("\r\n\r\n/status\n"));
+        let response = exchange(addr, "GET /missing HTTP/1.0\r\n\r\n").await;
+        assert!(
+            response.starts_with("HTTP/1.0 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_head_without_a_body() {
+        let addr = echo_server().await;
+        let response = exchange(addr, "HEAD /healthz HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("content-length: 9\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn refuses_other_methods() {
+        let addr = echo_server().await;
+        let request = "POST /status HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\nhi";
+        let response = exchange(addr, request).await;
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("allow: GET, HEAD\r\n"));
+        let response = exchange(addr, "DELETE / HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_requests() {
+        let addr = echo_server().await;
+        for request in &[
+            "GET\r\n\r\n",
+            "GET / HTTP/1.1 extra\r\n\r\n",
+            "GET / NOTHTTP\r\n\r\n",
+            "GET / HTTP/1.1\r\nno colon here\r\n\r\n",
+        ] {
+            let response = exchange(addr, request).await;
+            assert!(
+                response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+                "{:?} got {:?}",
+                request,
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_and_checks_the_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for status in &["204 No Content", "500 Internal Server Error"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"{}") {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                let body = "went wrong";
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    if status.starts_with('2') {
+                        0
+                    } else {
+                        body.len()
+                    },
+                    if status.starts_with('2') { "" } else { body }
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+        let url = format!("http://{}/hook", addr);
+        http_send("POST", &url, "application/json", "{}")
+            .await
+            .unwrap();
+        let err = http_send("POST", &url, "application/json", "{}")
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("500 Internal Server Error went wrong"),
+            "{}",
+            err
+        );
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(requests[0].contains("content-type: application/json\r\n"));
+    }
+
+    #[tokio::test]
+    async fn refuses_urls_it_cannot_send_to() {
+        let err = http_send("POST", "https://example.com/", "text/plain", "")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Config(_)), "{}", err);
+        let err = http_send("POST", "http://localhost:http/", "text/plain", "")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Config(_)), "{}", err);
+    }
+}
@@ -0,0 +1,68 @@
+/// Writing JSON for machines to read, such as monitoring systems
+pub use serde_json::Value as Json;
+
+/// Build a JSON object from its members, which are written in the order given
+///
+/// ```
+/// # use git_sync::{json_object, Json};
+/// let value = json_object(vec![
+///     ("name", Json::from("mirror \"one\"")),
+///     ("bytes", Json::from(1234_u64)),
+///     ("ok", Json::from(true)),
+///     ("error", Json::Null),
+///     ("tags", Json::Array(vec![Json::from("v1.0")])),
+/// ]);
+/// assert_eq!(
+///     value.to_string(),
+///     r#"{"name":"mirror \"one\"","bytes":1234,"ok":true,"error":null,"tags":["v1.0"]}"#
+/// );
+/// ```
+pub fn json_object<K: Into<String>>(members: Vec<(K, Json)>) -> Json {
+    Json::Object(
+        members
+            .into_iter()
+            .map(|(key, value)| (key.into(), value))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_control_characters() {
+        let value = Json::from("tab\there\nnul\u{0} bell\u{7} esc\u{1b} del\u{7f}");
+        // DEL isn't a control character as far as JSON is concerned
+        assert_eq!(
+            value.to_string(),
+            "\"tab\\there\\nnul\\u0000 bell\\u0007 esc\\u001b del\u{7f}\""
+        );
+        assert_eq!(Json::from("\\ \"").to_string(), r#""\\ \"""#);
+    }
+
+    #[test]
+    fn writes_non_ascii_as_is() {
+        let value = json_object(vec![("naïve ✓", Json::from("日本語 🦀"))]);
+        assert_eq!(value.to_string(), "{\"naïve ✓\":\"日本語 🦀\"}");
+    }
+
+    #[test]
+    fn keeps_the_order_of_members() {
+        let value = json_object(vec![
+            ("z", Json::from(1_u64)),
+            ("a", Json::from(2_u64)),
+            ("m", Json::from(3_u64)),
+        ]);
+        assert_eq!(value.to_string(), r#"{"z":1,"a":2,"m":3}"#);
+    }
+
+    #[test]
+    fn writes_numbers_json_can_hold() {
+        assert_eq!(Json::from(1.5_f64).to_string(), "1.5");
+        assert_eq!(Json::from(f64::NAN).to_string(), "null");
+        assert_eq!(Json::from(f64::INFINITY).to_string(), "null");
+        assert_eq!(Json::from(u64::MAX).to_string(), u64::MAX.to_string());
+        assert_eq!(Json::from(None::<u64>).to_string(), "null");
+    }
+}
@@ -1,8 +1,93 @@
+mod ancestry;
+mod audit;
+mod bundle;
+mod cancel;
+mod cert;
+mod codec;
+mod color;
+pub mod compat;
+mod config;
+mod diff;
+mod error;
+mod event;
+mod exit;
 mod fetch;
+mod hook;
+mod http;
+mod json;
+mod lock;
+mod logging;
+mod metrics;
+mod notify;
+mod pack;
+mod pattern;
+mod plan;
+mod progress;
 mod protocol;
+mod proxy;
+#[cfg(feature = "quic")]
+mod quic;
+mod refspec;
+mod report;
+mod savepack;
+mod schedule;
 mod send;
+mod spool;
+mod ssh;
+mod state;
+mod summary;
+mod sync;
+mod systemd;
+mod throttle;
+mod transport;
+mod url;
+mod watch;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 pub use protocol::*;
 
+pub use ancestry::*;
+pub use audit::*;
+pub use bundle::*;
+pub use cancel::*;
+pub use cert::*;
+pub use codec::*;
+pub use color::*;
+pub use config::*;
+pub use diff::*;
+pub use error::*;
+pub use event::*;
+pub use exit::*;
 pub use fetch::*;
+pub use hook::*;
+pub use http::*;
+pub use json::*;
+pub use lock::*;
+pub use logging::*;
+pub use metrics::*;
+pub use notify::*;
+pub use pack::*;
+pub use pattern::*;
+pub use plan::*;
+pub use progress::*;
+pub use proxy::*;
+#[cfg(feature = "quic")]
+pub use quic::*;
+pub use refspec::*;
+pub use report::*;
+pub use savepack::*;
+pub use schedule::*;
 pub use send::*;
+pub use spool::*;
+pub use ssh::*;
+pub use state::*;
+pub use summary::*;
+pub use sync::*;
+pub use systemd::*;
+pub use throttle::*;
+pub use transport::*;
+pub use url::*;
+pub use watch::*;
+#[cfg(feature = "websocket")]
+pub use websocket::*;
@@ -0,0 +1,128 @@
+/// Advisory locks which stop two syncs updating the same target at once
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{CancellationToken, Error, RemoteUrl};
+
+/// How often to try again for a lock which another sync holds
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An advisory lock on the target of a sync, held until it's dropped.  The lock
+/// is taken on a file which is left in place afterwards, since removing it could
+/// let two syncs think they both hold the lock.
+///
+/// ```
+/// # use git_sync::{Error, TargetLock};
+/// let path = std::env::temp_dir().join(format!("git-sync-lock-{}", std::process::id()));
+/// let lock = TargetLock::try_acquire(&path).unwrap();
+/// assert!(matches!(TargetLock::try_acquire(&path), Err(Error::Locked(_))));
+/// drop(lock);
+/// assert!(TargetLock::try_acquire(&path).is_ok());
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct TargetLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl TargetLock {
+    /// The lock file for syncs into `url`.  For a repository on this machine it's
+    /// next to the repository, e.g. `/srv/mirror.git.git-sync.lock`; for others it's
+    /// named after the URL, in `git-sync/locks` in `$XDG_RUNTIME_DIR` or
+    /// `git-sync-locks` in the temporary directory.
+    ///
+    /// ```
+    /// # use git_sync::{RemoteUrl, TargetLock};
+    /// # use std::path::Path;
+    /// let url: RemoteUrl = "/nonexistent/mirror.git".parse().unwrap();
+    /// assert_eq!(
+    ///     TargetLock::path_for(&url),
+    ///     Path::new("/nonexistent/mirror.git.git-sync.lock")
+    /// );
+    /// let url: RemoteUrl = "git@example.com:mirror.git".parse().unwrap();
+    /// let path = TargetLock::path_for(&url);
+    /// assert_eq!(path.file_name().unwrap(), "git%40example.com%3Amirror.git.lock");
+    /// ```
+    pub fn path_for(url: &RemoteUrl) -> PathBuf {
+        if let RemoteUrl::Local(path) = url {
+            // Resolve the path so that every way of naming the repository shares a lock
+            let path = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+            if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+                let mut name = name.to_os_string();
+                name.push(".git-sync.lock");
+                return parent.join(name);
+            }
+        }
+        let dir = match env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("git-sync").join("locks"),
+            _ => env::temp_dir().join("git-sync-locks"),
+        };
+        dir.join(format!("{}.lock", escape(&url.to_string())))
+    }
+
+    /// Take the lock at `path`, failing with [`Error::Locked`] if something else
+    /// holds it
+    pub fn try_acquire(path: &Path) -> Result<TargetLock, Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(|err| {
+                Error::Transport(format!("Cannot open lock file {}: {}", path.display(), err))
+            })?;
+        match file.try_lock() {
+            Ok(()) => Ok(TargetLock {
+                _file: file,
+                path: path.to_path_buf(),
+            }),
+            Err(TryLockError::WouldBlock) => Err(Error::Locked(path.to_path_buf())),
+            Err(TryLockError::Error(err)) => Err(Error::Transport(format!(
+                "Cannot lock {}: {}",
+                path.display(),
+                err
+            ))),
+        }
+    }
+
+    /// Take the lock at `path`, waiting for whatever holds it to let it go, unless
+    /// `cancel` is cancelled first
+    pub async fn acquire(path: &Path, cancel: &CancellationToken) -> Result<TargetLock, Error> {
+        loop {
+            match TargetLock::try_acquire(path) {
+                Err(Error::Locked(_)) => {}
+                result => return result,
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+                _ = cancel.cancelled() => return Err(Error::Cancelled),
+            }
+        }
+    }
+
+    /// The lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Make a URL into a file name, escaping anything but letters, digits, `.`, `-`
+/// and `_` as `%` and two hex digits, so that different URLs get different names
+fn escape(url: &str) -> String {
+    let mut escaped = String::new();
+    for byte in url.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' => {
+                escaped.push(byte as char)
+            }
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,98 @@
+/// Writing the library's traces to stderr, for programs which don't have a
+/// subscriber of their own
+use std::io::{self, Write};
+
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use super::Error;
+
+/// Parse which traces to write, as `RUST_LOG` says, e.g.
+/// `info,git_sync::transport=trace`.  A level alone applies to every target, a
+/// target (a module path, such as `git_sync::transport`) alone asks for
+/// everything from it, and a span's name in brackets, such as `[pair]`, asks
+/// for everything within such spans.
+///
+/// ```
+/// # use git_sync::log_filter;
+/// let filter = log_filter("warn,git_sync::transport=trace,git_sync::sync=off").unwrap();
+/// assert_eq!(filter.max_level_hint(), Some(tracing::level_filters::LevelFilter::TRACE));
+/// assert!(log_filter("git_sync=loud").is_err());
+/// ```
+pub fn log_filter(spec: &str) -> Result<EnvFilter, Error> {
+    EnvFilter::builder().parse(spec).map_err(|err| {
+        Error::Config(format!(
+            "Cannot understand the log filter {}: {}",
+            spec, err
+        ))
+    })
+}
+
+/// Write each event which passes `filter` as a line on stderr, with its level,
+/// the spans it's in and its target, followed by its fields as `key=value`,
+/// failing if there's already a subscriber
+pub fn install_logging(filter: EnvFilter) -> Result<(), Error> {
+    install(filter, io::stderr)
+}
+
+/// Write events as [`install_logging`] does, but handing each line, without its
+/// line ending, to `write` rather than writing it to stderr, so that it can be
+/// labelled or held back along with the rest of the output
+pub fn install_logging_with(filter: EnvFilter, write: fn(&str)) -> Result<(), Error> {
+    install(filter, LineWriter { write })
+}
+
+fn install<W>(filter: EnvFilter, writer: W) -> Result<(), Error>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(false)
+        .without_time()
+        .try_init()
+        .map_err(|err| Error::Config(err.to_string()))
+}
+
+/// Hands the lines of each event to a function once the event is written
+#[derive(Debug, Clone, Copy)]
+struct LineWriter {
+    write: fn(&str),
+}
+
+impl<'a> MakeWriter<'a> for LineWriter {
+    type Writer = EventLines;
+
+    fn make_writer(&'a self) -> EventLines {
+        EventLines {
+            write: self.write,
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// An event as it's written, which is handed on line by line once it's done
+struct EventLines {
+    write: fn(&str),
+    buf: Vec<u8>,
+}
+
+impl Write for EventLines {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventLines {
+    fn drop(&mut self) {
+        for line in String::from_utf8_lossy(&self.buf).lines() {
+            (self.write)(line);
+        }
+    }
+}
@@ -1,281 +1,3247 @@
 use tokio::io;
-use tokio::prelude::*;
-use tokio::process::{ChildStdin, ChildStdout, Command};
-use tokio::task::JoinHandle;
+use tokio::net::TcpListener;
+use tokio::process::Command;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Semaphore};
 
-use std::collections::HashSet;
-use std::io::Cursor;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{IsTerminal, Write};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::process::ExitStatus;
+use std::pin::Pin;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use git_sync::*;
 
+use structopt::clap::ErrorKind;
 use structopt::StructOpt;
+use tracing::Instrument;
 
+tokio::task_local! {
+    /// The name of the pair being worked on, when several are worked on at once
+    static LABEL: String;
+    /// The pair of a configuration file being worked on
+    static PAIR: Arc<PairSlot>;
+}
+
+/// A pair of a configuration file being worked on, where its sync records what
+/// it did
+struct PairSlot {
+    /// Where the pair is in the file
+    index: usize,
+    name: String,
+    outcome: Mutex<Option<SyncOutcome>>,
+    /// The lines of output held back until the pair is done, when its output is
+    /// to be grouped, each with whether it's for stderr
+    output: Option<Mutex<Vec<(bool, String)>>>,
+}
+
+/// The label for a line of output, if it needs one
+fn label() -> String {
+    LABEL
+        .try_with(|label| format!("[{}] ", label))
+        .unwrap_or_default()
+}
+
+/// Print a line to stderr, or otherwise to stdout, unless it's to be held back
+/// with the rest of the output of the pair it's about
+fn write_line(stderr: bool, line: String) {
+    let held = PAIR.try_with(|pair| match &pair.output {
+        Some(output) => {
+            output.lock().unwrap().push((stderr, line.clone()));
+            true
+        }
+        None => false,
+    });
+    match held {
+        Ok(true) => {}
+        _ if stderr => eprintln!("{}", line),
+        _ => println!("{}", line),
+    }
+}
+
+/// Print the output held back for a pair, all together
+fn release_output(slot: &PairSlot) {
+    let lines = match &slot.output {
+        Some(output) => std::mem::take(&mut *output.lock().unwrap()),
+        None => return,
+    };
+    let mut stdout = std::io::stdout().lock();
+    let mut stderr = std::io::stderr().lock();
+    for (to_stderr, line) in lines {
+        let _ = match to_stderr {
+            true => writeln!(stderr, "{}", line),
+            false => writeln!(stdout, "{}", line),
+        };
+    }
+}
+
+/// Whether output can only be shown a whole line at a time, since it's labelled
+/// with its pair or held back
+fn whole_lines_only() -> bool {
+    LABEL.try_with(|_| ()).is_ok() || PAIR.try_with(|pair| pair.output.is_some()).unwrap_or(false)
+}
+
+/// Whether stdout carries JSON events, rather than messages for people
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Whether only warnings and errors are to be reported
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Whether to show the progress of pack transfers on stderr
+static PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Whether to colour what's printed to stdout, and to stderr
+static COLOR_STDOUT: AtomicBool = AtomicBool::new(false);
+static COLOR_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Paint some text which is to be printed to stderr, or otherwise to stdout
+fn paint(paint: Paint, text: &str, stderr: bool) -> String {
+    let color = match stderr {
+        true => &COLOR_STDERR,
+        false => &COLOR_STDOUT,
+    };
+    paint.paint(text, color.load(Ordering::Relaxed))
+}
+
+/// Print a line of output, labelled with the pair it's about when needed, unless
+/// stdout is carrying JSON instead or we're to be quiet
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        if !json_output() && !quiet() {
+            write_line(false, format!("{}{}", label(), format_args!($($arg)*)))
+        }
+    };
+}
+
+/// The reports of the syncs done, with the position of their pairs in the
+/// configuration file, when they're to be written to a summary file
+static SUMMARIES: Mutex<Option<Vec<(usize, Json)>>> = Mutex::new(None);
+
+/// Keep a sync's report for the summary file, if there's to be one
+fn keep_summary(summary: &SyncSummary) {
+    if let Some(summaries) = SUMMARIES.lock().unwrap().as_mut() {
+        let (index, pair) = PAIR
+            .try_with(|pair| (pair.index, Some(pair.name.clone())))
+            .unwrap_or_default();
+        let mut report = summary.to_json();
+        if let Json::Object(members) = &mut report {
+            members.shift_insert(0, "pair".to_string(), Json::from(pair));
+        }
+        summaries.push((index, report));
+    }
+}
+
+/// Write the reports kept of the syncs done to a file
+async fn write_summary(
+    path: &Path,
+    started: SystemTime,
+    result: &io::Result<i32>,
+) -> io::Result<()> {
+    let mut syncs = SUMMARIES.lock().unwrap().take().unwrap_or_default();
+    syncs.sort_by_key(|(index, _)| *index);
+    let summary = json_object(vec![
+        ("success", Json::from(matches!(result, Ok(0)))),
+        ("started", unix_time(started)),
+        (
+            "duration",
+            Json::from(started.elapsed().map(|elapsed| elapsed.as_secs_f64()).ok()),
+        ),
+        (
+            "syncs",
+            Json::Array(syncs.into_iter().map(|(_, report)| report).collect()),
+        ),
+        (
+            "exit_code",
+            Json::from(i64::from(match result {
+                Ok(status) => *status,
+                Err(err) => ExitReason::for_io_error(err).code(),
+            })),
+        ),
+        (
+            "error",
+            Json::from(result.as_ref().err().map(|err| err.to_string())),
+        ),
+    ]);
+    tokio::fs::write(path, format!("{}\n", summary))
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Cannot write the summary to {}: {}", path.display(), err),
+            )
+        })
+}
+
+/// Print an event as a line of JSON, saying which pair it's about when working
+/// through a configuration file
+fn print_json(event: &str, fields: Vec<(&str, Json)>) {
+    let mut members = vec![("event", Json::from(event))];
+    if let Ok(pair) = PAIR.try_with(|pair| pair.name.clone()) {
+        members.push(("pair", Json::from(pair)));
+    }
+    members.extend(fields);
+    write_line(false, json_object(members).to_string());
+}
+
+/// Describe a ref update as JSON, with `null` for a missing old or new object
+fn update_json(update: &RefUpdate) -> Vec<(&'static str, Json)> {
+    let sha = |sha: &str| Json::from(Some(sha).filter(|sha| *sha != NULLSHA));
+    vec![
+        ("ref", Json::from(update.refname.as_str())),
+        ("change", Json::from(update.kind().as_str())),
+        ("old", sha(&update.oldsha)),
+        ("new", sha(&update.newsha)),
+    ]
+}
+
+/// Print a line to stderr, labelled with the pair it's about when needed
+macro_rules! errln {
+    ($($arg:tt)*) => {
+        write_line(true, format!("{}{}", label(), format_args!($($arg)*)))
+    };
+}
+
+/// What the exit statuses mean, as `ExitReason` has them
+const EXIT_STATUS: &str = "EXIT STATUS:
+    0    Success
+    1    The target rejected some ref updates
+    2    A remote couldn't be reached, or the connection failed
+    3    A remote broke the git protocol, reported an error, or lacks a capability
+    4    A policy refused an update, e.g. --max-delete or non-fast-forwards
+    5    The command line or configuration is wrong
+    6    Another sync into the target is in progress (with --no-wait)
+    7    Something else went wrong
+    8    verify found refs in the target which differ from the source
+    130  Interrupted or terminated";
+
+/// Sync refs and objects between git repositories without a local copy of either
+#[derive(StructOpt)]
+#[structopt(after_help = EXIT_STATUS)]
+enum Cli {
+    /// Make the target's refs match the source's, sending whatever objects it lacks
+    Sync {
+        #[structopt(flatten)]
+        remotes: RemoteArgs,
+        #[structopt(flatten)]
+        plan: PlanArgs,
+        #[structopt(flatten)]
+        push: PushArgs,
+        #[structopt(flatten)]
+        connect: ConnectArgs,
+        #[structopt(flatten)]
+        output: OutputArgs,
+        #[structopt(flatten)]
+        log: LogArgs,
+    },
+    /// Delete the refs in the target which the source no longer has, without
+    /// creating or updating any, so that no objects are sent
+    Prune {
+        #[structopt(flatten)]
+        remotes: RemoteArgs,
+        #[structopt(flatten)]
+        plan: PlanArgs,
+        #[structopt(flatten)]
+        push: PushArgs,
+        #[structopt(flatten)]
+        connect: ConnectArgs,
+        #[structopt(flatten)]
+        output: OutputArgs,
+        #[structopt(flatten)]
+        log: LogArgs,
+    },
+    /// Show the ref updates a sync would make, without making them
+    Plan {
+        #[structopt(flatten)]
+        remotes: RemoteArgs,
+        #[structopt(flatten)]
+        plan: PlanArgs,
+        #[structopt(flatten)]
+        connect: ConnectArgs,
+        #[structopt(flatten)]
+        output: OutputArgs,
+        #[structopt(flatten)]
+        log: LogArgs,
+    },
+    /// Check that the target's refs are as a sync would leave them, listing any
+    /// which have drifted in the same form as `diff` and failing if there are any
+    Verify {
+        #[structopt(flatten)]
+        remotes: RemoteArgs,
+        #[structopt(flatten)]
+        plan: PlanArgs,
+        #[structopt(flatten)]
+        connect: ConnectArgs,
+        #[structopt(flatten)]
+        log: LogArgs,
+    },
+    /// Compare the refs of two repositories.  Each ref is listed on a tab-separated
+    /// line giving its state (`same`, `differs`, `missing` from the target or `extra`
+    /// in it), its name, and the objects it names in the source and target.
+    Diff {
+        #[structopt(flatten)]
+        remotes: RemoteArgs,
+        /// Leave out refs which are the same in both repositories
+        #[structopt(long = "changed")]
+        changed: bool,
+        /// Exit with status 1 if the repositories' refs differ
+        #[structopt(long = "exit-code")]
+        exit_code: bool,
+        #[structopt(flatten)]
+        connect: ConnectArgs,
+        #[structopt(flatten)]
+        log: LogArgs,
+    },
+    /// Sync every pair of repositories listed in a configuration file, again and
+    /// again until interrupted
+    Daemon {
+        /// The configuration file listing the pairs, as for `sync --config`
+        #[structopt(long = "config")]
+        config: PathBuf,
+        /// How long to wait between syncs of each pair without a `schedule` (a cron
+        /// expression such as `*/2 * * * *`, in UTC), e.g. `30s`, `5m` or `1h`
+        #[structopt(long = "interval", default_value = "5m", parse(try_from_str = parse_duration))]
+        interval: Duration,
+        /// Wait up to this much longer, chosen at random each time, so that daemons
+        /// started together don't sync together.  Defaults to a tenth of the interval.
+        #[structopt(long = "jitter", parse(try_from_str = parse_duration))]
+        jitter: Option<Duration>,
+        /// Work on up to this many pairs at once
+        #[structopt(long = "jobs", default_value = "1")]
+        jobs: NonZeroUsize,
+        /// Hold back the output about each sync until it's done, and then print it
+        /// all together
+        #[structopt(long = "group-output")]
+        group_output: bool,
+        /// Sync pairs whose source is on this machine soon after its refs change,
+        /// as well as at their usual times
+        #[structopt(long = "watch")]
+        watch: bool,
+        /// Serve `/healthz`, the state of each pair as JSON at `/status`, and metrics
+        /// for Prometheus at `/metrics`, over HTTP on this address, e.g. `127.0.0.1:8080`
+        #[structopt(long = "listen")]
+        listen: Option<SocketAddr>,
+        #[structopt(flatten)]
+        log: LogArgs,
+    },
+    /// List the refs a repository advertises
+    LsRemote {
+        /// If set, the repository is on this SSH server
+        #[structopt(long = "server", short = "s")]
+        server: Option<String>,
+        /// Resolve a repository name which is a remote configured in this repository
+        /// to its URL
+        #[structopt(long = "repo", short = "C")]
+        repo: Option<PathBuf>,
+        /// Show the refs which symbolic refs such as `HEAD` point at
+        #[structopt(long = "symref")]
+        symref: bool,
+        /// The repository, as a path or URL, or a remote name with --repo
+        location: String,
+        /// Only list refs matching these patterns, e.g. `refs/heads/*`
+        patterns: Vec<RefPattern>,
+        #[structopt(flatten)]
+        connect: ConnectArgs,
+        #[structopt(flatten)]
+        log: LogArgs,
+    },
+}
+
+/// Which repositories to sync between
 #[derive(StructOpt)]
-struct Cli {
+struct RemoteArgs {
     /// If set, the source is an SSH server
     #[structopt(long = "source-server", short = "s")]
     source_server: Option<String>,
     /// If set, the destination is an SSH server
     #[structopt(long = "dest-server", short = "d")]
     dest_server: Option<String>,
-    /// The source repository
-    source: PathBuf,
-    /// The target repository
-    target: PathBuf,
+    /// Resolve source and target names which are remotes configured in this repository
+    /// to their URLs (the push URL, for the target)
+    #[structopt(long = "repo", short = "C")]
+    repo: Option<PathBuf>,
+    /// Work on every pair of repositories listed in this configuration file, in turn.
+    /// Each `[[pair]]` gives a `source` and `target`, an optional `name`, and any
+    /// other options by their long names (e.g. `ssh-identity = "/path/to/key"` or
+    /// `exclude = ["refs/pull/*"]`); options set at the top of the file apply to
+    /// every pair which doesn't set them itself, except that a pair's lists (such as
+    /// `include`, `exclude` and `refspec`) add to them unless the pair's `replace`
+    /// list names them.  Pairs may share a target if each has a `dest-prefix` of its
+    /// own, in which `{name}` stands for the pair's name, e.g. `dest-prefix =
+    /// "refs/mirrors/{name}"` to gather many sources into one repository.
+    #[structopt(long = "config")]
+    config: Option<PathBuf>,
+    /// With --config, work on up to this many pairs at once, labelling each line of
+    /// output with the name of the pair it's about
+    #[structopt(long = "jobs", default_value = "1")]
+    jobs: NonZeroUsize,
+    /// With --config, carry on with the remaining pairs when one fails, reporting
+    /// the failures at the end
+    #[structopt(long = "keep-going")]
+    keep_going: bool,
+    /// With --config, hold back the output about each pair until it's done, and
+    /// then print it all together, so that pairs worked on at once don't mingle
+    #[structopt(long = "group-output")]
+    group_output: bool,
+    /// The source repository, as a path or URL (`ssh://`, `[user@]host:path`, `file://`,
+    /// `git://`, `ws[s]://` or `ext::<command>`), or a remote name with --repo.  It
+    /// may be a bundle, as `bundle:<path>` or a path ending `.bundle` (or
+    /// `.bundle.gz` or `.bundle.zst` if compressed), whose refs are synced as
+    /// though it were a repository.
+    #[structopt(required_unless = "config", conflicts_with = "config")]
+    source: Option<String>,
+    /// The target repository, as a path or URL, or a remote name with --repo.  It
+    /// may be a bundle to write, as `bundle:<path>` or a path ending `.bundle`,
+    /// holding the refs pushed into it, and those of --bundle-basis.  It's
+    /// compressed with gzip if the path ends `.gz`, or zstd if it ends `.zst`.
+    #[structopt(required_unless = "config", conflicts_with = "config")]
+    target: Option<String>,
 }
-struct Service {
-    handle: JoinHandle<Result<ExitStatus, io::Error>>,
-    reader: ChildStdout,
-    writer: ChildStdin,
+
+/// Which refs to sync, and which updates of them are permitted
+#[derive(StructOpt)]
+struct PlanArgs {
+    /// Permit non-fast-forward updates of any ref
+    #[structopt(long = "force", short = "f")]
+    force: bool,
+    /// Permit non-fast-forward updates of refs matching this pattern (may be repeated)
+    #[structopt(long = "force-ref", number_of_values = 1)]
+    force_refs: Vec<RefPattern>,
+    /// Sync only the refs selected by this refspec, e.g. `+refs/heads/*:refs/remotes/upstream/*`
+    /// (may be repeated).  By default every ref is synced under its own name.
+    #[structopt(long = "refspec", number_of_values = 1)]
+    refspecs: Vec<Refspec>,
+    /// Only sync refs matching this pattern (may be repeated, along with
+    /// --branches-only and --tags-only)
+    #[structopt(long = "include", number_of_values = 1)]
+    include: Vec<RefPattern>,
+    /// Only sync branches (`refs/heads/*`).  Not with --tags-only: give both
+    /// patterns with --include to sync both.
+    #[structopt(long = "branches-only", conflicts_with = "tags-only")]
+    branches_only: bool,
+    /// Only sync tags (`refs/tags/*`)
+    #[structopt(long = "tags-only")]
+    tags_only: bool,
+    /// Never sync refs matching this pattern (may be repeated)
+    #[structopt(long = "exclude", number_of_values = 1)]
+    exclude: Vec<RefPattern>,
+    /// Exclude refs which forges use internally, such as `refs/pull/*`,
+    /// `refs/merge-requests/*`, `refs/changes/*` and `refs/keep-around/*`
+    #[structopt(long = "exclude-forge-refs")]
+    exclude_forge_refs: bool,
+    /// Only sync source refs under this prefix, removing it from their names
+    /// (e.g. `refs/namespaces/foo/`)
+    #[structopt(long = "strip-source-prefix", parse(from_str = ref_prefix))]
+    strip_source_prefix: Option<String>,
+    /// Place synced refs under this prefix in the target (e.g. `refs/namespaces/foo/`),
+    /// leaving refs outside it untouched
+    #[structopt(long = "dest-prefix", parse(from_str = ref_prefix))]
+    dest_prefix: Option<String>,
+    /// Make the target identical to the source, deleting refs it lacks (the default)
+    #[structopt(long = "mirror")]
+    mirror: bool,
+    /// Only create and update refs in the target, never deleting any
+    #[structopt(long = "no-delete", conflicts_with = "mirror")]
+    no_delete: bool,
+    /// Delete tags which the source lacks, even with --no-delete
+    #[structopt(long = "prune-tags")]
+    prune_tags: bool,
+    /// Keep tags which the source lacks, even when mirroring
+    #[structopt(long = "no-prune-tags", conflicts_with = "prune-tags")]
+    no_prune_tags: bool,
+    /// Never delete or force-update refs matching this pattern (may be repeated)
+    #[structopt(long = "protect", number_of_values = 1)]
+    protect: Vec<RefPattern>,
+    /// Abort if more than this many refs (or percentage of the target's refs, e.g. `50%`)
+    /// would be deleted.  A percentage always permits deleting up to 10 refs, so
+    /// that small repositories can rename or drop their few branches.
+    #[structopt(long = "max-delete", default_value = "50%")]
+    max_delete: DeleteLimit,
+    /// Proceed even if more refs would be deleted than --max-delete permits
+    #[structopt(long = "yes-really-delete")]
+    yes_really_delete: bool,
 }
 
-impl Service {
-    pub async fn launch<P>(service: &str, path: P) -> Result<Service, io::Error>
-    where
-        P: AsRef<Path>,
-    {
-        let mut child = Command::new(service)
-            .arg(path.as_ref())
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .spawn()?;
-
-        let reader = child.stdout.take().expect("Did not get a stdout handle?");
-        let writer = child.stdin.take().expect("Did not get a stdin handle?");
-
-        let handle = tokio::spawn(async move { child.wait().await });
-
-        Ok(Service {
-            handle,
-            reader,
-            writer,
-        })
+/// How to push the planned updates
+#[derive(StructOpt)]
+struct PushArgs {
+    /// The minimum number of objects expected in a pack which is needed
+    /// to create or update refs; smaller packs are reported as suspicious
+    #[structopt(long = "min-objects", default_value = "1")]
+    min_objects: u32,
+    /// Abort the sync, rather than warning, if a pack has fewer objects than expected
+    #[structopt(long = "strict-object-check")]
+    strict_object_check: bool,
+    /// Abort the sync if the pack from the source is larger than this, e.g.
+    /// `512M` or `2G`, before the target has the whole of it
+    #[structopt(long = "max-pack-size", parse(try_from_str = parse_size))]
+    max_pack_size: Option<u64>,
+    /// Abort the sync if the pack from the source has more objects than this,
+    /// as soon as its header says so
+    #[structopt(long = "max-objects")]
+    max_objects: Option<u32>,
+    /// Sign the push with a push certificate, using `gpg:<keyid>` or `ssh:<keyfile>`
+    #[structopt(long = "sign-with")]
+    sign_with: Option<Signer>,
+    /// Require an atomic push, failing if the target does not support one.
+    /// By default an atomic push is used only when the target supports it.
+    #[structopt(long = "atomic")]
+    atomic: bool,
+    /// Never request an atomic push
+    #[structopt(long = "no-atomic", conflicts_with = "atomic")]
+    no_atomic: bool,
+    /// Ask the target's receive-pack to suppress its progress output
+    #[structopt(long = "quiet-remote")]
+    quiet_remote: bool,
+    /// Set the target's HEAD to match the source's default branch after syncing
+    #[structopt(long = "set-head")]
+    set_head: bool,
+    /// Push at most this many ref updates per receive-pack session, using a fresh
+    /// pair of sessions (and pack) for each batch
+    #[structopt(long = "batch-size")]
+    batch_size: Option<usize>,
+    /// Pack the target's refs (`git pack-refs --all`) after updating enough of
+    /// them, as --pack-refs-threshold says
+    #[structopt(long = "pack-refs")]
+    pack_refs: bool,
+    /// How many refs a sync must update before --pack-refs packs them, so that
+    /// frequent small syncs don't rewrite every ref each time
+    #[structopt(long = "pack-refs-threshold", default_value = "100")]
+    pack_refs_threshold: usize,
+    /// Create the target with `git init --bare`, locally or over SSH, if there's
+    /// nothing at its path, using the source's object format and default branch
+    #[structopt(long = "create")]
+    create: bool,
+    /// Tidy the target after each successful sync: `update-server-info` for
+    /// targets served over dumb HTTP, or `gc` or `maintenance` to repack them
+    /// when they need it.  May be given more than once.
+    #[structopt(
+        long = "maintenance",
+        number_of_values = 1,
+        possible_values = &["update-server-info", "gc", "maintenance"]
+    )]
+    maintenance: Vec<Maintenance>,
+    /// Don't ask the source for a thin pack
+    #[structopt(long = "no-thin")]
+    no_thin: bool,
+    /// Don't show the progress messages from the source and target, asking them
+    /// not to send any.  This is the default when neither stdout nor stderr is a
+    /// terminal, as under cron or CI.
+    #[structopt(long = "no-remote-progress")]
+    no_remote_progress: bool,
+    /// Show the progress messages from the source and target even when neither
+    /// stdout nor stderr is a terminal, e.g. to have them as `--json` events
+    #[structopt(long = "remote-progress", conflicts_with = "no-remote-progress")]
+    remote_progress: bool,
+    /// Give up if the source hasn't started sending the pack within this long of
+    /// being asked for it, e.g. `5m`
+    #[structopt(long = "negotiation-timeout", parse(try_from_str = parse_duration))]
+    negotiation_timeout: Option<Duration>,
+    /// Give up if the pack stops coming from the source, or going into the
+    /// target, for this long
+    #[structopt(long = "idle-timeout", parse(try_from_str = parse_duration))]
+    idle_timeout: Option<Duration>,
+    /// Give up if the whole sync takes longer than this, retries and all, e.g.
+    /// `1h` (or `3600`)
+    #[structopt(
+        long = "max-duration",
+        alias = "timeout",
+        parse(try_from_str = parse_duration)
+    )]
+    max_duration: Option<Duration>,
+    /// Try the sync again up to this many times if it fails in a way which may
+    /// not happen again, such as a connection being refused or dropped, waiting
+    /// longer each time.  It's only tried again if no ref updates were sent.
+    #[structopt(long = "retries", default_value = "0")]
+    retries: usize,
+    /// Relay pack data no faster than this many bytes a second, e.g. `512K` or
+    /// `10M`, to leave room for others on a shared link
+    #[structopt(long = "max-bandwidth")]
+    max_bandwidth: Option<Bandwidth>,
+    /// Fetch each pack whole into a temporary file in this directory, checking
+    /// its checksum, before pushing it, so that a slow target doesn't hold up
+    /// the source.  A push which fails before the target has the whole pack is
+    /// tried again (with --retries) from the file, without fetching it again.
+    #[structopt(long = "spool")]
+    spool: Option<PathBuf>,
+    /// Compress the packs spooled with --spool: `none`, `gzip` or `zstd`
+    #[structopt(long = "spool-compress", requires = "spool")]
+    spool_compress: Option<Codec>,
+    /// Save a copy of the pack pushed into the target in this file, to look into
+    /// if the target rejects it.  It starts with the ref updates pushed, as `<old>
+    /// <new> <ref>` lines, after `-<object>` lines for the objects the target had
+    /// which the pack may be based on, and a blank line ends them.  It's
+    /// compressed if the file's name ends `.gz` (gzip) or `.zst` (zstd).  It can be
+    /// pushed again with --from-pack.  Not with --batch-size, --also-to or
+    /// --two-way.
+    #[structopt(long = "save-pack")]
+    save_pack: Option<PathBuf>,
+    /// Push the pack saved in this file by --save-pack, rather than fetching one
+    /// from the source, which is named but not contacted.  Each saved ref update
+    /// is pushed if the target's ref is still as it was when the pack was saved,
+    /// and refused if it has moved since.  Not with --batch-size, --spool,
+    /// --save-pack, --set-head, --create, --also-to or --two-way.
+    #[structopt(long = "from-pack")]
+    from_pack: Option<PathBuf>,
+    /// Record the refs of the source and target after each sync which wholly
+    /// succeeds in a file in this directory, one for each pair and its options.
+    /// The next sync leaves the target alone, without connecting to it, if the
+    /// source's refs haven't changed, and otherwise tells the source that the
+    /// target has the commits recorded, so it can send a smaller pack.  Anything
+    /// else pushing into the target is only put right once the state is older
+    /// than --state-max-age.  Not with --from-pack, --also-to or --two-way.
+    #[structopt(long = "state-dir")]
+    state_dir: Option<PathBuf>,
+    /// How old a state recorded with --state-dir may be before the next sync
+    /// connects to the target anyway and compares its refs afresh, putting
+    /// right anything else which changed them
+    #[structopt(long = "state-max-age", default_value = "1d", parse(try_from_str = parse_duration))]
+    state_max_age: Duration,
+    /// Fail at once, rather than waiting, if another sync into the same target is
+    /// in progress
+    #[structopt(long = "no-wait")]
+    no_wait: bool,
+    /// Sync into this repository as well as the target, as a path or URL, or a
+    /// remote name with --repo.  The objects every target needs are fetched from
+    /// the source once, in one pack, which is relayed into each of them.  May be
+    /// given more than once, but not with --batch-size, --spool or --save-pack.
+    #[structopt(long = "also-to", number_of_values = 1)]
+    also_to: Vec<String>,
+    /// Sync both ways: each ref which has moved on in either repository is
+    /// fast-forwarded in the other, and each ref only one has is created in the
+    /// other.  Nothing is deleted.  Both must be local or reached over SSH.
+    #[structopt(long = "two-way", conflicts_with = "also-to")]
+    two_way: bool,
+    /// With --two-way, what to do about a ref which has moved in both repositories:
+    /// refuse to sync it (`skip`), overwrite the target's with the source's
+    /// (`prefer-a`) or the source's with the target's (`prefer-b`), or stop the
+    /// sync before pushing anything (`fail`)
+    #[structopt(
+        long = "conflict",
+        default_value = "skip",
+        possible_values = &["prefer-a", "prefer-b", "skip", "fail"]
+    )]
+    conflict: ConflictPolicy,
+    /// Push metrics about the sync to this Prometheus Pushgateway afterwards, e.g.
+    /// `http://pushgateway:9091`, grouped by the name of the pair (or the target).
+    /// Only plain HTTP is supported.
+    #[structopt(long = "pushgateway", parse(try_from_str = parse_http_url))]
+    pushgateway: Option<String>,
+    /// Append a line of JSON to this file for every sync, recording when it ran
+    /// and who ran it, the source and target, every planned ref update with its
+    /// old and new objects, and what became of each.  The sync isn't started if
+    /// the file can't be opened, and fails if its entry can't be written.
+    #[structopt(long = "audit-log")]
+    audit_log: Option<PathBuf>,
+    /// Run this shell command once the sync is planned, before pushing, and stop
+    /// the sync if it fails.  The planned updates are on its stdin as `<old> <new>
+    /// <ref>` lines, and its environment gives the pair (`GIT_SYNC_PAIR`), the
+    /// source and target, and how many refs are to be created, updated and
+    /// deleted (`GIT_SYNC_CREATE` and so on).  A hook which runs for over five
+    /// minutes is killed, and counts as failing.
+    #[structopt(long = "pre-sync")]
+    pre_sync: Option<String>,
+    /// Run this shell command once the sync is over, with the updates made on its
+    /// stdin as for --pre-sync, and `GIT_SYNC_RESULT` set to `success`, `rejected`
+    /// or `failure`.  It's only warned about if it fails.
+    #[structopt(long = "post-sync")]
+    post_sync: Option<String>,
+    /// `POST` how the sync went to this `http://` URL once it's over, as a JSON
+    /// object like the `result` event of --json with its `source`, `target` and
+    /// `event` (`success`, `rejected` or `failure`).  May be given more than once.
+    /// HTTPS isn't supported: use --notify-command with curl for that.
+    #[structopt(long = "notify-url", number_of_values = 1, parse(try_from_str = parse_notify_url))]
+    notify_urls: Vec<String>,
+    /// Run this shell command once the sync is over, with the JSON --notify-url
+    /// would send on its stdin and `GIT_SYNC_EVENT` set to the event, e.g. to post
+    /// to chat with curl.  May be given more than once.
+    #[structopt(long = "notify-command", number_of_values = 1)]
+    notify_commands: Vec<String>,
+    /// Only notify about syncs which end in these events, rather than every sync
+    #[structopt(
+        long = "notify-on",
+        number_of_values = 1,
+        possible_values = &["success", "rejected", "failure"]
+    )]
+    notify_on: Vec<NotifyOn>,
+}
+
+/// How to report what happens
+#[derive(StructOpt)]
+struct OutputArgs {
+    /// Print JSON events on stdout, one per line, instead of messages for people:
+    /// what each side advertised, the planned updates, what happened to each ref,
+    /// and finally a `result`.  Errors and warnings still go to stderr.
+    #[structopt(long = "json")]
+    json: bool,
+    /// Write a JSON report to this file once done: for each sync, what became of
+    /// each ref, the time spent connecting, planning and pushing, the capabilities
+    /// used, how much pack data was sent, and any warnings and errors
+    #[structopt(long = "summary")]
+    summary: Option<PathBuf>,
+    /// Don't show how the transfer of pack data is going.  It's shown on stderr
+    /// only when that's a terminal, and not with --json or --quiet.
+    #[structopt(long = "no-progress")]
+    no_progress: bool,
+}
+
+/// How much to say, and how
+#[derive(StructOpt)]
+struct LogArgs {
+    /// Log what the library is doing to stderr: -v for the main steps, -vv for
+    /// details, -vvv for every protocol line.  `RUST_LOG` (e.g.
+    /// `info,git_sync::transport=debug`) overrides this.
+    #[structopt(long = "verbose", short = "v", parse(from_occurrences))]
+    verbose: u8,
+    /// Only report warnings and errors
+    #[structopt(long = "quiet", short = "q", conflicts_with = "verbose")]
+    quiet: bool,
+    /// When to colour the output: `auto` (when it goes to a terminal, unless
+    /// `NO_COLOR` is set), `always` or `never`
+    #[structopt(
+        long = "color",
+        default_value = "auto",
+        possible_values = &["auto", "always", "never"]
+    )]
+    color: ColorChoice,
+}
+
+impl LogArgs {
+    /// Log to stderr as asked, from now on
+    fn install(&self) -> io::Result<()> {
+        let filter = match std::env::var("RUST_LOG") {
+            Ok(spec) => log_filter(&spec)?,
+            Err(_) => log_filter(match (self.quiet, self.verbose) {
+                (true, _) => "error",
+                (false, 0) => "warn",
+                (false, 1) => "info",
+                (false, 2) => "debug",
+                (false, _) => "trace",
+            })?,
+        };
+        QUIET.store(self.quiet, Ordering::Relaxed);
+        let color = |is_terminal| self.color.enabled(is_terminal);
+        COLOR_STDOUT.store(color(std::io::stdout().is_terminal()), Ordering::Relaxed);
+        COLOR_STDERR.store(color(std::io::stderr().is_terminal()), Ordering::Relaxed);
+        // Log lines are labelled and held back as the rest of the output is
+        Ok(install_logging_with(filter, |line| {
+            write_line(true, format!("{}{}", label(), line))
+        })?)
     }
+}
 
-    pub async fn launch_ssh<P>(server: &str, service: &str, path: P) -> Result<Service, io::Error>
-    where
-        P: AsRef<Path>,
+/// How to reach the repositories
+#[derive(StructOpt)]
+struct ConnectArgs {
+    /// Give up if a service hasn't started and advertised its refs within this long,
+    /// e.g. `30s` (or just `30`) or `2m`
+    #[structopt(long = "connect-timeout", parse(try_from_str = parse_duration))]
+    connect_timeout: Option<Duration>,
+    /// Introduce ourselves to the source and target with this `agent` capability,
+    /// rather than as `git_sync/` and our version
+    #[structopt(long = "agent")]
+    agent: Option<String>,
+    /// Connect to git:// remotes through this proxy, `http://[user:pass@]host:port`
+    /// (HTTP CONNECT) or `socks5://[user:pass@]host:port`.  Defaults to `ALL_PROXY`.
+    #[structopt(long = "proxy")]
+    proxy: Option<Proxy>,
+    /// Never use a proxy, even if one is configured in the environment
+    #[structopt(long = "no-proxy", conflicts_with = "proxy")]
+    no_proxy: bool,
+    /// Connect to SSH remotes on this port, unless their URL gives one
+    #[structopt(long = "ssh-port")]
+    ssh_port: Option<u16>,
+    /// Log in to SSH remotes as this user, unless their URL gives one
+    #[structopt(long = "ssh-user")]
+    ssh_user: Option<String>,
+    /// Authenticate to SSH remotes with this private key
+    #[structopt(long = "ssh-identity", short = "i")]
+    ssh_identity: Option<PathBuf>,
+    /// Pass this option to ssh, e.g. `StrictHostKeyChecking=yes` (may be repeated)
+    #[structopt(long = "ssh-option", short = "o", number_of_values = 1)]
+    ssh_options: Vec<String>,
+    /// Never let ssh prompt for passwords or passphrases
+    #[structopt(long = "ssh-batch-mode")]
+    ssh_batch_mode: bool,
+    /// Whether ssh should refuse hosts whose keys aren't known (`strict`) or record
+    /// them (`accept-new`), regardless of the ssh configuration
+    #[structopt(long = "ssh-host-key-policy")]
+    ssh_host_key_policy: Option<HostKeyPolicy>,
+    /// Check and record SSH host keys in this file instead of the user's own
+    #[structopt(long = "ssh-known-hosts")]
+    ssh_known_hosts: Option<PathBuf>,
+    /// Reuse one SSH connection per host through a control socket at this path,
+    /// e.g. `~/.ssh/git-sync-%C`.  Connections linger briefly, so repeated runs
+    /// against the same host reuse them too.
+    #[structopt(long = "ssh-control-path")]
+    ssh_control_path: Option<String>,
+    /// Run this shell command instead of ssh, overriding `GIT_SSH_COMMAND` and `GIT_SSH`
+    #[structopt(long = "ssh-command")]
+    ssh_command: Option<String>,
+    /// The conventions of the ssh program (`ssh`, `plink`, `tortoiseplink` or
+    /// `simple`), if they can't be guessed from its name
+    #[structopt(long = "ssh-variant")]
+    ssh_variant: Option<SshVariant>,
+    /// Run this command instead of `git-upload-pack` in the source, e.g.
+    /// `/opt/git/bin/git-upload-pack`
+    #[structopt(long = "upload-pack")]
+    upload_pack: Option<String>,
+    /// Run this command instead of `git-receive-pack` in the target, e.g.
+    /// `sudo -u git git-receive-pack`
+    #[structopt(long = "receive-pack")]
+    receive_pack: Option<String>,
+    /// Run upload-pack and receive-pack with this configuration, e.g.
+    /// `uploadpack.allowFilter=true` (may be repeated)
+    #[structopt(long = "service-config", number_of_values = 1, parse(try_from_str = parse_config))]
+    service_config: Vec<String>,
+    /// When syncing into a bundle, base it on this bundle, or list of refs as
+    /// `git show-ref -d` prints them, leaving out the objects they name for a
+    /// receiver which already has them
+    #[structopt(long = "bundle-basis")]
+    bundle_basis: Option<PathBuf>,
+}
+
+/// Normalise a ref prefix so that it always ends in a `/`
+fn ref_prefix(s: &str) -> String {
+    if s.ends_with('/') {
+        s.to_string()
+    } else {
+        format!("{}/", s)
+    }
+}
+
+/// Parse a duration such as `90`, `30s`, `5m`, `2h` or `1d`, where a bare number
+/// is in seconds
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, "s"),
+    };
+    let scale: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Unknown unit {} in duration {}", unit, s)),
+    };
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
     {
-        let mut child = Command::new("ssh")
-            .arg(server)
-            .arg(service)
-            .arg(path.as_ref())
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .spawn()?;
-        let reader = child.stdout.take().expect("Did not get a stdout handle?");
-        let writer = child.stdin.take().expect("Did not get a stdin handle?");
-
-        let handle = tokio::spawn(async move { child.wait().await });
-
-        Ok(Service {
-            handle,
-            reader,
-            writer,
-        })
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!("Expected a duration such as 30s or 5m, not {}", s)),
     }
+}
 
-    pub async fn die(self) -> Result<ExitStatus, io::Error> {
-        self.handle.await?
+/// Parse a size such as `4096`, `512K`, `10M` or `2GiB`, in binary units, where
+/// a bare number is in bytes
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let scale: u64 = match unit.trim_end_matches("iB").trim_end_matches('B') {
+        "" => 1,
+        "k" | "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Unknown unit {} in size {}", unit, s)),
+    };
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+    {
+        Some(bytes) => Ok(bytes),
+        None => Err(format!("Expected a size such as 512M or 2G, not {}", s)),
     }
+}
 
-    pub fn reader(&mut self) -> &mut ChildStdout {
-        &mut self.reader
+/// Describe a duration to the second, e.g. `1h2m3s`
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{}s", m, s),
+        (h, m, s) => format!("{}h{}m{}s", h, m, s),
     }
+}
 
-    pub fn writer(&mut self) -> &mut ChildStdin {
-        &mut self.writer
+/// Check a `key=value` configuration setting
+fn parse_config(s: &str) -> Result<String, String> {
+    match s.find('=') {
+        Some(idx) if idx > 0 => Ok(s.to_string()),
+        _ => Err(format!("Expected key=value, not {}", s)),
     }
+}
 
-    pub fn streams(&mut self) -> (&mut ChildStdout, &mut ChildStdin) {
-        (&mut self.reader, &mut self.writer)
+/// Check a URL to send things to, which must be plain HTTP
+fn parse_http_url(s: &str) -> Result<String, String> {
+    match s.strip_prefix("http://") {
+        Some(rest) if !rest.is_empty() => Ok(s.to_string()),
+        _ if s.starts_with("https://") => Err(format!(
+            "HTTPS isn't supported, only http:// URLs, not {}",
+            s
+        )),
+        _ => Err(format!("Expected an http:// URL, not {}", s)),
     }
 }
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    let opts: Cli = Cli::from_args();
 
-    println!("Connecting to services...");
-    let mut upload_pack = if let Some(server) = opts.source_server.as_deref() {
-        Service::launch_ssh(server, "git-upload-pack", &opts.source).await?
+/// Check a URL to notify, pointing HTTPS users at --notify-command instead
+fn parse_notify_url(s: &str) -> Result<String, String> {
+    parse_http_url(s).map_err(|msg| {
+        if s.starts_with("https://") {
+            format!(
+                "{}; use --notify-command with e.g. `curl --json @- {}` to post over HTTPS",
+                msg, s
+            )
+        } else {
+            msg
+        }
+    })
+}
+
+/// The proxy to use when connecting to `host` for a git:// or WebSocket remote
+fn proxy_for(opts: &ConnectArgs, host: &str) -> Result<Option<Proxy>, String> {
+    if opts.no_proxy {
+        Ok(None)
+    } else if let Some(proxy) = &opts.proxy {
+        Ok(Some(proxy.clone()))
     } else {
-        Service::launch("git-upload-pack", &opts.source).await?
+        Proxy::from_env(host)
+    }
+}
+
+/// Look up the URL of a remote configured in a repository, or `None` if there is
+/// no such remote.  Relative paths in the URL are taken relative to the repository.
+async fn remote_url(repo: &Path, name: &str, push: bool) -> io::Result<Option<String>> {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C").arg(repo).args(["remote", "get-url"]);
+    if push {
+        cmd.arg("--push");
+    }
+    let output = cmd
+        .arg("--")
+        .arg(name)
+        .stderr(Stdio::null())
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let url = String::from_utf8_lossy(&output.stdout)
+        .trim_end()
+        .to_string();
+    Ok(Some(match url.parse() {
+        Ok(RemoteUrl::Local(path)) if path.is_relative() => {
+            repo.join(path).to_string_lossy().into_owned()
+        }
+        _ => url,
+    }))
+}
+
+/// Work out an endpoint from an optional SSH server and a path or URL.  The proxy
+/// (if any) for network transports is determined from the command line or
+/// environment, and the SSH options from the command line.
+fn endpoint(opts: &ConnectArgs, server: Option<&str>, location: &str) -> Result<Endpoint, Error> {
+    let url = match server {
+        Some(server) => match location.parse().map_err(Error::Config)? {
+            RemoteUrl::Local(_) | RemoteUrl::Ssh { .. } => RemoteUrl::ssh(server, location),
+            _ => {
+                return Err(Error::Config(format!(
+                    "Cannot use an SSH server with {}",
+                    location
+                )))
+            }
+        },
+        None => location.parse().map_err(Error::Config)?,
     };
-    let mut receive_pack = if let Some(server) = opts.dest_server.as_deref() {
-        Service::launch_ssh(server, "git-receive-pack", &opts.target).await?
-    } else {
-        Service::launch("git-receive-pack", &opts.target).await?
+    let proxy = match (&url, url.host()) {
+        (RemoteUrl::Git { .. }, Some(host)) | (RemoteUrl::WebSocket(_), Some(host)) => {
+            proxy_for(opts, host).map_err(Error::Config)?
+        }
+        _ => None,
+    };
+    let connect_opts = ConnectOptions {
+        proxy,
+        ssh: SshOptions {
+            port: opts.ssh_port,
+            user: opts.ssh_user.clone(),
+            identity_file: opts.ssh_identity.clone(),
+            options: opts.ssh_options.clone(),
+            batch_mode: opts.ssh_batch_mode,
+            host_key_policy: opts.ssh_host_key_policy,
+            known_hosts: opts.ssh_known_hosts.clone(),
+            control_path: opts.ssh_control_path.clone(),
+            program: match &opts.ssh_command {
+                Some(command) => Some(SshProgram::Shell(command.clone())),
+                None => SshProgram::from_env(),
+            },
+            variant: opts.ssh_variant,
+        },
+        upload_pack: opts.upload_pack.clone(),
+        receive_pack: opts.receive_pack.clone(),
+        service_config: opts.service_config.clone(),
+        bundle_basis: opts.bundle_basis.clone(),
     };
+    Endpoint::new(url, connect_opts)
+}
+
+impl PlanArgs {
+    /// Set up the options which decide what a sync will do
+    fn apply(&self, mut builder: SyncOptionsBuilder) -> SyncOptionsBuilder {
+        builder = builder
+            .mode(if self.mirror || !self.no_delete {
+                SyncMode::Mirror
+            } else {
+                SyncMode::NoDelete
+            })
+            .force(self.force)
+            .max_delete(self.max_delete)
+            .ignore_max_delete(self.yes_really_delete);
+        if self.prune_tags || self.no_prune_tags {
+            builder = builder.prune_tags(self.prune_tags);
+        }
+        for pattern in &self.protect {
+            builder = builder.protect(pattern.clone());
+        }
+        for pattern in &self.include {
+            builder = builder.include(pattern.clone());
+        }
+        if self.branches_only {
+            builder = builder.include("refs/heads/*".parse().unwrap());
+        }
+        if self.tags_only {
+            builder = builder.include("refs/tags/*".parse().unwrap());
+        }
+        for pattern in &self.exclude {
+            builder = builder.exclude(pattern.clone());
+        }
+        if self.exclude_forge_refs {
+            for pattern in FORGE_INTERNAL_REFS {
+                builder = builder.exclude(pattern.parse().unwrap());
+            }
+        }
+        for refspec in &self.refspecs {
+            builder = builder.refspec(refspec.clone());
+        }
+        if let Some(prefix) = &self.strip_source_prefix {
+            builder = builder.strip_source_prefix(prefix.as_str());
+        }
+        if let Some(prefix) = &self.dest_prefix {
+            builder = builder.dest_prefix(prefix.as_str());
+        }
+        for pattern in &self.force_refs {
+            builder = builder.force_ref(pattern.clone());
+        }
+        builder
+    }
+}
 
-    println!("Reading ref set available in source...");
-    let source_advert = RefAdvertisement::read_from(upload_pack.reader()).await?;
+impl PushArgs {
+    /// Whether the remotes' progress messages are wanted, which they aren't if
+    /// there's nobody watching for them unless asked for explicitly
+    fn wants_remote_progress(&self) -> bool {
+        if self.remote_progress {
+            true
+        } else if self.no_remote_progress {
+            false
+        } else {
+            !quiet() && (std::io::stdout().is_terminal() || std::io::stderr().is_terminal())
+        }
+    }
 
-    for cap in source_advert.caps() {
-        println!(
-            "  Capability: {}{}{}",
-            cap.0.as_str(),
-            if cap.1.is_some() { "=" } else { "" },
-            cap.1.as_deref().unwrap_or("")
-        );
+    /// Set up the options which decide how a sync pushes
+    fn apply(&self, mut builder: SyncOptionsBuilder) -> SyncOptionsBuilder {
+        builder = builder
+            .min_objects(self.min_objects)
+            .strict_object_check(self.strict_object_check)
+            .atomic(if self.no_atomic {
+                AtomicMode::Never
+            } else if self.atomic {
+                AtomicMode::Required
+            } else {
+                AtomicMode::IfSupported
+            })
+            .quiet_remote(self.quiet_remote)
+            .set_head(self.set_head)
+            .pack_refs(self.pack_refs)
+            .pack_refs_threshold(self.pack_refs_threshold)
+            .create_target(self.create)
+            .thin_pack(!self.no_thin)
+            .remote_progress(self.wants_remote_progress());
+        if let Some(signer) = &self.sign_with {
+            builder = builder.sign_with(signer.clone());
+        }
+        if let Some(size) = self.batch_size {
+            builder = builder.batch_size(size);
+        }
+        for task in &self.maintenance {
+            builder = builder.maintenance(*task);
+        }
+        if let Some(limit) = self.negotiation_timeout {
+            builder = builder.negotiation_timeout(limit);
+        }
+        if let Some(limit) = self.idle_timeout {
+            builder = builder.idle_timeout(limit);
+        }
+        if let Some(limit) = self.max_duration {
+            builder = builder.timeout(limit);
+        }
+        if let Some(bytes) = self.max_pack_size {
+            builder = builder.max_pack_size(bytes);
+        }
+        if let Some(max) = self.max_objects {
+            builder = builder.max_objects(max);
+        }
+        if let Some(rate) = self.max_bandwidth {
+            builder = builder.max_bandwidth(rate);
+        }
+        if let Some(dir) = &self.spool {
+            builder = builder
+                .spool(dir)
+                .spool_codec(self.spool_compress.unwrap_or_default());
+        }
+        if let Some(path) = &self.save_pack {
+            builder = builder.save_pack(path);
+        }
+        if let Some(path) = &self.from_pack {
+            builder = builder.from_pack(path);
+        }
+        if let Some(dir) = &self.state_dir {
+            builder = builder.state_dir(dir).state_max_age(self.state_max_age);
+        }
+        builder.retries(self.retries)
     }
 
-    println!("Reading ref set available in target...");
-    let target_advert = RefAdvertisement::read_from(receive_pack.reader()).await?;
+    /// Where to send notifications about a sync which ended as `on` says
+    fn notify_targets(&self, on: NotifyOn) -> Vec<NotifyTarget> {
+        if !self.notify_on.is_empty() && !self.notify_on.contains(&on) {
+            return Vec::new();
+        }
+        let urls = self.notify_urls.iter().cloned().map(NotifyTarget::Webhook);
+        let commands = self
+            .notify_commands
+            .iter()
+            .cloned()
+            .map(NotifyTarget::Command);
+        urls.chain(commands).collect()
+    }
+}
+
+impl ConnectArgs {
+    /// Set up the options which decide how a sync connects
+    fn apply(&self, mut builder: SyncOptionsBuilder) -> SyncOptionsBuilder {
+        if let Some(limit) = self.connect_timeout {
+            builder = builder.connect_timeout(limit);
+        }
+        if let Some(agent) = &self.agent {
+            builder = builder.agent(agent.as_str());
+        }
+        builder
+    }
+}
+
+impl RemoteArgs {
+    /// The source and target as given, which are required without --config
+    fn names(&self) -> (&str, &str) {
+        match (&self.source, &self.target) {
+            (Some(source), Some(target)) => (source, target),
+            _ => unreachable!("The source and target are required without --config"),
+        }
+    }
+}
+
+/// Work out where the source and target are, resolving remote names if asked
+async fn locations(remotes: &RemoteArgs) -> io::Result<(String, String)> {
+    let (source, target) = remotes.names();
+    Ok(match &remotes.repo {
+        Some(repo) => (
+            remote_url(repo, source, false)
+                .await?
+                .unwrap_or_else(|| source.to_string()),
+            remote_url(repo, target, true)
+                .await?
+                .unwrap_or_else(|| target.to_string()),
+        ),
+        None => (source.to_string(), target.to_string()),
+    })
+}
+
+/// The source and target given on the command line
+async fn endpoints(
+    remotes: &RemoteArgs,
+    connect: &ConnectArgs,
+) -> io::Result<(Endpoint, Endpoint)> {
+    let (source_location, target_location) = locations(remotes).await?;
+    Ok((
+        endpoint(connect, remotes.source_server.as_deref(), &source_location)?,
+        endpoint(connect, remotes.dest_server.as_deref(), &target_location)?,
+    ))
+}
+
+/// Prepare to sync between the repositories given on the command line
+async fn syncer(
+    remotes: &RemoteArgs,
+    connect: &ConnectArgs,
+    options: SyncOptions,
+) -> io::Result<Syncer> {
+    let (source, target) = endpoints(remotes, connect).await?;
+    Ok(Syncer::new(source, target, options)?)
+}
+
+/// Print a syncer's events as they happen, except for those `skip` selects,
+/// returning a task which finishes once the syncer has gone, with a report of
+/// what it did
+fn print_events(
+    syncer: &mut Syncer,
+    skip: fn(&SyncEvent) -> bool,
+) -> tokio::task::JoinHandle<SyncSummary> {
+    print_labelled_events(syncer, skip, LABEL.try_with(Clone::clone).ok())
+}
+
+/// Print a syncer's events as [`print_events`] does, labelling each line with
+/// `label` if given
+fn print_labelled_events(
+    syncer: &mut Syncer,
+    skip: fn(&SyncEvent) -> bool,
+    label: Option<String>,
+) -> tokio::task::JoinHandle<SyncSummary> {
+    let mut summary = SyncSummary::new(&syncer.source().to_string(), &syncer.target().to_string());
+    // Labelled output comes from several syncs at once, and held back output comes
+    // later, so a line showing the progress of one of them would make no sense
+    let mut transfer =
+        (PROGRESS.load(Ordering::Relaxed) && !whole_lines_only()).then(TransferProgress::new);
+    let mut events = syncer.subscribe();
+    let printer = async move {
+        let mut progress = RemoteProgress::default();
+        while let Some(event) = events.recv().await {
+            summary.observe(&event);
+            if let Some(transfer) = &mut transfer {
+                transfer.observe(&event);
+            }
+            let mut print = || match &event {
+                event if skip(event) => {}
+                event if json_output() => match event_json(event) {
+                    Some((kind, fields)) => print_json(kind, fields),
+                    // Whatever goes to stderr still does
+                    None => match event {
+                        SyncEvent::Cancelled
+                        | SyncEvent::RemoteError(..)
+                        | SyncEvent::ServiceStderr(..)
+                        | SyncEvent::Warning(_) => print_event(event),
+                        _ => {}
+                    },
+                },
+                SyncEvent::RemoteProgress(side, message) => {
+                    progress.print(*side, message, transfer.is_some())
+                }
+                event => print_event(event),
+            };
+            // Anything else printed makes way for the progress bar.  Remote progress
+            // is only printed a whole line at a time while the bar is shown.
+            let printing = match &event {
+                SyncEvent::PackBytes(_) | SyncEvent::PackObjects(_) | SyncEvent::Progress(..) => {
+                    false
+                }
+                SyncEvent::RemoteProgress(_, message) => message.contains('\n'),
+                _ => true,
+            };
+            match &transfer {
+                Some(transfer) if printing && transfer.is_shown() => transfer.suspend(print),
+                _ => print(),
+            }
+        }
+        drop(transfer);
+        summary
+    };
+    // The printer says which pair it's printing about, as the sync's task does
+    let printer: Pin<Box<dyn Future<Output = SyncSummary> + Send>> = match PAIR.try_with(Arc::clone)
+    {
+        Ok(pair) => Box::pin(PAIR.scope(pair, printer)),
+        Err(_) => Box::pin(printer),
+    };
+    match label {
+        Some(label) => tokio::spawn(LABEL.scope(label, printer)),
+        None => tokio::spawn(printer),
+    }
+}
+
+/// The remotes' progress messages, as they're shown
+#[derive(Default)]
+struct RemoteProgress {
+    /// What's come of a line which is only shown whole, so far
+    pending: String,
+    /// Whether a line which updates in place has been begun
+    midline: bool,
+}
+
+impl RemoteProgress {
+    /// Show a remote's progress messages, each headed `remote:` and dimmed.  When
+    /// the output is labelled or held back, or our own progress is shown, only whole lines are
+    /// shown, since progress which updates a line in place can't be, so anything
+    /// after the last line break is kept until the rest comes.
+    fn print(&mut self, side: SyncSide, message: &str, whole_lines: bool) {
+        if quiet() {
+            return;
+        }
+        let stage = stage_prefix(Some(side_stage(side)), false);
+        if !whole_lines_only() && !whole_lines {
+            for part in message.split_inclusive(['\r', '\n']) {
+                let text = part.trim_end_matches(['\r', '\n']);
+                let ending = &part[text.len()..];
+                if self.midline {
+                    print!("{}{}", paint(Paint::Remote, text, false), ending);
+                } else {
+                    let text = format!("remote: {}", text);
+                    print!("{}{}{}", stage, paint(Paint::Remote, &text, false), ending);
+                }
+                self.midline = ending.is_empty();
+            }
+            return;
+        }
+        self.pending.push_str(message);
+        while let Some(idx) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=idx).collect();
+            match line.trim_end().rsplit('\r').next() {
+                Some(line) if !line.is_empty() => {
+                    let line = format!("remote: {}", line);
+                    outln!("{}{}", stage, paint(Paint::Remote, &line, false))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The stage of a sync an event is about, to head the lines describing it
+fn event_stage(event: &SyncEvent) -> Option<&'static str> {
+    match event {
+        SyncEvent::AdvertisementRead(..) | SyncEvent::TargetCreated { .. } => Some("connect"),
+        SyncEvent::Refused(..) | SyncEvent::PlanComputed(_) => Some("plan"),
+        SyncEvent::CapabilitiesRequested(side, _)
+        | SyncEvent::RemoteProgress(side, _)
+        | SyncEvent::Progress(side, _)
+        | SyncEvent::RemoteError(side, _)
+        | SyncEvent::ServiceStderr(side, _) => Some(side_stage(*side)),
+        SyncEvent::PackBytes(_) | SyncEvent::PackObjects(_) => Some("fetch"),
+        SyncEvent::BatchStarted { .. }
+        | SyncEvent::RefsPacked
+        | SyncEvent::Maintained(_)
+        | SyncEvent::HeadSet(..)
+        | SyncEvent::RefResult(_)
+        | SyncEvent::Completed(_) => Some("push"),
+        SyncEvent::Warning(_) | SyncEvent::Retrying { .. } | SyncEvent::Cancelled => None,
+    }
+}
+
+/// The stage of a sync in which a side is spoken with: the source is fetched
+/// from, and the target pushed to
+fn side_stage(side: SyncSide) -> &'static str {
+    match side {
+        SyncSide::Source => "fetch",
+        SyncSide::Target => "push",
+    }
+}
+
+/// The heading of a line about a stage of a sync, e.g. `[fetch] `, if it has one
+fn stage_prefix(stage: Option<&str>, stderr: bool) -> String {
+    match stage {
+        Some(stage) => format!("{} ", paint(Paint::Stage, &format!("[{}]", stage), stderr)),
+        None => String::new(),
+    }
+}
 
-    for cap in target_advert.caps() {
-        println!(
-            "  Capability: {}{}{}",
-            cap.0.as_str(),
-            if cap.1.is_some() { "=" } else { "" },
-            cap.1.as_deref().unwrap_or("")
+/// Describe an event as the kind of JSON event and its fields, or `None` for
+/// those which aren't reported as JSON
+fn event_json(event: &SyncEvent) -> Option<(&'static str, Vec<(&'static str, Json)>)> {
+    Some(match event {
+        SyncEvent::AdvertisementRead(side, advert) => {
+            let mut caps: Vec<_> = advert
+                .caps()
+                .iter()
+                .map(|(cap, value)| cap_string(*cap, value.as_deref()))
+                .collect();
+            caps.sort();
+            (
+                "advertisement",
+                vec![
+                    ("side", Json::from(side.to_string())),
+                    ("refs", Json::from(advert.refs().len())),
+                    (
+                        "capabilities",
+                        Json::Array(caps.into_iter().map(Json::from).collect()),
+                    ),
+                ],
+            )
+        }
+        SyncEvent::CapabilitiesRequested(side, caps) => (
+            "capabilities",
+            vec![
+                ("side", Json::from(side.to_string())),
+                (
+                    "capabilities",
+                    Json::Array(
+                        caps.iter()
+                            .map(|(cap, value)| Json::from(cap_string(*cap, value.as_deref())))
+                            .collect(),
+                    ),
+                ),
+            ],
+        ),
+        SyncEvent::Refused(update, reason) => {
+            let mut fields = update_json(update);
+            fields.push(("reason", Json::from(reason.as_str())));
+            ("refused", fields)
+        }
+        SyncEvent::PlanComputed(plan) => (
+            "plan",
+            vec![
+                (
+                    "updates",
+                    Json::Array(
+                        plan.updates
+                            .iter()
+                            .map(|update| json_object(update_json(update)))
+                            .collect(),
+                    ),
+                ),
+                ("refused", Json::from(plan.refused.len())),
+            ],
+        ),
+        SyncEvent::BatchStarted {
+            batch,
+            batches,
+            updates,
+        } => (
+            "batch",
+            vec![
+                ("batch", Json::from(*batch)),
+                ("batches", Json::from(*batches)),
+                ("updates", Json::from(*updates)),
+            ],
+        ),
+        SyncEvent::Progress(side, update) => (
+            "progress",
+            vec![
+                ("side", Json::from(side.to_string())),
+                ("phase", Json::from(update.phase.as_str())),
+                ("current", Json::from(update.current)),
+                ("total", Json::from(update.total)),
+                ("percent", Json::from(update.percent())),
+                ("done", Json::from(update.done)),
+            ],
+        ),
+        SyncEvent::Retrying {
+            attempt,
+            retries,
+            delay,
+            error,
+        } => (
+            "retry",
+            vec![
+                ("attempt", Json::from(*attempt)),
+                ("retries", Json::from(*retries)),
+                ("delay", Json::from(delay.as_secs_f64())),
+                ("error", Json::from(error.as_str())),
+            ],
+        ),
+        SyncEvent::TargetCreated {
+            object_format,
+            head,
+        } => (
+            "target_created",
+            vec![
+                ("object_format", Json::from(object_format.as_str())),
+                ("head", Json::from(head.as_deref())),
+            ],
+        ),
+        SyncEvent::RefsPacked => ("refs_packed", vec![]),
+        SyncEvent::Maintained(task) => ("maintained", vec![("task", Json::from(task.as_str()))]),
+        SyncEvent::HeadSet(symref, head) => (
+            "head_set",
+            vec![
+                ("symref", Json::from(symref.as_str())),
+                ("target", Json::from(head.as_str())),
+            ],
+        ),
+        SyncEvent::RefResult(outcome) => {
+            let mut fields = update_json(&outcome.update);
+            match &outcome.status {
+                RefStatus::Ok => fields.push(("status", Json::from("ok"))),
+                RefStatus::Rejected(reason) => {
+                    fields.push(("status", Json::from("rejected")));
+                    fields.push(("reason", Json::from(reason.as_str())));
+                }
+            }
+            ("ref", fields)
+        }
+        // The pack bytes and objects are given in the result, rather than every
+        // time more are relayed
+        SyncEvent::PackBytes(_)
+        | SyncEvent::PackObjects(_)
+        | SyncEvent::RemoteProgress(..)
+        | SyncEvent::RemoteError(..)
+        | SyncEvent::ServiceStderr(..)
+        | SyncEvent::Warning(_)
+        | SyncEvent::Completed(_)
+        | SyncEvent::Cancelled => return None,
+    })
+}
+
+/// Describe what's happening during the sync, each line headed by the stage it's
+/// about, with what the remotes said dimmed
+fn print_event(event: &SyncEvent) {
+    let out = stage_prefix(event_stage(event), false);
+    let err = stage_prefix(event_stage(event), true);
+    match event {
+        SyncEvent::AdvertisementRead(side, advert) => {
+            outln!(
+                "{}The {} advertised {} ref(s)",
+                out,
+                side,
+                advert.refs().len()
+            );
+            for (cap, value) in advert.caps() {
+                outln!(
+                    "{}  Capability: {}",
+                    out,
+                    cap_string(*cap, value.as_deref())
+                )
+            }
+        }
+        SyncEvent::Refused(update, reason) => {
+            outln!("{}Refusing update of {}, {}", out, update.refname, reason)
+        }
+        SyncEvent::PlanComputed(plan) => {
+            outln!("{}Pushing {} ref update(s)", out, plan.updates.len())
+        }
+        SyncEvent::BatchStarted {
+            batch,
+            batches,
+            updates,
+        } if *batches > 1 => outln!(
+            "{}Pushing batch {} of {} ({} ref update(s))",
+            out,
+            batch,
+            batches,
+            updates
+        ),
+        SyncEvent::BatchStarted { .. }
+        | SyncEvent::CapabilitiesRequested(..)
+        | SyncEvent::PackBytes(_)
+        | SyncEvent::PackObjects(_)
+        | SyncEvent::RemoteProgress(..)
+        | SyncEvent::Progress(..)
+        | SyncEvent::Completed(_) => {}
+        SyncEvent::Cancelled => errln!("Cancelled, stopping the sync"),
+        // Errors from the remotes are told apart from our own by their heading
+        SyncEvent::RemoteError(_, message) => errln!(
+            "{}{} {}",
+            err,
+            paint(Paint::RemoteError, "remote error:", true),
+            message.trim_end()
+        ),
+        SyncEvent::ServiceStderr(_, line) => errln!(
+            "{}{}",
+            err,
+            paint(Paint::Remote, &format!("remote: {}", line), true)
+        ),
+        SyncEvent::Warning(message) => {
+            errln!("{} {}", paint(Paint::Warning, "Warning:", true), message)
+        }
+        SyncEvent::Retrying {
+            attempt,
+            retries,
+            delay,
+            error,
+        } => errln!(
+            "{} trying again in {:.1}s (retry {} of {}): {}",
+            paint(Paint::Warning, "Sync failed,", true),
+            delay.as_secs_f64(),
+            attempt,
+            retries,
+            error
+        ),
+        SyncEvent::TargetCreated {
+            object_format,
+            head,
+        } => match head {
+            Some(head) => outln!(
+                "{}Created the target, using {} with HEAD at {}",
+                out,
+                object_format,
+                head
+            ),
+            None => outln!("{}Created the target, using {}", out, object_format),
+        },
+        SyncEvent::RefsPacked => outln!("{}Packed refs in target", out),
+        SyncEvent::Maintained(task) => outln!("{}Ran {} in target", out, task),
+        SyncEvent::HeadSet(symref, head) => {
+            outln!("{}Set {} in target to {}", out, symref, head)
+        }
+        SyncEvent::RefResult(outcome) => {
+            let update = &outcome.update;
+            let change = match (&outcome.status, update.kind()) {
+                (RefStatus::Rejected(_), _) => paint(Paint::Error, "rejected", false),
+                (RefStatus::Ok, RefChangeKind::Create) => paint(Paint::Success, "created", false),
+                (RefStatus::Ok, RefChangeKind::Update) => paint(Paint::Success, "updated", false),
+                (RefStatus::Ok, RefChangeKind::Delete) => paint(Paint::Success, "deleted", false),
+            };
+            match &outcome.status {
+                RefStatus::Ok => outln!("{}  {} {}", out, change, update.refname),
+                RefStatus::Rejected(reason) => {
+                    outln!("{}  {} {} ({})", out, change, update.refname, reason)
+                }
+            }
+        }
+    }
+}
+
+/// A token which is cancelled when we're interrupted or asked to terminate
+fn interrupt_token() -> CancellationToken {
+    static TOKEN: OnceLock<CancellationToken> = OnceLock::new();
+    TOKEN
+        .get_or_init(|| {
+            let token = CancellationToken::new();
+            let interrupted = token.clone();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    interrupted.cancel();
+                }
+            });
+            if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+                let terminated = token.clone();
+                tokio::spawn(async move {
+                    if terminate.recv().await.is_some() {
+                        terminated.cancel();
+                    }
+                });
+            }
+            token
+        })
+        .clone()
+}
+
+/// Sync the target with the source, or just prune it, then push metrics about the
+/// sync if asked
+async fn sync(
+    remotes: RemoteArgs,
+    plan: PlanArgs,
+    push: PushArgs,
+    connect: ConnectArgs,
+    prune_only: bool,
+) -> io::Result<()> {
+    let started = Instant::now();
+    let mut audit_log = match &push.audit_log {
+        Some(path) => Some(AuditLog::open(path).await?),
+        None => None,
+    };
+    let mut outcome = None;
+    let mut summaries = Vec::new();
+    let mut result = sync_once(
+        &remotes,
+        plan,
+        &push,
+        connect,
+        prune_only,
+        &mut outcome,
+        &mut summaries,
+    )
+    .await;
+    let summaries = finish_summaries(&remotes, summaries, &result);
+    if let Some(audit_log) = &mut audit_log {
+        let operator = Operator::current().await;
+        for summary in &summaries {
+            let mut entry = summary.to_audit_json(&operator);
+            if let (Json::Object(members), Ok(pair)) =
+                (&mut entry, PAIR.try_with(|pair| pair.name.clone()))
+            {
+                members.shift_insert(0, "pair".to_string(), Json::from(pair));
+            }
+            if let Err(err) = audit_log.append(&entry).await {
+                let err = io::Error::other(format!(
+                    "Cannot write to the audit log {}: {}",
+                    audit_log.path().display(),
+                    err
+                ));
+                result = result.and(Err(err));
+                break;
+            }
+        }
+    }
+    let pair = match PAIR.try_with(Arc::clone) {
+        Ok(pair) => {
+            *pair.outcome.lock().unwrap() = outcome.clone();
+            pair.name.clone()
+        }
+        Err(_) => pair_name(&remotes),
+    };
+    if let Some(url) = &push.pushgateway {
+        let mut metrics = SyncMetrics::new();
+        metrics.record(&pair, started.elapsed(), outcome.as_ref(), result.is_ok());
+        if let Err(err) = push_metrics(url, &pair, &metrics).await {
+            errln!("{} {}", paint(Paint::Warning, "Warning:", true), err);
+        }
+    }
+    let mut fields = vec![("success", Json::from(result.is_ok()))];
+    fields.extend(outcome_json(outcome.as_ref()));
+    fields.push(("duration", Json::from(started.elapsed().as_secs_f64())));
+    fields.push((
+        "error",
+        Json::from(result.as_ref().err().map(|err| err.to_string())),
+    ));
+    notify(&remotes, &push, &pair, &result, &fields).await;
+    if json_output() {
+        print_json("result", fields);
+    }
+    result
+}
+
+/// Send notifications about how a sync of `pair` ended, with the fields of its
+/// `result` event, warning of any which can't be sent
+async fn notify<T>(
+    remotes: &RemoteArgs,
+    push: &PushArgs,
+    pair: &str,
+    result: &io::Result<T>,
+    fields: &[(&str, Json)],
+) {
+    let on = NotifyOn::for_exit(match result {
+        Ok(_) => ExitReason::Success,
+        Err(err) => ExitReason::for_io_error(err),
+    });
+    let targets = push.notify_targets(on);
+    if targets.is_empty() {
+        return;
+    }
+    let (source, target) = remotes.names();
+    let mut members = vec![
+        ("event", Json::from(on.as_str())),
+        ("pair", Json::from(pair)),
+        ("source", Json::from(source)),
+        ("target", Json::from(target)),
+    ];
+    members.extend(fields.iter().cloned());
+    let payload = json_object(members);
+    for target in targets {
+        if let Err(err) = target.send(on, &payload).await {
+            errln!("{} {}", paint(Paint::Warning, "Warning:", true), err);
+        }
+    }
+}
+
+/// The name of the pair being synced, which is the target's unless it's named in a
+/// configuration file
+fn pair_name(remotes: &RemoteArgs) -> String {
+    PAIR.try_with(|pair| pair.name.clone())
+        .unwrap_or_else(|_| remotes.names().1.to_string())
+}
+
+/// Finish and keep the reports of a sync into each of its targets, or of its
+/// failure to start, returning them.  Those of a fan-out have been finished
+/// already, each with how its own target fared.
+fn finish_summaries<T>(
+    remotes: &RemoteArgs,
+    mut summaries: Vec<SyncSummary>,
+    result: &io::Result<T>,
+) -> Vec<SyncSummary> {
+    if summaries.is_empty() {
+        let (source, target) = remotes.names();
+        summaries.push(SyncSummary::new(source, target));
+    }
+    for summary in &mut summaries {
+        if !summary.is_finished() {
+            summary.finish(result.as_ref().err().map(|err| err.to_string()));
+        }
+        keep_summary(summary);
+    }
+    summaries
+}
+
+/// What a sync did, as JSON, with nothing done if it didn't get as far as pushing
+fn outcome_json(outcome: Option<&SyncOutcome>) -> Vec<(&'static str, Json)> {
+    let count = |count: fn(&SyncOutcome) -> usize| Json::from(outcome.map_or(0, count));
+    vec![
+        ("created", count(|o| o.report.count(RefChangeKind::Create))),
+        ("updated", count(|o| o.report.count(RefChangeKind::Update))),
+        ("deleted", count(|o| o.report.count(RefChangeKind::Delete))),
+        ("rejected", count(|o| o.report.rejected().count())),
+        ("refused", count(|o| o.refused.len())),
+        (
+            "pack_bytes",
+            Json::from(outcome.map_or(0, |o| o.pack_bytes)),
+        ),
+        (
+            "pack_objects",
+            Json::from(outcome.map_or(0, |o| o.pack_objects)),
+        ),
+        ("negotiations", count(|o| o.negotiations)),
+        (
+            "throughput",
+            Json::from(outcome.and_then(SyncOutcome::throughput)),
+        ),
+        (
+            "phases",
+            Json::from(outcome.map(|o| {
+                let timings = &o.timings;
+                let mut phases: Vec<_> = timings
+                    .phases()
+                    .iter()
+                    .map(|(name, took)| (*name, Json::from(took.as_secs_f64())))
+                    .collect();
+                phases.push(("transfer", Json::from(timings.transfer.as_secs_f64())));
+                json_object(phases)
+            })),
+        ),
+    ]
+}
+
+/// Say how much a sync relayed, how quickly, and how long each phase took
+fn print_statistics(outcome: &SyncOutcome) {
+    if outcome.negotiations > 0 {
+        let rate = outcome
+            .throughput()
+            .map(|rate| format!(", averaging {}", Bandwidth(rate as u64)))
+            .unwrap_or_default();
+        outln!(
+            "Relayed {} of pack data, {} object(s), in {} negotiation(s){}",
+            format_bytes(outcome.pack_bytes),
+            outcome.pack_objects,
+            outcome.negotiations,
+            rate
         );
     }
+    let timings = &outcome.timings;
+    let mut phases: Vec<_> = timings
+        .phases()
+        .iter()
+        .map(|(name, took)| format!("{} {:.2}s", name, took.as_secs_f64()))
+        .collect();
+    if outcome.negotiations > 0 {
+        phases.push(format!(
+            "of which transfer {:.2}s",
+            timings.transfer.as_secs_f64()
+        ));
+    }
+    outln!(
+        "Took {:.2}s: {}",
+        timings.total().as_secs_f64(),
+        phases.join(", ")
+    );
+}
 
-    // Compute the set of things we want to fetch
-    let wants: HashSet<_> = source_advert
-        .refs()
+/// Push metrics about a pair to a Prometheus Pushgateway, grouped by the pair's
+/// name.  Only the metrics pushed are replaced, so the time of the last success
+/// stays put when a sync fails.
+async fn push_metrics(url: &str, pair: &str, metrics: &SyncMetrics) -> Result<(), Error> {
+    // The pair's name goes in the path, so it's encoded as URL-safe base64
+    let pair = base64(pair.as_bytes()).replace('+', "-").replace('/', "_");
+    let url = format!(
+        "{}/metrics/job/git_sync/pair@base64/{}",
+        url.trim_end_matches('/'),
+        pair
+    );
+    http_send("POST", &url, PROMETHEUS_TEXT, &metrics.render()).await
+}
+
+/// The content type of Prometheus's text format
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4";
+
+/// Sync the target with the source, or just prune it, keeping the outcome if the
+/// sync gets that far, and a report of the sync if it starts
+async fn sync_once(
+    remotes: &RemoteArgs,
+    plan: PlanArgs,
+    push: &PushArgs,
+    connect: ConnectArgs,
+    prune_only: bool,
+    recorded: &mut Option<SyncOutcome>,
+    summaries: &mut Vec<SyncSummary>,
+) -> io::Result<()> {
+    let mut builder = connect
+        .apply(push.apply(plan.apply(SyncOptions::builder())))
+        .prune_only(prune_only);
+    let hook = |command: &String| Hook::new(command).env("GIT_SYNC_PAIR", pair_name(remotes));
+    if let Some(command) = &push.pre_sync {
+        builder = builder.pre_sync(hook(command));
+    }
+    if let Some(command) = &push.post_sync {
+        builder = builder.post_sync(hook(command));
+    }
+    if !push.also_to.is_empty() {
+        return fan_out(
+            remotes,
+            &connect,
+            push,
+            builder.build()?,
+            recorded,
+            summaries,
+        )
+        .await;
+    }
+    if push.two_way {
+        return two_way(
+            remotes,
+            &connect,
+            push,
+            builder.build()?,
+            recorded,
+            summaries,
+        )
+        .await;
+    }
+    let mut syncer = syncer(remotes, &connect, builder.build()?).await?;
+
+    // Keep other syncs out of the target until this one is done with it
+    let lock_path = TargetLock::path_for(syncer.target().url());
+    let _lock = match TargetLock::try_acquire(&lock_path) {
+        Err(Error::Locked(_)) if !push.no_wait => {
+            outln!("Waiting for another sync into {}", syncer.target().url());
+            TargetLock::acquire(&lock_path, &interrupt_token()).await?
+        }
+        result => result?,
+    };
+
+    let printer = print_events(&mut syncer, |_| false);
+    syncer.set_cancellation(interrupt_token());
+    let outcome = syncer.run().await;
+    // Let the printer catch up before saying anything more
+    drop(syncer);
+    summaries.push(printer.await?);
+    let outcome = recorded.insert(outcome?);
+    outln!("{}", counts(&outcome.report));
+    print_statistics(outcome);
+    if let Some(err) = outcome_error(outcome) {
+        return Err(err.into());
+    }
+    outln!("Done");
+    Ok(())
+}
+
+/// How many refs a sync created, updated, deleted and had rejected
+fn counts(report: &SyncReport) -> String {
+    format!(
+        "{} created, {} updated, {} deleted, {} rejected",
+        report.count(RefChangeKind::Create),
+        report.count(RefChangeKind::Update),
+        report.count(RefChangeKind::Delete),
+        report.rejected().count()
+    )
+}
+
+/// Why a sync which pushed what it could still failed, if it did: because some of
+/// its updates were rejected by the target, or refused
+fn outcome_error(outcome: &SyncOutcome) -> Option<Error> {
+    let report = &outcome.report;
+    if report.rejected().next().is_some() {
+        return Some(Error::RefsRejected(report.rejected().cloned().collect()));
+    }
+    if !outcome.refused.is_empty() {
+        return Some(Error::Refused(format!(
+            "{} non-fast-forward update(s) were refused",
+            outcome.refused.len()
+        )));
+    }
+    None
+}
+
+/// Sync the source into the target and every --also-to target at once, fetching
+/// from the source only once, keeping the outcome of them all together and a
+/// report of the sync into each
+async fn fan_out(
+    remotes: &RemoteArgs,
+    connect: &ConnectArgs,
+    push: &PushArgs,
+    options: SyncOptions,
+    recorded: &mut Option<SyncOutcome>,
+    summaries: &mut Vec<SyncSummary>,
+) -> io::Result<()> {
+    let (source, target) = endpoints(remotes, connect).await?;
+    let mut targets = vec![target];
+    for name in &push.also_to {
+        let location = match &remotes.repo {
+            Some(repo) => remote_url(repo, name, true).await?,
+            None => None,
+        };
+        let location = location.as_deref().unwrap_or(name);
+        targets.push(endpoint(connect, remotes.dest_server.as_deref(), location)?);
+    }
+    let mut syncers = targets
+        .into_iter()
+        .map(|target| Syncer::new(source.clone(), target, options.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let printers = labelled_printers(syncers.iter_mut());
+    let fan_out = FanOut::new(syncers)?;
+    let targets: Vec<_> = fan_out.syncers().iter().map(Syncer::target).collect();
+    let _locks = lock_targets(targets, push.no_wait).await?;
+    let results = fan_out.run().await;
+    let targets: Vec<_> = fan_out
+        .syncers()
         .iter()
-        // filter out any peeled refs
-        .filter(|(k, v)| !k.ends_with("^{}"))
-        // filter out anything the target already has since we don't need to fetch that
-        .filter(|(k, v)| target_advert.refs().values().find(|vv| v == vv).is_none())
-        .map(|(_, v)| v.as_str())
+        .map(|syncer| syncer.target().to_string())
         .collect();
-    // And the set of things we already have
-    let haves: HashSet<_> = target_advert
+    // Let the printers catch up before saying anything more
+    drop(fan_out);
+    for printer in printers {
+        summaries.push(printer.await?);
+    }
+    let results = results?;
+
+    let total = results.len();
+    let mut combined = SyncOutcome::default();
+    let mut failed = Vec::new();
+    for ((result, target), summary) in results.into_iter().zip(&targets).zip(summaries) {
+        match result {
+            Ok(outcome) => {
+                outln!("{}: {}", target, counts(&outcome.report));
+                summary.finish(outcome_error(&outcome).map(|err| err.to_string()));
+                add_outcome(&mut combined, outcome);
+            }
+            Err(err) => {
+                errln!(
+                    "{} Syncing into {} failed: {}",
+                    paint(Paint::Error, "Error:", true),
+                    target,
+                    err
+                );
+                summary.finish(Some(err.to_string()));
+                failed.push(err);
+            }
+        }
+    }
+    let outcome = recorded.insert(combined);
+    print_statistics(outcome);
+    if let Some(err) = failed.first() {
+        return Err(Error::PairsFailed {
+            failed: failed.len(),
+            total,
+            reason: ExitReason::for_error(err),
+        }
+        .into());
+    }
+    if let Some(err) = outcome_error(outcome) {
+        return Err(err.into());
+    }
+    outln!("Done");
+    Ok(())
+}
+
+/// Sync the source and target with each other, pushing each ref whichever way
+/// it has moved, keeping the outcome of both ways together and a report of each
+async fn two_way(
+    remotes: &RemoteArgs,
+    connect: &ConnectArgs,
+    push: &PushArgs,
+    options: SyncOptions,
+    recorded: &mut Option<SyncOutcome>,
+    summaries: &mut Vec<SyncSummary>,
+) -> io::Result<()> {
+    let (source, target) = endpoints(remotes, connect).await?;
+    let mut a_to_b = Syncer::new(source.clone(), target.clone(), options.clone())?;
+    let mut b_to_a = Syncer::new(target, source, options)?;
+    let printers = labelled_printers(vec![&mut a_to_b, &mut b_to_a]);
+    let two_way = TwoWay::new(a_to_b, b_to_a, push.conflict)?;
+    let targets: Vec<_> = two_way.syncers().iter().map(|s| s.target()).collect();
+    let _locks = lock_targets(targets, push.no_wait).await?;
+    let result = two_way.run().await;
+    let targets: Vec<_> = two_way
+        .syncers()
+        .iter()
+        .map(|syncer| syncer.target().to_string())
+        .collect();
+    // Let the printers catch up before saying anything more
+    drop(two_way);
+    for printer in printers {
+        summaries.push(printer.await?);
+    }
+    let (into_b, into_a) = result?;
+
+    let mut combined = SyncOutcome::default();
+    let outcomes = vec![into_b, into_a];
+    for ((outcome, target), summary) in outcomes.into_iter().zip(&targets).zip(summaries) {
+        outln!("{}: {}", target, counts(&outcome.report));
+        summary.finish(outcome_error(&outcome).map(|err| err.to_string()));
+        add_outcome(&mut combined, outcome);
+    }
+    let outcome = recorded.insert(combined);
+    print_statistics(outcome);
+    if let Some(err) = outcome_error(outcome) {
+        return Err(err.into());
+    }
+    outln!("Done");
+    Ok(())
+}
+
+/// Print the events of syncs into several targets, labelling each line with the
+/// target it's about as well as with any pair, and have them cancelled when
+/// we're interrupted
+fn labelled_printers<'a>(
+    syncers: impl IntoIterator<Item = &'a mut Syncer>,
+) -> Vec<tokio::task::JoinHandle<SyncSummary>> {
+    syncers
+        .into_iter()
+        .map(|syncer| {
+            let label = match LABEL.try_with(Clone::clone) {
+                Ok(pair) => format!("{} {}", pair, syncer.target()),
+                Err(_) => syncer.target().to_string(),
+            };
+            syncer.set_cancellation(interrupt_token());
+            print_labelled_events(syncer, |_| false, Some(label))
+        })
+        .collect()
+}
+
+/// Keep other syncs out of several targets until we're done with them, taking
+/// their locks in order so that syncs into the same targets can't deadlock
+async fn lock_targets(targets: Vec<&Endpoint>, no_wait: bool) -> io::Result<Vec<TargetLock>> {
+    let mut locked: Vec<_> = targets
+        .into_iter()
+        .map(|target| (TargetLock::path_for(target.url()), target))
+        .collect();
+    locked.sort_by(|a, b| a.0.cmp(&b.0));
+    locked.dedup_by(|a, b| a.0 == b.0);
+    let mut locks = Vec::with_capacity(locked.len());
+    for (lock_path, target) in locked {
+        locks.push(match TargetLock::try_acquire(&lock_path) {
+            Err(Error::Locked(_)) if !no_wait => {
+                outln!("Waiting for another sync into {}", target.url());
+                TargetLock::acquire(&lock_path, &interrupt_token()).await?
+            }
+            result => result?,
+        });
+    }
+    Ok(locks)
+}
+
+/// Add what a sync into one of several targets did to what they did together, as
+/// if one sync had pushed into them all at once
+fn add_outcome(combined: &mut SyncOutcome, outcome: SyncOutcome) {
+    combined.report.merge(outcome.report);
+    combined.refused.extend(outcome.refused);
+    combined.pack_bytes += outcome.pack_bytes;
+    combined.pack_objects += outcome.pack_objects;
+    combined.negotiations = combined.negotiations.max(outcome.negotiations);
+    let (timings, took) = (&mut combined.timings, outcome.timings);
+    timings.connect = timings.connect.max(took.connect);
+    timings.plan = timings.plan.max(took.plan);
+    timings.push = timings.push.max(took.push);
+    timings.transfer = timings.transfer.max(took.transfer);
+}
+
+/// Show what a sync would do
+async fn plan(remotes: RemoteArgs, plan: PlanArgs, connect: ConnectArgs) -> io::Result<()> {
+    let mut summary = None;
+    let result = plan_once(&remotes, plan, connect, &mut summary).await;
+    finish_summaries(&remotes, summary.into_iter().collect(), &result);
+    if json_output() {
+        let count = |kind| {
+            Json::from(result.as_ref().map_or(0, |plan| {
+                plan.updates
+                    .iter()
+                    .filter(|update| update.kind() == kind)
+                    .count()
+            }))
+        };
+        print_json(
+            "result",
+            vec![
+                ("success", Json::from(result.is_ok())),
+                ("create", count(RefChangeKind::Create)),
+                ("update", count(RefChangeKind::Update)),
+                ("delete", count(RefChangeKind::Delete)),
+                (
+                    "refused",
+                    Json::from(result.as_ref().map_or(0, |plan| plan.refused.len())),
+                ),
+                (
+                    "error",
+                    Json::from(result.as_ref().err().map(|err| err.to_string())),
+                ),
+            ],
+        );
+    }
+    let plan = result?;
+    for update in &plan.updates {
+        match update.kind() {
+            RefChangeKind::Create => outln!("  create {} {}", update.refname, update.newsha),
+            RefChangeKind::Update => outln!(
+                "  update {} {}..{}",
+                update.refname,
+                update.oldsha,
+                update.newsha
+            ),
+            RefChangeKind::Delete => outln!("  delete {}", update.refname),
+        }
+    }
+    let count = |kind| {
+        plan.updates
+            .iter()
+            .filter(|update| update.kind() == kind)
+            .count()
+    };
+    outln!(
+        "{} to create, {} to update, {} to delete, {} refused",
+        count(RefChangeKind::Create),
+        count(RefChangeKind::Update),
+        count(RefChangeKind::Delete),
+        plan.refused.len()
+    );
+    Ok(())
+}
+
+/// Work out what a sync would do, keeping a report of it if it starts
+async fn plan_once(
+    remotes: &RemoteArgs,
+    plan: PlanArgs,
+    connect: ConnectArgs,
+    summary: &mut Option<SyncSummary>,
+) -> io::Result<SyncPlan> {
+    let builder = connect.apply(plan.apply(SyncOptions::builder()));
+    let mut syncer = syncer(remotes, &connect, builder.build()?).await?;
+
+    // The plan is described in full afterwards, except as JSON
+    let printer = print_events(&mut syncer, |event| {
+        !json_output() && matches!(event, SyncEvent::PlanComputed(_))
+    });
+    let plan = async {
+        let session = syncer.connect().await?;
+        let plan = session.plan().await;
+        session.abort().await;
+        plan
+    }
+    .await;
+    drop(syncer);
+    *summary = Some(printer.await?);
+    Ok(plan?)
+}
+
+/// Check that the target has been synced with the source
+async fn verify(remotes: RemoteArgs, plan: PlanArgs, connect: ConnectArgs) -> io::Result<()> {
+    let builder = connect.apply(plan.apply(SyncOptions::builder()));
+    let mut syncer = syncer(&remotes, &connect, builder.build()?).await?;
+    let printer = print_events(&mut syncer, |event| {
+        matches!(event, SyncEvent::AdvertisementRead(..))
+    });
+    let drift = syncer.verify().await;
+    drop(syncer);
+    printer.await?;
+    let drift = drift?;
+    for diff in &drift {
+        outln!("{}", diff);
+    }
+    if !drift.is_empty() {
+        return Err(Error::Drifted(drift.len()).into());
+    }
+    outln!("The target matches the source");
+    Ok(())
+}
+
+/// Compare the refs of the source and target, returning whether they're the same
+async fn diff(remotes: RemoteArgs, changed: bool, connect: ConnectArgs) -> io::Result<bool> {
+    let (source, target) = endpoints(&remotes, &connect).await?;
+    let (source_advert, target_advert) =
+        tokio::try_join!(source.advertisement(), target.advertisement())?;
+    let diffs = diff_refs(source_advert.refs(), target_advert.refs());
+    let mut same = true;
+    for diff in &diffs {
+        match diff.state {
+            RefState::Same(_) if changed => continue,
+            RefState::Same(_) => {}
+            _ => same = false,
+        }
+        outln!("{}", diff);
+    }
+    Ok(same)
+}
+
+/// List the refs in a repository, in the manner of `git ls-remote`
+async fn ls_remote(
+    server: Option<String>,
+    repo: Option<PathBuf>,
+    symref: bool,
+    location: String,
+    patterns: Vec<RefPattern>,
+    connect: ConnectArgs,
+) -> io::Result<()> {
+    let location = match &repo {
+        Some(repo) => remote_url(repo, &location, false)
+            .await?
+            .unwrap_or(location),
+        None => location,
+    };
+    let advert = endpoint(&connect, server.as_deref(), &location)?
+        .advertisement()
+        .await?;
+    let mut refs: Vec<_> = advert
         .refs()
         .iter()
-        .map(|(_, v)| v.as_str())
+        .filter(|(name, _)| {
+            patterns.is_empty() || patterns.iter().any(|pattern| pattern.matches(name))
+        })
         .collect();
-    let caps = &[
-        (Capability::SideBand64K, None),
-        (Capability::OfsDelta, None),
-        (Capability::ThinPack, None),
-        (Capability::Agent, Some("git_sync/0.1")),
-    ];
+    // HEAD comes first, as it does for git
+    refs.sort_by_key(|(name, _)| (name.as_str() != "HEAD", name.as_str()));
+    for (name, sha) in refs {
+        if let (true, Some(target)) = (symref, advert.symrefs().get(name)) {
+            outln!("ref: {}\t{}", target, name);
+        }
+        outln!("{}\t{}", sha, name);
+    }
+    Ok(())
+}
 
-    let expecting_pack_data = !wants.is_empty();
-    let want_iter = wants.iter().copied();
-    let have_iter = haves.iter().copied();
-    let caps_iter = caps.iter().copied();
-    // Finally send that out to the upload_pack service so it knows what to send to us.
-    {
-        let (reader, writer) = upload_pack.streams();
-        println!("Sending pack request to uploader...");
-        request_pack(reader, writer, want_iter, have_iter, caps_iter).await?;
+impl Cli {
+    /// The repositories the command works with, if it works with a pair of them
+    fn remotes(&self) -> Option<&RemoteArgs> {
+        match self {
+            Cli::Sync { remotes, .. }
+            | Cli::Prune { remotes, .. }
+            | Cli::Plan { remotes, .. }
+            | Cli::Verify { remotes, .. }
+            | Cli::Diff { remotes, .. } => Some(remotes),
+            Cli::Daemon { .. } | Cli::LsRemote { .. } => None,
+        }
     }
 
-    let upload_caps = &[
-        (Capability::ReportStatus, None),
-        (Capability::Atomic, None),
-        (Capability::SideBand64K, None),
-        (Capability::Agent, Some("git_sync/0.1")),
-    ];
+    /// How much the command is to say
+    fn log(&self) -> &LogArgs {
+        match self {
+            Cli::Sync { log, .. }
+            | Cli::Prune { log, .. }
+            | Cli::Plan { log, .. }
+            | Cli::Verify { log, .. }
+            | Cli::Diff { log, .. }
+            | Cli::Daemon { log, .. }
+            | Cli::LsRemote { log, .. } => log,
+        }
+    }
+
+    /// How the command reports what happens, if it can be asked
+    fn output(&self) -> Option<&OutputArgs> {
+        match self {
+            Cli::Sync { output, .. } | Cli::Prune { output, .. } | Cli::Plan { output, .. } => {
+                Some(output)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A pair's settings as options, in the order of their keys
+fn setting_args(settings: &ConfigTable) -> Vec<Vec<OsString>> {
+    settings
+        .iter()
+        .map(|(key, value)| match value {
+            ConfigValue::Boolean(true) => vec![format!("--{}", key).into()],
+            ConfigValue::Boolean(false) => vec![],
+            ConfigValue::Array(values) => values
+                .iter()
+                .map(|value| format!("--{}={}", key, value).into())
+                .collect(),
+            value => vec![format!("--{}={}", key, value).into()],
+        })
+        .collect()
+}
+
+/// The command line we were given, without `--config`, to run for each pair
+fn args_without_config() -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut given = std::env::args_os();
+    while let Some(arg) = given.next() {
+        if arg == "--config" {
+            given.next();
+        } else if !arg.to_string_lossy().starts_with("--config=") {
+            args.push(arg);
+        }
+    }
+    args
+}
+
+/// The command to run for one pair of a configuration file: the `base` command,
+/// with those of the pair's settings which it takes as options.  Every setting
+/// must be an option of `sync`, so a file which works for syncing works for the
+/// other subcommands too.
+fn pair_cli(base: &[OsString], pair: &PairConfig) -> Result<Cli, String> {
+    let remotes: Vec<OsString> = vec!["--".into(), (&pair.source).into(), (&pair.target).into()];
+    let with = |args: &[OsString]| -> Vec<OsString> {
+        base.iter().chain(args).chain(&remotes).cloned().collect()
+    };
+    let message = |err: structopt::clap::Error| {
+        let msg = err.message.lines().next().unwrap_or_default();
+        msg.trim_start_matches("error: ").to_string()
+    };
+    let settings = setting_args(&pair.settings);
+    let mut args = vec!["git-sync".into(), "sync".into()];
+    args.extend(settings.iter().flatten().cloned());
+    args.extend(remotes.iter().cloned());
+    Cli::from_iter_safe(args).map_err(message)?;
+
+    let mut args = Vec::new();
+    for setting in settings {
+        match Cli::from_iter_safe(with(&setting)) {
+            Err(err) if err.kind == ErrorKind::UnknownArgument => {}
+            _ => args.extend(setting),
+        }
+    }
+    Cli::from_iter_safe(with(&args)).map_err(message)
+}
+
+/// What happened to one pair of a configuration file
+struct PairOutcome {
+    /// Where the pair is in the list
+    index: usize,
+    result: io::Result<i32>,
+    /// How long working on the pair took
+    duration: Duration,
+    /// What its sync did, if it got that far
+    outcome: Option<SyncOutcome>,
+}
+
+/// Work on the pairs in a queue until it's empty, or until told to stop
+async fn pair_worker(
+    queue: Arc<Mutex<VecDeque<(usize, PairConfig, Cli)>>>,
+    stop: CancellationToken,
+    labelled: bool,
+    keep_going: bool,
+    group_output: bool,
+) -> Vec<PairOutcome> {
+    let mut outcomes = Vec::new();
+    while !stop.is_cancelled() {
+        let (index, pair, cli) = match queue.lock().unwrap().pop_front() {
+            Some(next) => next,
+            None => break,
+        };
+        let outcome = run_pair(index, &pair, cli, labelled, group_output).await;
+        if outcome.result.is_err() && !keep_going {
+            stop.cancel();
+        }
+        outcomes.push(outcome);
+    }
+    outcomes
+}
+
+/// Do as `cli` asks for the pair at `index`, labelling its output with its name
+/// if asked, and holding the output back until it's done if asked to group it
+async fn run_pair(
+    index: usize,
+    pair: &PairConfig,
+    cli: Cli,
+    labelled: bool,
+    group_output: bool,
+) -> PairOutcome {
+    let slot = Arc::new(PairSlot {
+        index,
+        name: pair.name.clone(),
+        outcome: Mutex::new(None),
+        output: group_output.then(|| Mutex::new(Vec::new())),
+    });
+    let started = Instant::now();
+    let work = PAIR
+        .scope(slot.clone(), async {
+            outln!("Pair {}: {} to {}", pair.name, pair.source, pair.target);
+            run_one(cli).await
+        })
+        .instrument(tracing::info_span!("pair", name = %pair.name));
+    let result = if labelled {
+        LABEL.scope(pair.name.clone(), work).await
+    } else {
+        work.await
+    };
+    release_output(&slot);
+    let outcome = slot.outcome.lock().unwrap().take();
+    PairOutcome {
+        index,
+        result,
+        duration: started.elapsed(),
+        outcome,
+    }
+}
+
+/// Run the `base` command for each pair, working on up to `jobs` of them at once,
+/// and returning what happened to each pair attempted, in order.  No more pairs
+/// are started once `stop` is cancelled, which happens when one fails unless
+/// asked to keep going.  Each pair's output is held back until it's done if
+/// asked to group it.
+async fn run_pairs(
+    pairs: &[PairConfig],
+    base: &[OsString],
+    jobs: usize,
+    keep_going: bool,
+    group_output: bool,
+    stop: &CancellationToken,
+) -> io::Result<Vec<PairOutcome>> {
+    // Check every pair's options before working on any of them
+    let mut queue = VecDeque::new();
+    let mut outcomes = Vec::new();
+    for (index, pair) in pairs.iter().enumerate() {
+        match pair_cli(base, pair) {
+            Ok(cli) => queue.push_back((index, pair.clone(), cli)),
+            Err(msg) if keep_going => outcomes.push(PairOutcome {
+                index,
+                result: Err(Error::Config(msg).into()),
+                duration: Duration::ZERO,
+                outcome: None,
+            }),
+            Err(msg) => return Err(Error::Config(format!("{}: {}", pair.name, msg)).into()),
+        }
+    }
+
+    let queue = Arc::new(Mutex::new(queue));
+    let workers: Vec<_> = (0..jobs.min(pairs.len()))
+        .map(|_| {
+            tokio::spawn(pair_worker(
+                queue.clone(),
+                stop.clone(),
+                jobs > 1,
+                keep_going,
+                group_output,
+            ))
+        })
+        .collect();
+    for worker in workers {
+        outcomes.extend(worker.await?);
+    }
+    outcomes.sort_by_key(|outcome| outcome.index);
+    Ok(outcomes)
+}
+
+/// Report what happened to the pairs, returning the worst status of those which
+/// succeeded, the number which failed and why the first of those did
+fn summarise(pairs: &[PairConfig], outcomes: &[PairOutcome]) -> (i32, usize, ExitReason) {
+    let mut status = 0;
+    let mut failures = 0;
+    let mut reason = ExitReason::Success;
+    for outcome in outcomes {
+        match &outcome.result {
+            Ok(pair_status) => status = status.max(*pair_status),
+            Err(err) => {
+                let failed = format!("Pair {} failed:", pairs[outcome.index].name);
+                errln!("{} {}", paint(Paint::Error, &failed, true), err);
+                if failures == 0 {
+                    reason = ExitReason::for_io_error(err);
+                }
+                failures += 1;
+            }
+        }
+    }
+    if json_output() {
+        print_json(
+            "summary",
+            vec![
+                ("succeeded", Json::from(outcomes.len() - failures)),
+                ("failed", Json::from(failures)),
+                ("not_attempted", Json::from(pairs.len() - outcomes.len())),
+            ],
+        );
+    } else if pairs.len() > 1 {
+        outln!(
+            "{} pair(s) succeeded, {} failed, {} not attempted",
+            outcomes.len() - failures,
+            failures,
+            pairs.len() - outcomes.len()
+        );
+    }
+    (status, failures, reason)
+}
 
-    println!("Sending refset change to receiver...");
-    // Now let's ensure that we're doing *something* to the target
-    let expecting_to_send = send_refchange(
-        receive_pack.writer(),
-        target_advert.refs(),
-        source_advert.refs(),
-        upload_caps.iter().copied(),
+/// Do as the command line asks for each pair in a configuration file, working on
+/// up to `jobs` of them at once, and returning the worst status.  Unless asked to
+/// keep going, no more pairs are started once one has failed.
+async fn run_config(
+    path: &Path,
+    jobs: usize,
+    keep_going: bool,
+    group_output: bool,
+) -> io::Result<i32> {
+    let pairs = SyncConfig::load(path).await?.pairs();
+    let outcomes = run_pairs(
+        &pairs,
+        &args_without_config(),
+        jobs,
+        keep_going,
+        group_output,
+        &interrupt_token(),
     )
     .await?;
+    let (status, failures, reason) = summarise(&pairs, &outcomes);
+    if failures > 0 {
+        return Err(Error::PairsFailed {
+            failed: failures,
+            total: pairs.len(),
+            reason,
+        }
+        .into());
+    }
+    Ok(status)
+}
+
+/// A time as seconds since the epoch
+fn unix_time(time: SystemTime) -> Json {
+    Json::from(
+        time.duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .ok(),
+    )
+}
 
-    // Now process the pack data...
+/// A random duration of up to `max`
+fn random_duration(max: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
 
-    println!(
-        "We do{} expect to transfer pack data",
-        if expecting_pack_data { "" } else { " not" }
-    );
-    match expecting_to_send {
-        SendActivity::Nothing => println!("We're doing nothing with receive-pack"),
-        SendActivity::Deleting => {
-            println!("We're not needing to send a pack, but we need to read a report")
+/// The path of a pair's source, if it's a repository on this machine
+fn local_source(pair: &PairConfig) -> Option<PathBuf> {
+    if pair.settings.contains_key("source-server") {
+        return None;
+    }
+    match pair.source.parse() {
+        Ok(RemoteUrl::Local(path)) if path.is_dir() => Some(path),
+        _ => None,
+    }
+}
+
+/// Send a pair's name to `changes` whenever the refs of its source change
+async fn watch_source(name: String, path: PathBuf, changes: mpsc::Sender<String>) {
+    let mut watcher = RefWatcher::new(&path).await;
+    loop {
+        watcher.changed().await;
+        if changes.send(name.clone()).await.is_err() {
+            break;
         }
-        SendActivity::Sending => {
-            println!("We're definitely needing to send a pack to receive-pack")
+    }
+}
+
+/// How the daemon's last sync of a pair went
+#[derive(Clone)]
+struct LastSync {
+    finished: SystemTime,
+    duration: Duration,
+    /// Why the sync failed, if it did
+    error: Option<String>,
+    /// What the sync did, if it got that far
+    outcome: Option<SyncOutcome>,
+}
+
+/// A pair which the daemon syncs
+struct DaemonPair {
+    config: PairConfig,
+    /// When the pair should next be synced
+    due: SystemTime,
+    /// The task watching the pair's source, if any
+    watcher: Option<tokio::task::JoinHandle<()>>,
+    /// How the last sync went, if there has been one
+    last: Option<LastSync>,
+    /// When the pair last synced successfully
+    last_success: Option<SystemTime>,
+    /// When the pair's current sync started, if it's being synced
+    running: Option<SystemTime>,
+}
+
+impl Drop for DaemonPair {
+    fn drop(&mut self) {
+        if let Some(watcher) = &self.watcher {
+            watcher.abort();
         }
-    };
+    }
+}
 
-    if expecting_pack_data {
-        println!("Transferring pack data");
-        loop {
-            match ProtocolLine::read_from(upload_pack.reader(), false).await? {
-                ProtocolLine::Data(cow) => match cow[0] {
-                    1 => {
-                        let data = &cow[1..];
-                        // We need to send this content on to the receiver
-                        receive_pack.writer().write_all(data).await?;
-                    }
-                    2 => print!("{}", String::from_utf8_lossy(&cow[1..])),
-                    3 => eprint!("{}", String::from_utf8_lossy(&cow[1..])),
-                    v => eprintln!("Received {} bytes on channel {}", cow.len() - 1, v),
-                },
-                ProtocolLine::Flush => break,
-                l => {
-                    println!("Encountered a {:?}", l);
-                    break;
+/// How the daemon was asked to sync the pairs in its configuration file
+struct Daemon {
+    path: PathBuf,
+    interval: Duration,
+    jitter: Duration,
+    jobs: usize,
+    group_output: bool,
+    watch: bool,
+    /// The command to run for each pair
+    base: [OsString; 2],
+    /// Where to tell systemd how we're getting on, if it's listening
+    notifier: Option<Notifier>,
+    /// Where to serve the daemon's status over HTTP, if anywhere
+    listen: Option<SocketAddr>,
+    /// The daemon's status, as served at `/status`
+    status: Arc<Mutex<Json>>,
+    /// Metrics about the pairs' syncs, as served at `/metrics`
+    metrics: Arc<Mutex<SyncMetrics>>,
+}
+
+impl Daemon {
+    /// Tell systemd something, if it's listening, warning if it can't be told
+    fn tell_systemd(&self, notify: impl FnOnce(&Notifier) -> Result<(), Error>) {
+        if let Some(notifier) = &self.notifier {
+            if let Err(err) = notify(notifier) {
+                errln!("{} {}", paint(Paint::Warning, "Warning:", true), err);
+            }
+        }
+    }
+
+    /// Read the configuration file, checking each pair's options and schedule so
+    /// that mistakes are found at once rather than when syncing
+    async fn load(&self) -> io::Result<Vec<PairConfig>> {
+        let pairs = SyncConfig::load(&self.path).await?.pairs();
+        for pair in &pairs {
+            pair_cli(&self.base, pair)
+                .map_err(|msg| Error::Config(format!("{}: {}", pair.name, msg)))?;
+            if let Some(schedule) = &pair.schedule {
+                if schedule.next_after(SystemTime::now()).is_none() {
+                    return Err(Error::Config(format!(
+                        "{}: The schedule {} never matches",
+                        pair.name, schedule
+                    ))
+                    .into());
                 }
             }
         }
-    } else if matches!(expecting_to_send, SendActivity::Sending) {
-        println!("We're expected to send a pack, but we have no objects to send");
-        println!("Let's send the magical empty pack to the receive-pack service...");
-        receive_pack.writer().write_all(EMPTY_PACK).await?;
+        if pairs.is_empty() {
+            return Err(Error::Config("There are no pairs to sync".to_string()).into());
+        }
+        Ok(pairs)
+    }
+
+    /// Start on a pair, which is due at once unless it has a schedule, and whose
+    /// source is watched if asked
+    fn start(&self, config: PairConfig, changes: &mpsc::Sender<String>) -> DaemonPair {
+        let due = match config.schedule {
+            Some(_) => self.next_due(&config),
+            None => SystemTime::now(),
+        };
+        let watcher = match local_source(&config) {
+            Some(path) if self.watch => Some(tokio::spawn(watch_source(
+                config.name.clone(),
+                path,
+                changes.clone(),
+            ))),
+            _ => None,
+        };
+        DaemonPair {
+            config,
+            due,
+            watcher,
+            last: None,
+            last_success: None,
+            running: None,
+        }
     }
 
-    println!("Shutting down upload-pack service");
-    // Done with upload pack:
-    upload_pack.die().await?;
+    /// When a pair should next be synced: at the next time its schedule matches,
+    /// or after the interval if it has none, plus up to the jitter
+    fn next_due(&self, pair: &PairConfig) -> SystemTime {
+        let now = SystemTime::now();
+        let due = pair
+            .schedule
+            .as_ref()
+            .and_then(|schedule| schedule.next_after(now))
+            .unwrap_or(now + self.interval);
+        due + random_duration(self.jitter)
+    }
 
-    if !matches!(expecting_to_send, SendActivity::Nothing) {
-        println!("Waiting for result from receive-pack service");
-        // We've now sent the pack to the other end, let's read and report the receive pack output
-        let mut rp_out = Vec::new();
-        loop {
-            match ProtocolLine::read_from(receive_pack.reader(), false).await? {
-                ProtocolLine::Data(cow) => match cow[0] {
-                    1 => {
-                        let data = &cow[1..];
-                        rp_out.extend_from_slice(data);
+    /// Update the status served at `/status` with the state of the pairs, those
+    /// indexed by `syncing` being synced now
+    fn publish(&self, started: SystemTime, pairs: &[DaemonPair], syncing: &[usize]) {
+        let pairs = pairs
+            .iter()
+            .enumerate()
+            .map(|(idx, pair)| {
+                let last = pair.last.as_ref().map(|last| {
+                    let mut fields = vec![
+                        ("finished", unix_time(last.finished)),
+                        ("duration", Json::from(last.duration.as_secs_f64())),
+                        (
+                            "outcome",
+                            Json::from(if last.error.is_none() {
+                                "success"
+                            } else {
+                                "failure"
+                            }),
+                        ),
+                        ("error", Json::from(last.error.clone())),
+                    ];
+                    fields.extend(outcome_json(last.outcome.as_ref()));
+                    json_object(fields)
+                });
+                json_object(vec![
+                    ("name", Json::from(pair.config.name.as_str())),
+                    ("source", Json::from(pair.config.source.as_str())),
+                    ("target", Json::from(pair.config.target.as_str())),
+                    ("syncing", Json::from(syncing.contains(&idx))),
+                    ("next_sync", unix_time(pair.due)),
+                    ("last_success", Json::from(pair.last_success.map(unix_time))),
+                    ("last_sync", Json::from(last)),
+                ])
+            })
+            .collect();
+        *self.status.lock().unwrap() = json_object(vec![
+            ("started", unix_time(started)),
+            ("pairs", Json::Array(pairs)),
+        ]);
+    }
+
+    /// Serve `/healthz`, `/status` and `/metrics` over HTTP, if asked to
+    async fn serve(&self) -> io::Result<()> {
+        let addr = match self.listen {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+        let listener = TcpListener::bind(addr).await.map_err(|err| {
+            io::Error::new(err.kind(), format!("Cannot listen on {}: {}", addr, err))
+        })?;
+        let status = self.status.clone();
+        let metrics = self.metrics.clone();
+        tokio::spawn(serve_http(listener, move |path: &str| match path {
+            "/healthz" => HttpResponse::ok("text/plain; charset=utf-8", "ok\n"),
+            "/status" => {
+                HttpResponse::ok("application/json", format!("{}\n", status.lock().unwrap()))
+            }
+            "/metrics" => HttpResponse::ok(PROMETHEUS_TEXT, metrics.lock().unwrap().render()),
+            _ => HttpResponse::text(404, "Not found, try /healthz, /status or /metrics\n"),
+        }));
+        Ok(())
+    }
+
+    /// Reread the configuration file, starting on the pairs which are new or have
+    /// changed and stopping those which have gone, while leaving the rest be.  If
+    /// the file can't be read, the pairs are left as they were.
+    async fn reload(&self, pairs: &mut Vec<DaemonPair>, changes: &mpsc::Sender<String>) {
+        self.tell_systemd(Notifier::reloading);
+        let configs = match self.load().await {
+            Ok(configs) => configs,
+            Err(err) => {
+                errln!("Not reloading the configuration: {}", err);
+                self.tell_systemd(|notifier| notifier.ready("Kept the old configuration"));
+                return;
+            }
+        };
+        let mut old = std::mem::take(pairs);
+        let mut kept = 0;
+        for config in configs {
+            match old.iter().position(|pair| pair.config == config) {
+                Some(idx) => {
+                    pairs.push(old.remove(idx));
+                    kept += 1;
+                }
+                None => {
+                    let mut pair = self.start(config, changes);
+                    // A pair whose options changed still has its history, and
+                    // isn't synced again while its old options are being used
+                    if let Some(old) = old.iter().find(|old| old.config.name == pair.config.name) {
+                        pair.last = old.last.clone();
+                        pair.last_success = old.last_success;
+                        pair.running = old.running;
                     }
-                    2 => print!("{}", String::from_utf8_lossy(&cow[1..])),
-                    3 => eprint!("{}", String::from_utf8_lossy(&cow[1..])),
-                    v => eprintln!("Received {} bytes on channel {}", cow.len() - 1, v),
-                },
-                ProtocolLine::Flush => break,
-                l => {
-                    println!("RPE: Encountered a {:?}", l);
-                    break;
+                    pairs.push(pair);
                 }
             }
         }
+        let mut metrics = self.metrics.lock().unwrap();
+        for pair in &old {
+            if !pairs
+                .iter()
+                .any(|kept| kept.config.name == pair.config.name)
+            {
+                metrics.remove(&pair.config.name);
+            }
+        }
+        drop(metrics);
+        let status = format!(
+            "Reloaded the configuration: {} pair(s) unchanged, {} new or changed, {} removed",
+            kept,
+            pairs.len() - kept,
+            old.len()
+        );
+        outln!("{}", status);
+        self.tell_systemd(|notifier| notifier.ready(&status));
+    }
+
+    /// Start syncing a pair, which waits until fewer than `jobs` pairs are being
+    /// synced, and sends the pair's name and what happened to `finished`
+    fn spawn_sync(
+        &self,
+        pair: &mut DaemonPair,
+        permits: &Arc<Semaphore>,
+        stop: &CancellationToken,
+        finished: &mpsc::Sender<(String, PairOutcome)>,
+    ) {
+        pair.running = Some(SystemTime::now());
+        let config = pair.config.clone();
+        let base = self.base.clone();
+        let labelled = self.jobs > 1;
+        let group_output = self.group_output;
+        let permits = permits.clone();
+        let stop = stop.clone();
+        let finished = finished.clone();
+        tokio::spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            let outcome = match pair_cli(&base, &config) {
+                _ if stop.is_cancelled() => PairOutcome {
+                    index: 0,
+                    result: Err(Error::Cancelled.into()),
+                    duration: Duration::ZERO,
+                    outcome: None,
+                },
+                Ok(cli) => run_pair(0, &config, cli, labelled, group_output).await,
+                Err(msg) => PairOutcome {
+                    index: 0,
+                    result: Err(Error::Config(msg).into()),
+                    duration: Duration::ZERO,
+                    outcome: None,
+                },
+            };
+            let _ = finished.send((config.name, outcome)).await;
+        });
+    }
+
+    /// Record what happened when a pair was synced, and when it's next due
+    fn record(&self, pair: &mut DaemonPair, outcome: PairOutcome, started: Instant) {
+        summarise(
+            std::slice::from_ref(&pair.config),
+            std::slice::from_ref(&outcome),
+        );
+        let now = SystemTime::now();
+        let name = &pair.config.name;
+        match (&outcome.result, pair.last_success) {
+            (Ok(_), _) => pair.last_success = Some(now),
+            (Err(_), Some(when)) => errln!(
+                "Pair {} last synced {} ago",
+                name,
+                format_duration(now.duration_since(when).unwrap_or_default())
+            ),
+            (Err(_), None) => errln!(
+                "Pair {} has not synced in the {} since starting",
+                name,
+                format_duration(started.elapsed())
+            ),
+        }
+        self.metrics.lock().unwrap().record(
+            name,
+            outcome.duration,
+            outcome.outcome.as_ref(),
+            outcome.result.is_ok(),
+        );
+        pair.last = Some(LastSync {
+            finished: now,
+            duration: outcome.duration,
+            error: outcome.result.err().map(|err| err.to_string()),
+            outcome: outcome.outcome,
+        });
+        // A pair whose source changed while it was syncing is synced again at once
+        if pair.running.take().is_none_or(|since| pair.due <= since) {
+            pair.due = self.next_due(&pair.config);
+        }
+    }
+
+    /// Sync the pairs again and again until interrupted, each at the times its
+    /// schedule gives, or if it has none, once at the start and then after
+    /// waiting the interval plus up to the jitter each time.  If asked to watch,
+    /// pairs whose source is on this machine are also synced when it changes.
+    /// Each pair is synced on its own, with up to `jobs` being synced at once,
+    /// and the configuration is reloaded on SIGHUP even while pairs are syncing.
+    ///
+    /// When run by systemd as a `Type=notify` service, the daemon says when it's
+    /// ready, reloading and stopping, and keeps its watchdog fed.  If asked, it
+    /// serves its health and the state of each pair over HTTP.
+    async fn run(&self) -> io::Result<()> {
+        let (changed, mut changes) = mpsc::channel(16);
+        let mut pairs: Vec<DaemonPair> = self
+            .load()
+            .await?
+            .into_iter()
+            .map(|config| self.start(config, &changed))
+            .collect();
+        let mut hangups = signal(SignalKind::hangup())?;
+        let started_at = SystemTime::now();
+        self.publish(started_at, &pairs, &[]);
+        self.serve().await?;
+        self.tell_systemd(|notifier| notifier.ready(&format!("Syncing {} pair(s)", pairs.len())));
+        if let (Some(interval), Some(notifier)) =
+            (Notifier::watchdog_interval(), Notifier::from_env())
+        {
+            tokio::spawn(async move {
+                while notifier.watchdog().is_ok() {
+                    tokio::time::sleep(interval).await;
+                }
+            });
+        }
 
-        println!("Report from receive-pack is {} bytes:", rp_out.len());
-        let mut cursor = Cursor::new(rp_out);
+        let stop = interrupt_token();
+        let started = Instant::now();
+        let permits = Arc::new(Semaphore::new(self.jobs));
+        let (finished, mut results) = mpsc::channel(self.jobs.max(1));
         loop {
-            match ProtocolLine::read_from(&mut cursor, true).await? {
-                ProtocolLine::Data(cow) => {
-                    let s = String::from_utf8_lossy(&cow);
-                    println!("remote: {}", s);
+            let now = SystemTime::now();
+            for pair in pairs.iter_mut() {
+                if pair.running.is_none() && pair.due <= now {
+                    self.spawn_sync(pair, &permits, &stop, &finished);
+                }
+            }
+
+            let next = pairs
+                .iter()
+                .filter(|pair| pair.running.is_none())
+                .min_by_key(|pair| pair.due);
+            let wait = next.map(|next| {
+                let wait = next
+                    .due
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+                let status = format!(
+                    "Syncing {} next, in {}",
+                    next.config.name,
+                    format_duration(wait)
+                );
+                outln!("{}", status);
+                self.tell_systemd(|notifier| notifier.status(&status));
+                wait
+            });
+            let syncing: Vec<usize> = (0..pairs.len())
+                .filter(|&i| pairs[i].running.is_some())
+                .collect();
+            self.publish(started_at, &pairs, &syncing);
+            tokio::select! {
+                _ = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => {}
+                Some(name) = changes.recv() => {
+                    if let Some(pair) = pairs.iter_mut().find(|pair| pair.config.name == name) {
+                        outln!("The refs in the source of {} changed", name);
+                        pair.due = SystemTime::now();
+                    }
                 }
-                ProtocolLine::Flush => break,
-                l => {
-                    println!("RPE: Encountered encapsulated {:?}", l);
-                    break;
+                Some(()) = hangups.recv() => self.reload(&mut pairs, &changed).await,
+                Some((name, outcome)) = results.recv() => {
+                    // A pair removed while it was syncing is forgotten
+                    if let Some(pair) = pairs.iter_mut().find(|pair| pair.config.name == name) {
+                        self.record(pair, outcome, started);
+                    }
                 }
+                _ = stop.cancelled() => break,
             }
         }
+
+        // Wait for the pairs being synced to stop
+        while pairs.iter().any(|pair| pair.running.is_some()) {
+            let (name, outcome) = match results.recv().await {
+                Some(result) => result,
+                None => break,
+            };
+            if let Some(pair) = pairs.iter_mut().find(|pair| pair.config.name == name) {
+                self.record(pair, outcome, started);
+            }
+        }
+        outln!("Interrupted, stopping");
+        self.tell_systemd(Notifier::stopping);
+        Ok(())
+    }
+}
+
+/// Do as the command line asks, returning the status to exit with
+async fn run(cli: Cli) -> io::Result<i32> {
+    cli.log().install()?;
+    if cli.output().is_some_and(|output| output.json) {
+        JSON_OUTPUT.store(true, Ordering::Relaxed);
+    }
+    if cli.output().is_some_and(|output| !output.no_progress)
+        && !json_output()
+        && !quiet()
+        && std::io::stderr().is_terminal()
+    {
+        PROGRESS.store(true, Ordering::Relaxed);
+    }
+    let summary = match cli.output().and_then(|output| output.summary.clone()) {
+        Some(path) => path,
+        None => return run_command(cli).await,
+    };
+    *SUMMARIES.lock().unwrap() = Some(Vec::new());
+    let started = SystemTime::now();
+    let result = run_command(cli).await;
+    write_summary(&summary, started, &result).await?;
+    result
+}
+
+/// Do as the command line asks, for one pair or those of a configuration file
+async fn run_command(cli: Cli) -> io::Result<i32> {
+    match (&cli, cli.remotes()) {
+        (
+            Cli::Daemon {
+                config,
+                interval,
+                jitter,
+                jobs,
+                group_output,
+                watch,
+                listen,
+                ..
+            },
+            _,
+        ) => {
+            let program = std::env::args_os()
+                .next()
+                .unwrap_or_else(|| "git-sync".into());
+            let daemon = Daemon {
+                path: config.clone(),
+                interval: *interval,
+                jitter: jitter.unwrap_or(*interval / 10),
+                jobs: jobs.get(),
+                group_output: *group_output,
+                watch: *watch,
+                base: [program, "sync".into()],
+                notifier: Notifier::from_env(),
+                listen: *listen,
+                status: Arc::new(Mutex::new(Json::Null)),
+                metrics: Arc::new(Mutex::new(SyncMetrics::new())),
+            };
+            daemon.run().await?;
+            Ok(0)
+        }
+        (
+            _,
+            Some(RemoteArgs {
+                config: Some(path),
+                jobs,
+                keep_going,
+                group_output,
+                ..
+            }),
+        ) => run_config(path, jobs.get(), *keep_going, *group_output).await,
+        _ => run_one(cli).await,
+    }
+}
+
+/// Do as the command line asks for a single pair of repositories
+async fn run_one(cli: Cli) -> io::Result<i32> {
+    match cli {
+        Cli::Sync {
+            remotes,
+            plan,
+            push,
+            connect,
+            ..
+        } => sync(remotes, plan, push, connect, false).await?,
+        Cli::Prune {
+            remotes,
+            plan,
+            push,
+            connect,
+            ..
+        } => sync(remotes, plan, push, connect, true).await?,
+        Cli::Plan {
+            remotes,
+            plan: plan_args,
+            connect,
+            ..
+        } => plan(remotes, plan_args, connect).await?,
+        Cli::Verify {
+            remotes,
+            plan,
+            connect,
+            ..
+        } => verify(remotes, plan, connect).await?,
+        Cli::Diff {
+            remotes,
+            changed,
+            exit_code,
+            connect,
+            ..
+        } => {
+            if !diff(remotes, changed, connect).await? && exit_code {
+                return Ok(1);
+            }
+        }
+        Cli::Daemon { .. } => unreachable!("The daemon is run by run()"),
+        Cli::LsRemote {
+            server,
+            repo,
+            symref,
+            location,
+            patterns,
+            connect,
+            ..
+        } => ls_remote(server, repo, symref, location, patterns, connect).await?,
+    }
+    Ok(0)
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = match Cli::from_iter_safe(std::env::args_os()) {
+        Ok(cli) => cli,
+        Err(err) if err.use_stderr() => {
+            eprintln!("{}", err.message);
+            std::process::exit(ExitReason::Config.code());
+        }
+        // Help and the version are asked for, so they aren't errors
+        Err(err) => err.exit(),
+    };
+    // Report errors in full, since those from failed services carry their stderr
+    match run(cli).await {
+        Ok(status) => std::process::exit(status),
+        Err(err) => {
+            eprintln!("{} {}", paint(Paint::Error, "Error:", true), err);
+            std::process::exit(ExitReason::for_io_error(&err).code());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn refuses_durations_which_overflow() {
+        assert_eq!(
+            parse_duration("999999999999999999d"),
+            Err("Expected a duration such as 30s or 5m, not 999999999999999999d".to_string())
+        );
+        assert!(parse_duration("99999999999999999999").is_err());
+        let most = u64::MAX.to_string();
+        assert_eq!(parse_duration(&most), Ok(Duration::from_secs(u64::MAX)));
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("512k"), Ok(512 << 10));
+        assert_eq!(parse_size("10M"), Ok(10 << 20));
+        assert_eq!(parse_size("10MB"), Ok(10 << 20));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert!(parse_size("2P").is_err());
+        assert!(parse_size("G").is_err());
+    }
+
+    #[test]
+    fn refuses_sizes_which_overflow() {
+        assert_eq!(
+            parse_size("99999999999T"),
+            Err("Expected a size such as 512M or 2G, not 99999999999T".to_string())
+        );
+        assert!(parse_size("99999999999999999999").is_err());
     }
-    // We're done, let's close down our connections
-    println!("Shutting down receive-pack service");
-    receive_pack.die().await?;
-    println!("Done");
-    Ok(())
 }
@@ -0,0 +1,249 @@
+/// Counting what syncs do, for Prometheus to scrape or be pushed
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use prometheus::{
+    CounterVec, Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+
+use super::{RefChangeKind, SyncOutcome};
+
+/// The upper bounds, in seconds, of the buckets of the sync duration histogram
+const DURATION_BUCKETS: &[f64] = &[0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// The phases of a sync whose durations are counted, the last of which is part
+/// of pushing
+const PHASES: &[&str] = &["connect", "plan", "push", "transfer"];
+
+/// The changes to refs which are counted
+const CHANGES: &[&str] = &["created", "updated", "deleted", "rejected"];
+
+/// Metrics about the syncs of some pairs of repositories, labelled with each
+/// pair's name, written out in Prometheus's text format
+///
+/// ```
+/// # use git_sync::{SyncMetrics, SyncOutcome, SyncTimings};
+/// # use std::time::Duration;
+/// let mut metrics = SyncMetrics::new();
+/// let outcome = SyncOutcome {
+///     pack_bytes: 2048,
+///     pack_objects: 12,
+///     negotiations: 1,
+///     timings: SyncTimings {
+///         push: Duration::from_secs(2),
+///         transfer: Duration::from_millis(1500),
+///         ..SyncTimings::default()
+///     },
+///     ..SyncOutcome::default()
+/// };
+/// metrics.record("mirror", Duration::from_secs(3), Some(&outcome), true);
+/// metrics.record("mirror", Duration::from_secs(20), None, false);
+/// let text = metrics.render();
+/// assert!(text.contains("git_sync_syncs_total{pair=\"mirror\"} 2\n"));
+/// assert!(text.contains("git_sync_failures_total{pair=\"mirror\"} 1\n"));
+/// assert!(text.contains("git_sync_pack_bytes_total{pair=\"mirror\"} 2048\n"));
+/// assert!(text.contains("git_sync_pack_objects_total{pair=\"mirror\"} 12\n"));
+/// assert!(text.contains("git_sync_sync_duration_seconds_bucket{pair=\"mirror\",le=\"5\"} 1\n"));
+/// assert!(text.contains("git_sync_sync_duration_seconds_bucket{pair=\"mirror\",le=\"+Inf\"} 2\n"));
+/// assert!(text.contains("git_sync_sync_duration_seconds_sum{pair=\"mirror\"} 23\n"));
+/// assert!(text.contains("git_sync_phase_seconds_total{pair=\"mirror\",phase=\"transfer\"} 1.5\n"));
+///
+/// metrics.remove("mirror");
+/// assert!(!metrics.render().contains("mirror"));
+/// ```
+#[derive(Debug)]
+pub struct SyncMetrics {
+    registry: Registry,
+    durations: HistogramVec,
+    syncs: IntCounterVec,
+    failures: IntCounterVec,
+    pack_bytes: IntCounterVec,
+    pack_objects: IntCounterVec,
+    negotiations: IntCounterVec,
+    ref_updates: IntCounterVec,
+    phase_seconds: CounterVec,
+    last_sync: GaugeVec,
+    last_success: GaugeVec,
+}
+
+impl Default for SyncMetrics {
+    fn default() -> Self {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounterVec::new(Opts::new(name, help), &["pair"]).unwrap();
+            registry.register(Box::new(counter.clone())).unwrap();
+            counter
+        };
+        let gauge = |name: &str, help: &str| {
+            let gauge = GaugeVec::new(Opts::new(name, help), &["pair"]).unwrap();
+            registry.register(Box::new(gauge.clone())).unwrap();
+            gauge
+        };
+        let durations = HistogramVec::new(
+            HistogramOpts::new("git_sync_sync_duration_seconds", "How long syncs took")
+                .buckets(DURATION_BUCKETS.to_vec()),
+            &["pair"],
+        )
+        .unwrap();
+        registry.register(Box::new(durations.clone())).unwrap();
+        let ref_updates = IntCounterVec::new(
+            Opts::new(
+                "git_sync_ref_updates_total",
+                "Ref updates pushed to targets, by what they did",
+            ),
+            &["pair", "change"],
+        )
+        .unwrap();
+        registry.register(Box::new(ref_updates.clone())).unwrap();
+        let phase_seconds = CounterVec::new(
+            Opts::new(
+                "git_sync_phase_seconds_total",
+                "Seconds spent in each phase of syncs which got as far as pushing",
+            ),
+            &["pair", "phase"],
+        )
+        .unwrap();
+        registry.register(Box::new(phase_seconds.clone())).unwrap();
+        SyncMetrics {
+            durations,
+            syncs: counter("git_sync_syncs_total", "Syncs attempted"),
+            failures: counter("git_sync_failures_total", "Syncs which failed"),
+            pack_bytes: counter(
+                "git_sync_pack_bytes_total",
+                "Bytes of pack data relayed from sources to targets",
+            ),
+            pack_objects: counter(
+                "git_sync_pack_objects_total",
+                "Objects in the packs relayed from sources to targets",
+            ),
+            negotiations: counter(
+                "git_sync_negotiation_round_trips_total",
+                "Times a pack was negotiated with a source",
+            ),
+            ref_updates,
+            phase_seconds,
+            last_sync: gauge(
+                "git_sync_last_sync_timestamp_seconds",
+                "When the last sync finished",
+            ),
+            last_success: gauge(
+                "git_sync_last_success_timestamp_seconds",
+                "When the last successful sync finished",
+            ),
+            registry,
+        }
+    }
+}
+
+impl SyncMetrics {
+    pub fn new() -> SyncMetrics {
+        SyncMetrics::default()
+    }
+
+    /// Count a sync of `pair` which took `duration`, and whose outcome is given
+    /// if it got as far as pushing
+    pub fn record(
+        &mut self,
+        pair: &str,
+        duration: Duration,
+        outcome: Option<&SyncOutcome>,
+        succeeded: bool,
+    ) {
+        let labels = &[pair];
+        self.durations
+            .with_label_values(labels)
+            .observe(duration.as_secs_f64());
+        self.syncs.with_label_values(labels).inc();
+        // Every pair synced has all its counters, even those still at nought
+        for counter in [
+            &self.failures,
+            &self.pack_bytes,
+            &self.pack_objects,
+            &self.negotiations,
+        ] {
+            counter.with_label_values(labels).inc_by(0);
+        }
+        for change in CHANGES {
+            self.ref_updates
+                .with_label_values(&[pair, change])
+                .inc_by(0);
+        }
+        if let Some(outcome) = outcome {
+            let report = &outcome.report;
+            self.pack_bytes
+                .with_label_values(labels)
+                .inc_by(outcome.pack_bytes);
+            self.pack_objects
+                .with_label_values(labels)
+                .inc_by(outcome.pack_objects);
+            self.negotiations
+                .with_label_values(labels)
+                .inc_by(outcome.negotiations as u64);
+            let changes = [
+                report.count(RefChangeKind::Create),
+                report.count(RefChangeKind::Update),
+                report.count(RefChangeKind::Delete),
+                report.rejected().count(),
+            ];
+            for (change, count) in CHANGES.iter().zip(changes) {
+                self.ref_updates
+                    .with_label_values(&[pair, change])
+                    .inc_by(count as u64);
+            }
+            let timings = &outcome.timings;
+            let phases = [
+                timings.connect,
+                timings.plan,
+                timings.push,
+                timings.transfer,
+            ];
+            for (phase, took) in PHASES.iter().zip(phases) {
+                self.phase_seconds
+                    .with_label_values(&[pair, phase])
+                    .inc_by(took.as_secs_f64());
+            }
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        self.last_sync.with_label_values(labels).set(now);
+        if succeeded {
+            self.last_success.with_label_values(labels).set(now);
+        } else {
+            self.failures.with_label_values(labels).inc();
+        }
+    }
+
+    /// Forget a pair, e.g. once it's no longer being synced
+    pub fn remove(&mut self, pair: &str) {
+        let labels = &[pair];
+        // Metrics the pair never had can't be removed, which is no matter
+        let _ = self.durations.remove_label_values(labels);
+        for counter in [
+            &self.syncs,
+            &self.failures,
+            &self.pack_bytes,
+            &self.pack_objects,
+            &self.negotiations,
+        ] {
+            let _ = counter.remove_label_values(labels);
+        }
+        for change in CHANGES {
+            let _ = self.ref_updates.remove_label_values(&[pair, change]);
+        }
+        for phase in PHASES {
+            let _ = self.phase_seconds.remove_label_values(&[pair, phase]);
+        }
+        let _ = self.last_sync.remove_label_values(labels);
+        let _ = self.last_success.remove_label_values(labels);
+    }
+
+    /// The metrics in Prometheus's text exposition format
+    pub fn render(&self) -> String {
+        let mut out = Vec::new();
+        // Encoding what the registry gathered into memory can't fail
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut out);
+        String::from_utf8_lossy(&out).into_owned()
+    }
+}
@@ -0,0 +1,224 @@
+/// Telling other systems how syncs went, by webhook or by running a command
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::{http_send, run_shell, Error, ExitReason, Json};
+
+/// How long a notification may take before it's given up on, so that a stuck
+/// webhook or command can't hold up the next sync
+pub const NOTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How a sync ended, for deciding whether to notify anyone about it
+///
+/// ```
+/// # use git_sync::{ExitReason, NotifyOn};
+/// assert_eq!(NotifyOn::for_exit(ExitReason::Success), NotifyOn::Success);
+/// assert_eq!(NotifyOn::for_exit(ExitReason::Partial), NotifyOn::Rejected);
+/// assert_eq!(NotifyOn::for_exit(ExitReason::Transport), NotifyOn::Failure);
+/// assert_eq!("rejected".parse::<NotifyOn>().unwrap(), NotifyOn::Rejected);
+/// assert!("sometimes".parse::<NotifyOn>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyOn {
+    /// Every update was pushed
+    Success,
+    /// Some updates were rejected by the target, or refused by a policy
+    Rejected,
+    /// The sync failed for some other reason
+    Failure,
+}
+
+impl NotifyOn {
+    /// How a sync which exited for `reason` ended
+    pub fn for_exit(reason: ExitReason) -> NotifyOn {
+        match reason {
+            ExitReason::Success => NotifyOn::Success,
+            ExitReason::Partial | ExitReason::Refused => NotifyOn::Rejected,
+            _ => NotifyOn::Failure,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NotifyOn::Success => "success",
+            NotifyOn::Rejected => "rejected",
+            NotifyOn::Failure => "failure",
+        }
+    }
+}
+
+impl FromStr for NotifyOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "success" => Ok(NotifyOn::Success),
+            "rejected" => Ok(NotifyOn::Rejected),
+            "failure" => Ok(NotifyOn::Failure),
+            _ => Err(format!("Expected success, rejected or failure, not {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for NotifyOn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Where to send notifications
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyTarget {
+    /// An `http://` URL to `POST` the payload to.  HTTPS isn't supported, but a
+    /// command such as `curl --json @- https://...` can post the payload instead.
+    Webhook(String),
+    /// A shell command to run with the payload on its stdin
+    Command(String),
+}
+
+impl NotifyTarget {
+    /// Send `payload`, about a sync which ended as `on` says, to the target
+    /// within [`NOTIFY_TIMEOUT`]
+    pub async fn send(&self, on: NotifyOn, payload: &Json) -> Result<(), Error> {
+        let send = async {
+            match self {
+                NotifyTarget::Webhook(url) => {
+                    http_send("POST", url, "application/json", &payload.to_string()).await
+                }
+                NotifyTarget::Command(command) => run_command(command, on, payload).await,
+            }
+        };
+        tokio::time::timeout(NOTIFY_TIMEOUT, send)
+            .await
+            .map_err(|_| Error::Transport(format!("{} took too long", self)))?
+    }
+}
+
+impl fmt::Display for NotifyTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotifyTarget::Webhook(url) => write!(f, "Webhook {}", url),
+            NotifyTarget::Command(command) => write!(f, "Notification command `{}`", command),
+        }
+    }
+}
+
+/// Run `command` with the shell, writing `payload` to its stdin as a line of
+/// JSON, and with `GIT_SYNC_EVENT` saying how the sync ended
+async fn run_command(command: &str, on: NotifyOn, payload: &Json) -> Result<(), Error> {
+    run_shell(
+        &format!("Notification command `{}`", command),
+        command,
+        &[("GIT_SYNC_EVENT", on.as_str())],
+        format!("{}\n", payload).as_bytes(),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::json_object;
+
+    fn payload() -> Json {
+        json_object(vec![
+            ("pair", Json::from("docs")),
+            ("exit", Json::from("partial")),
+        ])
+    }
+
+    #[test]
+    fn sorts_every_exit_into_an_event() {
+        use ExitReason::*;
+        let events: Vec<_> = [
+            Success, Partial, Transport, Protocol, Refused, Config, Locked, Drifted, Failed,
+            Cancelled,
+        ]
+        .iter()
+        .map(|reason| NotifyOn::for_exit(*reason))
+        .collect();
+        use NotifyOn::{Failure as F, Rejected as R, Success as S};
+        assert_eq!(events, vec![S, R, F, F, R, F, F, F, F, F]);
+    }
+
+    #[test]
+    fn parses_what_it_shows() {
+        for on in &[NotifyOn::Success, NotifyOn::Rejected, NotifyOn::Failure] {
+            assert_eq!(on.to_string().parse::<NotifyOn>(), Ok(*on));
+        }
+        assert_eq!(
+            "Success".parse::<NotifyOn>(),
+            Err("Expected success, rejected or failure, not Success".to_string())
+        );
+    }
+
+    #[test]
+    fn names_targets() {
+        assert_eq!(
+            NotifyTarget::Webhook("http://ci/hook".to_string()).to_string(),
+            "Webhook http://ci/hook"
+        );
+        assert_eq!(
+            NotifyTarget::Command("mail -s sync ops".to_string()).to_string(),
+            "Notification command `mail -s sync ops`"
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_commands_the_payload_and_event() {
+        let path = std::env::temp_dir().join(format!("git-sync-notify-{}", std::process::id()));
+        let target = NotifyTarget::Command(format!(
+            r#"{{ echo "$GIT_SYNC_EVENT"; cat; }} > "{}""#,
+            path.display()
+        ));
+        target.send(NotifyOn::Rejected, &payload()).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("rejected\n{}\n", payload())
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let err = NotifyTarget::Command("exit 1".to_string())
+            .send(NotifyOn::Success, &payload())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ChildFailed { .. }), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn posts_the_payload_to_webhooks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"}") {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        NotifyTarget::Webhook(format!("http://{}/sync", addr))
+            .send(NotifyOn::Rejected, &payload())
+            .await
+            .unwrap();
+        let request = server.await.unwrap();
+        assert!(
+            request.starts_with("POST /sync HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+        assert!(request.contains("content-type: application/json\r\n"));
+        assert!(request.ends_with(&format!("\r\n\r\n{}", payload())));
+    }
+}
@@ -0,0 +1,315 @@
+/// Inspection of pack data as it passes through
+use std::convert::TryInto;
+
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+/// The fixed size of a pack header
+pub const PACK_HEADER_LEN: usize = 12;
+
+/// The header at the start of every pack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackHeader {
+    pub version: u32,
+    pub objects: u32,
+}
+
+impl PackHeader {
+    /// Parse a pack header from the start of a buffer
+    ///
+    /// ```
+    /// # use git_sync::{PackHeader, EMPTY_PACK};
+    /// let header = PackHeader::parse(EMPTY_PACK).unwrap();
+    /// assert_eq!(header, PackHeader { version: 2, objects: 0 });
+    /// assert!(PackHeader::parse(b"PACK").is_none());
+    /// ```
+    pub fn parse(buf: &[u8]) -> Option<PackHeader> {
+        if buf.len() < PACK_HEADER_LEN || &buf[..4] != b"PACK" {
+            return None;
+        }
+        Some(PackHeader {
+            version: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            objects: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+        })
+    }
+}
+
+/// Watches the start of a pack stream, which may arrive in arbitrarily small
+/// pieces, until the pack header can be parsed
+#[derive(Default)]
+pub struct PackHeaderScanner {
+    buf: Vec<u8>,
+    header: Option<PackHeader>,
+}
+
+impl PackHeaderScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed some pack data through the scanner, returning the header if this
+    /// data was what completed it
+    pub fn feed(&mut self, data: &[u8]) -> Option<PackHeader> {
+        if self.header.is_some() || self.buf.len() >= PACK_HEADER_LEN {
+            return None;
+        }
+        let needed = PACK_HEADER_LEN - self.buf.len();
+        self.buf.extend_from_slice(&data[..needed.min(data.len())]);
+        self.header = PackHeader::parse(&self.buf);
+        self.header
+    }
+
+    /// The pack header, if enough data has been seen to parse it
+    pub fn header(&self) -> Option<PackHeader> {
+        self.header
+    }
+}
+
+/// The hash a repository names its objects with, and so checksums its packs with
+///
+/// ```
+/// # use git_sync::ObjectFormat;
+/// assert_eq!(ObjectFormat::from_name("sha256"), Some(ObjectFormat::Sha256));
+/// assert_eq!(ObjectFormat::from_name("md5"), None);
+/// assert_eq!(ObjectFormat::Sha1.checksum_len(), 20);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFormat {
+    Sha1,
+    Sha256,
+}
+
+impl ObjectFormat {
+    /// The format named as the `object-format` capability names it, if it's one
+    /// we know
+    pub fn from_name(name: &str) -> Option<ObjectFormat> {
+        match name {
+            "sha1" => Some(ObjectFormat::Sha1),
+            "sha256" => Some(ObjectFormat::Sha256),
+            _ => None,
+        }
+    }
+
+    /// How many bytes the checksum a pack ends with takes
+    pub fn checksum_len(self) -> usize {
+        match self {
+            ObjectFormat::Sha1 => <Sha1 as Digest>::output_size(),
+            ObjectFormat::Sha256 => <Sha256 as Digest>::output_size(),
+        }
+    }
+}
+
+/// A hash in whichever format a pack is checksummed in
+#[derive(Debug, Clone)]
+enum PackHash {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl PackHash {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            PackHash::Sha1(hash) => hash.update(data),
+            PackHash::Sha256(hash) => hash.update(data),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            PackHash::Sha1(hash) => hash.finalize().to_vec(),
+            PackHash::Sha256(hash) => hash.finalize().to_vec(),
+        }
+    }
+}
+
+/// Works out the checksum of a pack as its data goes by, to compare with the
+/// checksum it ends with, which is SHA-1 unless the pack comes from a
+/// repository with another object format
+///
+/// ```
+/// # use git_sync::{ObjectFormat, PackChecksum, EMPTY_PACK};
+/// let mut checksum = PackChecksum::new();
+/// checksum.feed(&EMPTY_PACK[..20]);
+/// checksum.feed(&EMPTY_PACK[20..]);
+/// assert!(checksum.is_whole());
+/// assert!(checksum.check().is_ok());
+/// let mut checksum = PackChecksum::new();
+/// checksum.feed(&EMPTY_PACK[..8]);
+/// assert!(checksum.check().is_err());
+///
+/// let mut checksum = PackChecksum::for_format(ObjectFormat::Sha256);
+/// checksum.feed(&EMPTY_PACK);
+/// assert!(checksum.check().is_err());
+/// ```
+#[derive(Debug, Clone)]
+pub struct PackChecksum {
+    hash: PackHash,
+    /// How long the checksum the pack ends with is
+    len: usize,
+    /// The last bytes seen, which may turn out to be the checksum, so aren't
+    /// hashed yet
+    tail: Vec<u8>,
+    size: u64,
+}
+
+impl Default for PackChecksum {
+    fn default() -> Self {
+        PackChecksum::for_format(ObjectFormat::Sha1)
+    }
+}
+
+impl PackChecksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check a pack from a repository with the given object format
+    pub fn for_format(format: ObjectFormat) -> Self {
+        PackChecksum {
+            hash: match format {
+                ObjectFormat::Sha1 => PackHash::Sha1(Sha1::new()),
+                ObjectFormat::Sha256 => PackHash::Sha256(Sha256::new()),
+            },
+            len: format.checksum_len(),
+            tail: Vec::new(),
+            size: 0,
+        }
+    }
+
+    /// Feed the next piece of the pack through
+    pub fn feed(&mut self, data: &[u8]) {
+        self.size += data.len() as u64;
+        // A piece long enough to hold the checksum needn't be copied to be hashed
+        if data.len() >= self.len {
+            let (body, tail) = data.split_at(data.len() - self.len);
+            self.hash.update(&self.tail);
+            self.hash.update(body);
+            self.tail.clear();
+            self.tail.extend_from_slice(tail);
+            return;
+        }
+        self.tail.extend_from_slice(data);
+        let done = self.tail.len().saturating_sub(self.len);
+        self.hash.update(&self.tail[..done]);
+        self.tail.drain(..done);
+    }
+
+    /// Whether the data so far makes a whole pack, ending with the checksum of
+    /// the rest of it, for telling where a pack ends when nothing else does
+    pub fn is_whole(&self) -> bool {
+        self.size >= (PACK_HEADER_LEN + self.len) as u64
+            && self.hash.clone().finish()[..] == self.tail[..]
+    }
+
+    /// Check that the pack ends with the checksum of the rest of it, saying
+    /// what's wrong with it if not
+    pub fn check(self) -> Result<(), String> {
+        if self.size < (PACK_HEADER_LEN + self.len) as u64 {
+            return Err(format!("ended after only {} bytes", self.size));
+        }
+        let checksum = self.hash.finish();
+        if checksum[..] != self.tail[..] {
+            return Err(format!(
+                "is corrupt: it ends with the checksum {} but its contents give {}",
+                to_hex(&self.tail),
+                to_hex(&checksum)
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A checksum written out as hex, as git writes object names
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EMPTY_PACK;
+
+    /// A pack of `objects` objects with `body` for them, ending with the
+    /// checksum `format` gives it all
+    fn pack(format: ObjectFormat, objects: u32, body: &[u8]) -> Vec<u8> {
+        let mut pack = b"PACK\0\0\0\x02".to_vec();
+        pack.extend_from_slice(&objects.to_be_bytes());
+        pack.extend_from_slice(body);
+        let checksum = match format {
+            ObjectFormat::Sha1 => Sha1::digest(&pack).to_vec(),
+            ObjectFormat::Sha256 => Sha256::digest(&pack).to_vec(),
+        };
+        pack.extend_from_slice(&checksum);
+        pack
+    }
+
+    fn check(format: ObjectFormat, pieces: &[&[u8]]) -> Result<(), String> {
+        let mut checksum = PackChecksum::for_format(format);
+        for piece in pieces {
+            checksum.feed(piece);
+        }
+        checksum.check()
+    }
+
+    #[test]
+    fn accepts_packs_ending_with_their_checksum() {
+        for &format in &[ObjectFormat::Sha1, ObjectFormat::Sha256] {
+            let pack = pack(format, 1, b"some object data");
+            assert_eq!(check(format, &[&pack]), Ok(()));
+            // However the pack arrives in pieces
+            let bytes: Vec<&[u8]> = pack.chunks(1).collect();
+            assert_eq!(check(format, &bytes), Ok(()));
+            let (start, end) = pack.split_at(pack.len() - 3);
+            assert_eq!(check(format, &[start, end]), Ok(()));
+        }
+    }
+
+    #[test]
+    fn checksums_as_git_does() {
+        assert_eq!(pack(ObjectFormat::Sha1, 0, b""), EMPTY_PACK);
+    }
+
+    #[test]
+    fn refuses_truncated_trailers() {
+        for &format in &[ObjectFormat::Sha1, ObjectFormat::Sha256] {
+            let pack = pack(format, 1, b"some object data");
+            let error = check(format, &[&pack[..pack.len() - 1]]).unwrap_err();
+            assert!(error.starts_with("is corrupt"), "{}", error);
+            // Too short to hold a header and a checksum at all
+            let short = &pack[..PACK_HEADER_LEN + format.checksum_len() - 1];
+            let error = check(format, &[short]).unwrap_err();
+            assert_eq!(error, format!("ended after only {} bytes", short.len()));
+        }
+    }
+
+    #[test]
+    fn refuses_corrupted_trailers() {
+        for &format in &[ObjectFormat::Sha1, ObjectFormat::Sha256] {
+            let mut pack = pack(format, 1, b"some object data");
+            let last = pack.len() - 1;
+            pack[last] ^= 1;
+            let error = check(format, &[&pack]).unwrap_err();
+            assert!(error.starts_with("is corrupt"), "{}", error);
+            assert!(error.contains(&to_hex(&pack[last + 1 - format.checksum_len()..])));
+        }
+    }
+
+    #[test]
+    fn refuses_checksums_in_the_wrong_format() {
+        let sha1 = pack(ObjectFormat::Sha1, 0, b"");
+        assert!(check(ObjectFormat::Sha256, &[&sha1]).is_err());
+        let sha256 = pack(ObjectFormat::Sha256, 0, b"");
+        assert!(check(ObjectFormat::Sha1, &[&sha256]).is_err());
+    }
+
+    #[test]
+    fn tells_when_a_pack_is_whole() {
+        let pack = pack(ObjectFormat::Sha1, 1, b"some object data");
+        let mut checksum = PackChecksum::new();
+        for byte in pack.chunks(1) {
+            assert!(!checksum.is_whole());
+            checksum.feed(byte);
+        }
+        assert!(checksum.is_whole());
+    }
+}
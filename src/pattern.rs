@@ -0,0 +1,83 @@
+/// Glob style patterns for selecting refs
+use std::fmt;
+use std::str::FromStr;
+
+/// A pattern matching ref names, where `*` matches any run of characters
+/// (including `/`) and `?` matches any single character
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefPattern(String);
+
+impl RefPattern {
+    /// Check whether a ref name matches this pattern
+    ///
+    /// ```
+    /// # use git_sync::RefPattern;
+    /// let pat: RefPattern = "refs/heads/release-*".parse().unwrap();
+    /// assert!(pat.matches("refs/heads/release-1.0"));
+    /// assert!(pat.matches("refs/heads/release-1/hotfix"));
+    /// assert!(!pat.matches("refs/heads/main"));
+    /// let pat: RefPattern = "refs/tags/v?".parse().unwrap();
+    /// assert!(pat.matches("refs/tags/v1"));
+    /// assert!(!pat.matches("refs/tags/v10"));
+    /// ```
+    pub fn matches(&self, refname: &str) -> bool {
+        glob_match(self.0.as_bytes(), refname.as_bytes())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where to resume if we need to let the most recent `*` swallow another character
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            Some(c) if *c == name[n] => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match backtrack {
+            Some((bp, bn)) => {
+                p = bp + 1;
+                n = bn + 1;
+                backtrack = Some((bp, bn + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+impl FromStr for RefPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            Err("Empty ref pattern".to_string())
+        } else {
+            Ok(RefPattern(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for RefPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
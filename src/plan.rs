@@ -0,0 +1,231 @@
+/// Policy which shapes the ref updates planned for a sync
+use std::fmt;
+use std::str::FromStr;
+
+use super::{RefPattern, Refspec};
+
+/// Patterns for refs which code forges create for their own purposes, and which
+/// receive-pack on another forge is likely to reject
+pub const FORGE_INTERNAL_REFS: &[&str] = &[
+    "refs/pull/*",
+    "refs/merge-requests/*",
+    "refs/changes/*",
+    "refs/keep-around/*",
+];
+
+/// How a sync treats refs which exist in the target but not the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Make the target identical to the source, deleting refs it lacks
+    Mirror,
+    /// Only create and update refs, never deleting anything
+    NoDelete,
+}
+
+/// Options controlling which ref updates are planned
+#[derive(Debug, Clone)]
+pub struct PlanOptions {
+    pub mode: SyncMode,
+    /// Whether tags missing from the source are deleted from the target,
+    /// regardless of the mode
+    pub prune_tags: bool,
+    /// Refs which must never be deleted or force-updated
+    pub protect: Vec<RefPattern>,
+    /// If not empty, only source refs matching one of these patterns are synced.
+    /// Patterns apply to source ref names after any source prefix is stripped.
+    pub include: Vec<RefPattern>,
+    /// Source refs matching any of these patterns are never synced, even if included.
+    /// Patterns apply to source ref names after any source prefix is stripped.
+    pub exclude: Vec<RefPattern>,
+    /// Refspecs selecting and renaming the refs to sync; if empty then every
+    /// ref is synced under its own name
+    pub refspecs: Vec<Refspec>,
+    /// Only refs under this prefix in the source are synced, with the prefix removed
+    pub strip_source_prefix: Option<String>,
+    /// Refs are placed under this prefix in the target, and only refs under it there
+    /// are considered part of the sync
+    pub dest_prefix: Option<String>,
+}
+
+impl Default for PlanOptions {
+    fn default() -> Self {
+        PlanOptions {
+            mode: SyncMode::Mirror,
+            prune_tags: true,
+            protect: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            refspecs: Vec::new(),
+            strip_source_prefix: None,
+            dest_prefix: None,
+        }
+    }
+}
+
+impl PlanOptions {
+    /// Whether a ref is protected from deletion and forced updates
+    pub fn is_protected(&self, refname: &str) -> bool {
+        self.protect.iter().any(|pat| pat.matches(refname))
+    }
+
+    /// The name in the target of a ref from the source, if it is to be synced at all
+    ///
+    /// ```
+    /// # use git_sync::PlanOptions;
+    /// let opts = PlanOptions {
+    ///     strip_source_prefix: Some("refs/namespaces/foo/".into()),
+    ///     dest_prefix: Some("refs/mirrors/foo/".into()),
+    ///     ..PlanOptions::default()
+    /// };
+    /// assert_eq!(
+    ///     opts.map_source("refs/namespaces/foo/refs/heads/main").as_deref(),
+    ///     Some("refs/mirrors/foo/refs/heads/main")
+    /// );
+    /// assert_eq!(opts.map_source("refs/heads/main"), None);
+    /// assert!(opts.in_scope("refs/mirrors/foo/refs/tags/v1"));
+    /// assert!(!opts.in_scope("refs/heads/main"));
+    /// // The prefixed HEAD --set-head leaves there is no ref of the source's
+    /// assert!(!opts.in_scope("refs/mirrors/foo/HEAD"));
+    /// ```
+    pub fn map_source(&self, refname: &str) -> Option<String> {
+        let refname = match &self.strip_source_prefix {
+            Some(prefix) => refname.strip_prefix(prefix.as_str())?,
+            None => refname,
+        };
+        if !self.selected(refname) {
+            return None;
+        }
+        let mapped = if self.refspecs.is_empty() {
+            refname.to_string()
+        } else {
+            self.refspecs.iter().find_map(|spec| spec.map(refname))?
+        };
+        Some(match &self.dest_prefix {
+            Some(prefix) => format!("{}{}", prefix, mapped),
+            None => mapped,
+        })
+    }
+
+    /// The name of a target ref with any destination prefix removed, or `None`
+    /// if it lies outside the destination prefix
+    fn unprefixed<'a>(&self, refname: &'a str) -> Option<&'a str> {
+        match &self.dest_prefix {
+            Some(prefix) => refname.strip_prefix(prefix.as_str()),
+            None => Some(refname),
+        }
+    }
+
+    /// Whether a (prefix stripped) source ref is selected by the include and exclude patterns
+    fn selected(&self, refname: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|pat| pat.matches(refname)))
+            && !self.exclude.iter().any(|pat| pat.matches(refname))
+    }
+
+    /// Whether a ref in the target is within the scope of the sync, and so may be
+    /// updated or deleted by it.  This is the case when some ref in the source which
+    /// would be selected could map onto it.
+    pub fn in_scope(&self, refname: &str) -> bool {
+        let refname = match self.unprefixed(refname) {
+            Some(refname) if refname.starts_with("refs/") => refname,
+            _ => return false,
+        };
+        if self.refspecs.is_empty() {
+            self.selected(refname)
+        } else {
+            self.refspecs
+                .iter()
+                .filter_map(|spec| spec.reverse_map(refname))
+                .any(|source| self.selected(&source))
+        }
+    }
+
+    /// Whether a refspec permits non-fast-forward updates of this ref in the target
+    pub fn is_forced(&self, refname: &str) -> bool {
+        match self.unprefixed(refname) {
+            Some(refname) => self
+                .refspecs
+                .iter()
+                .any(|spec| spec.force() && spec.matches_destination(refname)),
+            None => false,
+        }
+    }
+
+    /// Whether a ref which the source lacks may be deleted from the target
+    pub fn may_delete(&self, refname: &str) -> bool {
+        let is_tag = self
+            .unprefixed(refname)
+            .is_some_and(|name| name.starts_with("refs/tags/"));
+        let allowed = if is_tag {
+            self.prune_tags
+        } else {
+            self.mode == SyncMode::Mirror
+        };
+        allowed && !self.is_protected(refname)
+    }
+}
+
+/// How many refs a sync may always delete, whatever percentage of the target's
+/// refs that is, so that a small repository can still rename its few branches
+pub const DELETE_LIMIT_FLOOR: usize = 10;
+
+/// A limit on how many refs a single sync may delete from the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteLimit {
+    /// At most this many refs may be deleted
+    Count(usize),
+    /// At most this percentage of the target's refs may be deleted, or
+    /// [`DELETE_LIMIT_FLOOR`] refs if that's more
+    Percent(u8),
+}
+
+impl DeleteLimit {
+    /// Whether deleting `deletes` of the target's `total` refs exceeds this limit
+    ///
+    /// ```
+    /// # use git_sync::DeleteLimit;
+    /// let limit: DeleteLimit = "50%".parse().unwrap();
+    /// assert!(!limit.exceeded(50, 100));
+    /// assert!(limit.exceeded(51, 100));
+    /// // A few refs may be deleted however few the target has
+    /// assert!(!limit.exceeded(1, 1));
+    /// assert!(!limit.exceeded(10, 12));
+    /// assert!(limit.exceeded(11, 12));
+    /// let limit: DeleteLimit = "3".parse().unwrap();
+    /// assert!(!limit.exceeded(3, 10));
+    /// assert!(limit.exceeded(4, 10));
+    /// ```
+    pub fn exceeded(self, deletes: usize, total: usize) -> bool {
+        match self {
+            DeleteLimit::Count(max) => deletes > max,
+            DeleteLimit::Percent(pct) => {
+                deletes > DELETE_LIMIT_FLOOR && deletes * 100 > total * usize::from(pct)
+            }
+        }
+    }
+}
+
+impl FromStr for DeleteLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(pct) = s.strip_suffix('%') {
+            match pct.parse() {
+                Ok(pct) if pct <= 100 => Ok(DeleteLimit::Percent(pct)),
+                _ => Err(format!("Invalid percentage '{}'", s)),
+            }
+        } else {
+            s.parse()
+                .map(DeleteLimit::Count)
+                .map_err(|_| format!("Invalid deletion limit '{}'", s))
+        }
+    }
+}
+
+impl fmt::Display for DeleteLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeleteLimit::Count(max) => write!(f, "{}", max),
+            DeleteLimit::Percent(pct) => write!(f, "{}%", pct),
+        }
+    }
+}
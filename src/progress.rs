@@ -0,0 +1,287 @@
+/// Following the transfer of a pack, for showing on a line which updates in place
+use std::fmt;
+use std::time::Duration;
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use super::{SyncEvent, SyncSide};
+
+/// The frames of the spinner shown while waiting for pack data, the last of
+/// which is shown once it stops
+const SPINNER: &str = "|/-\\ ";
+
+/// A step of a remote's progress, parsed from one of git's progress messages
+/// such as `Counting objects:  45% (9/20)`
+///
+/// ```
+/// # use git_sync::ProgressUpdate;
+/// let update = ProgressUpdate::parse("Receiving objects:  45% (9/20), 1.20 MiB | 300.00 KiB/s").unwrap();
+/// assert_eq!(update.phase, "Receiving objects");
+/// assert_eq!((update.current, update.total), (9, Some(20)));
+/// assert_eq!(update.percent(), Some(45));
+/// assert!(!update.done);
+///
+/// let update = ProgressUpdate::parse("Enumerating objects: 7, done.").unwrap();
+/// assert_eq!((update.current, update.total, update.done), (7, None, true));
+/// assert_eq!(update.to_string(), "Enumerating objects: 7, done");
+///
+/// assert_eq!(ProgressUpdate::parse("Total 7 (delta 0), reused 0 (delta 0)"), None);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressUpdate {
+    /// What the remote is doing, e.g. `Counting objects`
+    pub phase: String,
+    /// How many things it has done so far
+    pub current: u64,
+    /// How many things there are to do, if it knows
+    pub total: Option<u64>,
+    /// Whether this phase is over
+    pub done: bool,
+}
+
+impl ProgressUpdate {
+    /// Parse a progress message, without its line ending, returning `None` if it
+    /// isn't in git's progress format
+    pub fn parse(message: &str) -> Option<ProgressUpdate> {
+        let (phase, rest) = message.trim().split_once(": ")?;
+        let (rest, done) = match rest.strip_suffix(", done.") {
+            Some(rest) => (rest, true),
+            None => (rest, false),
+        };
+        // Anything after the counts, such as the throughput, is left out
+        let counts = rest.split(',').next().unwrap_or_default().trim();
+        let (current, total) = match counts.split_once('(') {
+            Some((_percent, fraction)) => {
+                let (current, total) = fraction.strip_suffix(')')?.split_once('/')?;
+                (current.parse().ok()?, Some(total.parse().ok()?))
+            }
+            None => (counts.parse().ok()?, None),
+        };
+        Some(ProgressUpdate {
+            phase: phase.to_string(),
+            current,
+            total,
+            done,
+        })
+    }
+
+    /// How far through the phase the remote is, if it knows
+    pub fn percent(&self) -> Option<u64> {
+        match self.total {
+            Some(0) => Some(100),
+            Some(total) => Some(self.current.min(total) * 100 / total),
+            None => None,
+        }
+    }
+
+    /// Parse each of the progress messages in some data the remote sent, which
+    /// are ended by carriage returns (when the next replaces it) or line feeds
+    pub fn parse_all(data: &str) -> Vec<ProgressUpdate> {
+        data.split(['\r', '\n'])
+            .filter_map(ProgressUpdate::parse)
+            .collect()
+    }
+}
+
+impl fmt::Display for ProgressUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.phase)?;
+        match (self.percent(), self.total) {
+            (Some(percent), Some(total)) => write!(f, "{}% ({}/{})", percent, self.current, total)?,
+            _ => write!(f, "{}", self.current)?,
+        }
+        if self.done {
+            f.write_str(", done")?;
+        }
+        Ok(())
+    }
+}
+
+/// What's happening to the pack of the batch being pushed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    /// Not pushing, so there's nothing to show
+    Idle,
+    /// Negotiating a pack with the source
+    Negotiating,
+    /// Relaying pack data to the target, of which this many bytes have gone
+    Relaying(u64),
+}
+
+/// The progress of the pack transfers of a sync, built from its events and
+/// shown on a progress bar: a spinner while a pack is negotiated, and then how
+/// much pack data has been relayed, of how many objects, how fast and for how
+/// long, along with what the remotes last said they're doing.  A remote which
+/// counts objects without saying how many there are is taken to be counting
+/// those of the pack, as its header says, so that how far through them it is
+/// can be shown.
+///
+/// ```
+/// # use git_sync::{ProgressUpdate, SyncEvent, SyncSide, TransferProgress};
+/// # use indicatif::ProgressBar;
+/// let bar = ProgressBar::hidden();
+/// let mut progress = TransferProgress::with_bar(bar.clone());
+/// assert!(!progress.is_shown());
+/// progress.observe(&SyncEvent::BatchStarted { batch: 1, batches: 1, updates: 3 });
+/// assert!(progress.is_shown());
+/// let counting = ProgressUpdate::parse("Counting objects:  45% (9/20)").unwrap();
+/// progress.observe(&SyncEvent::Progress(SyncSide::Source, counting));
+/// assert_eq!(bar.message(), " - source: Counting objects: 45% (9/20)");
+/// progress.observe(&SyncEvent::PackBytes(3 * 1024 * 1024));
+/// assert_eq!(bar.position(), 3 * 1024 * 1024);
+/// progress.observe(&SyncEvent::PackObjects(20));
+/// let receiving = ProgressUpdate::parse("Receiving objects: 5").unwrap();
+/// progress.observe(&SyncEvent::Progress(SyncSide::Target, receiving));
+/// assert_eq!(bar.prefix(), " of 20 objects");
+/// assert_eq!(bar.message(), " - target: Receiving objects: 25% (5/20)");
+/// progress.observe(&SyncEvent::Cancelled);
+/// assert!(!progress.is_shown());
+/// ```
+#[derive(Debug)]
+pub struct TransferProgress {
+    bar: ProgressBar,
+    transfer: Transfer,
+    /// What a remote said it's doing most recently, unless it has finished
+    remote: Option<(SyncSide, ProgressUpdate)>,
+    /// How many objects the pack of the batch has, once its header has arrived
+    objects: Option<u64>,
+}
+
+impl TransferProgress {
+    /// Show the progress on stderr, if it's a terminal
+    pub fn new() -> TransferProgress {
+        TransferProgress::with_bar(ProgressBar::new_spinner())
+    }
+
+    /// Show the progress on the given bar
+    pub fn with_bar(bar: ProgressBar) -> TransferProgress {
+        bar.finish_and_clear();
+        TransferProgress {
+            bar,
+            transfer: Transfer::Idle,
+            remote: None,
+            objects: None,
+        }
+    }
+
+    /// Whether there's a transfer being shown
+    pub fn is_shown(&self) -> bool {
+        self.transfer != Transfer::Idle
+    }
+
+    /// Hide the progress while `f` runs, so that it can print without the two
+    /// getting mixed up
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.bar.suspend(f)
+    }
+
+    /// Record what an event says about the transfer, and show it
+    pub fn observe(&mut self, event: &SyncEvent) {
+        match event {
+            SyncEvent::Progress(_, update) if update.done => self.remote = None,
+            SyncEvent::Progress(side, update) => self.remote = Some((*side, update.clone())),
+            SyncEvent::BatchStarted { .. } => {
+                self.remote = None;
+                self.objects = None;
+            }
+            SyncEvent::PackObjects(objects) => self.objects = Some(*objects),
+            _ => {}
+        }
+        let transfer = match (event, self.transfer) {
+            (SyncEvent::BatchStarted { .. }, _) => Transfer::Negotiating,
+            (SyncEvent::PackBytes(bytes), Transfer::Negotiating)
+            | (SyncEvent::PackBytes(bytes), Transfer::Relaying(_)) => Transfer::Relaying(*bytes),
+            // The results come once the pack has gone
+            (SyncEvent::RefResult(_), _)
+            | (SyncEvent::Completed(_), _)
+            | (SyncEvent::Retrying { .. }, _)
+            | (SyncEvent::Cancelled, _) => Transfer::Idle,
+            (_, transfer) => transfer,
+        };
+        match (self.transfer, transfer) {
+            (_, Transfer::Idle) => {
+                self.bar.disable_steady_tick();
+                self.bar.finish_and_clear();
+            }
+            (Transfer::Negotiating, Transfer::Negotiating) => {}
+            (_, Transfer::Negotiating) => {
+                self.bar.set_style(style(NEGOTIATING));
+                self.bar.reset();
+                self.bar.enable_steady_tick(PROGRESS_TICK);
+            }
+            (Transfer::Relaying(_), Transfer::Relaying(bytes)) => self.bar.set_position(bytes),
+            (_, Transfer::Relaying(bytes)) => {
+                self.bar.set_style(style(RELAYING));
+                self.bar.reset();
+                self.bar.set_position(bytes);
+                self.bar.enable_steady_tick(PROGRESS_TICK);
+            }
+        }
+        self.transfer = transfer;
+        self.bar.set_prefix(match self.objects {
+            Some(objects) => format!(" of {} objects", objects),
+            None => String::new(),
+        });
+        self.bar.set_message(match &self.remote {
+            Some((side, update)) => {
+                let mut update = update.clone();
+                if update.total.is_none() && update.phase.ends_with(" objects") {
+                    update.total = self.objects;
+                }
+                format!(" - {}: {}", side, update)
+            }
+            None => String::new(),
+        });
+    }
+}
+
+impl Default for TransferProgress {
+    fn default() -> TransferProgress {
+        TransferProgress::new()
+    }
+}
+
+impl Drop for TransferProgress {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// How often the progress of a pack transfer is redrawn
+const PROGRESS_TICK: Duration = Duration::from_millis(200);
+
+/// How the progress of a pack transfer is shown while the pack is negotiated
+const NEGOTIATING: &str =
+    "{spinner} Negotiating a pack with the source ({elapsed_precise}){wide_msg}";
+
+/// How the progress of a pack transfer is shown while pack data is relayed
+const RELAYING: &str =
+    "Relaying pack data: {binary_bytes}{prefix}, {binary_bytes_per_sec} ({elapsed_precise}){wide_msg}";
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("progress templates are valid")
+        .tick_chars(SPINNER)
+}
+
+/// Describe a number of bytes in the largest binary unit which suits, e.g. `1.5 MiB`
+///
+/// ```
+/// # use git_sync::format_bytes;
+/// assert_eq!(format_bytes(512), "512 B");
+/// assert_eq!(format_bytes(1536), "1.5 KiB");
+/// assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+/// ```
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
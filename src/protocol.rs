@@ -4,8 +4,12 @@ use std::collections::HashMap;
 use std::convert::TryFrom;
 
 use std::marker::Unpin;
+
+use bytes::{Bytes, BytesMut};
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use super::Error;
+
 pub const NULLSHA: &str = "0000000000000000000000000000000000000000";
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -43,21 +47,24 @@ impl ProtocolLine<'_> {
         }
     }
 
-    pub async fn write_str<W, S>(writer: &mut W, s: S) -> io::Result<()>
+    pub async fn write_str<W, S>(writer: &mut W, s: S) -> Result<(), Error>
     where
-        W: AsyncWrite + Unpin,
+        W: AsyncWrite + Unpin + ?Sized,
         S: AsRef<str>,
     {
         let s = s.as_ref();
+        tracing::trace!("> {}", s.trim_end());
         let pktlen = format!("{:04x}", s.len() + 4);
         writer.write_all(pktlen.as_bytes()).await?;
-        writer.write_all(s.as_bytes()).await
+        writer.write_all(s.as_bytes()).await?;
+        Ok(())
     }
 
-    pub async fn write_to<W>(&self, writer: &mut W) -> io::Result<()>
+    pub async fn write_to<W>(&self, writer: &mut W) -> Result<(), Error>
     where
-        W: AsyncWrite + Unpin,
+        W: AsyncWrite + Unpin + ?Sized,
     {
+        tracing::trace!("> {}", self.describe());
         match self {
             ProtocolLine::Flush => writer.write_all(b"0000").await?,
             ProtocolLine::Delimiter => writer.write_all(b"0001").await?,
@@ -74,41 +81,260 @@ impl ProtocolLine<'_> {
     pub async fn read_from<R>(
         reader: &mut R,
         chomp_newline: bool,
-    ) -> io::Result<ProtocolLine<'static>>
+    ) -> Result<ProtocolLine<'static>, Error>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut data = Vec::new();
+        let special = match Self::read_into(reader, chomp_newline, &mut data).await? {
+            ProtocolLine::Data(_) => None,
+            line => Some(line.into_owned()),
+        };
+        Ok(special.unwrap_or(ProtocolLine::Data(Cow::from(data))))
+    }
+
+    /// Read a line as [`read_from`](Self::read_from) does, but into `buf`, reusing
+    /// the room it has, so that data read comes back borrowed from it.  Whatever
+    /// `buf` held is replaced.
+    ///
+    /// ```
+    /// # use git_sync::ProtocolLine;
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let mut input = &b"0009done\n0000"[..];
+    /// let mut buf = Vec::new();
+    /// match ProtocolLine::read_into(&mut input, true, &mut buf).await.unwrap() {
+    ///     ProtocolLine::Data(data) => assert_eq!(&data[..], b"done"),
+    ///     line => panic!("{:?}", line),
+    /// }
+    /// assert_eq!(buf, b"done");
+    /// let line = ProtocolLine::read_into(&mut input, true, &mut buf).await.unwrap();
+    /// assert_eq!(line, ProtocolLine::Flush);
+    /// # }
+    /// ```
+    pub async fn read_into<'b, R>(
+        reader: &mut R,
+        chomp_newline: bool,
+        buf: &'b mut Vec<u8>,
+    ) -> Result<ProtocolLine<'b>, Error>
     where
-        R: AsyncRead + Unpin,
+        R: AsyncRead + Unpin + ?Sized,
     {
-        let mut lenbuf = [b'0'; 4];
-        reader.read_exact(&mut lenbuf).await?;
-        Ok(match &lenbuf {
-            b"0000" => ProtocolLine::Flush,
-            b"0001" => ProtocolLine::Delimiter,
-            b"0002" => ProtocolLine::ResponseEnd,
-            b"0003" => return Err(io::Error::from(io::ErrorKind::Other)),
-            _ => {
-                lenbuf.iter_mut().for_each(|v| {
-                    *v = match *v {
-                        b'0'..=b'9' => *v - b'0',
-                        b'a'..=b'f' => *v - b'a' + 10,
-                        _ => 0,
+        buf.clear();
+        let line = match read_packet_start(reader).await? {
+            PacketStart::Special(line) => line,
+            PacketStart::Data(pktlen) => {
+                // Filled exactly, since reading to the end would grow it first
+                buf.resize(pktlen, 0);
+                let mut filled = 0;
+                while filled < pktlen {
+                    match reader.read(&mut buf[filled..]).await? {
+                        0 => return Err(cut_short(filled, pktlen)),
+                        read => filled += read,
                     }
-                });
-                let pktlen = ((lenbuf[0] as usize) << 12)
-                    + ((lenbuf[1] as usize) << 8)
-                    + ((lenbuf[2] as usize) << 4)
-                    + (lenbuf[3] as usize)
-                    - 4 /* For the header */;
-                let mut data: Vec<u8> = Vec::with_capacity(pktlen);
-                if pktlen != reader.take(pktlen as u64).read_to_end(&mut data).await? {
-                    return Err(io::Error::from(io::ErrorKind::BrokenPipe));
                 }
-                if chomp_newline && !data.is_empty() && data[data.len() - 1] == b'\n' {
-                    data.pop();
+                if chomp_newline && buf.last() == Some(&b'\n') {
+                    buf.pop();
                 }
-                ProtocolLine::Data(Cow::from(data))
+                ProtocolLine::Data(Cow::from(&buf[..]))
             }
+        };
+        tracing::trace!("< {}", line.describe());
+        Ok(line)
+    }
+
+    /// Take the line `buf` starts with, if all of it is there, borrowing its data,
+    /// along with how many bytes of `buf` it took up
+    ///
+    /// ```
+    /// # use git_sync::ProtocolLine;
+    /// let buf = b"0009done\n0000";
+    /// let (line, len) = ProtocolLine::parse(buf).unwrap().unwrap();
+    /// assert_eq!(line, ProtocolLine::from(&b"done\n"[..]));
+    /// assert_eq!(ProtocolLine::parse(&buf[len..]).unwrap(), Some((ProtocolLine::Flush, 4)));
+    /// assert_eq!(ProtocolLine::parse(&buf[..len - 1]).unwrap(), None);
+    /// assert!(ProtocolLine::parse(b"00zz").is_err());
+    /// ```
+    pub fn parse(buf: &[u8]) -> Result<Option<(ProtocolLine<'_>, usize)>, Error> {
+        let lenbuf = match buf.get(..4) {
+            Some(lenbuf) => <&[u8; 4]>::try_from(lenbuf).expect("four bytes"),
+            None => return Ok(None),
+        };
+        Ok(match packet_start(lenbuf)? {
+            PacketStart::Special(line) => Some((line, 4)),
+            PacketStart::Data(pktlen) => buf
+                .get(4..4 + pktlen)
+                .map(|data| (ProtocolLine::Data(Cow::from(data)), 4 + pktlen)),
         })
     }
+
+    /// The line as it's worth logging, with binary data (such as pack data) only
+    /// described
+    fn describe(&self) -> String {
+        match self {
+            ProtocolLine::Flush => "flush".to_string(),
+            ProtocolLine::Delimiter => "delimiter".to_string(),
+            ProtocolLine::ResponseEnd => "response-end".to_string(),
+            ProtocolLine::Data(data) => match std::str::from_utf8(data) {
+                // The NUL before the capabilities in an advertisement is as good as a space
+                Ok(text)
+                    if !text
+                        .trim_end()
+                        .contains(|c: char| c.is_control() && c != '\0') =>
+                {
+                    text.trim_end().replace('\0', " ")
+                }
+                _ => format!("({} bytes)", data.len()),
+            },
+        }
+    }
+}
+
+/// How a packet starts: as one of the special packets, or with the length of the
+/// data which follows
+enum PacketStart {
+    Special(ProtocolLine<'static>),
+    Data(usize),
+}
+
+/// Read the length which starts a packet
+async fn read_packet_start<R>(reader: &mut R) -> Result<PacketStart, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut lenbuf = [b'0'; 4];
+    reader.read_exact(&mut lenbuf).await.map_err(|err| {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            Error::Protocol("The remote end hung up unexpectedly".to_string())
+        } else {
+            Error::Io(err)
+        }
+    })?;
+    packet_start(&lenbuf)
+}
+
+/// Make sense of the length which starts a packet
+fn packet_start(lenbuf: &[u8; 4]) -> Result<PacketStart, Error> {
+    Ok(match lenbuf {
+        b"0000" => PacketStart::Special(ProtocolLine::Flush),
+        b"0001" => PacketStart::Special(ProtocolLine::Delimiter),
+        b"0002" => PacketStart::Special(ProtocolLine::ResponseEnd),
+        b"0003" => return Err(Error::Protocol("Invalid packet length 0003".to_string())),
+        _ => {
+            let pktlen = std::str::from_utf8(lenbuf)
+                .ok()
+                .and_then(|len| usize::from_str_radix(len, 16).ok())
+                .ok_or_else(|| {
+                    Error::Protocol(format!(
+                        "Invalid packet length {:?}",
+                        String::from_utf8_lossy(lenbuf)
+                    ))
+                })?;
+            PacketStart::Data(pktlen - 4 /* For the header */)
+        }
+    })
+}
+
+/// The error for a connection which closed partway through a packet
+fn cut_short(read: usize, pktlen: usize) -> Error {
+    Error::Protocol(format!(
+        "Connection closed {} bytes into a {} byte packet",
+        read, pktlen
+    ))
+}
+
+/// A packet read by a [`PacketReader`], whose data shares the reader's buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Flush,
+    Delimiter,
+    ResponseEnd,
+    Data(Bytes),
+}
+
+impl Packet {
+    /// The packet as a [`ProtocolLine`] borrowing its data
+    pub fn as_line(&self) -> ProtocolLine<'_> {
+        match self {
+            Packet::Flush => ProtocolLine::Flush,
+            Packet::Delimiter => ProtocolLine::Delimiter,
+            Packet::ResponseEnd => ProtocolLine::ResponseEnd,
+            Packet::Data(data) => ProtocolLine::Data(Cow::from(&data[..])),
+        }
+    }
+}
+
+/// Reads packets into one buffer, splitting the data of each off it as [`Bytes`]
+/// which share the buffer, so that the data can be sliced (say, to leave out its
+/// sideband channel) and passed on without being copied.  Room in the buffer is
+/// used again once all that was split off it has been dropped.
+///
+/// ```
+/// # use git_sync::{Packet, PacketReader};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let mut input = &b"0009\x01PACK0007\x02hi0000"[..];
+/// let mut packets = PacketReader::new();
+/// let pack = match packets.read_from(&mut input).await.unwrap() {
+///     Packet::Data(data) => data.slice(1..),
+///     packet => panic!("{:?}", packet),
+/// };
+/// assert_eq!(&pack[..], b"PACK");
+/// assert_eq!(
+///     packets.read_from(&mut input).await.unwrap(),
+///     Packet::Data(b"\x02hi"[..].into())
+/// );
+/// assert_eq!(packets.read_from(&mut input).await.unwrap(), Packet::Flush);
+/// assert!(packets.read_from(&mut input).await.is_err());
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PacketReader {
+    buf: BytesMut,
+}
+
+/// How much room a [`PacketReader`] makes in its buffer at a time, for a dozen
+/// or so of the largest packets
+const PACKET_BUFFER: usize = 1024 * 1024;
+
+impl Default for PacketReader {
+    fn default() -> Self {
+        PacketReader {
+            buf: BytesMut::with_capacity(PACKET_BUFFER),
+        }
+    }
+}
+
+impl PacketReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the next packet, no further than its end
+    pub async fn read_from<R>(&mut self, reader: &mut R) -> Result<Packet, Error>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let packet = match read_packet_start(reader).await? {
+            PacketStart::Special(ProtocolLine::Flush) => Packet::Flush,
+            PacketStart::Special(ProtocolLine::Delimiter) => Packet::Delimiter,
+            PacketStart::Special(_) => Packet::ResponseEnd,
+            PacketStart::Data(pktlen) => {
+                if self.buf.capacity() < pktlen {
+                    self.buf.reserve(PACKET_BUFFER.max(pktlen));
+                }
+                let mut limited = reader.take(pktlen as u64);
+                while self.buf.len() < pktlen {
+                    if limited.read_buf(&mut self.buf).await? == 0 {
+                        return Err(cut_short(self.buf.len(), pktlen));
+                    }
+                }
+                Packet::Data(self.buf.split().freeze())
+            }
+        };
+        tracing::trace!("< {}", packet.as_line().describe());
+        Ok(packet)
+    }
 }
 
 impl<'a, T> From<T> for ProtocolLine<'a>
@@ -120,7 +346,7 @@ where
     }
 }
 
-#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub enum Capability {
     MultiAck,
     MultiAckDetailed,
@@ -218,36 +444,40 @@ impl<'a> TryFrom<&'a str> for Capability {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct RefAdvertisement {
     caps: HashMap<Capability, Option<String>>,
     refs: HashMap<String, String>,
+    symrefs: HashMap<String, String>,
 }
 
 impl RefAdvertisement {
-    pub async fn read_from<R>(reader: &mut R) -> io::Result<Self>
+    pub async fn read_from<R>(reader: &mut R) -> Result<Self, Error>
     where
-        R: AsyncRead + Unpin,
+        R: AsyncRead + Unpin + ?Sized,
     {
         let mut ret = Self {
             caps: HashMap::new(),
             refs: HashMap::new(),
+            symrefs: HashMap::new(),
         };
         loop {
             match ProtocolLine::read_from(reader, true).await? {
                 ProtocolLine::Flush => break,
                 ProtocolLine::Delimiter | ProtocolLine::ResponseEnd => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Unexpected protocol packet",
+                    return Err(Error::Protocol(
+                        "Unexpected packet in ref advertisement".to_string(),
+                    ));
+                }
+                ProtocolLine::Data(cow) if cow.starts_with(b"ERR ") => {
+                    return Err(Error::Remote(
+                        String::from_utf8_lossy(&cow[4..]).into_owned(),
                     ));
                 }
                 ProtocolLine::Data(cow) => {
                     let mut bits = cow.split(|v| *v == 0);
                     let refpart = bits.next().ok_or_else(|| {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            "Unable to find ref-part of announcement line",
-                        )
+                        Error::Protocol("Unable to find ref-part of announcement line".to_string())
                     })?;
                     if let Some(caps) = bits.next() {
                         // We have some capabilities to process
@@ -260,6 +490,15 @@ impl RefAdvertisement {
                                 (cap, None)
                             };
                             if let Ok(cap) = Capability::try_from(capname) {
+                                // symref may be repeated, so we gather each one up
+                                if let (Capability::SymRef, Some(value)) = (cap, capvalue) {
+                                    if let Some(idx) = value.find(':') {
+                                        ret.symrefs.insert(
+                                            value[..idx].to_string(),
+                                            value[idx + 1..].to_string(),
+                                        );
+                                    }
+                                }
                                 ret.caps.insert(cap, capvalue.map(ToOwned::to_owned));
                             }
                         }
@@ -273,7 +512,7 @@ impl RefAdvertisement {
                         };
                         ret.refs.insert(refname.to_string(), sha.to_string());
                     } else {
-                        return Err(io::Error::new(io::ErrorKind::Other, "Malformed ref line"));
+                        return Err(Error::Protocol(format!("Malformed ref line: {}", refpart)));
                     }
                 }
             }
@@ -288,4 +527,9 @@ impl RefAdvertisement {
     pub fn refs(&self) -> &HashMap<String, String> {
         &self.refs
     }
+
+    /// The symbolic refs advertised, such as `HEAD` mapping to `refs/heads/main`
+    pub fn symrefs(&self) -> &HashMap<String, String> {
+        &self.symrefs
+    }
 }
@@ -0,0 +1,228 @@
+/// Refspecs for selecting and renaming refs during a sync
+use std::fmt;
+use std::str::FromStr;
+
+/// One side of a refspec: either an exact ref name or a pattern with a single `*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RefspecSide {
+    Exact(String),
+    Glob { prefix: String, suffix: String },
+}
+
+impl RefspecSide {
+    fn parse(s: &str) -> Result<RefspecSide, String> {
+        if s.is_empty() {
+            return Err("Empty side in refspec".to_string());
+        }
+        match s.find('*') {
+            None => Ok(RefspecSide::Exact(s.to_string())),
+            Some(idx) if !s[idx + 1..].contains('*') => Ok(RefspecSide::Glob {
+                prefix: s[..idx].to_string(),
+                suffix: s[idx + 1..].to_string(),
+            }),
+            Some(_) => Err(format!("Too many '*' in refspec side '{}'", s)),
+        }
+    }
+
+    /// Match a ref name, returning the text matched by the `*` (empty for exact matches)
+    fn matches<'a>(&self, refname: &'a str) -> Option<&'a str> {
+        match self {
+            RefspecSide::Exact(name) if name == refname => Some(""),
+            RefspecSide::Exact(_) => None,
+            RefspecSide::Glob { prefix, suffix } => {
+                if refname.len() >= prefix.len() + suffix.len()
+                    && refname.starts_with(prefix.as_str())
+                    && refname.ends_with(suffix.as_str())
+                {
+                    Some(&refname[prefix.len()..refname.len() - suffix.len()])
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn expand(&self, matched: &str) -> String {
+        match self {
+            RefspecSide::Exact(name) => name.clone(),
+            RefspecSide::Glob { prefix, suffix } => format!("{}{}{}", prefix, matched, suffix),
+        }
+    }
+
+    fn is_glob(&self) -> bool {
+        matches!(self, RefspecSide::Glob { .. })
+    }
+}
+
+impl fmt::Display for RefspecSide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefspecSide::Exact(name) => f.write_str(name),
+            RefspecSide::Glob { prefix, suffix } => write!(f, "{}*{}", prefix, suffix),
+        }
+    }
+}
+
+/// A refspec of the form `[+]<src>[:<dst>]`, mapping refs in the source to refs in the target
+///
+/// ```
+/// # use git_sync::Refspec;
+/// let spec: Refspec = "+refs/heads/*:refs/remotes/upstream/*".parse().unwrap();
+/// assert!(spec.force());
+/// assert_eq!(
+///     spec.map("refs/heads/main").as_deref(),
+///     Some("refs/remotes/upstream/main")
+/// );
+/// assert_eq!(spec.map("refs/tags/v1.0"), None);
+/// assert!(spec.matches_destination("refs/remotes/upstream/feature/x"));
+/// assert_eq!(
+///     spec.reverse_map("refs/remotes/upstream/main").as_deref(),
+///     Some("refs/heads/main")
+/// );
+///
+/// let spec: Refspec = "refs/tags/v1.*".parse().unwrap();
+/// assert!(!spec.force());
+/// assert_eq!(spec.map("refs/tags/v1.2").as_deref(), Some("refs/tags/v1.2"));
+/// assert_eq!(spec.map("refs/tags/v2.0"), None);
+///
+/// assert!("refs/heads/*:refs/heads/main".parse::<Refspec>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refspec {
+    force: bool,
+    src: RefspecSide,
+    dst: RefspecSide,
+}
+
+impl Refspec {
+    /// Whether this refspec permits non-fast-forward updates
+    pub fn force(&self) -> bool {
+        self.force
+    }
+
+    /// Map a source ref name to its name in the target, if this refspec selects it
+    pub fn map(&self, refname: &str) -> Option<String> {
+        self.src
+            .matches(refname)
+            .map(|matched| self.dst.expand(matched))
+    }
+
+    /// Whether a ref in the target falls within the destination side of this refspec
+    pub fn matches_destination(&self, refname: &str) -> bool {
+        self.dst.matches(refname).is_some()
+    }
+
+    /// Map a ref name in the target back to the source ref which would produce it
+    pub fn reverse_map(&self, refname: &str) -> Option<String> {
+        self.dst
+            .matches(refname)
+            .map(|matched| self.src.expand(matched))
+    }
+}
+
+impl FromStr for Refspec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (force, spec) = match s.strip_prefix('+') {
+            Some(spec) => (true, spec),
+            None => (false, s),
+        };
+        let (src, dst) = match spec.find(':') {
+            Some(idx) => (&spec[..idx], &spec[idx + 1..]),
+            None => (spec, spec),
+        };
+        let src = RefspecSide::parse(src)?;
+        let dst = RefspecSide::parse(dst)?;
+        if src.is_glob() != dst.is_glob() {
+            return Err(format!(
+                "Refspec '{}' must use '*' on both sides or neither",
+                s
+            ));
+        }
+        Ok(Refspec { force, src, dst })
+    }
+}
+
+impl fmt::Display for Refspec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.force {
+            f.write_str("+")?;
+        }
+        write!(f, "{}:{}", self.src, self.dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(s: &str) -> Refspec {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn renames_exact_refs() {
+        let spec = spec("refs/meta/config:refs/mirror/config");
+        assert_eq!(
+            spec.map("refs/meta/config").as_deref(),
+            Some("refs/mirror/config")
+        );
+        assert_eq!(spec.map("refs/meta/configs"), None);
+        assert!(spec.matches_destination("refs/mirror/config"));
+        assert!(!spec.matches_destination("refs/meta/config"));
+        assert_eq!(
+            spec.reverse_map("refs/mirror/config").as_deref(),
+            Some("refs/meta/config")
+        );
+    }
+
+    #[test]
+    fn globs_keep_what_the_star_matched() {
+        let spec = spec("refs/heads/*/tip:refs/tips/*");
+        assert_eq!(
+            spec.map("refs/heads/team/a/tip").as_deref(),
+            Some("refs/tips/team/a")
+        );
+        assert_eq!(spec.map("refs/heads/team/a/base"), None);
+        // The prefix and suffix mustn't share the ref's characters
+        assert_eq!(spec.map("refs/heads/tip"), None);
+        assert_eq!(
+            spec.reverse_map("refs/tips/x").as_deref(),
+            Some("refs/heads/x/tip")
+        );
+        assert_eq!(spec.reverse_map("refs/heads/x/tip"), None);
+    }
+
+    #[test]
+    fn shows_both_sides() {
+        assert_eq!(spec("refs/tags/*").to_string(), "refs/tags/*:refs/tags/*");
+        assert_eq!(
+            spec("+refs/heads/*:refs/b/*").to_string(),
+            "+refs/heads/*:refs/b/*"
+        );
+        assert_eq!(
+            spec("+refs/heads/*:refs/b/*")
+                .to_string()
+                .parse::<Refspec>(),
+            Ok(spec("+refs/heads/*:refs/b/*"))
+        );
+    }
+
+    #[test]
+    fn refuses_malformed_refspecs() {
+        let err = |s: &str| s.parse::<Refspec>().unwrap_err();
+        assert_eq!(err(""), "Empty side in refspec");
+        assert_eq!(err("+"), "Empty side in refspec");
+        assert_eq!(err("refs/heads/main:"), "Empty side in refspec");
+        assert_eq!(err(":refs/heads/main"), "Empty side in refspec");
+        assert_eq!(
+            err("refs/*/x/*"),
+            "Too many '*' in refspec side 'refs/*/x/*'"
+        );
+        assert_eq!(
+            err("refs/heads/main:refs/b/*"),
+            "Refspec 'refs/heads/main:refs/b/*' must use '*' on both sides or neither"
+        );
+    }
+}
@@ -0,0 +1,379 @@
+/// Reports of what happened to the refs we asked receive-pack to change
+use std::collections::HashMap;
+use std::marker::Unpin;
+
+use tokio::io::AsyncRead;
+
+use super::{Error, ProtocolLine, RefUpdate};
+
+/// The status receive-pack reported for a single ref
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefStatus {
+    Ok,
+    Rejected(String),
+}
+
+/// The parsed form of a `report-status` response from receive-pack
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportStatus {
+    /// Whether the pack was unpacked, and the reason if not
+    pub unpack: Result<(), String>,
+    /// The status of each ref named in the report
+    pub refs: HashMap<String, RefStatus>,
+}
+
+impl Default for ReportStatus {
+    fn default() -> Self {
+        ReportStatus {
+            unpack: Ok(()),
+            refs: HashMap::new(),
+        }
+    }
+}
+
+impl ReportStatus {
+    /// Process one line of a report, without its trailing newline
+    ///
+    /// ```
+    /// # use git_sync::{RefStatus, ReportStatus};
+    /// let mut report = ReportStatus::default();
+    /// report.push_line("unpack ok").unwrap();
+    /// report.push_line("ok refs/heads/main").unwrap();
+    /// report.push_line("ng refs/heads/wip non-fast-forward").unwrap();
+    /// assert_eq!(report.unpack, Ok(()));
+    /// assert_eq!(report.refs["refs/heads/main"], RefStatus::Ok);
+    /// assert_eq!(
+    ///     report.refs["refs/heads/wip"],
+    ///     RefStatus::Rejected("non-fast-forward".into())
+    /// );
+    /// assert!(report.push_line("bogus").is_err());
+    /// ```
+    pub fn push_line(&mut self, line: &str) -> Result<(), Error> {
+        if let Some(status) = line.strip_prefix("unpack ") {
+            if status != "ok" {
+                self.unpack = Err(status.to_string());
+            }
+        } else if let Some(refname) = line.strip_prefix("ok ") {
+            self.refs.insert(refname.to_string(), RefStatus::Ok);
+        } else if let Some(rest) = line.strip_prefix("ng ") {
+            let (refname, reason) = match rest.find(' ') {
+                Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+                None => (rest, "rejected"),
+            };
+            self.refs
+                .insert(refname.to_string(), RefStatus::Rejected(reason.to_string()));
+        } else {
+            return Err(Error::Protocol(format!(
+                "Unexpected line in status report: {}",
+                line
+            )));
+        }
+        Ok(())
+    }
+
+    /// Read a report from a stream of pkt-lines, up to the terminating flush
+    pub async fn read_from<R>(reader: &mut R) -> Result<ReportStatus, Error>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut ret = ReportStatus::default();
+        loop {
+            match ProtocolLine::read_from(reader, true).await? {
+                ProtocolLine::Data(cow) => ret.push_line(&String::from_utf8_lossy(&cow))?,
+                ProtocolLine::Flush => break,
+                l => {
+                    return Err(Error::Protocol(format!(
+                        "Unexpected {:?} in status report",
+                        l
+                    )))
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// Parses a report as it arrives in pieces, such as on receive-pack's sideband
+/// channel, a line at a time, keeping no more of it than a line which has yet to
+/// arrive whole
+///
+/// ```
+/// # use git_sync::{RefStatus, ReportParser};
+/// let mut parser = ReportParser::new();
+/// parser.feed(b"000eunpack ok\n0017ok refs/").unwrap();
+/// parser.feed(b"heads/main\n0000").unwrap();
+/// let report = parser.finish().unwrap();
+/// assert_eq!(report.unpack, Ok(()));
+/// assert_eq!(report.refs["refs/heads/main"], RefStatus::Ok);
+///
+/// let mut parser = ReportParser::new();
+/// parser.feed(b"000eunpack ok\n").unwrap();
+/// assert!(parser.finish().is_err());
+/// ```
+#[derive(Debug, Default)]
+pub struct ReportParser {
+    /// The start of a line which hasn't all arrived yet
+    pending: Vec<u8>,
+    report: ReportStatus,
+    /// Whether the flush ending the report has arrived
+    ended: bool,
+}
+
+impl ReportParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take the next piece of the report.  Anything after the flush which ends
+    /// it is ignored.
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.ended {
+            return Ok(());
+        }
+        self.pending.extend_from_slice(data);
+        let mut used = 0;
+        while let Some((line, len)) = ProtocolLine::parse(&self.pending[used..])? {
+            used += len;
+            match line {
+                ProtocolLine::Data(data) => {
+                    let line = String::from_utf8_lossy(&data);
+                    self.report
+                        .push_line(line.strip_suffix('\n').unwrap_or(&line))?;
+                }
+                ProtocolLine::Flush => {
+                    self.ended = true;
+                    break;
+                }
+                l => {
+                    return Err(Error::Protocol(format!(
+                        "Unexpected {:?} in status report",
+                        l
+                    )))
+                }
+            }
+        }
+        self.pending.drain(..used);
+        Ok(())
+    }
+
+    /// The report, once the flush which ends it has arrived
+    pub fn finish(self) -> Result<ReportStatus, Error> {
+        if !self.ended {
+            return Err(Error::Protocol(
+                "The status report ended before it was complete".to_string(),
+            ));
+        }
+        Ok(self.report)
+    }
+}
+
+/// What kind of change a ref update makes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefChangeKind {
+    Create,
+    Update,
+    Delete,
+}
+
+impl RefChangeKind {
+    /// The change as a verb, e.g. `create`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RefChangeKind::Create => "create",
+            RefChangeKind::Update => "update",
+            RefChangeKind::Delete => "delete",
+        }
+    }
+}
+
+/// The final outcome of a single ref update
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefOutcome {
+    pub update: RefUpdate,
+    pub status: RefStatus,
+}
+
+/// The outcome of every ref update made during a sync
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub outcomes: Vec<RefOutcome>,
+}
+
+impl SyncReport {
+    /// Join the updates we sent against the status receive-pack reported for them.
+    /// Updates which receive-pack did not mention are considered rejected.
+    pub fn new(updates: &[RefUpdate], status: &ReportStatus) -> SyncReport {
+        let outcomes = updates
+            .iter()
+            .map(|update| {
+                let status = match (&status.unpack, status.refs.get(&update.refname)) {
+                    (_, Some(status)) => status.clone(),
+                    (Err(reason), None) => {
+                        RefStatus::Rejected(format!("unpack failed: {}", reason))
+                    }
+                    (Ok(()), None) => {
+                        RefStatus::Rejected("not reported by receive-pack".to_string())
+                    }
+                };
+                RefOutcome {
+                    update: update.clone(),
+                    status,
+                }
+            })
+            .collect();
+        SyncReport { outcomes }
+    }
+
+    /// Fold the outcomes of another report (e.g. from a later batch) into this one
+    pub fn merge(&mut self, other: SyncReport) {
+        self.outcomes.extend(other.outcomes);
+    }
+
+    /// The outcomes of updates which were rejected
+    pub fn rejected(&self) -> impl Iterator<Item = &RefOutcome> {
+        self.outcomes
+            .iter()
+            .filter(|outcome| outcome.status != RefStatus::Ok)
+    }
+
+    /// Count the successful updates of the given kind
+    pub fn count(&self, kind: RefChangeKind) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| outcome.status == RefStatus::Ok && outcome.update.kind() == kind)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::NULLSHA;
+
+    const OLD: &str = "1111111111111111111111111111111111111111";
+    const NEW: &str = "2222222222222222222222222222222222222222";
+
+    fn update(refname: &str, oldsha: &str, newsha: &str) -> RefUpdate {
+        RefUpdate {
+            refname: refname.to_string(),
+            oldsha: oldsha.to_string(),
+            newsha: newsha.to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_each_kind_of_line() {
+        let mut report = ReportStatus::default();
+        report.push_line("unpack index-pack abnormal exit").unwrap();
+        report.push_line("ng refs/heads/bare").unwrap();
+        report
+            .push_line("ng refs/heads/hooked pre-receive hook declined")
+            .unwrap();
+        assert_eq!(report.unpack, Err("index-pack abnormal exit".to_string()));
+        assert_eq!(
+            report.refs["refs/heads/bare"],
+            RefStatus::Rejected("rejected".into())
+        );
+        assert_eq!(
+            report.refs["refs/heads/hooked"],
+            RefStatus::Rejected("pre-receive hook declined".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_reports_from_streams() {
+        let mut stream: &[u8] = b"000eunpack ok\n0017ok refs/heads/main\n0000trailing";
+        let report = ReportStatus::read_from(&mut stream).await.unwrap();
+        assert_eq!(report.refs.len(), 1);
+        assert_eq!(report.refs["refs/heads/main"], RefStatus::Ok);
+        // Nothing past the flush is read
+        assert_eq!(stream, b"trailing");
+
+        let mut stream: &[u8] = b"000eunpack ok\n0001";
+        assert!(ReportStatus::read_from(&mut stream).await.is_err());
+        let mut stream: &[u8] = b"000eunpack ok\n";
+        assert!(ReportStatus::read_from(&mut stream).await.is_err());
+    }
+
+    #[test]
+    fn parses_reports_in_any_pieces() {
+        let whole = b"000eunpack ok\n0017ok refs/heads/main\n001ang refs/heads/x stale\n0000";
+        for size in 1..whole.len() {
+            let mut parser = ReportParser::new();
+            for piece in whole.chunks(size) {
+                parser.feed(piece).unwrap();
+            }
+            let report = parser.finish().unwrap();
+            assert_eq!(report.refs["refs/heads/main"], RefStatus::Ok);
+            assert_eq!(
+                report.refs["refs/heads/x"],
+                RefStatus::Rejected("stale".into())
+            );
+        }
+
+        let mut parser = ReportParser::new();
+        parser.feed(b"0000").unwrap();
+        // Whatever follows the end is ignored, even if it isn't a report
+        parser.feed(b"garbage").unwrap();
+        assert_eq!(parser.finish().unwrap(), ReportStatus::default());
+
+        let mut parser = ReportParser::new();
+        assert!(parser.feed(b"000abogus\n").is_err());
+        let mut parser = ReportParser::new();
+        assert!(parser.feed(b"zzzz").is_err());
+    }
+
+    #[test]
+    fn joins_updates_with_their_statuses() {
+        let updates = [
+            update("refs/heads/new", NULLSHA, NEW),
+            update("refs/heads/moved", OLD, NEW),
+            update("refs/heads/gone", OLD, NULLSHA),
+            update("refs/heads/forgotten", OLD, NEW),
+        ];
+        let mut status = ReportStatus::default();
+        for line in &[
+            "ok refs/heads/new",
+            "ok refs/heads/moved",
+            "ng refs/heads/gone deletion prohibited",
+        ] {
+            status.push_line(line).unwrap();
+        }
+        let mut report = SyncReport::new(&updates, &status);
+        assert_eq!(report.count(RefChangeKind::Create), 1);
+        assert_eq!(report.count(RefChangeKind::Update), 1);
+        assert_eq!(report.count(RefChangeKind::Delete), 0);
+        let rejected: Vec<_> = report
+            .rejected()
+            .map(|outcome| (outcome.update.refname.as_str(), outcome.status.clone()))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                (
+                    "refs/heads/gone",
+                    RefStatus::Rejected("deletion prohibited".into())
+                ),
+                (
+                    "refs/heads/forgotten",
+                    RefStatus::Rejected("not reported by receive-pack".into())
+                ),
+            ]
+        );
+
+        // Unmentioned updates in a batch whose pack failed failed with it
+        let mut failed = ReportStatus::default();
+        failed.push_line("unpack out of space").unwrap();
+        report.merge(SyncReport::new(
+            &[update("refs/heads/later", NULLSHA, NEW)],
+            &failed,
+        ));
+        assert_eq!(report.outcomes.len(), 5);
+        assert_eq!(
+            report.outcomes[4].status,
+            RefStatus::Rejected("unpack failed: out of space".into())
+        );
+        assert_eq!(report.count(RefChangeKind::Create), 1);
+    }
+}
@@ -0,0 +1,504 @@
+/// Keeping a copy of a pack pushed into a target, along with what it was pushed
+/// for, to look into afterwards
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+use super::{
+    open_decompressed, Codec, CompressedFile, Decompressed, Error, PackChecksum, PackHeaderScanner,
+    RefUpdate, SpooledPack, NULLSHA,
+};
+
+/// The first line of a saved pack
+pub const SAVED_PACK_SIGNATURE: &str = "# git-sync pack v1";
+
+/// Writes a saved pack: a file which starts, much like a git bundle, with its
+/// signature line, a `-<object>` line for each object the target had which the
+/// pack may have been based on, and an `<old> <new> <ref>` line for each ref
+/// update pushed with it, and then after a blank line has the pack itself.
+/// The whole file is compressed if its name ends `.gz` (gzip) or `.zst` (zstd).
+///
+/// ```
+/// # use git_sync::{PackSaver, RefUpdate, EMPTY_PACK};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let path = std::env::temp_dir().join(format!("saved-{}.pack", std::process::id()));
+/// let update = RefUpdate {
+///     refname: "refs/heads/main".to_string(),
+///     oldsha: "1111111111111111111111111111111111111111".to_string(),
+///     newsha: "2222222222222222222222222222222222222222".to_string(),
+/// };
+/// let have = "1111111111111111111111111111111111111111";
+/// let mut saver = PackSaver::create(&path, [have].iter().copied(), &[update])
+///     .await
+///     .unwrap();
+/// saver.write(EMPTY_PACK).await.unwrap();
+/// saver.finish().await.unwrap();
+/// let saved = std::fs::read(&path).unwrap();
+/// let header = "# git-sync pack v1\n\
+///     -1111111111111111111111111111111111111111\n\
+///     1111111111111111111111111111111111111111 \
+///     2222222222222222222222222222222222222222 refs/heads/main\n\n";
+/// assert_eq!(&saved[..header.len()], header.as_bytes());
+/// assert_eq!(&saved[header.len()..], EMPTY_PACK);
+/// # std::fs::remove_file(&path).unwrap();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PackSaver {
+    file: CompressedFile,
+    path: PathBuf,
+}
+
+impl PackSaver {
+    /// Start saving the pack for `updates` at `path`, replacing whatever was
+    /// there, where the target had the objects `haves`
+    pub async fn create(
+        path: &Path,
+        haves: impl Iterator<Item = &str>,
+        updates: &[RefUpdate],
+    ) -> Result<PackSaver, Error> {
+        let mut header = format!("{}\n", SAVED_PACK_SIGNATURE);
+        // An empty target advertises the null object id, which is no object
+        for have in haves
+            .filter(|have| *have != NULLSHA)
+            .collect::<BTreeSet<_>>()
+        {
+            header.push_str(&format!("-{}\n", have));
+        }
+        for update in updates {
+            header.push_str(&format!("{}\n", update.command()));
+        }
+        header.push('\n');
+        let cannot = |err| {
+            Error::Config(format!(
+                "Cannot save the pack to {}: {}",
+                path.display(),
+                err
+            ))
+        };
+        let mut file = CompressedFile::create(path, Codec::for_path(path))
+            .await
+            .map_err(cannot)?;
+        file.write_all(header.as_bytes()).await.map_err(cannot)?;
+        Ok(PackSaver {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Where the pack is being saved
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add the next piece of the pack
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        Ok(self.file.write_all(data).await?)
+    }
+
+    /// Add the whole of a spooled pack
+    pub async fn write_spooled(&mut self, pack: &SpooledPack) -> Result<(), Error> {
+        tokio::io::copy(&mut pack.open().await?, &mut self.file).await?;
+        Ok(())
+    }
+
+    /// Finish saving the pack, making sure it's all on disk
+    pub async fn finish(self) -> Result<(), Error> {
+        self.file.finish().await?.sync_data().await?;
+        Ok(())
+    }
+}
+
+/// A pack saved by a [`PackSaver`], read back to push it again
+///
+/// ```
+/// # use git_sync::{PackSaver, RefUpdate, SavedPack, EMPTY_PACK};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let path = std::env::temp_dir().join(format!("replay-{}.pack", std::process::id()));
+/// let update = RefUpdate {
+///     refname: "refs/heads/main".to_string(),
+///     oldsha: "1111111111111111111111111111111111111111".to_string(),
+///     newsha: "2222222222222222222222222222222222222222".to_string(),
+/// };
+/// let have = "1111111111111111111111111111111111111111";
+/// let mut saver = PackSaver::create(&path, [have].iter().copied(), &[update.clone()])
+///     .await
+///     .unwrap();
+/// saver.write(EMPTY_PACK).await.unwrap();
+/// saver.finish().await.unwrap();
+/// let saved = SavedPack::open(&path).await.unwrap();
+/// assert_eq!(saved.prerequisites(), [have]);
+/// assert_eq!(saved.updates(), [update]);
+/// assert_eq!(saved.size(), EMPTY_PACK.len() as u64);
+/// assert_eq!(saved.objects(), 0);
+///
+/// // A pack which was cut short isn't pushed
+/// let whole = std::fs::read(&path).unwrap();
+/// std::fs::write(&path, &whole[..whole.len() - 1]).unwrap();
+/// assert!(SavedPack::open(&path).await.is_err());
+/// # std::fs::remove_file(&path).unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SavedPack {
+    path: PathBuf,
+    prerequisites: Vec<String>,
+    updates: Vec<RefUpdate>,
+    /// Where the pack starts in the file
+    offset: u64,
+    size: u64,
+    /// How many objects the pack's header says it has
+    objects: u64,
+}
+
+impl SavedPack {
+    /// Read the saved pack at `path`, which may be compressed, checking that the
+    /// pack is whole
+    pub async fn open(path: &Path) -> Result<SavedPack, Error> {
+        let cannot = |why: String| {
+            Error::Config(format!(
+                "Cannot push the pack saved in {}: {}",
+                path.display(),
+                why
+            ))
+        };
+        let file = open_decompressed(path, 0)
+            .await
+            .map_err(|err| cannot(err.to_string()))?;
+        let mut file = BufReader::new(file);
+        let mut header = Vec::new();
+        let mut offset = 0;
+        loop {
+            let mut line = Vec::new();
+            let read = file.read_until(b'\n', &mut line).await?;
+            if read == 0 {
+                return Err(cannot("it ends before the pack".to_string()));
+            }
+            let first = offset == 0;
+            offset += read as u64;
+            let line = match String::from_utf8(line) {
+                Ok(line) if !first || line.trim_end() == SAVED_PACK_SIGNATURE => line,
+                _ => return Err(cannot("it isn't a saved pack".to_string())),
+            };
+            if line == "\n" {
+                break;
+            }
+            if !first {
+                header.push(line.trim_end_matches('\n').to_string());
+            }
+        }
+
+        let mut prerequisites = Vec::new();
+        let mut updates = Vec::new();
+        for line in &header {
+            if let Some(have) = line.strip_prefix('-').filter(|have| is_object_id(have)) {
+                prerequisites.push(have.to_string());
+                continue;
+            }
+            match line.splitn(3, ' ').collect::<Vec<_>>()[..] {
+                [old, new, refname]
+                    if is_object_id(old) && is_object_id(new) && refname.starts_with("refs/") =>
+                {
+                    updates.push(RefUpdate {
+                        refname: refname.to_string(),
+                        oldsha: old.to_string(),
+                        newsha: new.to_string(),
+                    })
+                }
+                _ => return Err(cannot(format!("it has a bad line {:?}", line))),
+            }
+        }
+
+        // Nothing is pushed from a pack which didn't survive being saved
+        let mut checksum = PackChecksum::new();
+        let mut scanner = PackHeaderScanner::new();
+        let mut buf = vec![0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let read = file.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            checksum.feed(&buf[..read]);
+            scanner.feed(&buf[..read]);
+            size += read as u64;
+        }
+        if size > 0 {
+            checksum
+                .check()
+                .map_err(|err| cannot(format!("the pack {}", err)))?;
+        }
+        Ok(SavedPack {
+            path: path.to_path_buf(),
+            prerequisites,
+            updates,
+            offset,
+            size,
+            objects: scanner.header().map_or(0, |header| header.objects.into()),
+        })
+    }
+
+    /// Where the pack is saved
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The objects the target had when the pack was saved, which the pack may be
+    /// based on
+    pub fn prerequisites(&self) -> &[String] {
+        &self.prerequisites
+    }
+
+    /// The ref updates the pack was pushed for
+    pub fn updates(&self) -> &[RefUpdate] {
+        &self.updates
+    }
+
+    /// How many bytes the pack has, which is none if it was pushed only to
+    /// delete refs
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// How many objects the pack has, which are none if it was pushed only to
+    /// delete refs
+    pub fn objects(&self) -> u64 {
+        self.objects
+    }
+
+    /// Open the pack to read it from the start
+    pub async fn open_pack(&self) -> Result<Decompressed, Error> {
+        Ok(open_decompressed(&self.path, self.offset).await?)
+    }
+}
+
+/// Whether `name` is a SHA-1 object id, as git shows them
+fn is_object_id(name: &str) -> bool {
+    name.len() == 40 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sha1::{Digest, Sha1};
+
+    use crate::{PackSpool, EMPTY_PACK};
+
+    const ONE: &str = "1111111111111111111111111111111111111111";
+    const TWO: &str = "2222222222222222222222222222222222222222";
+
+    /// A pack whose header says it has `objects` objects, with `body` for them
+    /// and the checksum of it all at the end
+    fn fake_pack(objects: u32, body: &[u8]) -> Vec<u8> {
+        let mut pack = b"PACK\0\0\0\x02".to_vec();
+        pack.extend_from_slice(&objects.to_be_bytes());
+        pack.extend_from_slice(body);
+        let checksum = Sha1::digest(&pack);
+        pack.extend_from_slice(&checksum);
+        pack
+    }
+
+    fn update(refname: &str, oldsha: &str, newsha: &str) -> RefUpdate {
+        RefUpdate {
+            refname: refname.to_string(),
+            oldsha: oldsha.to_string(),
+            newsha: newsha.to_string(),
+        }
+    }
+
+    /// Somewhere for a test's saved pack, which it should remove when it's done
+    fn saved_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("git-sync-{}-{}", std::process::id(), name))
+    }
+
+    async fn read_pack(saved: &SavedPack) -> Vec<u8> {
+        let mut pack = Vec::new();
+        saved
+            .open_pack()
+            .await
+            .unwrap()
+            .read_to_end(&mut pack)
+            .await
+            .unwrap();
+        pack
+    }
+
+    #[tokio::test]
+    async fn saves_what_the_target_had_once_each() {
+        let path = saved_path("haves.pack");
+        let haves = [TWO, NULLSHA, ONE, TWO];
+        let saver = PackSaver::create(&path, haves.iter().copied(), &[])
+            .await
+            .unwrap();
+        assert_eq!(saver.path(), path);
+        saver.finish().await.unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            saved,
+            format!("{}\n-{}\n-{}\n\n", SAVED_PACK_SIGNATURE, ONE, TWO)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saves_compressed_packs() {
+        let pack = fake_pack(3, &[7; 5000]);
+        for (name, codec) in &[
+            ("saved.pack.gz", Codec::Gzip),
+            ("saved.pack.zst", Codec::Zstd),
+        ] {
+            let path = saved_path(name);
+            let updates = [
+                update("refs/heads/main", ONE, TWO),
+                update("refs/heads/gone", TWO, NULLSHA),
+            ];
+            let mut saver = PackSaver::create(&path, [ONE].iter().copied(), &updates)
+                .await
+                .unwrap();
+            for piece in pack.chunks(1000) {
+                saver.write(piece).await.unwrap();
+            }
+            saver.finish().await.unwrap();
+            let start = std::fs::read(&path).unwrap();
+            assert_eq!(Codec::detect(&start), *codec);
+            assert!(start.len() < pack.len());
+
+            let saved = SavedPack::open(&path).await.unwrap();
+            assert_eq!(saved.updates(), updates);
+            assert_eq!(saved.size(), pack.len() as u64);
+            assert_eq!(saved.objects(), 3);
+            assert_eq!(read_pack(&saved).await, pack);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn saves_spooled_packs() {
+        let pack = fake_pack(2, b"objects");
+        let mut spool = PackSpool::create(&std::env::temp_dir(), None, Codec::Zstd)
+            .await
+            .unwrap();
+        spool.write(&pack).await.unwrap();
+        let spooled = spool.finish().await.unwrap();
+        let path = saved_path("spooled.pack");
+        let updates = [update("refs/heads/main", NULLSHA, TWO)];
+        let mut saver = PackSaver::create(&path, std::iter::empty(), &updates)
+            .await
+            .unwrap();
+        saver.write_spooled(&spooled).await.unwrap();
+        saver.finish().await.unwrap();
+        let saved = SavedPack::open(&path).await.unwrap();
+        assert!(saved.prerequisites().is_empty());
+        assert_eq!(saved.objects(), 2);
+        assert_eq!(read_pack(&saved).await, pack);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cannot_save_where_there_is_no_directory() {
+        let path = saved_path("missing/saved.pack");
+        let err = PackSaver::create(&path, std::iter::empty(), &[])
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with(&format!("Cannot save the pack to {}: ", path.display())));
+    }
+
+    /// Why the saved pack written as `contents` at `path` can't be pushed,
+    /// removing it
+    async fn refusal(path: &Path, contents: &[u8]) -> String {
+        std::fs::write(path, contents).unwrap();
+        let err = SavedPack::open(path).await.unwrap_err().to_string();
+        std::fs::remove_file(path).unwrap();
+        let prefix = format!("Cannot push the pack saved in {}: ", path.display());
+        err.strip_prefix(&prefix).unwrap_or(&err).to_string()
+    }
+
+    #[tokio::test]
+    async fn replays_deletions_without_a_pack() {
+        let path = saved_path("deletions.pack");
+        let updates = [update("refs/tags/old", ONE, NULLSHA)];
+        let saver = PackSaver::create(&path, [ONE].iter().copied(), &updates)
+            .await
+            .unwrap();
+        saver.finish().await.unwrap();
+        let saved = SavedPack::open(&path).await.unwrap();
+        assert_eq!(saved.path(), path);
+        assert_eq!(saved.updates(), updates);
+        assert_eq!(saved.size(), 0);
+        assert_eq!(saved.objects(), 0);
+        assert!(read_pack(&saved).await.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_what_is_not_a_whole_saved_pack() {
+        let path = saved_path("refused.pack");
+        let header = |lines: &[&str]| {
+            let mut header = format!("{}\n", SAVED_PACK_SIGNATURE);
+            for line in lines {
+                header.push_str(line);
+                header.push('\n');
+            }
+            header.push('\n');
+            header.into_bytes()
+        };
+        assert_eq!(
+            refusal(&path, b"# v2 git bundle\n\n").await,
+            "it isn't a saved pack"
+        );
+        assert_eq!(
+            refusal(
+                &path,
+                format!("{}\n-{}\n", SAVED_PACK_SIGNATURE, ONE).as_bytes()
+            )
+            .await,
+            "it ends before the pack"
+        );
+        let bad = format!("{} {} HEAD", ONE, TWO);
+        assert_eq!(
+            refusal(&path, &header(&[&bad])).await,
+            format!("it has a bad line {:?}", bad)
+        );
+        assert_eq!(
+            refusal(&path, &header(&["-1234"])).await,
+            "it has a bad line \"-1234\""
+        );
+        let mut corrupted = header(&[]);
+        corrupted.extend_from_slice(EMPTY_PACK);
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(refusal(&path, &corrupted)
+            .await
+            .starts_with("the pack is corrupt: it ends with the checksum"));
+    }
+
+    #[tokio::test]
+    async fn refuses_truncated_compressed_packs() {
+        let pack = fake_pack(
+            1,
+            &(0..20_000_u32)
+                .map(|n| (n * 7 % 256) as u8)
+                .collect::<Vec<_>>(),
+        );
+        for name in &["cut.pack.gz", "cut.pack.zst"] {
+            let path = saved_path(name);
+            let updates = [update("refs/heads/main", ONE, TWO)];
+            let mut saver = PackSaver::create(&path, [ONE].iter().copied(), &updates)
+                .await
+                .unwrap();
+            saver.write(&pack).await.unwrap();
+            saver.finish().await.unwrap();
+            SavedPack::open(&path).await.unwrap();
+            // Whether the stream says it was cut short or the pack's checksum does,
+            // it isn't pushed
+            let whole = std::fs::read(&path).unwrap();
+            std::fs::write(&path, &whole[..whole.len() * 3 / 4]).unwrap();
+            assert!(SavedPack::open(&path).await.is_err(), "{}", name);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
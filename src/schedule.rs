@@ -0,0 +1,341 @@
+/// Cron-style schedules saying when to sync
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How far ahead to look for a time a schedule matches before deciding it never will
+const SEARCH_DAYS: i64 = 5 * 366;
+
+/// A schedule written as a cron expression of five fields, the minute, hour, day
+/// of the month, month and day of the week (0 or 7 being Sunday), in UTC.  Each
+/// field is `*`, a number, a range such as `1-5`, any of those followed by a step
+/// such as `*/15`, or a list of them separated by commas; months and days of the
+/// week may also be given by name.  `@hourly`, `@daily`, `@weekly`, `@monthly`
+/// and `@yearly` are accepted too.
+///
+/// As for cron, when both the day of the month and the day of the week are
+/// restricted, a day matching either will do.  As for Vixie cron, a field which
+/// starts with `*`, such as `*/2`, doesn't count as restricted.
+///
+/// ```
+/// # use git_sync::Schedule;
+/// # use std::time::{Duration, UNIX_EPOCH};
+/// let schedule: Schedule = "*/15 9-17 * * mon-fri".parse().unwrap();
+/// // Thursday 1st January 1970, 17:50
+/// let time = UNIX_EPOCH + Duration::from_secs(17 * 3600 + 50 * 60);
+/// // The next time is 09:00 on Friday
+/// let next = schedule.next_after(time).unwrap();
+/// assert_eq!(next, UNIX_EPOCH + Duration::from_secs(86400 + 9 * 3600));
+/// assert_eq!(schedule.next_after(next).unwrap(), next + Duration::from_secs(15 * 60));
+///
+/// let daily: Schedule = "@daily".parse().unwrap();
+/// assert_eq!(daily.next_after(time).unwrap(), UNIX_EPOCH + Duration::from_secs(86400));
+///
+/// assert!("0 0 30 feb *".parse::<Schedule>().unwrap().next_after(time).is_none());
+/// assert!("60 * * * *".parse::<Schedule>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    text: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of the month and day of the week fields were both restricted
+    either_day: bool,
+}
+
+impl Schedule {
+    /// The first time after `time` which the schedule matches, to the minute, or
+    /// `None` if it matches no time in the next few years
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+        // Start from the next whole minute
+        let mut minute = secs.div_euclid(60) + 1;
+        let limit = minute + SEARCH_DAYS * 24 * 60;
+        while minute < limit {
+            let day = minute.div_euclid(24 * 60);
+            if !self.matches_day(day) {
+                minute = (day + 1) * 24 * 60;
+                continue;
+            }
+            let hour = minute.div_euclid(60);
+            if !has(self.hours, hour.rem_euclid(24)) {
+                minute = (hour + 1) * 60;
+                continue;
+            }
+            if has(self.minutes, minute.rem_euclid(60)) {
+                return Some(UNIX_EPOCH + Duration::from_secs(minute as u64 * 60));
+            }
+            minute += 1;
+        }
+        None
+    }
+
+    /// Whether the schedule matches the day this many days after the epoch
+    fn matches_day(&self, day: i64) -> bool {
+        let (_, month, mday) = civil_from_days(day);
+        if !has(self.months, month) {
+            return false;
+        }
+        // The epoch was a Thursday
+        let weekday = (day + 4).rem_euclid(7);
+        let mday_ok = has(self.days, mday);
+        let weekday_ok = has(self.weekdays, weekday);
+        if self.either_day {
+            mday_ok || weekday_ok
+        } else {
+            mday_ok && weekday_ok
+        }
+    }
+}
+
+fn has(set: u64, value: i64) -> bool {
+    set & (1 << value) != 0
+}
+
+/// The year, month and day of the month of a count of days since the epoch
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Parse one field of a cron expression into the set of values it matches,
+/// returning whether it was restricted (doesn't start with `*`) as well
+fn parse_field(
+    field: &str,
+    min: i64,
+    max: i64,
+    names: &[&str],
+    name_base: i64,
+) -> Result<(u64, bool), String> {
+    let value = |s: &str| -> Result<i64, String> {
+        let lower = s.to_ascii_lowercase();
+        match names.iter().position(|name| *name == lower) {
+            Some(idx) => Ok(idx as i64 + name_base),
+            None => s
+                .parse()
+                .map_err(|_| format!("Cannot understand {:?} in schedule", s)),
+        }
+    };
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(idx) => (&part[..idx], value(&part[idx + 1..])?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.find('-') {
+            _ if range == "*" => (min, max),
+            Some(idx) => (value(&range[..idx])?, value(&range[idx + 1..])?),
+            // A single value with a step runs to the end of the field's range
+            None if part.contains('/') => (value(range)?, max),
+            None => {
+                let v = value(range)?;
+                (v, v)
+            }
+        };
+        if start < min || end > max || start > end || step < 1 {
+            return Err(format!("{:?} is out of range in schedule", part));
+        }
+        for v in (start..=end).step_by(step as usize) {
+            set |= 1 << v;
+        }
+    }
+    Ok((set, !field.starts_with('*')))
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Schedule, String> {
+        let expanded = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Expected five fields in schedule {:?}", s));
+        }
+        let (minutes, _) = parse_field(fields[0], 0, 59, &[], 0)?;
+        let (hours, _) = parse_field(fields[1], 0, 23, &[], 0)?;
+        let (days, days_restricted) = parse_field(fields[2], 1, 31, &[], 0)?;
+        let (months, _) = parse_field(fields[3], 1, 12, MONTHS, 1)?;
+        let (mut weekdays, weekdays_restricted) = parse_field(fields[4], 0, 7, WEEKDAYS, 0)?;
+        // Sunday may be written as 7 as well as 0
+        if has(weekdays, 7) {
+            weekdays |= 1;
+        }
+        Ok(Schedule {
+            text: s.trim().to_string(),
+            minutes,
+            hours,
+            days,
+            months,
+            weekdays,
+            either_day: days_restricted && weekdays_restricted,
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of Monday 1st January 2024
+    const NEW_YEAR_2024: u64 = 1_704_067_200;
+
+    /// The time this many days, hours and minutes into 2024
+    fn at(day: u64, hour: u64, minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(NEW_YEAR_2024 + (day * 24 + hour) * 3600 + minute * 60)
+    }
+
+    /// The next few times `schedule` matches after `start`
+    fn runs(schedule: &str, start: SystemTime, count: usize) -> Vec<SystemTime> {
+        let schedule: Schedule = schedule.parse().unwrap();
+        let mut times = Vec::new();
+        let mut time = start;
+        for _ in 0..count {
+            time = schedule.next_after(time).unwrap();
+            times.push(time);
+        }
+        times
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(
+            runs("0 9-11 * * *", at(0, 0, 0), 4),
+            vec![at(0, 9, 0), at(0, 10, 0), at(0, 11, 0), at(1, 9, 0)]
+        );
+        assert_eq!(
+            runs("30 8,12-13 * * *", at(0, 0, 0), 3),
+            vec![at(0, 8, 30), at(0, 12, 30), at(0, 13, 30)]
+        );
+    }
+
+    #[test]
+    fn steps() {
+        assert_eq!(
+            runs("*/20 * * * *", at(0, 0, 0), 3),
+            vec![at(0, 0, 20), at(0, 0, 40), at(0, 1, 0)]
+        );
+        assert_eq!(
+            runs("5-50/15 * * * *", at(0, 0, 0), 5),
+            vec![
+                at(0, 0, 5),
+                at(0, 0, 20),
+                at(0, 0, 35),
+                at(0, 0, 50),
+                at(0, 1, 5)
+            ]
+        );
+        // A single value with a step runs to the end of the field
+        assert_eq!(
+            runs("10/25 * * * *", at(0, 0, 0), 3),
+            vec![at(0, 0, 10), at(0, 0, 35), at(0, 1, 10)]
+        );
+    }
+
+    #[test]
+    fn named_months_and_weekdays() {
+        // 1st March 2024 is the 60th day after New Year's Day
+        assert_eq!(runs("0 0 1 mar *", at(0, 0, 0), 1), vec![at(60, 0, 0)]);
+        assert_eq!(runs("0 0 1 MAR *", at(0, 0, 0), 1), vec![at(60, 0, 0)]);
+        assert_eq!(
+            runs("0 0 1 jan-feb *", at(0, 0, 0), 2),
+            vec![at(31, 0, 0), at(366, 0, 0)]
+        );
+        assert_eq!(runs("0 0 * * sat", at(0, 0, 0), 1), vec![at(5, 0, 0)]);
+        assert_eq!(
+            runs("0 0 * * Tue-thu", at(0, 0, 0), 4),
+            vec![at(1, 0, 0), at(2, 0, 0), at(3, 0, 0), at(8, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn sunday_is_0_or_7() {
+        let sunday = vec![at(6, 0, 0), at(13, 0, 0)];
+        assert_eq!(runs("0 0 * * 0", at(0, 0, 0), 2), sunday);
+        assert_eq!(runs("0 0 * * 7", at(0, 0, 0), 2), sunday);
+        assert_eq!(runs("0 0 * * sun", at(0, 0, 0), 2), sunday);
+        assert_eq!(
+            runs("0 0 * * 5-7", at(0, 0, 0), 4),
+            vec![at(4, 0, 0), at(5, 0, 0), at(6, 0, 0), at(11, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn restricted_days_of_the_month_and_week_are_ored() {
+        // Tuesdays, and the 13th (a Saturday)
+        assert_eq!(
+            runs("0 0 13 * tue", at(0, 0, 0), 4),
+            vec![at(1, 0, 0), at(8, 0, 0), at(12, 0, 0), at(15, 0, 0)]
+        );
+        // With only one restricted, the other matches every day
+        assert_eq!(runs("0 0 13 * *", at(0, 0, 0), 1), vec![at(12, 0, 0)]);
+    }
+
+    #[test]
+    fn fields_starting_with_a_star_are_unrestricted() {
+        // Odd days of the month which are Mondays, rather than either
+        assert_eq!(
+            runs("0 0 */2 * mon", at(0, 0, 0), 2),
+            vec![at(14, 0, 0), at(28, 0, 0)]
+        );
+        // The 1st of a month falling on Sunday, Tuesday, Thursday or Saturday,
+        // rather than either: Thursday 1st February
+        assert_eq!(runs("0 0 1 * */2", at(0, 0, 0), 1), vec![at(31, 0, 0)]);
+    }
+
+    #[test]
+    fn shorthands() {
+        assert_eq!(runs("@hourly", at(0, 0, 30), 1), vec![at(0, 1, 0)]);
+        assert_eq!(runs("@weekly", at(0, 0, 0), 1), vec![at(6, 0, 0)]);
+        assert_eq!(runs("@monthly", at(0, 0, 0), 1), vec![at(31, 0, 0)]);
+        assert_eq!(runs("@yearly", at(0, 0, 0), 1), vec![at(366, 0, 0)]);
+    }
+
+    #[test]
+    fn refuses_bad_schedules() {
+        for bad in &[
+            "0 0 * *",
+            "0 0 * * * *",
+            "60 * * * *",
+            "0 24 * * *",
+            "0 0 0 * *",
+            "0 0 32 * *",
+            "0 0 * 13 *",
+            "0 0 * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "0 0 * * funday",
+            "@fortnightly",
+        ] {
+            assert!(bad.parse::<Schedule>().is_err(), "{}", bad);
+        }
+    }
+}
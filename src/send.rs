@@ -1,6 +1,6 @@
-use super::{Capability, ProtocolLine, NULLSHA};
-use std::collections::{HashMap, HashSet};
-use tokio::io::{self, AsyncWrite};
+use super::{Capability, Error, PlanOptions, ProtocolLine, RefChangeKind, NULLSHA};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use tokio::io::AsyncWrite;
 
 pub enum SendActivity {
     Nothing,
@@ -8,6 +8,77 @@ pub enum SendActivity {
     Sending,
 }
 
+impl SendActivity {
+    /// Work out what a receive-pack session will be doing for a given set of updates
+    pub fn for_updates(updates: &[RefUpdate]) -> SendActivity {
+        if updates.is_empty() {
+            SendActivity::Nothing
+        } else if updates.iter().all(RefUpdate::is_delete) {
+            SendActivity::Deleting
+        } else {
+            SendActivity::Sending
+        }
+    }
+}
+
+/// A single ref change to be sent to receive-pack
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefUpdate {
+    pub refname: String,
+    pub oldsha: String,
+    pub newsha: String,
+}
+
+impl RefUpdate {
+    pub fn is_create(&self) -> bool {
+        self.oldsha == NULLSHA
+    }
+
+    pub fn is_delete(&self) -> bool {
+        self.newsha == NULLSHA
+    }
+
+    pub fn kind(&self) -> RefChangeKind {
+        if self.is_create() {
+            RefChangeKind::Create
+        } else if self.is_delete() {
+            RefChangeKind::Delete
+        } else {
+            RefChangeKind::Update
+        }
+    }
+
+    /// The command line for this update, as sent to receive-pack or placed in a push certificate.
+    /// The missing side of a creation or deletion is as long as the other's object name, so that
+    /// it's the null name of a repository using SHA-256 as well as of one using SHA-1.
+    ///
+    /// ```
+    /// # use git_sync::{RefUpdate, NULLSHA};
+    /// let newsha = "ab".repeat(32);
+    /// let update = RefUpdate {
+    ///     refname: "refs/heads/main".into(),
+    ///     oldsha: NULLSHA.into(),
+    ///     newsha: newsha.clone(),
+    /// };
+    /// assert_eq!(update.command(), format!("{} {} refs/heads/main", "0".repeat(64), newsha));
+    /// ```
+    pub fn command(&self) -> String {
+        let null = |sha: &str, other: &str| {
+            if sha == NULLSHA {
+                "0".repeat(other.len().max(NULLSHA.len()))
+            } else {
+                sha.to_string()
+            }
+        };
+        format!(
+            "{} {} {}",
+            null(&self.oldsha, &self.newsha),
+            null(&self.newsha, &self.oldsha),
+            self.refname
+        )
+    }
+}
+
 pub const EMPTY_PACK: &[u8] = &[
     b'P', b'A', b'C', b'K', // Pack header starts 'PACK'
     0, 0, 0, 2, // Then we get the version number (2)
@@ -17,67 +88,148 @@ pub const EMPTY_PACK: &[u8] = &[
     0xfd, 0x3e, 0xd3, 0x1e,
 ];
 
-pub async fn send_refchange<W>(
-    writer: &mut W,
+/// Compute the ref updates needed to turn the `existing` ref set into the `target` one
+///
+/// The refs in `target` are first mapped through any refspecs in the options, and only
+/// refs in `existing` which are within the scope of those refspecs are considered.
+/// Refs are only deleted where the options permit it.  Protected refs are never deleted,
+/// though they may still be updated; it is up to the caller to ensure such updates are
+/// fast-forwards.
+pub fn compute_ref_updates(
     existing: &HashMap<String, String>,
     target: &HashMap<String, String>,
-    caps: impl Iterator<Item = (Capability, Option<&str>)>,
-) -> io::Result<SendActivity>
-where
-    W: AsyncWrite + Unpin,
-{
-    let mut capstring = {
-        let mut ret = String::new();
-        for (cap, val) in caps {
-            if ret.is_empty() {
-                ret.push('\0');
-            } else {
-                ret.push(' ');
-            }
-            ret.push_str(cap.as_str());
-            if let Some(val) = val {
-                ret.push('=');
-                ret.push_str(val);
-            }
-        }
-        Some(ret)
-    };
+    opts: &PlanOptions,
+) -> Vec<RefUpdate> {
     // The refchange set we want to transmit comes down to tuples of oldsha newsha refname
     // where oldsha is NULLSHA if we're creating something new, and newsha is NULLSHA if
     // we're deleting something old.  Where the shas are the same there's no need to
     // transmit the ref.
-    // In addition, existing may contain peeled refs, which we don't want to think about,
+    // In addition, either side may contain peeled refs, which we don't want to think about,
     // so we filter those out
-    let all_refs: HashSet<_> = existing
+    fn interesting(refname: &&String) -> bool {
+        refname.starts_with("refs/") && !refname.ends_with("^{}")
+    }
+    let target_names: BTreeSet<_> = target.keys().filter(interesting).collect();
+    let mut wanted: BTreeMap<String, &str> = BTreeMap::new();
+    for refname in target_names {
+        if let Some(mapped) = opts.map_source(refname) {
+            // If several source refs map to the same name, the first one wins
+            wanted.entry(mapped).or_insert(&target[refname]);
+        }
+    }
+    let current: BTreeMap<&str, &str> = existing
+        .iter()
+        .filter(|(k, _)| interesting(k) && opts.in_scope(k))
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+
+    let all_refs: BTreeSet<&str> = wanted
         .keys()
-        .chain(target.keys())
-        .filter(|k| k.starts_with("refs/") && !k.ends_with("^{}"))
+        .map(String::as_str)
+        .chain(current.keys().copied())
         .collect();
 
-    // For all the refs, write the change (if any) out
-    let mut need_pack = false;
-    for refname in all_refs {
-        let oldsha = existing.get(refname).map(String::as_str).unwrap_or(NULLSHA);
-        let newsha = target.get(refname).map(String::as_str).unwrap_or(NULLSHA);
-        if oldsha != newsha {
-            if newsha != NULLSHA {
-                need_pack = true;
-            }
-            // Worth sending the command
-            let cmd = if let Some(caps) = capstring.take() {
-                format!("{} {} {}{}\n", oldsha, newsha, refname, caps)
+    all_refs
+        .into_iter()
+        .filter_map(|refname| {
+            let oldsha = current.get(refname).copied().unwrap_or(NULLSHA);
+            let newsha = wanted.get(refname).copied().unwrap_or(NULLSHA);
+            if oldsha == newsha || (newsha == NULLSHA && !opts.may_delete(refname)) {
+                None
             } else {
-                format!("{} {} {}\n", oldsha, newsha, refname)
-            };
-            ProtocolLine::write_str(writer, cmd).await?;
+                Some(RefUpdate {
+                    refname: refname.to_string(),
+                    oldsha: oldsha.to_string(),
+                    newsha: newsha.to_string(),
+                })
+            }
+        })
+        .collect()
+}
+
+fn capability_string<'a>(caps: impl Iterator<Item = (Capability, Option<&'a str>)>) -> String {
+    let mut ret = String::new();
+    for (cap, val) in caps {
+        if ret.is_empty() {
+            ret.push('\0');
+        } else {
+            ret.push(' ');
         }
+        ret.push_str(cap.as_str());
+        if let Some(val) = val {
+            ret.push('=');
+            ret.push_str(val);
+        }
+    }
+    ret
+}
+
+pub async fn send_refchange<W>(
+    writer: &mut W,
+    existing: &HashMap<String, String>,
+    target: &HashMap<String, String>,
+    caps: impl Iterator<Item = (Capability, Option<&str>)>,
+) -> Result<SendActivity, Error>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let updates = compute_ref_updates(existing, target, &PlanOptions::default());
+    let sent = send_ref_updates(writer, &updates, caps).await?;
+    Ok(SendActivity::for_updates(&sent))
+}
+
+/// Send a precomputed set of ref updates to receive-pack, returning the commands sent
+pub async fn send_ref_updates<W>(
+    writer: &mut W,
+    updates: &[RefUpdate],
+    caps: impl Iterator<Item = (Capability, Option<&str>)>,
+) -> Result<Vec<RefUpdate>, Error>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut capstring = Some(capability_string(caps));
+
+    // For all the refs, write the change out
+    for update in updates {
+        // The first command carries our capabilities
+        let cmd = if let Some(caps) = capstring.take() {
+            format!("{}{}\n", update.command(), caps)
+        } else {
+            format!("{}\n", update.command())
+        };
+        ProtocolLine::write_str(writer, cmd).await?;
     }
     // We terminate the refset change with a flush
     ProtocolLine::Flush.write_to(writer).await?;
 
-    Ok(match (capstring.is_none(), need_pack) {
-        (false, _) => SendActivity::Nothing,
-        (true, false) => SendActivity::Deleting,
-        (true, true) => SendActivity::Sending,
-    })
+    Ok(updates.to_vec())
+}
+
+/// Send a set of ref updates to receive-pack in the form of a signed push certificate,
+/// returning the commands sent
+///
+/// The certificate must already contain the commands for the given updates, as produced
+/// by [`PushCert::render`](crate::PushCert::render) and then signed.
+pub async fn send_push_cert<W>(
+    writer: &mut W,
+    updates: &[RefUpdate],
+    cert: &str,
+    caps: impl Iterator<Item = (Capability, Option<&str>)>,
+) -> Result<Vec<RefUpdate>, Error>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if updates.is_empty() {
+        // Nothing to certify, so just end the command list
+        ProtocolLine::Flush.write_to(writer).await?;
+        return Ok(Vec::new());
+    }
+    ProtocolLine::write_str(writer, format!("push-cert{}", capability_string(caps))).await?;
+    for line in cert.split_inclusive('\n') {
+        ProtocolLine::write_str(writer, line).await?;
+    }
+    ProtocolLine::write_str(writer, "push-cert-end\n").await?;
+    ProtocolLine::Flush.write_to(writer).await?;
+
+    Ok(updates.to_vec())
 }
@@ -0,0 +1,143 @@
+/// Keeping a pack on disk between fetching it from the source and pushing it into
+/// the target, so that neither has to keep pace with the other
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+use super::{
+    open_decompressed, Codec, CompressedFile, Decompressed, Error, ObjectFormat, PackChecksum,
+};
+
+/// Tells apart the spool files of syncs running at once in this process
+static SPOOLED: AtomicUsize = AtomicUsize::new(0);
+
+/// A pack being written to a file in a spool directory as it arrives, compressed
+/// if asked, and checksummed as it goes.  The file is removed once it's no longer
+/// wanted.
+///
+/// ```
+/// # use git_sync::{Codec, ObjectFormat, PackSpool, EMPTY_PACK};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let dir = std::env::temp_dir();
+/// let mut spool = PackSpool::create(&dir, Some(ObjectFormat::Sha1), Codec::None)
+///     .await
+///     .unwrap();
+/// spool.write(&EMPTY_PACK[..5]).await.unwrap();
+/// spool.write(&EMPTY_PACK[5..]).await.unwrap();
+/// let spooled = spool.finish().await.unwrap();
+/// assert_eq!(spooled.size(), EMPTY_PACK.len() as u64);
+/// assert_eq!(std::fs::read(spooled.path()).unwrap(), EMPTY_PACK);
+/// let path = spooled.path().to_path_buf();
+/// drop(spooled);
+/// assert!(!path.exists());
+///
+/// let mut spool = PackSpool::create(&dir, Some(ObjectFormat::Sha1), Codec::Gzip)
+///     .await
+///     .unwrap();
+/// spool.write(&EMPTY_PACK[..EMPTY_PACK.len() - 1]).await.unwrap();
+/// spool.write(b"!").await.unwrap();
+/// assert!(spool.finish().await.is_err());
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PackSpool {
+    file: CompressedFile,
+    path: SpoolFile,
+    /// The checksum of the pack so far, if it's to be checked
+    checksum: Option<PackChecksum>,
+    size: u64,
+}
+
+/// A pack which has been spooled whole, to be read back as often as need be
+#[derive(Debug)]
+pub struct SpooledPack {
+    path: SpoolFile,
+    size: u64,
+}
+
+/// A spool file, which is removed when dropped
+#[derive(Debug)]
+struct SpoolFile(PathBuf);
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl PackSpool {
+    /// Start spooling a pack into a new file in `dir`, compressed with `codec`,
+    /// checking the checksum it ends with if its object format is given
+    pub async fn create(
+        dir: &Path,
+        verify: Option<ObjectFormat>,
+        codec: Codec,
+    ) -> Result<PackSpool, Error> {
+        let name = format!(
+            "git-sync-{}-{}.pack{}",
+            std::process::id(),
+            SPOOLED.fetch_add(1, Ordering::Relaxed),
+            codec.extension()
+        );
+        let path = dir.join(name);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+            .map_err(|err| {
+                Error::Config(format!("Cannot spool a pack in {}: {}", dir.display(), err))
+            })?;
+        Ok(PackSpool {
+            file: CompressedFile::new(file, codec),
+            path: SpoolFile(path),
+            checksum: verify.map(PackChecksum::for_format),
+            size: 0,
+        })
+    }
+
+    /// Add the next piece of the pack
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.file.write_all(data).await?;
+        self.size += data.len() as u64;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.feed(data);
+        }
+        Ok(())
+    }
+
+    /// Finish spooling the pack, failing if it doesn't end with the checksum of
+    /// the rest of it
+    pub async fn finish(self) -> Result<SpooledPack, Error> {
+        self.file.finish().await?;
+        if let Some(checksum) = self.checksum {
+            checksum
+                .check()
+                .map_err(|err| Error::Protocol(format!("The pack from the source {}", err)))?;
+        }
+        Ok(SpooledPack {
+            path: self.path,
+            size: self.size,
+        })
+    }
+}
+
+impl SpooledPack {
+    /// Where the pack is spooled
+    pub fn path(&self) -> &Path {
+        &self.path.0
+    }
+
+    /// How many bytes the pack has, before any compression
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Open the pack to read it back from the start
+    pub async fn open(&self) -> Result<Decompressed, Error> {
+        Ok(open_decompressed(self.path(), 0).await?)
+    }
+}
@@ -0,0 +1,317 @@
+/// Options for the `ssh` connections used to reach remote repositories
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use tokio::process::Command;
+
+/// How long a shared SSH connection is kept open after its last use
+const CONTROL_PERSIST_SECS: u32 = 60;
+
+/// The program run in place of plain `ssh`, in the manner of git's `GIT_SSH` and
+/// `GIT_SSH_COMMAND`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SshProgram {
+    /// A program run directly with the ssh arguments
+    Program(PathBuf),
+    /// A shell command line, to which the ssh arguments are appended
+    Shell(String),
+}
+
+impl SshProgram {
+    /// The program chosen by `GIT_SSH_COMMAND` or else `GIT_SSH`, if either is set
+    pub fn from_env() -> Option<SshProgram> {
+        let nonempty = |name| std::env::var_os(name).filter(|val| !val.is_empty());
+        if let Some(command) = nonempty("GIT_SSH_COMMAND") {
+            Some(SshProgram::Shell(command.to_string_lossy().into_owned()))
+        } else {
+            nonempty("GIT_SSH").map(|program| SshProgram::Program(program.into()))
+        }
+    }
+}
+
+/// The argument conventions of the program run as `ssh`, as with git's `ssh.variant`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SshVariant {
+    /// OpenSSH, including OpenSSH for Windows
+    Ssh,
+    /// PuTTY's `plink`, which takes `-P` for the port and `-batch` for batch mode
+    Plink,
+    /// TortoiseGit's `tortoiseplink`, which is like plink but always needs `-batch`
+    TortoisePlink,
+    /// A program which takes nothing but the destination and the command
+    Simple,
+}
+
+impl SshVariant {
+    /// Guess the variant from the name of the program, as git does, or use the one
+    /// named by `GIT_SSH_VARIANT` if that's set
+    ///
+    /// ```
+    /// # use git_sync::{SshProgram, SshVariant};
+    /// let program = SshProgram::Program(r"C:\Program Files\PuTTY\PLINK.EXE".into());
+    /// assert_eq!(SshVariant::detect(Some(&program)), SshVariant::Plink);
+    /// let program = SshProgram::Shell("tortoiseplink -v".into());
+    /// assert_eq!(SshVariant::detect(Some(&program)), SshVariant::TortoisePlink);
+    /// assert_eq!(SshVariant::detect(None), SshVariant::Ssh);
+    /// ```
+    pub fn detect(program: Option<&SshProgram>) -> SshVariant {
+        if let Some(variant) = std::env::var("GIT_SSH_VARIANT")
+            .ok()
+            .and_then(|name| name.parse().ok())
+        {
+            return variant;
+        }
+        let path = match program {
+            None => return SshVariant::Ssh,
+            Some(SshProgram::Program(path)) => path.to_string_lossy().into_owned(),
+            Some(SshProgram::Shell(command)) => {
+                command.split_whitespace().next().unwrap_or("").to_string()
+            }
+        };
+        let name = path.rsplit(['/', '\\']).next().unwrap_or("");
+        let name = name.to_ascii_lowercase();
+        match name.strip_suffix(".exe").unwrap_or(&name) {
+            "plink" | "putty" => SshVariant::Plink,
+            "tortoiseplink" => SshVariant::TortoisePlink,
+            _ => SshVariant::Ssh,
+        }
+    }
+}
+
+impl FromStr for SshVariant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ssh" => Ok(SshVariant::Ssh),
+            "plink" | "putty" => Ok(SshVariant::Plink),
+            "tortoiseplink" => Ok(SshVariant::TortoisePlink),
+            "simple" => Ok(SshVariant::Simple),
+            _ => Err(format!("Unknown ssh variant: {}", s)),
+        }
+    }
+}
+
+/// How ssh should treat host keys it doesn't already know
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    /// Refuse to connect to hosts whose keys aren't already known
+    Strict,
+    /// Record the keys of new hosts, but refuse hosts whose keys have changed
+    AcceptNew,
+}
+
+impl FromStr for HostKeyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(HostKeyPolicy::Strict),
+            "accept-new" => Ok(HostKeyPolicy::AcceptNew),
+            _ => Err(format!("Unknown host key policy: {}", s)),
+        }
+    }
+}
+
+/// How to run `ssh`, beyond what a remote's URL says
+///
+/// ```
+/// # use git_sync::SshOptions;
+/// let opts = SshOptions {
+///     port: Some(2222),
+///     user: Some("deploy".into()),
+///     batch_mode: true,
+///     ..SshOptions::default()
+/// };
+/// assert_eq!(
+///     opts.args(None, "example.com", None),
+///     ["-p", "2222", "-l", "deploy", "-o", "BatchMode=yes", "example.com"]
+/// );
+/// // Anything the URL specifies takes precedence
+/// assert_eq!(
+///     opts.args(Some("git"), "example.com", Some(22)),
+///     ["-p", "22", "-o", "BatchMode=yes", "git@example.com"]
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SshOptions {
+    /// The port to connect to, unless the remote's URL gives one
+    pub port: Option<u16>,
+    /// The user to log in as, unless the remote's URL gives one
+    pub user: Option<String>,
+    /// The private key to authenticate with
+    pub identity_file: Option<PathBuf>,
+    /// Extra `-o` options, e.g. `StrictHostKeyChecking=yes`
+    pub options: Vec<String>,
+    /// Never prompt for passwords or passphrases
+    pub batch_mode: bool,
+    /// How to treat unknown host keys, rather than whatever the ssh configuration says
+    pub host_key_policy: Option<HostKeyPolicy>,
+    /// The file to check and record host keys in, instead of the user's own
+    pub known_hosts: Option<PathBuf>,
+    /// Share one connection per host between every ssh we run, through a control
+    /// socket at this path (which may use ssh's `%` tokens, e.g. `~/.ssh/cm-%C`).
+    /// The master connection lingers for a while after use, so later runs reuse it too.
+    pub control_path: Option<String>,
+    /// What to run instead of `ssh`
+    pub program: Option<SshProgram>,
+    /// The conventions the program follows, if they can't be guessed from its name
+    pub variant: Option<SshVariant>,
+}
+
+impl SshOptions {
+    /// The conventions of the program we run, either as configured or as guessed
+    /// from its name
+    pub fn variant(&self) -> SshVariant {
+        self.variant
+            .unwrap_or_else(|| SshVariant::detect(self.program.as_ref()))
+    }
+
+    /// Check that the program we run can honour all of these options
+    pub fn validate(&self) -> Result<(), String> {
+        let variant = self.variant();
+        let unsupported =
+            |what: &str| Err(format!("{} is not supported by {:?} ssh", what, variant));
+        if variant == SshVariant::Simple {
+            if self.port.is_some() {
+                return unsupported("Setting the port");
+            }
+            if self.identity_file.is_some() {
+                return unsupported("Setting the identity file");
+            }
+            if self.batch_mode {
+                return unsupported("Batch mode");
+            }
+        }
+        if variant != SshVariant::Ssh {
+            if !self.options.is_empty() {
+                return unsupported("Passing -o options");
+            }
+            if self.control_path.is_some() {
+                return unsupported("Connection sharing");
+            }
+            if self.host_key_policy.is_some() || self.known_hosts.is_some() {
+                return unsupported("Setting the host key policy");
+            }
+        }
+        Ok(())
+    }
+
+    /// The arguments to `ssh` to reach the given host, to which the remote command
+    /// should be appended.  Options the variant of ssh can't take are left out, so
+    /// [`SshOptions::validate`] should be used to reject them first.
+    pub fn args(&self, user: Option<&str>, host: &str, port: Option<u16>) -> Vec<String> {
+        let variant = self.variant();
+        let mut args = Vec::new();
+        if variant == SshVariant::TortoisePlink || (variant == SshVariant::Plink && self.batch_mode)
+        {
+            args.push("-batch".to_string());
+        }
+        if let Some(port) = port.or(self.port) {
+            match variant {
+                SshVariant::Ssh => args.push("-p".to_string()),
+                SshVariant::Plink | SshVariant::TortoisePlink => args.push("-P".to_string()),
+                SshVariant::Simple => {}
+            }
+            if variant != SshVariant::Simple {
+                args.push(port.to_string());
+            }
+        }
+        // Simple ssh programs can only be told the user as part of the destination
+        let user = match (user, &self.user) {
+            (None, Some(user)) if variant == SshVariant::Simple => Some(user.as_str()),
+            (None, Some(user)) => {
+                args.push("-l".to_string());
+                args.push(user.clone());
+                None
+            }
+            (user, _) => user,
+        };
+        if let (Some(identity), true) = (&self.identity_file, variant != SshVariant::Simple) {
+            args.push("-i".to_string());
+            args.push(identity.to_string_lossy().into_owned());
+        }
+        if variant == SshVariant::Ssh {
+            for option in &self.options {
+                args.push("-o".to_string());
+                args.push(option.clone());
+            }
+            if self.batch_mode {
+                args.push("-o".to_string());
+                args.push("BatchMode=yes".to_string());
+            }
+            if let Some(policy) = self.host_key_policy {
+                args.push("-o".to_string());
+                args.push(match policy {
+                    HostKeyPolicy::Strict => "StrictHostKeyChecking=yes".to_string(),
+                    HostKeyPolicy::AcceptNew => "StrictHostKeyChecking=accept-new".to_string(),
+                });
+            }
+            if let Some(path) = &self.known_hosts {
+                args.push("-o".to_string());
+                args.push(format!("UserKnownHostsFile={}", path.to_string_lossy()));
+            }
+            if let Some(path) = &self.control_path {
+                for option in &[
+                    "ControlMaster=auto".to_string(),
+                    format!("ControlPath={}", path),
+                    format!("ControlPersist={}", CONTROL_PERSIST_SECS),
+                ] {
+                    args.push("-o".to_string());
+                    args.push(option.clone());
+                }
+            }
+        }
+        args.push(match user {
+            Some(user) => format!("{}@{}", user, host),
+            None => host.to_string(),
+        });
+        args
+    }
+
+    /// A command which runs `ssh` to the given host
+    pub fn command(&self, user: Option<&str>, host: &str, port: Option<u16>) -> Command {
+        let mut cmd = match &self.program {
+            None => Command::new("ssh"),
+            Some(SshProgram::Program(program)) => Command::new(program),
+            Some(SshProgram::Shell(command)) => {
+                // As git does, let the shell append the arguments to the command line
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c")
+                    .arg(format!("{} \"$@\"", command))
+                    .arg(command);
+                cmd
+            }
+        };
+        cmd.args(self.args(user, host, port));
+        cmd
+    }
+}
+
+/// Quote a path for the shell which runs the remote end of an SSH command.  A
+/// leading `~` or `~user` is left for the shell to expand.
+///
+/// ```
+/// # use git_sync::quote_remote_path;
+/// assert_eq!(quote_remote_path("/srv/my repo.git"), "'/srv/my repo.git'");
+/// assert_eq!(quote_remote_path("~alice/it's.git"), "~alice/'it'\\''s.git'");
+/// ```
+pub fn quote_remote_path(path: &str) -> String {
+    let (home, rest) = match path.find('/') {
+        Some(idx)
+            if path.starts_with('~')
+                && path[1..idx]
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)) =>
+        {
+            path.split_at(idx + 1)
+        }
+        _ => ("", path),
+    };
+    format!("{}{}", home, shell_quote(rest))
+}
+
+/// Quote a string as a single word for a POSIX shell
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
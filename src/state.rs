@@ -0,0 +1,250 @@
+/// What a sync records for the next sync of the same pair to go on
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use sha1::{Digest, Sha1};
+
+use super::Error;
+
+/// The first line of a state file
+const STATE_SIGNATURE: &str = "# git-sync state v1";
+
+/// The refs of a pair's source and target as the last sync which wholly
+/// succeeded left them.  The next sync can skip the target altogether if the
+/// source's refs are as they were, and can otherwise tell the source that the
+/// target has the objects they name.  A state file is text: its signature line,
+/// and then a `source <object> <ref>` or `target <object> <ref>` line for each
+/// ref.
+///
+/// ```
+/// # use git_sync::SyncState;
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let dir = std::env::temp_dir().join(format!("git-sync-state-{}", std::process::id()));
+/// let path = SyncState::path_for(&dir, "/srv/repo.git", "/srv/mirror.git", "");
+/// assert_ne!(path, SyncState::path_for(&dir, "/srv/repo.git", "/srv/mirror.git", "--force"));
+/// assert_eq!(SyncState::load(&path).await.unwrap(), None);
+///
+/// let mut state = SyncState::default();
+/// let object = "1111111111111111111111111111111111111111";
+/// state.source.insert("refs/heads/main".into(), object.into());
+/// state.target.insert("refs/heads/main".into(), object.into());
+/// state.save(&path).await.unwrap();
+/// assert_eq!(SyncState::load(&path).await.unwrap(), Some(state));
+///
+/// SyncState::clear(&path).await.unwrap();
+/// assert_eq!(SyncState::load(&path).await.unwrap(), None);
+/// # std::fs::remove_dir(&dir).unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncState {
+    /// The refs the source advertised, by name
+    pub source: BTreeMap<String, String>,
+    /// The target's refs once it was updated, by name
+    pub target: BTreeMap<String, String>,
+}
+
+impl SyncState {
+    /// The file in `dir` for the state of syncs from `source` into `target`.
+    /// `settings` describes whatever else decides which refs such a sync
+    /// updates, so that changing them starts afresh.
+    pub fn path_for(dir: &Path, source: &str, target: &str, settings: &str) -> PathBuf {
+        let mut hash = Sha1::new();
+        for part in [source, target, settings].iter() {
+            hash.update(part.as_bytes());
+            hash.update(b"\0");
+        }
+        let name: String = hash
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        dir.join(format!("{}.state", name))
+    }
+
+    /// Read the state recorded at `path`, if any has been
+    pub async fn load(path: &Path) -> Result<Option<SyncState>, Error> {
+        let cannot = |why: String| {
+            Error::Config(format!(
+                "Cannot read the sync state in {}: {}",
+                path.display(),
+                why
+            ))
+        };
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(cannot(err.to_string())),
+        };
+        let mut lines = contents.lines();
+        if lines.next() != Some(STATE_SIGNATURE) {
+            return Err(cannot("it isn't a state file".to_string()));
+        }
+        let mut state = SyncState::default();
+        for line in lines {
+            let (refs, object, refname) = match line.splitn(3, ' ').collect::<Vec<_>>()[..] {
+                ["source", object, refname] => (&mut state.source, object, refname),
+                ["target", object, refname] => (&mut state.target, object, refname),
+                _ => return Err(cannot(format!("it has a bad line {:?}", line))),
+            };
+            refs.insert(refname.to_string(), object.to_string());
+        }
+        Ok(Some(state))
+    }
+
+    /// Record the state at `path`, replacing what was there once it's whole
+    pub async fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut contents = format!("{}\n", STATE_SIGNATURE);
+        for (side, refs) in [("source", &self.source), ("target", &self.target)].iter() {
+            for (refname, object) in refs.iter() {
+                contents.push_str(&format!("{} {} {}\n", side, object, refname));
+            }
+        }
+        let cannot = |err: io::Error| {
+            Error::Config(format!(
+                "Cannot record the sync state in {}: {}",
+                path.display(),
+                err
+            ))
+        };
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await.map_err(cannot)?;
+        }
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        tokio::fs::write(&partial, contents).await.map_err(cannot)?;
+        tokio::fs::rename(&partial, path).await.map_err(cannot)
+    }
+
+    /// How long ago the state at `path` was recorded, if it was and the
+    /// filesystem can tell
+    pub async fn age(path: &Path) -> Option<Duration> {
+        let modified = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+        SystemTime::now().duration_since(modified).ok()
+    }
+
+    /// Forget the state recorded at `path`, if any was
+    pub async fn clear(path: &Path) -> Result<(), Error> {
+        match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(Error::Config(format!(
+                "Cannot forget the sync state in {}: {}",
+                path.display(),
+                err
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE: &str = "1111111111111111111111111111111111111111";
+    const TWO: &str = "2222222222222222222222222222222222222222";
+
+    /// A directory for a test's state, which it should remove when it's done
+    fn state_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("git-sync-state-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn names_files_stably() {
+        let dir = Path::new("/var/lib/git-sync");
+        // The name mustn't change between releases, or every pair starts afresh
+        assert_eq!(
+            SyncState::path_for(dir, "src", "dst", "--force"),
+            dir.join("91c4d2b2f72d20896ef9f50a8ca237c00f15cb44.state")
+        );
+        // Where one part ends and the next starts counts
+        assert_ne!(
+            SyncState::path_for(dir, "ab", "c", ""),
+            SyncState::path_for(dir, "a", "bc", "")
+        );
+        assert_ne!(
+            SyncState::path_for(dir, "a", "b", ""),
+            SyncState::path_for(dir, "b", "a", "")
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_text_a_line_per_ref() {
+        let dir = state_dir("text");
+        let path = dir.join("nested").join("pair.state");
+        let mut state = SyncState::default();
+        state.source.insert("refs/heads/main".into(), TWO.into());
+        state.source.insert("refs/heads/a".into(), ONE.into());
+        state.target.insert("refs/heads/main".into(), ONE.into());
+        state.save(&path).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!(
+                "{}\nsource {one} refs/heads/a\nsource {two} refs/heads/main\ntarget {one} refs/heads/main\n",
+                STATE_SIGNATURE,
+                one = ONE,
+                two = TWO
+            )
+        );
+        let age = SyncState::age(&path).await.unwrap();
+        assert!(age < Duration::from_secs(60));
+
+        // Saving again replaces the state whole
+        SyncState::default().save(&path).await.unwrap();
+        assert_eq!(
+            SyncState::load(&path).await.unwrap(),
+            Some(SyncState::default())
+        );
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        assert!(!Path::new(&partial).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_what_is_not_a_state_file() {
+        let dir = state_dir("refused");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pair.state");
+        let refusal = |contents: String| {
+            let path = path.clone();
+            async move {
+                std::fs::write(&path, contents).unwrap();
+                let err = SyncState::load(&path).await.unwrap_err().to_string();
+                let prefix = format!("Cannot read the sync state in {}: ", path.display());
+                err.strip_prefix(&prefix).unwrap_or(&err).to_string()
+            }
+        };
+        assert_eq!(
+            refusal("# git-sync state v2\n".to_string()).await,
+            "it isn't a state file"
+        );
+        assert_eq!(
+            refusal(format!("{}\nsource {}\n", STATE_SIGNATURE, ONE)).await,
+            format!("it has a bad line \"source {}\"", ONE)
+        );
+        assert_eq!(
+            refusal(format!(
+                "{}\nboth {} refs/heads/main\n",
+                STATE_SIGNATURE, ONE
+            ))
+            .await,
+            format!("it has a bad line \"both {} refs/heads/main\"", ONE)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn clears_state_whether_or_not_there_is_any() {
+        let dir = state_dir("cleared");
+        let path = dir.join("pair.state");
+        SyncState::clear(&path).await.unwrap();
+        assert_eq!(SyncState::age(&path).await, None);
+        SyncState::default().save(&path).await.unwrap();
+        SyncState::clear(&path).await.unwrap();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+/// Reports of what a sync did, for CI jobs and the like to keep and inspect
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{
+    json_object, Capability, Json, Operator, RefStatus, RefUpdate, SyncEvent, SyncSide, NULLSHA,
+};
+
+/// What became of a ref update
+#[derive(Debug, Clone)]
+enum RefState {
+    /// Planned, but the target hasn't said what it did with it
+    Planned,
+    /// Not pushed, since it wasn't a fast-forward
+    Refused(String),
+    Pushed,
+    Rejected(String),
+}
+
+/// A report of a sync, built from its events as they're received, which can be
+/// written out as JSON once it's over.  It records the time spent connecting,
+/// planning and pushing, the capabilities asked of each side, what became of
+/// each ref, how much pack data was relayed and what went wrong.
+///
+/// ```
+/// # use git_sync::{RefUpdate, SyncEvent, SyncPlan, SyncSummary};
+/// let mut summary = SyncSummary::new("/srv/source.git", "/srv/target.git");
+/// let update = RefUpdate {
+///     refname: "refs/heads/main".to_string(),
+///     oldsha: "0000000000000000000000000000000000000000".to_string(),
+///     newsha: "1111111111111111111111111111111111111111".to_string(),
+/// };
+/// summary.observe(&SyncEvent::PlanComputed(SyncPlan {
+///     updates: vec![update],
+///     refused: vec![],
+/// }));
+/// summary.observe(&SyncEvent::Warning("Something odd".to_string()));
+/// summary.finish(Some("The target went away".to_string()));
+/// let json = summary.to_json().to_string();
+/// assert!(json.contains(r#""success":false"#));
+/// assert!(json.contains(r#""ref":"refs/heads/main","change":"create""#));
+/// assert!(json.contains(r#""status":"planned""#));
+/// assert!(json.contains(r#""warnings":["Something odd"]"#));
+/// assert!(json.contains(r#""error":"The target went away""#));
+/// ```
+#[derive(Debug, Clone)]
+pub struct SyncSummary {
+    source: String,
+    target: String,
+    started: SystemTime,
+    clock: Instant,
+    /// The phase in progress, and when it began
+    phase: Option<(&'static str, Instant)>,
+    phases: Vec<(&'static str, Duration)>,
+    advertised: Vec<(SyncSide, usize)>,
+    capabilities: Vec<(SyncSide, Vec<String>)>,
+    refs: Vec<(RefUpdate, RefState)>,
+    batches: usize,
+    negotiations: usize,
+    /// The pack bytes relayed in batches before the current one
+    pack_bytes: u64,
+    batch_bytes: u64,
+    /// The objects in the packs relayed so far
+    pack_objects: u64,
+    target_created: bool,
+    refs_packed: bool,
+    /// The housekeeping tasks done in the target
+    maintained: Vec<&'static str>,
+    head_set: Option<(String, String)>,
+    warnings: Vec<String>,
+    remote_errors: Vec<(SyncSide, String)>,
+    /// Why each failed attempt before the last failed, and how long was waited
+    /// before trying again
+    retries: Vec<(String, Duration)>,
+    duration: Option<Duration>,
+    error: Option<String>,
+}
+
+impl SyncSummary {
+    /// Start a report of a sync from `source` into `target`, which is just
+    /// starting to connect to them
+    pub fn new(source: &str, target: &str) -> SyncSummary {
+        let clock = Instant::now();
+        SyncSummary {
+            source: source.to_string(),
+            target: target.to_string(),
+            started: SystemTime::now(),
+            clock,
+            phase: Some(("connect", clock)),
+            phases: Vec::new(),
+            advertised: Vec::new(),
+            capabilities: Vec::new(),
+            refs: Vec::new(),
+            batches: 0,
+            negotiations: 0,
+            pack_bytes: 0,
+            batch_bytes: 0,
+            pack_objects: 0,
+            target_created: false,
+            refs_packed: false,
+            maintained: Vec::new(),
+            head_set: None,
+            warnings: Vec::new(),
+            remote_errors: Vec::new(),
+            retries: Vec::new(),
+            duration: None,
+            error: None,
+        }
+    }
+
+    /// Record what an event says about the sync
+    pub fn observe(&mut self, event: &SyncEvent) {
+        match event {
+            SyncEvent::AdvertisementRead(side, advert) => {
+                self.advertised.push((*side, advert.refs().len()));
+                if *side == SyncSide::Target {
+                    self.begin(Some("plan"));
+                }
+            }
+            SyncEvent::CapabilitiesRequested(side, caps) => {
+                if *side == SyncSide::Source {
+                    self.negotiations += 1;
+                }
+                let caps = caps
+                    .iter()
+                    .map(|(cap, value)| cap_string(*cap, value.as_deref()))
+                    .collect();
+                // Each batch asks for the same, so only the latest is kept
+                self.capabilities.retain(|(seen, _)| seen != side);
+                self.capabilities.push((*side, caps));
+            }
+            SyncEvent::Refused(update, reason) => self
+                .refs
+                .push((update.clone(), RefState::Refused(reason.clone()))),
+            SyncEvent::PlanComputed(plan) => {
+                self.refs.extend(
+                    plan.updates
+                        .iter()
+                        .map(|update| (update.clone(), RefState::Planned)),
+                );
+                self.begin(None);
+            }
+            SyncEvent::BatchStarted { batch, .. } => {
+                self.batches = *batch;
+                self.pack_bytes += self.batch_bytes;
+                self.batch_bytes = 0;
+                if self.phase.is_none() {
+                    self.begin(Some("push"));
+                }
+            }
+            SyncEvent::PackBytes(bytes) => self.batch_bytes = *bytes,
+            SyncEvent::PackObjects(objects) => self.pack_objects += objects,
+            SyncEvent::RemoteError(side, message) => self
+                .remote_errors
+                .push((*side, message.trim_end().to_string())),
+            SyncEvent::Warning(message) => self.warnings.push(message.clone()),
+            SyncEvent::TargetCreated { .. } => self.target_created = true,
+            SyncEvent::RefsPacked => self.refs_packed = true,
+            SyncEvent::Maintained(task) => self.maintained.push(task.as_str()),
+            SyncEvent::HeadSet(symref, head) => {
+                self.head_set = Some((symref.clone(), head.clone()))
+            }
+            SyncEvent::RefResult(outcome) => {
+                let state = match &outcome.status {
+                    RefStatus::Ok => RefState::Pushed,
+                    RefStatus::Rejected(reason) => RefState::Rejected(reason.clone()),
+                };
+                match self
+                    .refs
+                    .iter_mut()
+                    .find(|(update, _)| update.refname == outcome.update.refname)
+                {
+                    Some((_, seen)) => *seen = state,
+                    None => self.refs.push((outcome.update.clone(), state)),
+                }
+            }
+            SyncEvent::Retrying { delay, error, .. } => {
+                // What the failed attempt did is done again, as far as it matters
+                self.retries.push((error.clone(), *delay));
+                self.advertised.clear();
+                self.refs.clear();
+                self.batches = 0;
+                self.batch_bytes = 0;
+                self.pack_objects = 0;
+                self.begin(Some("connect"));
+            }
+            SyncEvent::Completed(outcome) => {
+                self.pack_bytes = outcome.pack_bytes;
+                self.batch_bytes = 0;
+                self.pack_objects = outcome.pack_objects;
+                self.negotiations = outcome.negotiations;
+                self.begin(None);
+            }
+            SyncEvent::RemoteProgress(..)
+            | SyncEvent::Progress(..)
+            | SyncEvent::ServiceStderr(..)
+            | SyncEvent::Cancelled => {}
+        }
+    }
+
+    /// Record that the sync is over, and why it failed if it did
+    pub fn finish(&mut self, error: Option<String>) {
+        self.begin(None);
+        self.duration = Some(self.clock.elapsed());
+        self.error = error;
+    }
+
+    /// Whether the sync is over, whether or not it failed
+    pub fn is_finished(&self) -> bool {
+        self.duration.is_some()
+    }
+
+    /// Whether the sync finished without an error
+    pub fn is_success(&self) -> bool {
+        self.duration.is_some() && self.error.is_none()
+    }
+
+    /// End the phase in progress, and begin another if given.  Phases which are
+    /// gone through again, when the sync is retried, add up.
+    fn begin(&mut self, phase: Option<&'static str>) {
+        let now = Instant::now();
+        if let Some((name, began)) = self.phase.take() {
+            match self.phases.iter_mut().find(|(seen, _)| *seen == name) {
+                Some((_, spent)) => *spent += now - began,
+                None => self.phases.push((name, now - began)),
+            }
+        }
+        self.phase = phase.map(|name| (name, now));
+    }
+
+    /// Each ref the sync planned to update or refused to, with what became of it
+    fn refs_json(&self) -> Json {
+        let refs = self.refs.iter().map(|(update, state)| {
+            let sha = |sha: &str| Json::from(Some(sha).filter(|sha| *sha != NULLSHA));
+            let (status, reason) = match state {
+                RefState::Planned => ("planned", None),
+                RefState::Refused(reason) => ("refused", Some(reason.as_str())),
+                RefState::Pushed => ("pushed", None),
+                RefState::Rejected(reason) => ("rejected", Some(reason.as_str())),
+            };
+            json_object(vec![
+                ("ref", Json::from(update.refname.as_str())),
+                ("change", Json::from(update.kind().as_str())),
+                ("old", sha(&update.oldsha)),
+                ("new", sha(&update.newsha)),
+                ("status", Json::from(status)),
+                ("reason", Json::from(reason)),
+            ])
+        });
+        Json::Array(refs.collect())
+    }
+
+    /// When the sync started, as seconds since the epoch
+    fn started_json(&self) -> Json {
+        Json::from(
+            self.started
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .ok(),
+        )
+    }
+
+    /// An entry for an [`AuditLog`](super::AuditLog): when the sync started and
+    /// by whom, between which repositories, every ref it planned to update with
+    /// what became of it, and whether it succeeded
+    ///
+    /// ```
+    /// # use git_sync::{Operator, SyncSummary};
+    /// let mut summary = SyncSummary::new("/srv/source.git", "/srv/target.git");
+    /// summary.finish(None);
+    /// let entry = summary.to_audit_json(&Operator::default()).to_string();
+    /// assert!(entry.contains(r#""source":"/srv/source.git","target":"/srv/target.git""#));
+    /// assert!(entry.contains(r#""operator":{"user":null,"host":null,"ident":null}"#));
+    /// assert!(entry.contains(r#""refs":[],"success":true,"error":null"#));
+    /// ```
+    pub fn to_audit_json(&self, operator: &Operator) -> Json {
+        json_object(vec![
+            ("started", self.started_json()),
+            ("operator", operator.to_json()),
+            ("source", Json::from(self.source.as_str())),
+            ("target", Json::from(self.target.as_str())),
+            ("refs", self.refs_json()),
+            ("success", Json::from(self.is_success())),
+            ("error", Json::from(self.error.clone())),
+        ])
+    }
+
+    /// The report as a JSON object
+    pub fn to_json(&self) -> Json {
+        let side = |side: SyncSide| Json::from(side.to_string());
+        json_object(vec![
+            ("source", Json::from(self.source.as_str())),
+            ("target", Json::from(self.target.as_str())),
+            ("success", Json::from(self.is_success())),
+            ("started", self.started_json()),
+            (
+                "duration",
+                Json::from(self.duration.map(|duration| duration.as_secs_f64())),
+            ),
+            (
+                "phases",
+                json_object(
+                    self.phases
+                        .iter()
+                        .map(|(name, duration)| (*name, Json::from(duration.as_secs_f64())))
+                        .collect(),
+                ),
+            ),
+            (
+                "advertised_refs",
+                json_object(
+                    self.advertised
+                        .iter()
+                        .map(|(seen, count)| (seen.to_string(), Json::from(*count)))
+                        .collect(),
+                ),
+            ),
+            (
+                "capabilities",
+                json_object(
+                    self.capabilities
+                        .iter()
+                        .map(|(seen, caps)| {
+                            let caps = caps.iter().map(|cap| Json::from(cap.as_str()));
+                            (seen.to_string(), Json::Array(caps.collect()))
+                        })
+                        .collect(),
+                ),
+            ),
+            ("refs", self.refs_json()),
+            ("batches", Json::from(self.batches)),
+            ("negotiations", Json::from(self.negotiations)),
+            ("pack_bytes", Json::from(self.pack_bytes + self.batch_bytes)),
+            ("pack_objects", Json::from(self.pack_objects)),
+            ("target_created", Json::from(self.target_created)),
+            ("refs_packed", Json::from(self.refs_packed)),
+            (
+                "maintained",
+                Json::Array(
+                    self.maintained
+                        .iter()
+                        .map(|task| Json::from(*task))
+                        .collect(),
+                ),
+            ),
+            (
+                "head_set",
+                match &self.head_set {
+                    Some((symref, head)) => json_object(vec![
+                        ("symref", Json::from(symref.as_str())),
+                        ("target", Json::from(head.as_str())),
+                    ]),
+                    None => Json::Null,
+                },
+            ),
+            (
+                "warnings",
+                Json::Array(
+                    self.warnings
+                        .iter()
+                        .map(|warning| Json::from(warning.as_str()))
+                        .collect(),
+                ),
+            ),
+            (
+                "remote_errors",
+                Json::Array(
+                    self.remote_errors
+                        .iter()
+                        .map(|(seen, message)| {
+                            json_object(vec![
+                                ("side", side(*seen)),
+                                ("message", Json::from(message.as_str())),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "retries",
+                Json::Array(
+                    self.retries
+                        .iter()
+                        .map(|(error, delay)| {
+                            json_object(vec![
+                                ("error", Json::from(error.as_str())),
+                                ("delay", Json::from(delay.as_secs_f64())),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            ("error", Json::from(self.error.clone())),
+        ])
+    }
+}
+
+/// A capability as it's written in the protocol, with its value if it has one
+pub fn cap_string(cap: Capability, value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("{}={}", cap.as_str(), value),
+        None => cap.as_str().to_string(),
+    }
+}
@@ -0,0 +1,4227 @@
+/// Syncing the refs of one repository into another
+///
+/// A [`Syncer`] knows the source and target repositories and how the sync should
+/// behave.  Connecting it gives a [`SyncSession`], from which the ref updates can
+/// be planned and then pushed; [`Syncer::run`] does all of that in one go.
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, IoSlice};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, timeout_at, Instant};
+use tracing::Instrument;
+
+use super::{
+    committer_ident, compute_ref_updates, format_bytes, quote_remote_path, request_pack,
+    send_push_cert, send_ref_updates, Bandwidth, CancellationToken, Capability, Codec,
+    ConnectOptions, DeleteLimit, Error, ExitReason, ExtCommand, Hook, NotifyOn, ObjectFormat,
+    PackChecksum, PackHeader, PackHeaderScanner, PackSaver, PackSpool, Packet, PacketReader,
+    PlanOptions, ProgressUpdate, ProtocolLine, PushCert, RefAdvertisement, RefDiff, RefOutcome,
+    RefPattern, RefStatus, RefUpdate, Refspec, RemoteUrl, ReportParser, ReportStatus, RevGraph,
+    SavedPack, SendActivity, Signer, SpooledPack, SyncEvent, SyncEventReceiver, SyncEventSender,
+    SyncMode, SyncReport, SyncSide, SyncState, Throttle, Timeout, Transport, EMPTY_PACK, NULLSHA,
+};
+
+/// A repository we sync with, and how we reach it
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    url: RemoteUrl,
+    connect_opts: ConnectOptions,
+}
+
+impl Endpoint {
+    /// Check that the repository at `url` can be reached with the given options
+    pub fn new(url: RemoteUrl, connect_opts: ConnectOptions) -> Result<Endpoint, Error> {
+        match &url {
+            RemoteUrl::Http(_) => {
+                return Err(Error::Config(format!(
+                    "Cannot reach {}, git-sync does not support HTTP remotes",
+                    url
+                )))
+            }
+            #[cfg(not(feature = "websocket"))]
+            RemoteUrl::WebSocket(_) => {
+                return Err(Error::Config(format!(
+                    "Cannot reach {}, git-sync was built without WebSocket support",
+                    url
+                )))
+            }
+            #[cfg(not(feature = "quic"))]
+            RemoteUrl::Quic { .. } => {
+                return Err(Error::Config(format!(
+                    "Cannot reach {}, git-sync was built without QUIC support",
+                    url
+                )))
+            }
+            RemoteUrl::Ext(spec) => {
+                // Check the command parses now, rather than when we come to connect
+                ExtCommand::parse(spec, "git-upload-pack").map_err(Error::Config)?;
+            }
+            RemoteUrl::Ssh { .. } => connect_opts.ssh.validate().map_err(Error::Config)?,
+            _ => {}
+        }
+        Ok(Endpoint { url, connect_opts })
+    }
+
+    /// Where the repository lives
+    pub fn url(&self) -> &RemoteUrl {
+        &self.url
+    }
+
+    /// Start the given service (e.g. `git-upload-pack`) for this repository
+    pub async fn connect(&self, service: &str) -> Result<Box<dyn Transport>, Error> {
+        self.url.connect(service, &self.connect_opts).await
+    }
+
+    /// Read the refs and capabilities which the repository's upload-pack advertises,
+    /// without fetching anything
+    pub async fn advertisement(&self) -> Result<RefAdvertisement, Error> {
+        let mut transport = self.connect("git-upload-pack").await?;
+        match RefAdvertisement::read_from(transport.reader()).await {
+            Ok(advert) => {
+                // An empty request tells upload-pack that we want nothing after all
+                ProtocolLine::Flush.write_to(transport.writer()).await?;
+                transport.shutdown().await?;
+                Ok(advert)
+            }
+            Err(err) => match transport.shutdown().await {
+                Err(failed @ Error::ChildFailed { .. }) => Err(failed),
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Whether we're able to run arbitrary git commands in this repository
+    pub fn can_run_commands(&self) -> bool {
+        matches!(self.url, RemoteUrl::Local(_) | RemoteUrl::Ssh { .. })
+    }
+
+    /// Prepare a command to run on the machine the repository is on, either
+    /// locally or via SSH, with the repository's path as it should be given to
+    /// the command there
+    fn host_command(&self, program: &str) -> Result<(Command, OsString), Error> {
+        let (mut cmd, path) = match &self.url {
+            RemoteUrl::Local(path) => (Command::new(program), path.clone().into_os_string()),
+            RemoteUrl::Ssh { path, .. } => {
+                let mut cmd = self
+                    .url
+                    .ssh_command(&self.connect_opts.ssh)
+                    .expect("SSH URL without SSH command?");
+                cmd.arg(program);
+                (cmd, quote_remote_path(path).into())
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "Unable to run git commands in {}",
+                    self
+                )))
+            }
+        };
+        // Should the sync be abandoned, so is the command
+        cmd.kill_on_drop(true).stdin(Stdio::null());
+        Ok((cmd, path))
+    }
+
+    /// Prepare a git command to run in the repository, either locally or via SSH
+    pub fn git_command(&self) -> Result<Command, Error> {
+        let (mut cmd, path) = self.host_command("git")?;
+        cmd.arg("-C").arg(path);
+        Ok(cmd)
+    }
+
+    /// Create the repository, as a bare one using the given object format (e.g.
+    /// `sha256`) if there's nothing at its path yet, returning whether it was
+    /// created
+    pub async fn create(&self, object_format: Option<&str>) -> Result<bool, Error> {
+        let (mut test, path) = self.host_command("test")?;
+        let exists = test
+            .arg("-e")
+            .arg(&path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await?;
+        if exists.success() {
+            return Ok(false);
+        }
+        let (mut init, path) = self.host_command("git")?;
+        init.args(["init", "--bare", "--quiet"]);
+        // Leave the default alone for the sake of versions of git which don't
+        // know about object formats
+        if let Some(format) = object_format.filter(|format| *format != "sha1") {
+            init.arg(format!("--object-format={}", format));
+        }
+        let output = init
+            .arg(&path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(Error::ChildFailed {
+                command: format!("Creating {}", self),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        Ok(true)
+    }
+
+    /// Run a one-shot git command in the repository, failing with whatever it wrote
+    /// to stderr if it's unsuccessful
+    pub async fn run_git(&self, args: &[&str]) -> Result<(), Error> {
+        let output = self
+            .git_command()?
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(Error::ChildFailed {
+                command: format!("git {}", args.join(" ")),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            })
+        }
+    }
+
+    /// Run a one-shot git command in the repository, giving it `input`, and
+    /// returning what it wrote to stdout, failing with whatever it wrote to stderr
+    /// if it's unsuccessful
+    async fn git_output(&self, args: &[&str], input: String) -> Result<String, Error> {
+        let mut child = self
+            .git_command()?
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("git without stdin?");
+        // The output is read while the input is written, so that neither fills up
+        let write = async move { stdin.write_all(input.as_bytes()).await };
+        let (written, output) = tokio::join!(write, child.wait_with_output());
+        let output = output?;
+        if !output.status.success() {
+            return Err(Error::ChildFailed {
+                command: format!("git {}", args.join(" ")),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        written?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Determine whether `old` is an ancestor of `new` in the repository.  If the
+    /// repository doesn't know `old` then it cannot be shown to be an ancestor.
+    pub async fn is_ancestor(&self, old: &str, new: &str) -> Result<bool, Error> {
+        Ok(self.ancestors(&[(old, new)]).await?[0])
+    }
+
+    /// Determine, for each `(old, new)` pair, whether `old` is an ancestor of
+    /// `new` in the repository, running as few git commands as need be however
+    /// many pairs there are.  An `old`, or a `new`, which isn't a commit the
+    /// repository has cannot be shown to be an ancestor; any other failure of
+    /// git, such as the repository being unreachable, is an error.
+    pub async fn ancestors(&self, pairs: &[(&str, &str)]) -> Result<Vec<bool>, Error> {
+        if pairs.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Tags are peeled to the commits they name, and what isn't there is left out
+        let input: String = pairs
+            .iter()
+            .flat_map(|(old, new)| [old, new])
+            .map(|name| format!("{}^{{commit}}\n", name))
+            .collect();
+        let found = self
+            .git_output(&["cat-file", "--batch-check"], input)
+            .await?;
+        let commits: Vec<Option<&str>> = found
+            .lines()
+            .map(|line| match line.split(' ').collect::<Vec<_>>()[..] {
+                [commit, "commit", _] => Some(commit),
+                _ => None,
+            })
+            .collect();
+        if commits.len() != pairs.len() * 2 {
+            return Err(Error::Protocol(format!(
+                "git cat-file described {} objects in {} when asked about {}",
+                commits.len(),
+                self,
+                pairs.len() * 2
+            )));
+        }
+        let commits: Vec<Option<(&str, &str)>> = commits
+            .chunks(2)
+            .map(|pair| Some((pair[0]?, pair[1]?)))
+            .collect();
+
+        let input: String = commits
+            .iter()
+            .flatten()
+            .map(|(old, new)| format!("{}\n^{}\n", new, old))
+            .collect();
+        let graph = match input.is_empty() {
+            true => RevGraph::default(),
+            false => RevGraph::parse(
+                &self
+                    .git_output(&["rev-list", "--parents", "--stdin"], input)
+                    .await?,
+            ),
+        };
+        let mut ancestors = Vec::with_capacity(pairs.len());
+        for pair in commits {
+            ancestors.push(match pair {
+                None => false,
+                Some((old, new)) => match graph.descends(old, new) {
+                    Some(descends) => descends,
+                    None => self.merge_base_is_ancestor(old, new).await?,
+                },
+            });
+        }
+        Ok(ancestors)
+    }
+
+    /// Ask git whether the commit `old` is an ancestor of the commit `new`
+    async fn merge_base_is_ancestor(&self, old: &str, new: &str) -> Result<bool, Error> {
+        let output = self
+            .git_command()?
+            .args(["merge-base", "--is-ancestor", old, new])
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+        match output.status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(Error::ChildFailed {
+                command: format!("git merge-base --is-ancestor {} {}", old, new),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.url.fmt(f)
+    }
+}
+
+/// How we introduce ourselves to the services we talk to, with the `agent`
+/// capability, unless told otherwise
+pub const DEFAULT_AGENT: &str = concat!("git_sync/", env!("CARGO_PKG_VERSION"));
+
+/// How long a recorded [`SyncState`] is gone on for by default, so that a
+/// target something else changed is put right within a day
+pub const DEFAULT_STATE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many refs a sync must update before the target's refs are packed by
+/// default, so that a sync updating a ref or two doesn't rewrite them all
+pub const DEFAULT_PACK_REFS_THRESHOLD: usize = 100;
+
+/// When to ask the target to apply all of a push's ref updates atomically
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtomicMode {
+    /// Never ask for an atomic push
+    Never,
+    /// Ask for an atomic push if the target supports them
+    IfSupported,
+    /// Fail unless the target supports atomic pushes
+    Required,
+}
+
+/// Housekeeping to do in the target after a successful sync, since a repository
+/// fed only by receive-pack is never otherwise tidied
+///
+/// ```
+/// # use git_sync::Maintenance;
+/// let task: Maintenance = "update-server-info".parse().unwrap();
+/// assert_eq!(task.args(), ["update-server-info"]);
+/// assert_eq!(Maintenance::Maintenance.to_string(), "maintenance");
+/// assert!("fsck".parse::<Maintenance>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Maintenance {
+    /// Update the files which let the target be served over dumb HTTP
+    UpdateServerInfo,
+    /// Run `git gc --auto`, to repack once enough loose packs and objects build up
+    Gc,
+    /// Run `git maintenance run --auto`, as newer versions of git prefer
+    Maintenance,
+}
+
+impl Maintenance {
+    /// The name of the task, as it's given on the command line
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Maintenance::UpdateServerInfo => "update-server-info",
+            Maintenance::Gc => "gc",
+            Maintenance::Maintenance => "maintenance",
+        }
+    }
+
+    /// The arguments to git which do the task
+    pub fn args(self) -> &'static [&'static str] {
+        match self {
+            Maintenance::UpdateServerInfo => &["update-server-info"],
+            Maintenance::Gc => &["gc", "--auto", "--quiet"],
+            Maintenance::Maintenance => &["maintenance", "run", "--auto", "--quiet"],
+        }
+    }
+}
+
+impl FromStr for Maintenance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "update-server-info" => Ok(Maintenance::UpdateServerInfo),
+            "gc" => Ok(Maintenance::Gc),
+            "maintenance" => Ok(Maintenance::Maintenance),
+            _ => Err(format!(
+                "Expected update-server-info, gc or maintenance, not {}",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Maintenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The options which decide which refs a sync updates, one per line as `<name>
+/// <value>`, so that syncs which would update different refs don't share a
+/// recorded state.  This is what the state file is named after, so it must only
+/// change when what the options mean does.
+fn state_settings(opts: &SyncOptions) -> String {
+    let plan = &opts.plan;
+    let mode = match plan.mode {
+        SyncMode::Mirror => "mirror",
+        SyncMode::NoDelete => "no-delete",
+    };
+    let mut lines = vec![
+        format!("mode {}", mode),
+        format!("prune-tags {}", plan.prune_tags),
+        format!("force {}", opts.force),
+        format!("prune-only {}", opts.prune_only),
+    ];
+    let patterns = [
+        ("protect", &plan.protect),
+        ("include", &plan.include),
+        ("exclude", &plan.exclude),
+        ("force-ref", &opts.force_refs),
+    ];
+    for (name, list) in patterns.iter() {
+        lines.extend(list.iter().map(|pattern| format!("{} {}", name, pattern)));
+    }
+    lines.extend(
+        plan.refspecs
+            .iter()
+            .map(|refspec| format!("refspec {}", refspec)),
+    );
+    if let Some(prefix) = &plan.strip_source_prefix {
+        lines.push(format!("strip-source-prefix {}", prefix));
+    }
+    if let Some(prefix) = &plan.dest_prefix {
+        lines.push(format!("dest-prefix {}", prefix));
+    }
+    lines.join("\n")
+}
+
+/// How a sync should behave, beyond which refs it should sync
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// Which refs to sync, and how
+    pub plan: PlanOptions,
+    /// Permit non-fast-forward updates of any ref which isn't protected
+    pub force: bool,
+    /// Permit non-fast-forward updates of refs matching these patterns
+    pub force_refs: Vec<RefPattern>,
+    /// The most refs which may be deleted from the target
+    pub max_delete: DeleteLimit,
+    /// Delete refs even if that exceeds `max_delete`
+    pub ignore_max_delete: bool,
+    /// Only delete refs which the source lacks, creating and updating nothing, so
+    /// that no pack is sent
+    pub prune_only: bool,
+    /// The minimum number of objects expected in a pack which is needed to create
+    /// or update refs
+    pub min_objects: u32,
+    /// Fail, rather than warning, if a pack has fewer objects than expected
+    pub strict_object_check: bool,
+    /// The most bytes a pack may have.  A pack which has more is refused before
+    /// the target is given the whole of it.
+    pub max_pack_size: Option<u64>,
+    /// The most objects a pack may have, going by its header
+    pub max_objects: Option<u32>,
+    /// Sign the push with a push certificate
+    pub sign_with: Option<Signer>,
+    /// Whether to push atomically
+    pub atomic: AtomicMode,
+    /// Ask the target's receive-pack to suppress its progress output
+    pub quiet_remote: bool,
+    /// Set the target's HEAD to match the source's default branch
+    pub set_head: bool,
+    /// Push at most this many ref updates per receive-pack session
+    pub batch_size: Option<usize>,
+    /// Pack the target's refs after updating them
+    pub pack_refs: bool,
+    /// Only pack the target's refs if at least this many were updated
+    pub pack_refs_threshold: usize,
+    /// Create the target as a bare repository if it doesn't exist, matching the
+    /// source's object format and default branch
+    pub create_target: bool,
+    /// Housekeeping to do in the target, in order, after a successful sync
+    pub maintenance: Vec<Maintenance>,
+    /// Ask the source for a thin pack, with deltas against objects the target has
+    pub thin_pack: bool,
+    /// Ask the source for a pack which may use offset deltas
+    pub ofs_delta: bool,
+    /// How long to wait for each service to start and advertise its refs
+    pub connect_timeout: Option<Duration>,
+    /// How long to wait, once a pack has been asked of the source, for it to
+    /// start sending the pack
+    pub negotiation_timeout: Option<Duration>,
+    /// How long pack data may stop coming from the source, or going into the
+    /// target, while it's being relayed
+    pub idle_timeout: Option<Duration>,
+    /// How long the whole sync may take, including any retries
+    pub timeout: Option<Duration>,
+    /// How many times to try the sync again if it fails in a way which may not
+    /// happen again, before any ref updates were sent
+    pub retries: usize,
+    /// How fast pack data may be relayed from the source to the target
+    pub max_bandwidth: Option<Bandwidth>,
+    /// Fetch each pack whole into a file in this directory, checking it's intact,
+    /// before pushing it into the target, rather than relaying it as it comes
+    pub spool: Option<PathBuf>,
+    /// How to compress the packs spooled
+    pub spool_codec: Codec,
+    /// Save a copy of each pack pushed into the target in this file, with the
+    /// ref updates it was pushed for
+    pub save_pack: Option<PathBuf>,
+    /// Push the pack saved in this file, for the ref updates saved with it,
+    /// rather than fetching one from the source
+    pub from_pack: Option<PathBuf>,
+    /// Record the state each sync leaves the source and target in under this
+    /// directory, for the next sync of the pair to go on
+    pub state_dir: Option<PathBuf>,
+    /// How long a recorded state is gone on for.  Once it's older, the next sync
+    /// connects to the target and compares its refs afresh, so that whatever
+    /// else changed them is put right.
+    pub state_max_age: Duration,
+    /// Relay the progress messages the source and target send.  Without this,
+    /// the source is asked not to send any, and the target to keep quiet, if
+    /// they support it.
+    pub remote_progress: bool,
+    /// What to give as the `agent` capability to the source and target
+    pub agent: String,
+    /// Run before pushing, once the sync is planned, and able to stop the sync
+    /// by failing
+    pub pre_sync: Option<Hook>,
+    /// Run once the sync is over, whether it succeeded or not
+    pub post_sync: Option<Hook>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            plan: PlanOptions::default(),
+            force: false,
+            force_refs: Vec::new(),
+            max_delete: DeleteLimit::Percent(50),
+            ignore_max_delete: false,
+            prune_only: false,
+            min_objects: 1,
+            strict_object_check: false,
+            max_pack_size: None,
+            max_objects: None,
+            sign_with: None,
+            atomic: AtomicMode::IfSupported,
+            quiet_remote: false,
+            set_head: false,
+            batch_size: None,
+            pack_refs: false,
+            pack_refs_threshold: DEFAULT_PACK_REFS_THRESHOLD,
+            create_target: false,
+            maintenance: Vec::new(),
+            thin_pack: true,
+            ofs_delta: true,
+            connect_timeout: None,
+            negotiation_timeout: None,
+            idle_timeout: None,
+            timeout: None,
+            retries: 0,
+            max_bandwidth: None,
+            spool: None,
+            spool_codec: Codec::None,
+            save_pack: None,
+            from_pack: None,
+            state_dir: None,
+            state_max_age: DEFAULT_STATE_MAX_AGE,
+            remote_progress: true,
+            agent: DEFAULT_AGENT.to_string(),
+            pre_sync: None,
+            post_sync: None,
+        }
+    }
+}
+
+impl SyncOptions {
+    /// Start building options, beginning from the defaults
+    pub fn builder() -> SyncOptionsBuilder {
+        SyncOptionsBuilder::default()
+    }
+}
+
+/// Builds [`SyncOptions`], checking they make sense together
+///
+/// ```
+/// # use git_sync::{AtomicMode, SyncMode, SyncOptions};
+/// # use std::time::Duration;
+/// let opts = SyncOptions::builder()
+///     .mode(SyncMode::NoDelete)
+///     .include("refs/heads/*".parse().unwrap())
+///     .atomic(AtomicMode::Required)
+///     .connect_timeout(Duration::from_secs(30))
+///     .build()
+///     .unwrap();
+/// // Tags are only pruned by default when mirroring
+/// assert!(!opts.plan.prune_tags);
+/// assert!(SyncOptions::builder().batch_size(0).build().is_err());
+/// assert!(SyncOptions::builder().dest_prefix("mirror/").build().is_err());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptionsBuilder {
+    options: SyncOptions,
+    prune_tags: Option<bool>,
+}
+
+impl SyncOptionsBuilder {
+    /// Whether refs the source lacks are deleted from the target
+    pub fn mode(mut self, mode: SyncMode) -> Self {
+        self.options.plan.mode = mode;
+        self
+    }
+
+    /// Whether tags the source lacks are deleted from the target.  By default
+    /// they are when mirroring.
+    pub fn prune_tags(mut self, prune: bool) -> Self {
+        self.prune_tags = Some(prune);
+        self
+    }
+
+    /// Never delete or force-update refs matching this pattern
+    pub fn protect(mut self, pattern: RefPattern) -> Self {
+        self.options.plan.protect.push(pattern);
+        self
+    }
+
+    /// Sync source refs matching this pattern; if no patterns are included then all
+    /// refs are
+    pub fn include(mut self, pattern: RefPattern) -> Self {
+        self.options.plan.include.push(pattern);
+        self
+    }
+
+    /// Never sync source refs matching this pattern
+    pub fn exclude(mut self, pattern: RefPattern) -> Self {
+        self.options.plan.exclude.push(pattern);
+        self
+    }
+
+    /// Select and rename refs with this refspec
+    pub fn refspec(mut self, refspec: Refspec) -> Self {
+        self.options.plan.refspecs.push(refspec);
+        self
+    }
+
+    /// Only sync source refs under this prefix, removing it from their names
+    pub fn strip_source_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.options.plan.strip_source_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Place synced refs under this prefix in the target
+    pub fn dest_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.options.plan.dest_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Permit non-fast-forward updates of any ref which isn't protected
+    pub fn force(mut self, force: bool) -> Self {
+        self.options.force = force;
+        self
+    }
+
+    /// Permit non-fast-forward updates of refs matching this pattern
+    pub fn force_ref(mut self, pattern: RefPattern) -> Self {
+        self.options.force_refs.push(pattern);
+        self
+    }
+
+    /// The most refs which may be deleted from the target
+    pub fn max_delete(mut self, limit: DeleteLimit) -> Self {
+        self.options.max_delete = limit;
+        self
+    }
+
+    /// Delete refs even if that exceeds the limit
+    pub fn ignore_max_delete(mut self, ignore: bool) -> Self {
+        self.options.ignore_max_delete = ignore;
+        self
+    }
+
+    /// Only delete refs which the source lacks
+    pub fn prune_only(mut self, prune_only: bool) -> Self {
+        self.options.prune_only = prune_only;
+        self
+    }
+
+    /// The minimum number of objects expected in a pack which is needed
+    pub fn min_objects(mut self, min: u32) -> Self {
+        self.options.min_objects = min;
+        self
+    }
+
+    /// Fail, rather than warning, if a pack has fewer objects than expected
+    pub fn strict_object_check(mut self, strict: bool) -> Self {
+        self.options.strict_object_check = strict;
+        self
+    }
+
+    /// Refuse a pack with more than this many bytes
+    pub fn max_pack_size(mut self, bytes: u64) -> Self {
+        self.options.max_pack_size = Some(bytes);
+        self
+    }
+
+    /// Refuse a pack with more than this many objects
+    pub fn max_objects(mut self, max: u32) -> Self {
+        self.options.max_objects = Some(max);
+        self
+    }
+
+    /// Sign the push with a push certificate
+    pub fn sign_with(mut self, signer: Signer) -> Self {
+        self.options.sign_with = Some(signer);
+        self
+    }
+
+    /// Whether to push atomically
+    pub fn atomic(mut self, atomic: AtomicMode) -> Self {
+        self.options.atomic = atomic;
+        self
+    }
+
+    /// Ask the target's receive-pack to suppress its progress output
+    pub fn quiet_remote(mut self, quiet: bool) -> Self {
+        self.options.quiet_remote = quiet;
+        self
+    }
+
+    /// Set the target's HEAD to match the source's default branch
+    pub fn set_head(mut self, set_head: bool) -> Self {
+        self.options.set_head = set_head;
+        self
+    }
+
+    /// Push at most this many ref updates per receive-pack session
+    pub fn batch_size(mut self, size: usize) -> Self {
+        self.options.batch_size = Some(size);
+        self
+    }
+
+    /// Pack the target's refs after updating them
+    pub fn pack_refs(mut self, pack_refs: bool) -> Self {
+        self.options.pack_refs = pack_refs;
+        self
+    }
+
+    /// Only pack the target's refs once a sync has updated at least this many of
+    /// them, rather than [`DEFAULT_PACK_REFS_THRESHOLD`]
+    pub fn pack_refs_threshold(mut self, threshold: usize) -> Self {
+        self.options.pack_refs_threshold = threshold;
+        self
+    }
+
+    /// Whether to create the target if it doesn't exist
+    pub fn create_target(mut self, create: bool) -> Self {
+        self.options.create_target = create;
+        self
+    }
+
+    /// Do some housekeeping in the target after each successful sync
+    pub fn maintenance(mut self, task: Maintenance) -> Self {
+        self.options.maintenance.push(task);
+        self
+    }
+
+    /// Whether to ask the source for a thin pack
+    pub fn thin_pack(mut self, thin: bool) -> Self {
+        self.options.thin_pack = thin;
+        self
+    }
+
+    /// Whether to ask the source for a pack which may use offset deltas
+    pub fn ofs_delta(mut self, ofs_delta: bool) -> Self {
+        self.options.ofs_delta = ofs_delta;
+        self
+    }
+
+    /// How long to wait for each service to start and advertise its refs
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// How long to wait for the source to start sending a pack once asked
+    pub fn negotiation_timeout(mut self, timeout: Duration) -> Self {
+        self.options.negotiation_timeout = Some(timeout);
+        self
+    }
+
+    /// How long pack data may stop flowing while it's being relayed
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.options.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// How long the whole sync may take
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// How many times to try the sync again after a transient failure, waiting
+    /// longer each time
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.options.retries = retries;
+        self
+    }
+
+    /// How fast pack data may be relayed
+    pub fn max_bandwidth(mut self, rate: Bandwidth) -> Self {
+        self.options.max_bandwidth = Some(rate);
+        self
+    }
+
+    /// Spool each pack in a file in `dir` before pushing it.  A push which fails
+    /// before the target has the whole pack may be tried again from the spool,
+    /// without fetching the pack again.
+    pub fn spool(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.spool = Some(dir.into());
+        self
+    }
+
+    /// Compress the packs spooled with `codec`, trading time for space on disk
+    pub fn spool_codec(mut self, codec: Codec) -> Self {
+        self.options.spool_codec = codec;
+        self
+    }
+
+    /// Save a copy of the pack pushed into the target at `path`, as a
+    /// [`PackSaver`] writes it.  It's replaced by each attempt at the sync.
+    pub fn save_pack(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.save_pack = Some(path.into());
+        self
+    }
+
+    /// Push the pack saved at `path` by [`save_pack`](Self::save_pack), without
+    /// contacting the source.  Each update saved with it is pushed if the
+    /// target's ref is still as it was when it was saved, and refused if it has
+    /// moved on since, unless it's already been made.
+    pub fn from_pack(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.from_pack = Some(path.into());
+        self
+    }
+
+    /// Record the refs each sync which wholly succeeds leaves the source and
+    /// target with, as a [`SyncState`] in `dir`.  A sync which then finds the
+    /// source's refs unchanged leaves the target alone, without connecting to it,
+    /// and any other tells the source that the target has the objects recorded.
+    /// Both take it that nothing else updates the target, so a state is only
+    /// gone on until it's as old as [`state_max_age`](Self::state_max_age) says.
+    pub fn state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.state_dir = Some(dir.into());
+        self
+    }
+
+    /// How old a recorded state may be before a sync no longer goes on it, and
+    /// instead compares the target's refs afresh, repairing anything else which
+    /// changed them
+    pub fn state_max_age(mut self, age: Duration) -> Self {
+        self.options.state_max_age = age;
+        self
+    }
+
+    /// Whether to relay the progress messages the source and target send, or to
+    /// ask them not to send any
+    pub fn remote_progress(mut self, progress: bool) -> Self {
+        self.options.remote_progress = progress;
+        self
+    }
+
+    /// Introduce ourselves to the source and target as `agent` rather than as
+    /// [`DEFAULT_AGENT`]
+    pub fn agent(mut self, agent: impl Into<String>) -> Self {
+        self.options.agent = agent.into();
+        self
+    }
+
+    /// Run a hook once the sync is planned, before pushing, which stops the sync
+    /// if it fails.  Besides the planned updates on its stdin and their counts,
+    /// it's given `GIT_SYNC_REFUSED`, the number of updates refused.
+    pub fn pre_sync(mut self, hook: Hook) -> Self {
+        self.options.pre_sync = Some(hook);
+        self
+    }
+
+    /// Run a hook once the sync is over, unless it was cancelled.  Besides the
+    /// updates made on its stdin and their counts, it's given `GIT_SYNC_REJECTED`,
+    /// the number of updates rejected, `GIT_SYNC_RESULT`, which is `success`,
+    /// `rejected` or `failure`, and `GIT_SYNC_ERROR` if the sync failed.  A
+    /// failing hook is only warned about.
+    pub fn post_sync(mut self, hook: Hook) -> Self {
+        self.options.post_sync = Some(hook);
+        self
+    }
+
+    /// Check the options and produce them
+    pub fn build(self) -> Result<SyncOptions, Error> {
+        let mut options = self.options;
+        options.plan.prune_tags = self
+            .prune_tags
+            .unwrap_or(options.plan.mode == SyncMode::Mirror);
+        for prefix in options
+            .plan
+            .strip_source_prefix
+            .iter()
+            .chain(&options.plan.dest_prefix)
+        {
+            if !prefix.starts_with("refs/") || !prefix.ends_with('/') {
+                return Err(Error::Config(format!(
+                    "Ref prefix {} must start with refs/ and end with /",
+                    prefix
+                )));
+            }
+        }
+        if let DeleteLimit::Percent(pct) = options.max_delete {
+            if pct > 100 {
+                return Err(Error::Config(format!(
+                    "Deletion limit {}% is over 100%",
+                    pct
+                )));
+            }
+        }
+        if options.prune_only && options.plan.mode == SyncMode::NoDelete && !options.plan.prune_tags
+        {
+            return Err(Error::Config(
+                "Pruning needs a mode which deletes refs".to_string(),
+            ));
+        }
+        if options.batch_size == Some(0) {
+            return Err(Error::Config("Batch size must be at least 1".to_string()));
+        }
+        if options.from_pack.is_some()
+            && (options.batch_size.is_some()
+                || options.spool.is_some()
+                || options.save_pack.is_some()
+                || options.set_head
+                || options.create_target
+                || options.state_dir.is_some())
+        {
+            return Err(Error::Config(
+                "A saved pack is pushed as it is, without batches, spooling, saving, setting HEAD, creating the target or recording the state"
+                    .to_string(),
+            ));
+        }
+        if options.batch_size.is_some() && options.save_pack.is_some() {
+            return Err(Error::Config(
+                "Cannot save the pack when pushing in batches, which push several".to_string(),
+            ));
+        }
+        // Capabilities are separated by spaces, so the agent can't contain any
+        if options.agent.is_empty()
+            || options
+                .agent
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(Error::Config(format!(
+                "The agent must be printable and without spaces, not {:?}",
+                options.agent
+            )));
+        }
+        let timeouts = [
+            options.connect_timeout,
+            options.negotiation_timeout,
+            options.idle_timeout,
+            options.timeout,
+        ];
+        if timeouts.contains(&Some(Duration::from_secs(0))) {
+            return Err(Error::Config(
+                "Timeouts must be longer than zero".to_string(),
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// The ref updates a sync will push, and those it refuses to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// The updates to push to the target
+    pub updates: Vec<RefUpdate>,
+    /// Non-fast-forward updates which weren't permitted
+    pub refused: Vec<RefUpdate>,
+}
+
+/// What happened during a sync
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncOutcome {
+    /// What the target did with the updates we pushed
+    pub report: SyncReport,
+    /// Non-fast-forward updates which weren't pushed
+    pub refused: Vec<RefUpdate>,
+    /// How many bytes of pack data were relayed from the source to the target
+    pub pack_bytes: u64,
+    /// How many objects the packs relayed had, as their headers said
+    pub pack_objects: u64,
+    /// How many times a pack was negotiated with the source, which is once for
+    /// each batch needing objects
+    pub negotiations: usize,
+    /// How long each phase of the sync took
+    pub timings: SyncTimings,
+}
+
+impl SyncOutcome {
+    /// Whether every update was planned and pushed successfully
+    pub fn is_success(&self) -> bool {
+        self.refused.is_empty() && self.report.rejected().next().is_none()
+    }
+
+    /// The average rate, in bytes per second, at which pack data was relayed
+    /// while it was flowing, if any was
+    ///
+    /// ```
+    /// # use git_sync::{SyncOutcome, SyncTimings};
+    /// # use std::time::Duration;
+    /// let outcome = SyncOutcome {
+    ///     pack_bytes: 4096,
+    ///     timings: SyncTimings {
+    ///         transfer: Duration::from_secs(2),
+    ///         ..SyncTimings::default()
+    ///     },
+    ///     ..SyncOutcome::default()
+    /// };
+    /// assert_eq!(outcome.throughput(), Some(2048.0));
+    /// assert_eq!(SyncOutcome::default().throughput(), None);
+    /// ```
+    pub fn throughput(&self) -> Option<f64> {
+        let secs = self.timings.transfer.as_secs_f64();
+        if self.pack_bytes > 0 && secs > 0.0 {
+            Some(self.pack_bytes as f64 / secs)
+        } else {
+            None
+        }
+    }
+}
+
+/// How long the phases of a sync took.  Only the attempt which finished is
+/// counted when a sync is retried.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncTimings {
+    /// Starting the services and reading their ref advertisements
+    pub connect: Duration,
+    /// Working out which refs to update
+    pub plan: Duration,
+    /// Pushing the updates, including relaying the pack
+    pub push: Duration,
+    /// The part of the push spent relaying pack data, from its first byte to
+    /// its last
+    pub transfer: Duration,
+}
+
+impl SyncTimings {
+    /// The phases in order, with their names, leaving out the transfer since
+    /// it's part of the push
+    pub fn phases(&self) -> [(&'static str, Duration); 3] {
+        [
+            ("connect", self.connect),
+            ("plan", self.plan),
+            ("push", self.push),
+        ]
+    }
+
+    /// How long the phases took altogether
+    pub fn total(&self) -> Duration {
+        self.connect + self.plan + self.push
+    }
+}
+
+/// Syncs the refs of a source repository into a target repository
+#[derive(Debug, Clone)]
+pub struct Syncer {
+    source: Endpoint,
+    target: Endpoint,
+    options: SyncOptions,
+    events: Option<SyncEventSender>,
+    cancellation: Option<CancellationToken>,
+}
+
+impl Syncer {
+    /// Prepare to sync `source` into `target`
+    pub fn new(source: Endpoint, target: Endpoint, options: SyncOptions) -> Result<Syncer, Error> {
+        let housekeeping = options.pack_refs || !options.maintenance.is_empty();
+        if (housekeeping || options.set_head) && !target.can_run_commands() {
+            return Err(Error::Config(format!(
+                "Cannot tidy up or set HEAD in {}, git commands cannot be run there",
+                target
+            )));
+        }
+        if options.create_target && !target.can_run_commands() {
+            return Err(Error::Config(format!(
+                "Cannot create {}, git commands cannot be run there",
+                target
+            )));
+        }
+        Ok(Syncer {
+            source,
+            target,
+            options,
+            events: None,
+            cancellation: None,
+        })
+    }
+
+    /// Receive events describing the progress of every sync from now on
+    pub fn subscribe(&mut self) -> SyncEventReceiver {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.events = Some(sender);
+        receiver
+    }
+
+    /// Stop any sync run from now on when this token is cancelled
+    pub fn set_cancellation(&mut self, token: CancellationToken) {
+        self.cancellation = Some(token);
+    }
+
+    fn emit(&self, event: SyncEvent) {
+        match &self.events {
+            // If nobody's listening any more, that's their business
+            Some(events) => {
+                let _ = events.send(event);
+            }
+            // Without a subscriber, what ought to be seen is logged instead
+            None => match event {
+                SyncEvent::Warning(message) => tracing::warn!("{}", message),
+                SyncEvent::RemoteError(side, message) => {
+                    tracing::warn!(side = %side, "{}", message.trim_end())
+                }
+                SyncEvent::Retrying {
+                    attempt,
+                    delay,
+                    error,
+                    ..
+                } => {
+                    tracing::warn!(repo = %self.target, attempt = attempt, delay = ?delay, "Sync failed, trying again: {}", error)
+                }
+                _ => {}
+            },
+        }
+    }
+
+    /// The repository we sync from
+    pub fn source(&self) -> &Endpoint {
+        &self.source
+    }
+
+    /// The repository we sync into
+    pub fn target(&self) -> &Endpoint {
+        &self.target
+    }
+
+    /// How the sync behaves
+    pub fn options(&self) -> &SyncOptions {
+        &self.options
+    }
+
+    /// Connect to upload-pack in the source and receive-pack in the target, and read
+    /// what each has to offer
+    pub async fn connect(&self) -> Result<SyncSession<'_>, Error> {
+        let (source, target) = self.start_both().await?;
+        self.session(source, target, Vec::new()).await
+    }
+
+    /// Connect as [`connect`](Self::connect) does, unless the source's refs are
+    /// as `state` recorded them, in which case its upload-pack is stopped again
+    /// without the target having been connected to, and there's no session
+    async fn connect_if_changed(
+        &self,
+        state: Option<&SyncState>,
+    ) -> Result<Option<SyncSession<'_>>, Error> {
+        let (mut upload_pack, source_advert) = self.start(SyncSide::Source).await?;
+        let known = match state {
+            Some(state)
+                if source_advert.refs().iter().collect::<BTreeMap<_, _>>()
+                    == state.source.iter().collect() =>
+            {
+                self.emit(SyncEvent::AdvertisementRead(
+                    SyncSide::Source,
+                    source_advert,
+                ));
+                // An empty request tells upload-pack that we want nothing of it
+                ProtocolLine::Flush.write_to(upload_pack.writer()).await?;
+                upload_pack.shutdown().await?;
+                return Ok(None);
+            }
+            Some(state) => state.target.values().cloned().collect(),
+            None => Vec::new(),
+        };
+        let (source, target) = self.start_target((upload_pack, source_advert)).await?;
+        self.session(source, target, known).await.map(Some)
+    }
+
+    /// Make a session of a pair of services which have been started, where the
+    /// target is taken to have the objects `known` as well as those it advertised
+    async fn session(
+        &self,
+        (upload_pack, source_advert): Started,
+        (receive_pack, target_advert): Started,
+        known: Vec<String>,
+    ) -> Result<SyncSession<'_>, Error> {
+        self.emit(SyncEvent::AdvertisementRead(
+            SyncSide::Source,
+            source_advert.clone(),
+        ));
+        self.emit(SyncEvent::AdvertisementRead(
+            SyncSide::Target,
+            target_advert.clone(),
+        ));
+
+        let (source_format, target_format) =
+            (object_format(&source_advert), object_format(&target_advert));
+        let push_caps = match self.push_caps(&target_advert) {
+            // Objects can't be pushed into a repository which names them otherwise
+            Ok(_) if source_format != target_format => Err(Error::Config(format!(
+                "The source uses the {} object format but the target uses {}",
+                source_format, target_format
+            ))),
+            caps => caps,
+        };
+        let push_caps = match push_caps {
+            Ok(caps) => caps,
+            Err(err) => return Err(abort_services(err, upload_pack, receive_pack).await),
+        };
+        let fetch_caps = self.fetch_caps(&source_advert);
+        let pack_format = pack_format(&source_advert);
+
+        Ok(SyncSession {
+            syncer: self,
+            upload_pack,
+            receive_pack,
+            source_advert,
+            target_advert,
+            requests: Arc::new(PushRequests {
+                fetch_caps,
+                push_caps,
+                pack_format,
+                known,
+                commands_sent: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    /// Check whether the target's refs are as a sync would leave them, returning
+    /// those which differ (by their names in the target).  Nothing is pushed.
+    pub async fn verify(&self) -> Result<Vec<RefDiff>, Error> {
+        let ((upload_pack, source_advert), (receive_pack, target_advert)) =
+            self.start_both().await?;
+        close_services(upload_pack, receive_pack).await?;
+        let updates = compute_ref_updates(
+            target_advert.refs(),
+            source_advert.refs(),
+            &self.options.plan,
+        );
+        Ok(updates.iter().map(RefDiff::from).collect())
+    }
+
+    /// Connect, plan and push, returning what happened.  If the sync is cancelled,
+    /// its services are killed and [`Error::Cancelled`] is returned.
+    pub async fn run(&self) -> Result<SyncOutcome, Error> {
+        let span = tracing::info_span!("sync", source = %self.source, target = %self.target);
+        async {
+            let sync = async {
+                let mut attempt = 0;
+                // A pack spooled by an attempt which failed to push it may do for the next
+                let mut spooled = None;
+                loop {
+                    attempt += 1;
+                    match self.attempt(&mut spooled).await {
+                        (Err(err), false)
+                            if attempt <= self.options.retries && err.is_transient() =>
+                        {
+                            let delay = retry_delay(attempt);
+                            self.emit(SyncEvent::Retrying {
+                                attempt,
+                                retries: self.options.retries,
+                                delay,
+                                error: err.to_string(),
+                            });
+                            tokio::time::sleep(delay).await;
+                        }
+                        (result, _) => return result,
+                    }
+                }
+            };
+            let sync = async {
+                match self.options.timeout {
+                    Some(limit) => timeout(limit, sync)
+                        .await
+                        .map_err(|_| Error::TimedOut(Timeout::Sync))?,
+                    None => sync.await,
+                }
+            };
+            let result = match &self.cancellation {
+                Some(token) => tokio::select! {
+                    result = sync => result,
+                    _ = token.cancelled() => {
+                        self.emit(SyncEvent::Cancelled);
+                        Err(Error::Cancelled)
+                    }
+                },
+                None => sync.await,
+            };
+            match &self.options.post_sync {
+                Some(hook) if !matches!(result, Err(Error::Cancelled)) => {
+                    self.run_post_sync(hook, result.as_ref()).await;
+                }
+                _ => {}
+            }
+            result
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// The variables every hook is given: which hook it is, and the source and
+    /// target of the sync
+    fn hook_vars(&self, name: &str) -> Vec<(&'static str, String)> {
+        vec![
+            ("GIT_SYNC_HOOK", name.to_string()),
+            ("GIT_SYNC_SOURCE", self.source.to_string()),
+            ("GIT_SYNC_TARGET", self.target.to_string()),
+        ]
+    }
+
+    /// Run the pre-sync hook on a plan, turning its failure into a refusal
+    async fn run_pre_sync(&self, hook: &Hook, plan: &SyncPlan) -> Result<(), Error> {
+        let mut vars = self.hook_vars("pre-sync");
+        vars.extend(Hook::update_counts(&plan.updates));
+        vars.push(("GIT_SYNC_REFUSED", plan.refused.len().to_string()));
+        tracing::debug!(repo = %self.target, phase = "plan", "Running the pre-sync hook");
+        match hook.run(&vars, &plan.updates).await {
+            Err(err @ Error::ChildFailed { .. }) => Err(Error::Refused(format!(
+                "The pre-sync hook stopped the sync: {}",
+                err
+            ))),
+            result => result,
+        }
+    }
+
+    /// Run the post-sync hook on the result of a sync, warning if it fails
+    async fn run_post_sync(&self, hook: &Hook, result: Result<&SyncOutcome, &Error>) {
+        let mut vars = self.hook_vars("post-sync");
+        let pushed: Vec<RefUpdate> = match result {
+            Ok(outcome) => outcome
+                .report
+                .outcomes
+                .iter()
+                .filter(|outcome| outcome.status == RefStatus::Ok)
+                .map(|outcome| outcome.update.clone())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        vars.extend(Hook::update_counts(&pushed));
+        let (ended, rejected) = match result {
+            Ok(outcome) if outcome.is_success() => (NotifyOn::Success, 0),
+            Ok(outcome) => (NotifyOn::Rejected, outcome.report.rejected().count()),
+            Err(err) => (NotifyOn::for_exit(ExitReason::for_error(err)), 0),
+        };
+        vars.push(("GIT_SYNC_REJECTED", rejected.to_string()));
+        vars.push(("GIT_SYNC_RESULT", ended.to_string()));
+        if let Err(err) = result {
+            vars.push(("GIT_SYNC_ERROR", err.to_string()));
+        }
+        tracing::debug!(repo = %self.target, "Running the post-sync hook");
+        if let Err(err) = hook.run(&vars, &pushed).await {
+            self.emit(SyncEvent::Warning(format!(
+                "The post-sync hook failed: {}",
+                err
+            )));
+        }
+    }
+
+    /// Connect, plan and push once, returning whether any ref updates were sent to
+    /// the target along with the outcome
+    async fn attempt(&self, spooled: &mut Option<Spooled>) -> (Result<SyncOutcome, Error>, bool) {
+        if let Some(path) = &self.options.from_pack {
+            let commands_sent = AtomicBool::new(false);
+            let result = self.push_saved(path, &commands_sent).await;
+            return (result, commands_sent.load(Ordering::Relaxed));
+        }
+        let started = Instant::now();
+        let state_path = self.state_path();
+        let state = match &state_path {
+            Some(path) => match SyncState::age(path).await {
+                Some(age) if age > self.options.state_max_age => {
+                    tracing::info!(repo = %self.target, phase = "connect", "The recorded state is {}s old, so the target's refs are compared afresh",
+                        age.as_secs());
+                    None
+                }
+                _ => SyncState::load(path).await.unwrap_or_else(|err| {
+                    self.emit(SyncEvent::Warning(err.to_string()));
+                    None
+                }),
+            },
+            None => None,
+        };
+        let session = match self.connect_if_changed(state.as_ref()).await {
+            Ok(Some(session)) => session,
+            Ok(None) => return (Ok(self.unchanged(started)), false),
+            Err(err) => return (Err(err), false),
+        };
+        let connected = Instant::now();
+        let requests = session.requests.clone();
+        let advertised = (
+            session.source_advert.refs().clone(),
+            session.target_advert.refs().clone(),
+        );
+        let result = match session.plan().await {
+            Ok(plan) => {
+                let planned = Instant::now();
+                if let Some(hook) = &self.options.pre_sync {
+                    if let Err(err) = self.run_pre_sync(hook, &plan).await {
+                        session.abort().await;
+                        return (Err(err), false);
+                    }
+                }
+                session
+                    .push_spooled(plan, spooled)
+                    .await
+                    .map(|mut outcome| {
+                        outcome.timings.connect = connected - started;
+                        outcome.timings.plan = planned - connected;
+                        outcome
+                    })
+            }
+            Err(err) => {
+                session.abort().await;
+                Err(err)
+            }
+        };
+        if let Some(path) = &state_path {
+            self.record_state(path, advertised, &result).await;
+        }
+        (result, requests.commands_sent.load(Ordering::Relaxed))
+    }
+
+    /// Where the state of this pair's syncs is recorded, if it is
+    fn state_path(&self) -> Option<PathBuf> {
+        let opts = &self.options;
+        let dir = opts.state_dir.as_ref()?;
+        let (source, target) = (self.source.to_string(), self.target.to_string());
+        Some(SyncState::path_for(
+            dir,
+            &source,
+            &target,
+            &state_settings(opts),
+        ))
+    }
+
+    /// The outcome of a sync which found the source's refs as the last left them
+    fn unchanged(&self, started: Instant) -> SyncOutcome {
+        tracing::info!(repo = %self.target, phase = "connect", "The source's refs are as they were after the last sync, so the target was left alone");
+        let outcome = SyncOutcome {
+            timings: SyncTimings {
+                connect: started.elapsed(),
+                ..SyncTimings::default()
+            },
+            ..SyncOutcome::default()
+        };
+        self.emit(SyncEvent::Completed(outcome.clone()));
+        outcome
+    }
+
+    /// Record the refs a sync which wholly succeeded left the source and target
+    /// with, given those they advertised, or otherwise forget whatever was
+    /// recorded, since it may no longer hold
+    async fn record_state(
+        &self,
+        path: &Path,
+        (source, target): (HashMap<String, String>, HashMap<String, String>),
+        result: &Result<SyncOutcome, Error>,
+    ) {
+        let recorded = match result {
+            Ok(outcome) if outcome.is_success() => {
+                let mut state = SyncState {
+                    source: source.into_iter().collect(),
+                    target: target
+                        .into_iter()
+                        .filter(|(refname, _)| refname.starts_with("refs/"))
+                        .collect(),
+                };
+                for RefOutcome { update, .. } in &outcome.report.outcomes {
+                    if update.is_delete() {
+                        state.target.remove(&update.refname);
+                    } else {
+                        state
+                            .target
+                            .insert(update.refname.clone(), update.newsha.clone());
+                    }
+                }
+                state.save(path).await
+            }
+            _ => SyncState::clear(path).await,
+        };
+        if let Err(err) = recorded {
+            self.emit(SyncEvent::Warning(err.to_string()));
+        }
+    }
+
+    /// Work out the ref updates which would bring the target, as advertised, in
+    /// line with the source
+    async fn plan_updates(
+        &self,
+        source_advert: &RefAdvertisement,
+        target_advert: &RefAdvertisement,
+    ) -> Result<SyncPlan, Error> {
+        let opts = &self.options;
+        let source = &self.source;
+        let plan_opts = &opts.plan;
+        let mut updates =
+            compute_ref_updates(target_advert.refs(), source_advert.refs(), plan_opts);
+        if opts.prune_only {
+            updates.retain(RefUpdate::is_delete);
+        }
+
+        // Protected refs may never be rewound or rewritten
+        let mut refused = Vec::new();
+        if !opts.force || !plan_opts.protect.is_empty() {
+            let forced = |update: &RefUpdate| {
+                !plan_opts.is_protected(&update.refname)
+                    && (opts.force
+                        || plan_opts.is_forced(&update.refname)
+                        || opts
+                            .force_refs
+                            .iter()
+                            .any(|pat| pat.matches(&update.refname)))
+            };
+            let to_check: Vec<(&str, &str)> = updates
+                .iter()
+                .filter(|update| !update.is_create() && !update.is_delete() && !forced(update))
+                .map(|update| (update.oldsha.as_str(), update.newsha.as_str()))
+                .collect();
+            // Without a way to check in the source, receive-pack is left to refuse
+            // whatever isn't a fast-forward, as it does if it's configured to
+            let mut fast_forwards = match source.can_run_commands() {
+                true => Some(source.ancestors(&to_check).await?.into_iter()),
+                false => None,
+            };
+            let mut checked = Vec::with_capacity(updates.len());
+            let mut unchecked = 0;
+            for update in updates {
+                if update.is_create() || update.is_delete() || forced(&update) {
+                    checked.push(update);
+                    continue;
+                }
+                let protected = plan_opts.is_protected(&update.refname);
+                let reason = match fast_forwards.as_mut().map(|ff| ff.next() == Some(true)) {
+                    Some(true) => None,
+                    Some(false) if protected => {
+                        Some("non-fast-forward update of a protected ref".to_string())
+                    }
+                    Some(false) => {
+                        Some("non-fast-forward update (use --force to permit)".to_string())
+                    }
+                    None if protected => Some(format!(
+                        "fast-forwards cannot be checked in {}, and the ref is protected",
+                        source
+                    )),
+                    None => {
+                        unchecked += 1;
+                        None
+                    }
+                };
+                match reason {
+                    None => checked.push(update),
+                    Some(reason) => {
+                        self.emit(SyncEvent::Refused(update.clone(), reason));
+                        refused.push(update);
+                    }
+                }
+            }
+            if unchecked > 0 {
+                self.emit(SyncEvent::Warning(format!(
+                    "Fast-forwards cannot be checked in {}, so {} update(s) are pushed \
+                     unchecked, for the target to refuse if it denies non-fast-forwards",
+                    source, unchecked
+                )));
+            }
+            updates = checked;
+        }
+
+        // Guard against wiping out the target because the source looks empty or wrong
+        let deletes = updates.iter().filter(|update| update.is_delete()).count();
+        let target_refs = target_advert
+            .refs()
+            .keys()
+            .filter(|k| k.starts_with("refs/") && !k.ends_with("^{}"))
+            .count();
+        if !opts.ignore_max_delete && opts.max_delete.exceeded(deletes, target_refs) {
+            return Err(Error::Refused(format!(
+                "Refusing to delete {} of {} refs in the target (limit is {}), use --yes-really-delete to proceed",
+                deletes, target_refs, opts.max_delete
+            )));
+        }
+
+        let plan = SyncPlan { updates, refused };
+        tracing::info!(repo = %self.target, phase = "plan", updates = plan.updates.len(), refused = plan.refused.len(), "Planned the ref updates");
+        self.emit(SyncEvent::PlanComputed(plan.clone()));
+        Ok(plan)
+    }
+
+    /// The capabilities to ask of the target's receive-pack, given what it
+    /// advertised, failing if it lacks one which is required
+    fn push_caps(
+        &self,
+        target_advert: &RefAdvertisement,
+    ) -> Result<Vec<(Capability, Option<String>)>, Error> {
+        let mut push_caps = vec![
+            (Capability::ReportStatus, None),
+            (Capability::SideBand64K, None),
+            (Capability::Agent, Some(self.options.agent.clone())),
+        ];
+        // A service using another object format than SHA-1 has to be told that
+        // we know it does
+        let format = object_format(target_advert);
+        if format != "sha1" {
+            push_caps.push((Capability::ObjectFormat, Some(format.to_string())));
+        }
+        let atomic = target_advert.caps().contains_key(&Capability::Atomic);
+        match self.options.atomic {
+            AtomicMode::Never => {}
+            _ if atomic => push_caps.push((Capability::Atomic, None)),
+            AtomicMode::Required => return Err(Error::MissingCapability(Capability::Atomic)),
+            AtomicMode::IfSupported => self.emit(SyncEvent::Warning(
+                "Target does not support atomic pushes, refs will be updated individually"
+                    .to_string(),
+            )),
+        }
+        if self.options.quiet_remote {
+            if target_advert.caps().contains_key(&Capability::Quiet) {
+                push_caps.push((Capability::Quiet, None));
+            } else {
+                self.emit(SyncEvent::Warning(
+                    "Target does not support the quiet capability, so may be noisy".to_string(),
+                ));
+            }
+        } else if !self.options.remote_progress
+            && target_advert.caps().contains_key(&Capability::Quiet)
+        {
+            // Nobody would see the target's progress, so don't have it sent
+            push_caps.push((Capability::Quiet, None));
+        }
+        Ok(push_caps)
+    }
+
+    /// The capabilities to ask of the source's upload-pack, given what it advertised
+    fn fetch_caps(&self, source_advert: &RefAdvertisement) -> Vec<(Capability, Option<String>)> {
+        let mut fetch_caps = vec![
+            (Capability::SideBand64K, None),
+            (Capability::Agent, Some(self.options.agent.clone())),
+        ];
+        let format = object_format(source_advert);
+        if format != "sha1" {
+            fetch_caps.push((Capability::ObjectFormat, Some(format.to_string())));
+        }
+        if self.options.ofs_delta {
+            fetch_caps.push((Capability::OfsDelta, None));
+        }
+        if self.options.thin_pack {
+            fetch_caps.push((Capability::ThinPack, None));
+        }
+        if !self.options.remote_progress
+            && source_advert.caps().contains_key(&Capability::NoProgress)
+        {
+            fetch_caps.push((Capability::NoProgress, None));
+        }
+        fetch_caps
+    }
+
+    /// Push the pack saved at `path` into the target, along with those of the
+    /// updates saved with it which the target can still take
+    async fn push_saved(
+        &self,
+        path: &Path,
+        commands_sent: &AtomicBool,
+    ) -> Result<SyncOutcome, Error> {
+        let started = Instant::now();
+        let saved = SavedPack::open(path).await?;
+        check_pack_limits(self, saved.size(), Some(saved.objects()))?;
+        let (mut receive_pack, target_advert) = self.start(SyncSide::Target).await?;
+        self.emit(SyncEvent::AdvertisementRead(
+            SyncSide::Target,
+            target_advert.clone(),
+        ));
+        let connected = Instant::now();
+        let pushed = async {
+            let push_caps = self.push_caps(&target_advert)?;
+            let plan = self.plan_saved(&saved, &target_advert);
+            self.emit(SyncEvent::PlanComputed(plan.clone()));
+            if let Some(hook) = &self.options.pre_sync {
+                self.run_pre_sync(hook, &plan).await?;
+            }
+            let planned = Instant::now();
+            let sent = send_commands(
+                self,
+                receive_pack.as_mut(),
+                &target_advert,
+                &plan.updates,
+                &push_caps,
+                commands_sent,
+            )
+            .await?;
+            let expecting_to_send = SendActivity::for_updates(&sent);
+            let mut pushed = None;
+            if matches!(expecting_to_send, SendActivity::Sending) {
+                let started = Instant::now();
+                if saved.size() > 0 {
+                    replay_pack(self, receive_pack.as_mut(), saved.open_pack().await?).await?;
+                } else {
+                    receive_pack.writer().write_all(EMPTY_PACK).await?;
+                }
+                pushed = Some(Relayed {
+                    bytes: saved.size(),
+                    objects: saved.objects(),
+                    took: started.elapsed(),
+                });
+            }
+            let status = read_report(self, receive_pack.as_mut(), &expecting_to_send).await?;
+            Ok::<_, Error>((plan, sent, status, planned, pushed))
+        };
+        let (plan, sent, status, planned, pushed) = match pushed.await {
+            Ok(pushed) => pushed,
+            Err(err) => {
+                return Err(match receive_pack.abort().await {
+                    Err(failed @ Error::ChildFailed { .. }) => failed,
+                    _ => err,
+                })
+            }
+        };
+        receive_pack.shutdown().await?;
+
+        let report = SyncReport::new(&sent, &status);
+        for outcome in &report.outcomes {
+            self.emit(SyncEvent::RefResult(outcome.clone()));
+        }
+        self.tidy_target(None, &plan.refused, &report).await?;
+        let pushed = pushed.unwrap_or_default();
+        let outcome = SyncOutcome {
+            report,
+            refused: plan.refused,
+            pack_bytes: pushed.bytes,
+            pack_objects: pushed.objects,
+            negotiations: 0,
+            timings: SyncTimings {
+                connect: connected - started,
+                plan: planned - connected,
+                push: planned.elapsed(),
+                transfer: pushed.took,
+            },
+        };
+        tracing::info!(repo = %self.target, phase = "push", bytes = pushed.bytes, rejected = outcome.report.rejected().count(), "Pushed {} ref update(s) from {}", outcome.report.outcomes.len(), path.display());
+        self.emit(SyncEvent::Completed(outcome.clone()));
+        Ok(outcome)
+    }
+
+    /// Work out which of a saved pack's updates the target, as advertised, can
+    /// still take: those of refs which are as they were when the pack was saved.
+    /// Updates it has already taken are left out, and those of refs which have
+    /// moved since are refused.
+    fn plan_saved(&self, saved: &SavedPack, target_advert: &RefAdvertisement) -> SyncPlan {
+        let refs = target_advert.refs();
+        let mut plan = SyncPlan::default();
+        for update in saved.updates() {
+            let current = refs.get(&update.refname).map_or(NULLSHA, String::as_str);
+            if current == update.newsha {
+                continue;
+            }
+            if current == update.oldsha {
+                plan.updates.push(update.clone());
+                continue;
+            }
+            self.emit(SyncEvent::Refused(
+                update.clone(),
+                "the target's ref has moved since the pack was saved".to_string(),
+            ));
+            plan.refused.push(update.clone());
+        }
+        let missing = saved
+            .prerequisites()
+            .iter()
+            .filter(|have| !refs.values().any(|sha| sha == *have))
+            .count();
+        if missing > 0 && plan.updates.iter().any(|update| !update.is_delete()) {
+            self.emit(SyncEvent::Warning(format!(
+                "The target lacks {} of the {} ref(s) the saved pack may be based on, so may not be able to take it",
+                missing,
+                saved.prerequisites().len()
+            )));
+        }
+        plan
+    }
+
+    /// Tidy up the target once updates have been pushed to it, as asked: packing
+    /// its refs, pointing its HEAD at the source's default branch, and doing its
+    /// housekeeping if nothing was refused or rejected
+    async fn tidy_target(
+        &self,
+        source_advert: Option<&RefAdvertisement>,
+        refused: &[RefUpdate],
+        report: &SyncReport,
+    ) -> Result<(), Error> {
+        let opts = &self.options;
+        let updated = report.outcomes.len() - report.rejected().count();
+        if opts.pack_refs && updated > 0 && updated < opts.pack_refs_threshold {
+            tracing::debug!(repo = %self.target, "Not packing refs, as {} updated is fewer than {}",
+                updated,
+                opts.pack_refs_threshold);
+        } else if opts.pack_refs && updated > 0 {
+            self.emit(match self.target.run_git(&["pack-refs", "--all"]).await {
+                Ok(()) => SyncEvent::RefsPacked,
+                Err(err @ Error::ChildFailed { .. }) => {
+                    SyncEvent::Warning(format!("Unable to pack refs in target: {}", err))
+                }
+                Err(err) => return Err(err),
+            });
+        }
+        if let (true, Some(source_advert)) = (opts.set_head, source_advert) {
+            let head = source_advert
+                .symrefs()
+                .get("HEAD")
+                .and_then(|head| opts.plan.map_source(head));
+            match head {
+                Some(head)
+                    if !refused.iter().any(|update| update.refname == head)
+                        && !report
+                            .rejected()
+                            .any(|outcome| outcome.update.refname == head) =>
+                {
+                    let symref = format!("{}HEAD", opts.plan.dest_prefix.as_deref().unwrap_or(""));
+                    let result = self.target.run_git(&["symbolic-ref", &symref, &head]).await;
+                    self.emit(match result {
+                        Ok(()) => SyncEvent::HeadSet(symref, head),
+                        Err(err @ Error::ChildFailed { .. }) => SyncEvent::Warning(format!(
+                            "Unable to set {} in target: {}",
+                            symref, err
+                        )),
+                        Err(err) => return Err(err),
+                    });
+                }
+                _ => self.emit(SyncEvent::Warning(
+                    "Not setting target HEAD, the source's default branch was not synced"
+                        .to_string(),
+                )),
+            }
+        }
+        if refused.is_empty() && report.rejected().next().is_none() {
+            for task in &opts.maintenance {
+                self.emit(match self.target.run_git(task.args()).await {
+                    Ok(()) => SyncEvent::Maintained(*task),
+                    Err(err @ Error::ChildFailed { .. }) => {
+                        SyncEvent::Warning(format!("Unable to run {} in target: {}", task, err))
+                    }
+                    Err(err) => return Err(err),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Start upload-pack in the source and receive-pack in the target, creating the
+    /// target first if asked to and it doesn't exist.  Should the target's fail to
+    /// start, the source's is stopped again.
+    async fn start_both(&self) -> Result<(Started, Started), Error> {
+        let source = self.start(SyncSide::Source).await?;
+        self.start_target(source).await
+    }
+
+    /// Start receive-pack in the target once the source's upload-pack has been
+    /// started, as [`start_both`](Self::start_both) does
+    async fn start_target(&self, source: Started) -> Result<(Started, Started), Error> {
+        if self.options.create_target {
+            if let Err(err) = self.create_target(&source.1).await {
+                let _ = source.0.abort().await;
+                return Err(err);
+            }
+        }
+        match self.start(SyncSide::Target).await {
+            Ok(target) => Ok((source, target)),
+            Err(err) => {
+                let _ = source.0.abort().await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Create the target if it doesn't exist, with the object format and default
+    /// branch the source advertised
+    async fn create_target(&self, source_advert: &RefAdvertisement) -> Result<(), Error> {
+        let object_format = match source_advert.caps().get(&Capability::ObjectFormat) {
+            Some(Some(format)) => Some(format.as_str()),
+            _ => None,
+        };
+        if !self.target.create(object_format).await? {
+            return Ok(());
+        }
+        // The target's HEAD isn't the source's when its refs are synced under a prefix
+        let head = source_advert
+            .symrefs()
+            .get("HEAD")
+            .filter(|_| self.options.plan.dest_prefix.is_none())
+            .and_then(|head| self.options.plan.map_source(head));
+        if let Some(head) = &head {
+            self.target.run_git(&["symbolic-ref", "HEAD", head]).await?;
+        }
+        tracing::info!(repo = %self.target, phase = "connect", "Created the target");
+        self.emit(SyncEvent::TargetCreated {
+            object_format: object_format.unwrap_or("sha1").to_string(),
+            head,
+        });
+        Ok(())
+    }
+
+    /// Start upload-pack in the source or receive-pack in the target and read its
+    /// ref advertisement, within the connection timeout
+    async fn start(&self, side: SyncSide) -> Result<Started, Error> {
+        let (endpoint, service) = match side {
+            SyncSide::Source => (&self.source, "git-upload-pack"),
+            SyncSide::Target => (&self.target, "git-receive-pack"),
+        };
+        let start = async {
+            tracing::debug!(repo = %endpoint, phase = "connect", "Starting {}", service);
+            let mut transport = endpoint.connect(service).await?;
+            if let Some(events) = self.events.clone() {
+                transport.forward_stderr(Box::new(move |line| {
+                    let _ = events.send(SyncEvent::ServiceStderr(side, line.to_string()));
+                }));
+            }
+            match RefAdvertisement::read_from(transport.reader()).await {
+                Ok(advert) => {
+                    tracing::info!(repo = %endpoint, phase = "connect", refs = advert.refs().len(), "The {} advertised its refs", side);
+                    Ok((transport, advert))
+                }
+                // A service which gave up straight away has usually said why
+                Err(err) => match transport.shutdown().await {
+                    Err(failed @ Error::ChildFailed { .. }) => Err(failed),
+                    _ => Err(err),
+                },
+            }
+        };
+        match self.options.connect_timeout {
+            Some(limit) => timeout(limit, start).await.map_err(|_| {
+                Error::TimedOut(Timeout::Connect {
+                    service: service.to_string(),
+                    repo: endpoint.to_string(),
+                })
+            })?,
+            None => start.await,
+        }
+    }
+}
+
+/// A service which has been started, along with its ref advertisement
+type Started = (Box<dyn Transport>, RefAdvertisement);
+
+/// A connection to the services of a [`Syncer`]'s source and target
+pub struct SyncSession<'a> {
+    syncer: &'a Syncer,
+    upload_pack: Box<dyn Transport>,
+    receive_pack: Box<dyn Transport>,
+    source_advert: RefAdvertisement,
+    target_advert: RefAdvertisement,
+    requests: Arc<PushRequests>,
+}
+
+/// What a session asks of its services when pushing, and whether it has yet
+struct PushRequests {
+    /// The capabilities asked of upload-pack
+    fetch_caps: Vec<(Capability, Option<String>)>,
+    /// The capabilities asked of receive-pack
+    push_caps: Vec<(Capability, Option<String>)>,
+    /// The object format of the source, whose hash its packs end with a checksum
+    /// in, if it's one whose checksum can be checked
+    pack_format: Option<ObjectFormat>,
+    /// Objects the target is taken to have besides those it advertised, since it
+    /// had them at the end of the last sync
+    known: Vec<String>,
+    /// Set once ref updates have been sent to the target, after which the sync
+    /// can't safely be tried again
+    commands_sent: AtomicBool,
+}
+
+impl<'a> SyncSession<'a> {
+    /// The refs and capabilities the source advertised
+    pub fn source_advert(&self) -> &RefAdvertisement {
+        &self.source_advert
+    }
+
+    /// The refs and capabilities the target advertised
+    pub fn target_advert(&self) -> &RefAdvertisement {
+        &self.target_advert
+    }
+
+    /// Work out what we need to do to the target, refusing to rewind or rewrite refs
+    /// unless we've been told that's okay
+    pub async fn plan(&self) -> Result<SyncPlan, Error> {
+        self.syncer
+            .plan_updates(&self.source_advert, &self.target_advert)
+            .await
+    }
+
+    /// Close the session without pushing anything, telling both services that
+    /// nothing more is wanted of them, as when the plan turns out to be empty
+    pub async fn close(self) -> Result<(), Error> {
+        close_services(self.upload_pack, self.receive_pack).await
+    }
+
+    /// Stop both services without pushing anything, waiting for them to go
+    pub async fn abort(self) {
+        let _ = self.upload_pack.abort().await;
+        let _ = self.receive_pack.abort().await;
+    }
+
+    /// Push the planned updates, in batches with a fresh pair of sessions for each
+    /// if asked, and then tidy up the target as requested
+    pub async fn push(self, plan: SyncPlan) -> Result<SyncOutcome, Error> {
+        self.push_spooled(plan, &mut None).await
+    }
+
+    /// Push the planned updates, using a pack spooled by an earlier attempt if it
+    /// does for them, and keeping one which couldn't be pushed for the next
+    async fn push_spooled(
+        self,
+        plan: SyncPlan,
+        spooled: &mut Option<Spooled>,
+    ) -> Result<SyncOutcome, Error> {
+        let SyncSession {
+            syncer,
+            upload_pack,
+            receive_pack,
+            source_advert,
+            target_advert,
+            requests,
+        } = self;
+        let opts = &syncer.options;
+        let batches: Vec<&[RefUpdate]> = match opts.batch_size {
+            // With nothing to push, the target hears no more than its refs being read
+            _ if plan.updates.is_empty() => Vec::new(),
+            Some(size) if size > 0 && plan.updates.len() > size => {
+                plan.updates.chunks(size).collect()
+            }
+            _ => vec![&plan.updates],
+        };
+        let started = Instant::now();
+        let mut report = SyncReport::default();
+        let mut relayed = Relayed::default();
+        let mut negotiations = 0;
+        let mut session = Some((upload_pack, receive_pack, target_advert));
+        for (idx, batch) in batches.iter().enumerate() {
+            let (upload_pack, receive_pack, target_advert) = match session.take() {
+                Some(session) => session,
+                None => {
+                    let ((upload_pack, source_advert), (receive_pack, target_advert)) =
+                        syncer.start_both().await?;
+                    // What the source advertises now decides what it'll send, so
+                    // a batch planned from refs which have since moved can't go
+                    let advertised: HashSet<&String> = source_advert.refs().values().collect();
+                    let moved: Vec<&str> = batch
+                        .iter()
+                        .filter(|update| {
+                            !update.is_delete() && !advertised.contains(&update.newsha)
+                        })
+                        .map(|update| update.refname.as_str())
+                        .collect();
+                    if !moved.is_empty() {
+                        let err = Error::Transport(format!(
+                            "The source's refs moved while pushing in batches, so batch {} of {} wasn't pushed, for {}; sync again to push the rest",
+                            idx + 1,
+                            batches.len(),
+                            moved.join(", ")
+                        ));
+                        close_services(upload_pack, receive_pack).await?;
+                        return Err(err);
+                    }
+                    (upload_pack, receive_pack, target_advert)
+                }
+            };
+            syncer.emit(SyncEvent::BatchStarted {
+                batch: idx + 1,
+                batches: batches.len(),
+                updates: batch.len(),
+            });
+            tracing::debug!(repo = %syncer.target, phase = "push", batch = idx + 1, updates = batch.len(), "Pushing a batch of ref updates");
+            let (batch_report, batch_bytes) = push_updates(
+                syncer,
+                upload_pack,
+                receive_pack,
+                &target_advert,
+                batch,
+                &requests,
+                spooled,
+            )
+            .await?;
+            report.merge(batch_report);
+            if let Some(batch) = batch_bytes {
+                relayed.bytes += batch.bytes;
+                relayed.objects += batch.objects;
+                relayed.took += batch.took;
+                negotiations += 1;
+            }
+        }
+        if let Some((upload_pack, receive_pack, _)) = session {
+            tracing::debug!(repo = %syncer.target, phase = "push", "Nothing to push");
+            close_services(upload_pack, receive_pack).await?;
+        }
+
+        syncer
+            .tidy_target(Some(&source_advert), &plan.refused, &report)
+            .await?;
+
+        let outcome = SyncOutcome {
+            report,
+            refused: plan.refused,
+            pack_bytes: relayed.bytes,
+            pack_objects: relayed.objects,
+            negotiations,
+            timings: SyncTimings {
+                push: started.elapsed(),
+                transfer: relayed.took,
+                ..SyncTimings::default()
+            },
+        };
+        tracing::info!(repo = %syncer.target, phase = "push", bytes = relayed.bytes, rejected = outcome.report.rejected().count(), "Pushed {} ref update(s)", outcome.report.outcomes.len());
+        syncer.emit(SyncEvent::Completed(outcome.clone()));
+        Ok(outcome)
+    }
+}
+
+/// Syncs one source into several targets at once, fetching the objects all of
+/// them need from the source in a single pack and relaying it into each.  The
+/// source is reached, and the pack asked for, as the first syncer's options say;
+/// each target is planned, pushed to and tidied up as its own syncer's say.
+/// Events about the source go to every syncer's subscriber, except for its
+/// progress, which only goes to the first's.
+#[derive(Debug)]
+pub struct FanOut {
+    syncers: Vec<Syncer>,
+}
+
+/// A target of a fan-out which has been connected to and planned for
+struct FanOutTarget<'a> {
+    syncer: &'a Syncer,
+    receive_pack: Box<dyn Transport>,
+    target_advert: RefAdvertisement,
+    push_caps: Vec<(Capability, Option<String>)>,
+    plan: SyncPlan,
+    /// The ref updates sent to it, once they have been
+    sent: Vec<RefUpdate>,
+    /// Whether it takes objects from the pack
+    wants_objects: bool,
+    timings: SyncTimings,
+}
+
+impl FanOut {
+    /// Prepare to sync a source into the targets of `syncers`, which must all
+    /// share that source.  Batches aren't supported, since every target must
+    /// take the one pack.
+    pub fn new(syncers: Vec<Syncer>) -> Result<FanOut, Error> {
+        let source = match syncers.first() {
+            Some(syncer) => syncer.source.to_string(),
+            None => return Err(Error::Config("A fan-out needs a target".to_string())),
+        };
+        if let Some(syncer) = syncers.iter().find(|s| s.source.to_string() != source) {
+            return Err(Error::Config(format!(
+                "Cannot fan out from {} and {} at once, the targets must share a source",
+                source, syncer.source
+            )));
+        }
+        let mut targets: Vec<_> = syncers.iter().map(|s| s.target.to_string()).collect();
+        targets.sort();
+        if let Some(target) = targets.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(Error::Config(format!(
+                "Cannot sync into {} twice at once",
+                target[0]
+            )));
+        }
+        if syncers.iter().any(|s| s.options.batch_size.is_some()) {
+            return Err(Error::Config(
+                "Cannot push in batches when syncing into several targets".to_string(),
+            ));
+        }
+        if syncers
+            .iter()
+            .any(|s| s.options.spool.is_some() || s.options.save_pack.is_some())
+        {
+            return Err(Error::Config(
+                "Cannot spool or save the pack when syncing into several targets".to_string(),
+            ));
+        }
+        if syncers.iter().any(|s| s.options.from_pack.is_some()) {
+            return Err(Error::Config(
+                "Cannot push a saved pack when syncing into several targets".to_string(),
+            ));
+        }
+        if syncers.iter().any(|s| s.options.state_dir.is_some()) {
+            return Err(Error::Config(
+                "Cannot record the sync state when syncing into several targets".to_string(),
+            ));
+        }
+        Ok(FanOut { syncers })
+    }
+
+    /// The syncers for each target, in order
+    pub fn syncers(&self) -> &[Syncer] {
+        &self.syncers
+    }
+
+    /// Connect, plan and push into every target, returning what happened in each
+    /// in order.  A target which fails doesn't stop the others, but if the
+    /// source fails, or the fan-out times out or is cancelled as the first
+    /// syncer's options say, they all do, and that's the error returned.  A
+    /// fan-out is never tried again.
+    pub async fn run(&self) -> Result<Vec<Result<SyncOutcome, Error>>, Error> {
+        let span = tracing::info_span!(
+            "sync",
+            source = %self.syncers[0].source,
+            targets = self.syncers.len()
+        );
+        async {
+            let lead = &self.syncers[0];
+            let fan_out = async {
+                match lead.options.timeout {
+                    Some(limit) => timeout(limit, self.push_all())
+                        .await
+                        .map_err(|_| Error::TimedOut(Timeout::Sync))?,
+                    None => self.push_all().await,
+                }
+            };
+            let result = match &lead.cancellation {
+                Some(token) => tokio::select! {
+                    result = fan_out => result,
+                    _ = token.cancelled() => {
+                        for syncer in &self.syncers {
+                            syncer.emit(SyncEvent::Cancelled);
+                        }
+                        return Err(Error::Cancelled);
+                    }
+                },
+                None => fan_out.await,
+            };
+            match result {
+                Ok(results) => {
+                    for (syncer, result) in self.syncers.iter().zip(&results) {
+                        if let Some(hook) = &syncer.options.post_sync {
+                            syncer.run_post_sync(hook, result.as_ref()).await;
+                        }
+                    }
+                    Ok(results)
+                }
+                Err(err) => {
+                    for syncer in &self.syncers {
+                        if let Some(hook) = &syncer.options.post_sync {
+                            syncer.run_post_sync(hook, Err(&err)).await;
+                        }
+                    }
+                    Err(err)
+                }
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Fetch a pack with what all of the targets want from the source, and push
+    /// into each of them
+    async fn push_all(&self) -> Result<Vec<Result<SyncOutcome, Error>>, Error> {
+        let lead = &self.syncers[0];
+        let started = Instant::now();
+        let (mut upload_pack, source_advert) = lead.start(SyncSide::Source).await?;
+        for syncer in &self.syncers {
+            syncer.emit(SyncEvent::AdvertisementRead(
+                SyncSide::Source,
+                source_advert.clone(),
+            ));
+        }
+        let source_connect = started.elapsed();
+        let mut targets = Vec::with_capacity(self.syncers.len());
+        for syncer in &self.syncers {
+            let target = FanOut::prepare(syncer, &source_advert).await;
+            targets.push(target.map(|mut target| {
+                target.timings.connect += source_connect;
+                target
+            }));
+        }
+
+        // One pack must do for every target which needs objects, so it has what
+        // any of them wants, and is only made smaller by what all of them have
+        let mut wants = HashSet::new();
+        let mut haves: Option<HashSet<String>> = None;
+        for target in targets.iter_mut().filter_map(|target| target.as_mut().ok()) {
+            let wanted = wanted_objects(&target.target_advert, &target.plan.updates);
+            if wanted.is_empty() {
+                continue;
+            }
+            target.wants_objects = true;
+            wants.extend(wanted.into_iter().map(str::to_string));
+            let has = target.target_advert.refs().values().cloned().collect();
+            haves = Some(match haves {
+                Some(haves) => haves.intersection(&has).cloned().collect(),
+                None => has,
+            });
+        }
+        let haves = haves.unwrap_or_default();
+        let pushing = Instant::now();
+        let fetch_caps = lead.fetch_caps(&source_advert);
+        if !wants.is_empty() {
+            for syncer in &self.syncers {
+                syncer.emit(SyncEvent::CapabilitiesRequested(
+                    SyncSide::Source,
+                    fetch_caps.clone(),
+                ));
+            }
+        }
+        let negotiation = lead
+            .options
+            .negotiation_timeout
+            .map(|limit| Instant::now() + limit);
+        let requested = {
+            let (reader, writer) = upload_pack.streams();
+            let want_iter = wants.iter().map(String::as_str);
+            let have_iter = haves.iter().map(String::as_str);
+            let request = request_pack(
+                reader,
+                writer,
+                want_iter,
+                have_iter,
+                borrowed_caps(&fetch_caps),
+            );
+            by_deadline(negotiation, Timeout::Negotiation, request).await
+        };
+        if let Err(err) = requested {
+            return Err(FanOut::abort_all(err, Some(upload_pack), targets).await);
+        }
+
+        for slot in &mut targets {
+            let target = match slot {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+            let sent = send_commands(
+                target.syncer,
+                target.receive_pack.as_mut(),
+                &target.target_advert,
+                &target.plan.updates,
+                &target.push_caps,
+                &AtomicBool::new(false),
+            )
+            .await;
+            match sent {
+                Ok(sent) => target.sent = sent,
+                Err(err) => FanOut::drop_target(slot, err).await,
+            }
+        }
+
+        let mut pack = Relayed::default();
+        if !wants.is_empty() {
+            let (indices, mut sinks): (Vec<_>, Vec<_>) = targets
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(idx, slot)| slot.as_mut().ok().map(|target| (idx, target)))
+                .filter(|(_, target)| target.wants_objects && !target.sent.is_empty())
+                .map(|(idx, target)| {
+                    let sink = PackSink {
+                        syncer: target.syncer,
+                        receive_pack: target.receive_pack.as_mut(),
+                        failed: None,
+                    };
+                    (idx, sink)
+                })
+                .unzip();
+            let relayed = relay_pack(
+                lead,
+                upload_pack.as_mut(),
+                &mut sinks,
+                None,
+                None,
+                PackChecks {
+                    wanted: wants.len(),
+                    format: pack_format(&source_advert),
+                },
+                negotiation,
+            )
+            .await;
+            let failed: Vec<_> = indices
+                .into_iter()
+                .zip(sinks)
+                .filter_map(|(idx, sink)| sink.failed.map(|err| (idx, err)))
+                .collect();
+            match relayed {
+                Ok(relayed) => pack = relayed,
+                Err(err) => return Err(FanOut::abort_all(err, Some(upload_pack), targets).await),
+            }
+            for (idx, err) in failed {
+                FanOut::drop_target(&mut targets[idx], err).await;
+            }
+        }
+        if let Err(err) = upload_pack.shutdown().await {
+            return Err(FanOut::abort_all(err, None, targets).await);
+        }
+
+        let mut results = Vec::with_capacity(targets.len());
+        for target in targets {
+            results.push(match target {
+                Ok(target) => FanOut::finish(target, &source_advert, pushing, pack).await,
+                Err(err) => Err(err),
+            });
+        }
+        Ok(results)
+    }
+
+    /// Create the target if asked, connect to it, work out its updates and run
+    /// its pre-sync hook
+    async fn prepare<'a>(
+        syncer: &'a Syncer,
+        source_advert: &RefAdvertisement,
+    ) -> Result<FanOutTarget<'a>, Error> {
+        let started = Instant::now();
+        if syncer.options.create_target {
+            syncer.create_target(source_advert).await?;
+        }
+        let (receive_pack, target_advert) = syncer.start(SyncSide::Target).await?;
+        syncer.emit(SyncEvent::AdvertisementRead(
+            SyncSide::Target,
+            target_advert.clone(),
+        ));
+        let connected = Instant::now();
+        let planned = async {
+            let push_caps = syncer.push_caps(&target_advert)?;
+            let plan = syncer.plan_updates(source_advert, &target_advert).await?;
+            if let Some(hook) = &syncer.options.pre_sync {
+                syncer.run_pre_sync(hook, &plan).await?;
+            }
+            Ok::<_, Error>((push_caps, plan))
+        };
+        let (push_caps, plan) = match planned.await {
+            Ok(planned) => planned,
+            Err(err) => {
+                return Err(match receive_pack.abort().await {
+                    Err(failed @ Error::ChildFailed { .. }) => failed,
+                    _ => err,
+                })
+            }
+        };
+        Ok(FanOutTarget {
+            syncer,
+            receive_pack,
+            target_advert,
+            push_caps,
+            plan,
+            sent: Vec::new(),
+            wants_objects: false,
+            timings: SyncTimings {
+                connect: connected - started,
+                plan: connected.elapsed(),
+                ..SyncTimings::default()
+            },
+        })
+    }
+
+    /// Finish pushing into a target once the pack, if it wanted it, has been
+    /// relayed: read its report, shut it down and tidy it up
+    async fn finish(
+        mut target: FanOutTarget<'_>,
+        source_advert: &RefAdvertisement,
+        pushing: Instant,
+        pack: Relayed,
+    ) -> Result<SyncOutcome, Error> {
+        let syncer = target.syncer;
+        let expecting_to_send = SendActivity::for_updates(&target.sent);
+        let status = async {
+            if !target.wants_objects && matches!(expecting_to_send, SendActivity::Sending) {
+                // It has every object already, but receive-pack still expects a pack
+                target.receive_pack.writer().write_all(EMPTY_PACK).await?;
+            }
+            read_report(syncer, target.receive_pack.as_mut(), &expecting_to_send).await
+        };
+        let status = match status.await {
+            Ok(status) => status,
+            Err(err) => {
+                return Err(match target.receive_pack.abort().await {
+                    Err(failed @ Error::ChildFailed { .. }) => failed,
+                    _ => err,
+                })
+            }
+        };
+        target.receive_pack.shutdown().await?;
+
+        let report = SyncReport::new(&target.sent, &status);
+        for outcome in &report.outcomes {
+            syncer.emit(SyncEvent::RefResult(outcome.clone()));
+        }
+        syncer
+            .tidy_target(Some(source_advert), &target.plan.refused, &report)
+            .await?;
+        let relayed = target.wants_objects && !target.sent.is_empty();
+        let pack = if relayed { pack } else { Relayed::default() };
+        let outcome = SyncOutcome {
+            report,
+            refused: target.plan.refused,
+            pack_bytes: pack.bytes,
+            pack_objects: pack.objects,
+            negotiations: relayed as usize,
+            timings: SyncTimings {
+                push: pushing.elapsed(),
+                transfer: pack.took,
+                ..target.timings
+            },
+        };
+        tracing::info!(repo = %syncer.target, phase = "push", bytes = outcome.pack_bytes, rejected = outcome.report.rejected().count(), "Pushed {} ref update(s)", outcome.report.outcomes.len());
+        syncer.emit(SyncEvent::Completed(outcome.clone()));
+        Ok(outcome)
+    }
+
+    /// Give up on one target after `err`, stopping its receive-pack, and
+    /// preferring its own account of what went wrong if it had already failed
+    async fn drop_target(slot: &mut Result<FanOutTarget<'_>, Error>, err: Error) {
+        if let Ok(target) = std::mem::replace(slot, Err(Error::Cancelled)) {
+            *slot = Err(match target.receive_pack.abort().await {
+                Err(failed @ Error::ChildFailed { .. }) => failed,
+                _ => err,
+            });
+        }
+    }
+
+    /// Stop every service after `err` has ended the whole fan-out, preferring the
+    /// source's own account of what went wrong if it had already failed
+    async fn abort_all(
+        err: Error,
+        upload_pack: Option<Box<dyn Transport>>,
+        targets: Vec<Result<FanOutTarget<'_>, Error>>,
+    ) -> Error {
+        for target in targets.into_iter().flatten() {
+            let _ = target.receive_pack.abort().await;
+        }
+        let aborted = match upload_pack {
+            Some(upload_pack) => upload_pack.abort().await,
+            None => Ok(()),
+        };
+        match aborted {
+            Err(failed @ Error::ChildFailed { .. }) => failed,
+            _ => err,
+        }
+    }
+}
+
+/// What a two-way sync does about a ref which has moved on both sides since they
+/// last agreed, so that neither side's is a fast-forward of the other's
+///
+/// ```
+/// # use git_sync::ConflictPolicy;
+/// assert_eq!("prefer-b".parse::<ConflictPolicy>().unwrap(), ConflictPolicy::PreferB);
+/// assert_eq!(ConflictPolicy::Skip.to_string(), "skip");
+/// assert!("merge".parse::<ConflictPolicy>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Overwrite the ref in the second repository with the first's
+    PreferA,
+    /// Overwrite the ref in the first repository with the second's
+    PreferB,
+    /// Refuse to sync the ref either way, but sync the others
+    Skip,
+    /// Stop the sync before anything is pushed
+    Fail,
+}
+
+impl ConflictPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConflictPolicy::PreferA => "prefer-a",
+            ConflictPolicy::PreferB => "prefer-b",
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Fail => "fail",
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefer-a" => Ok(ConflictPolicy::PreferA),
+            "prefer-b" => Ok(ConflictPolicy::PreferB),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "fail" => Ok(ConflictPolicy::Fail),
+            _ => Err(format!(
+                "Expected prefer-a, prefer-b, skip or fail, not {}",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Syncs two repositories with each other.  Each ref which has moved on in one
+/// of them is fast-forwarded in the other, and each ref only one of them has is
+/// created in the other; nothing is deleted, since a ref one lacks can't be told
+/// apart from one the other has just created.  Refs which have moved in both are
+/// dealt with as the [`ConflictPolicy`] says.
+///
+/// It's done with a syncer for each direction, whose options say how to push
+/// that way, though only the first's say which refs to sync and how long the
+/// whole may take.  Both repositories must be able to run git commands, to work
+/// out which way each ref has moved.
+#[derive(Debug)]
+pub struct TwoWay {
+    a_to_b: Syncer,
+    b_to_a: Syncer,
+    policy: ConflictPolicy,
+}
+
+impl TwoWay {
+    /// Prepare to sync the source and target of `a_to_b` with each other, where
+    /// `b_to_a` syncs the other way
+    pub fn new(a_to_b: Syncer, b_to_a: Syncer, policy: ConflictPolicy) -> Result<TwoWay, Error> {
+        if a_to_b.source.to_string() != b_to_a.target.to_string()
+            || a_to_b.target.to_string() != b_to_a.source.to_string()
+        {
+            return Err(Error::Config(
+                "A two-way sync needs a syncer going each way between the same repositories"
+                    .to_string(),
+            ));
+        }
+        for endpoint in [&a_to_b.source, &a_to_b.target] {
+            if !endpoint.can_run_commands() {
+                return Err(Error::Config(format!(
+                    "Cannot sync both ways with {}, git commands cannot be run there",
+                    endpoint
+                )));
+            }
+        }
+        for options in [&a_to_b.options, &b_to_a.options] {
+            let plan = &options.plan;
+            if !plan.refspecs.is_empty()
+                || plan.strip_source_prefix.is_some()
+                || plan.dest_prefix.is_some()
+            {
+                return Err(Error::Config(
+                    "Cannot rename refs when syncing both ways".to_string(),
+                ));
+            }
+            if options.prune_only || options.set_head || options.create_target {
+                return Err(Error::Config(
+                    "Cannot prune, set HEAD or create a repository when syncing both ways"
+                        .to_string(),
+                ));
+            }
+            if options.save_pack.is_some() || options.from_pack.is_some() {
+                return Err(Error::Config(
+                    "Cannot save or push a saved pack when syncing both ways, which push two"
+                        .to_string(),
+                ));
+            }
+            if options.state_dir.is_some() {
+                return Err(Error::Config(
+                    "Cannot record the sync state when syncing both ways".to_string(),
+                ));
+            }
+        }
+        Ok(TwoWay {
+            a_to_b,
+            b_to_a,
+            policy,
+        })
+    }
+
+    /// The syncers for each direction, from the first repository to the second
+    /// and back
+    pub fn syncers(&self) -> [&Syncer; 2] {
+        [&self.a_to_b, &self.b_to_a]
+    }
+
+    /// Connect to both repositories, plan which way each ref goes and push both
+    /// ways, returning what happened going each way.  It's timed out and cancelled
+    /// as the first syncer's options say, and never tried again.
+    pub async fn run(&self) -> Result<(SyncOutcome, SyncOutcome), Error> {
+        let span = tracing::info_span!("sync", a = %self.a_to_b.source, b = %self.a_to_b.target);
+        async {
+            let lead = &self.a_to_b;
+            let both = async {
+                match lead.options.timeout {
+                    Some(limit) => timeout(limit, self.push_both())
+                        .await
+                        .map_err(|_| Error::TimedOut(Timeout::Sync))?,
+                    None => self.push_both().await,
+                }
+            };
+            let result = match &lead.cancellation {
+                Some(token) => tokio::select! {
+                    result = both => result,
+                    _ = token.cancelled() => {
+                        for syncer in self.syncers() {
+                            syncer.emit(SyncEvent::Cancelled);
+                        }
+                        return Err(Error::Cancelled);
+                    }
+                },
+                None => both.await,
+            };
+            let results = match &result {
+                Ok((a_to_b, b_to_a)) => [Ok(a_to_b), Ok(b_to_a)],
+                Err(err) => [Err(err), Err(err)],
+            };
+            for (syncer, result) in self.syncers().iter().zip(results) {
+                if let Some(hook) = &syncer.options.post_sync {
+                    syncer.run_post_sync(hook, result).await;
+                }
+            }
+            result
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Connect both ways, plan, and push into the second repository and then the
+    /// first
+    async fn push_both(&self) -> Result<(SyncOutcome, SyncOutcome), Error> {
+        let started = Instant::now();
+        let to_b = self.a_to_b.connect().await?;
+        let to_a = match self.b_to_a.connect().await {
+            Ok(session) => session,
+            Err(err) => {
+                to_b.abort().await;
+                return Err(err);
+            }
+        };
+        let connected = Instant::now();
+        let planned = async {
+            let (b_plan, a_plan) = self.plan(&to_b, &to_a).await?;
+            for (syncer, plan) in [(&self.a_to_b, &b_plan), (&self.b_to_a, &a_plan)] {
+                syncer.emit(SyncEvent::PlanComputed(plan.clone()));
+                if let Some(hook) = &syncer.options.pre_sync {
+                    syncer.run_pre_sync(hook, plan).await?;
+                }
+            }
+            Ok::<_, Error>((b_plan, a_plan))
+        };
+        let (b_plan, a_plan) = match planned.await {
+            Ok(plans) => plans,
+            Err(err) => {
+                to_b.abort().await;
+                to_a.abort().await;
+                return Err(err);
+            }
+        };
+        let planned = Instant::now();
+        let timings = |mut outcome: SyncOutcome| {
+            outcome.timings.connect = connected - started;
+            outcome.timings.plan = planned - connected;
+            outcome
+        };
+        let into_b = match to_b.push(b_plan).await {
+            Ok(outcome) => timings(outcome),
+            Err(err) => {
+                to_a.abort().await;
+                return Err(err);
+            }
+        };
+        let into_a = timings(to_a.push(a_plan).await?);
+        Ok((into_b, into_a))
+    }
+
+    /// Work out which way each ref is to go, returning the plans for pushing into
+    /// the second repository and into the first
+    async fn plan(
+        &self,
+        to_b: &SyncSession<'_>,
+        to_a: &SyncSession<'_>,
+    ) -> Result<(SyncPlan, SyncPlan), Error> {
+        let (a, b) = (&self.a_to_b.source, &self.a_to_b.target);
+        let plan_opts = &self.a_to_b.options.plan;
+        let a_refs = to_b.source_advert().refs();
+        let b_refs = to_a.source_advert().refs();
+        let names: BTreeSet<&String> = a_refs
+            .keys()
+            .chain(b_refs.keys())
+            .filter(|name| name.starts_with("refs/") && !name.ends_with("^{}"))
+            .filter(|name| plan_opts.map_source(name).is_some())
+            .collect();
+        let mut b_plan = SyncPlan::default();
+        let mut a_plan = SyncPlan::default();
+        let update = |name: &String, old: Option<&String>, new: &String| RefUpdate {
+            refname: name.clone(),
+            oldsha: old.map_or(NULLSHA, String::as_str).to_string(),
+            newsha: new.clone(),
+        };
+        let mut moved = Vec::new();
+        for name in names {
+            match (a_refs.get(name), b_refs.get(name)) {
+                (Some(ours), None) => b_plan.updates.push(update(name, None, ours)),
+                (None, Some(theirs)) => a_plan.updates.push(update(name, None, theirs)),
+                (Some(ours), Some(theirs)) if ours != theirs => moved.push((name, ours, theirs)),
+                _ => {}
+            }
+        }
+        // Whichever side has moved on has both objects, so can tell.  Should
+        // either check fail the plan fails with it, rather than taking the ref to
+        // have moved on both sides, which the policy might settle by force.
+        let pairs: Vec<_> = moved
+            .iter()
+            .map(|(_, ours, theirs)| (ours.as_str(), theirs.as_str()))
+            .collect();
+        let b_moved = b.ancestors(&pairs).await?;
+        let pairs: Vec<_> = moved
+            .iter()
+            .zip(&b_moved)
+            .filter(|(_, b_moved)| !**b_moved)
+            .map(|((_, ours, theirs), _)| (theirs.as_str(), ours.as_str()))
+            .collect();
+        let mut a_moved = a.ancestors(&pairs).await?.into_iter();
+        for ((name, ours, theirs), b_moved) in moved.into_iter().zip(b_moved) {
+            let update = |old: Option<&String>, new: &String| update(name, old, new);
+            if b_moved {
+                a_plan.updates.push(update(Some(ours), theirs));
+                continue;
+            }
+            if a_moved.next() == Some(true) {
+                b_plan.updates.push(update(Some(theirs), ours));
+                continue;
+            }
+            let protected = plan_opts.is_protected(name);
+            match self.policy {
+                ConflictPolicy::PreferA if !protected => {
+                    b_plan.updates.push(update(Some(theirs), ours))
+                }
+                ConflictPolicy::PreferB if !protected => {
+                    a_plan.updates.push(update(Some(ours), theirs))
+                }
+                ConflictPolicy::Fail => {
+                    return Err(Error::Refused(format!(
+                        "{} has moved in both {} and {}, so cannot be synced either way",
+                        name, a, b
+                    )))
+                }
+                _ => {
+                    let reason = match protected {
+                        true => "both sides have moved, and the ref is protected",
+                        false => "both sides have moved",
+                    };
+                    let into_b = update(Some(theirs), ours);
+                    self.a_to_b
+                        .emit(SyncEvent::Refused(into_b.clone(), reason.to_string()));
+                    b_plan.refused.push(into_b);
+                    let into_a = update(Some(ours), theirs);
+                    self.b_to_a
+                        .emit(SyncEvent::Refused(into_a.clone(), reason.to_string()));
+                    a_plan.refused.push(into_a);
+                }
+            }
+        }
+        tracing::info!(repo = %b, phase = "plan", into_a = a_plan.updates.len(), into_b = b_plan.updates.len(), refused = b_plan.refused.len(), "Planned the ref updates both ways");
+        Ok((b_plan, a_plan))
+    }
+}
+
+/// The object format a service which advertised `advert` uses, which is `sha1`
+/// unless it says otherwise
+fn object_format(advert: &RefAdvertisement) -> &str {
+    match advert.caps().get(&Capability::ObjectFormat) {
+        Some(Some(format)) => format,
+        _ => "sha1",
+    }
+}
+
+/// The object format whose hash the packs a source which advertised `advert`
+/// sends are checksummed with, or `None` if it's a format we can't check
+fn pack_format(advert: &RefAdvertisement) -> Option<ObjectFormat> {
+    ObjectFormat::from_name(object_format(advert))
+}
+
+/// Cross-check the object count in a pack against what we asked for
+fn check_object_count(syncer: &Syncer, header: &PackHeader, wanted: usize) -> Result<(), Error> {
+    let opts = &syncer.options;
+    if header.objects >= opts.min_objects {
+        return Ok(());
+    }
+    let msg = format!(
+        "Pack contains {} object(s) but at least {} were expected for {} wanted tip(s)",
+        header.objects, opts.min_objects, wanted
+    );
+    if opts.strict_object_check {
+        Err(Error::Protocol(msg))
+    } else {
+        syncer.emit(SyncEvent::Warning(msg));
+        Ok(())
+    }
+}
+
+/// Refuse a pack which is larger than the syncer's options permit, going by how
+/// many bytes of it there are, or have been so far, and how many objects its
+/// header says it has, once that has been seen
+fn check_pack_limits(syncer: &Syncer, bytes: u64, objects: Option<u64>) -> Result<(), Error> {
+    let opts = &syncer.options;
+    if let Some(max) = opts.max_pack_size.filter(|max| bytes > *max) {
+        return Err(Error::Refused(format!(
+            "Refusing a pack of more than {} ({} bytes)",
+            format_bytes(max),
+            max
+        )));
+    }
+    match (objects, opts.max_objects) {
+        (Some(objects), Some(max)) if objects > max.into() => Err(Error::Refused(format!(
+            "Refusing a pack of {} objects, more than the {} permitted",
+            objects, max
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Push a set of ref updates to the target, relaying whatever pack is needed from the source,
+/// and returning what the target made of them and what was relayed, if a pack was requested.
+/// Both services are shut down once the push is complete, or aborted if it fails.
+async fn push_updates(
+    syncer: &Syncer,
+    mut upload_pack: Box<dyn Transport>,
+    mut receive_pack: Box<dyn Transport>,
+    target_advert: &RefAdvertisement,
+    updates: &[RefUpdate],
+    requests: &PushRequests,
+    spooled: &mut Option<Spooled>,
+) -> Result<(SyncReport, Option<Relayed>), Error> {
+    let relayed = relay_updates(
+        syncer,
+        upload_pack.as_mut(),
+        receive_pack.as_mut(),
+        target_advert,
+        updates,
+        requests,
+        spooled,
+    )
+    .await;
+    let (sent, status, pack_bytes) = match relayed {
+        Ok(relayed) => relayed,
+        Err(err) => return Err(abort_services(err, upload_pack, receive_pack).await),
+    };
+    // We're done, let's close down our connections
+    upload_pack.shutdown().await?;
+    receive_pack.shutdown().await?;
+
+    let report = SyncReport::new(&sent, &status);
+    for outcome in &report.outcomes {
+        syncer.emit(SyncEvent::RefResult(outcome.clone()));
+    }
+
+    Ok((report, pack_bytes))
+}
+
+/// Tell a pair of services which have advertised their refs that nothing more is
+/// wanted of them, and wait for them to finish
+async fn close_services(
+    mut upload_pack: Box<dyn Transport>,
+    mut receive_pack: Box<dyn Transport>,
+) -> Result<(), Error> {
+    // Empty requests tell both services that we want nothing of them
+    ProtocolLine::Flush.write_to(upload_pack.writer()).await?;
+    ProtocolLine::Flush.write_to(receive_pack.writer()).await?;
+    upload_pack.shutdown().await?;
+    receive_pack.shutdown().await?;
+    Ok(())
+}
+
+/// Stop a pair of services after `err` has ended a sync, preferring their own
+/// account of what went wrong if they had already failed
+async fn abort_services(
+    err: Error,
+    upload_pack: Box<dyn Transport>,
+    receive_pack: Box<dyn Transport>,
+) -> Error {
+    match (upload_pack.abort().await, receive_pack.abort().await) {
+        (Err(failed @ Error::ChildFailed { .. }), _)
+        | (_, Err(failed @ Error::ChildFailed { .. })) => failed,
+        _ => err,
+    }
+}
+
+/// How long to wait before the first retry of a sync, doubling for each after
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest wait before retrying a sync
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How long to wait before trying a sync again after its `attempt`th failure:
+/// twice as long as the time before, up to a limit, less up to half of that at
+/// random so that syncs which failed together don't all retry together
+fn retry_delay(attempt: usize) -> Duration {
+    let doubled = RETRY_DELAY.saturating_mul(1 << (attempt - 1).min(16));
+    let delay = doubled.min(MAX_RETRY_DELAY);
+    let random = RandomState::new().build_hasher().finish();
+    delay - (delay / 2).mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// Capabilities as the protocol functions take them
+fn borrowed_caps(
+    caps: &[(Capability, Option<String>)],
+) -> impl Iterator<Item = (Capability, Option<&str>)> {
+    caps.iter().map(|(cap, value)| (*cap, value.as_deref()))
+}
+
+/// A pack spooled for a push, with the objects it was asked for and those the
+/// target had, so that it's only used again for the same request
+#[derive(Debug)]
+struct Spooled {
+    pack: SpooledPack,
+    wants: BTreeSet<String>,
+    haves: BTreeSet<String>,
+    /// What was fetched into the spool
+    fetched: Relayed,
+}
+
+/// How much of a spooled pack is pushed at a time
+const SPOOL_CHUNK: usize = 64 * 1024;
+
+/// Request a pack from upload-pack and relay it to receive-pack along with the ref
+/// updates, returning the commands sent, what receive-pack made of them and what
+/// was relayed, if a pack was requested.  A spooled pack is pushed instead of
+/// fetching one if it was asked for in the same way, and a spooled pack which
+/// couldn't be pushed is kept.
+async fn relay_updates(
+    syncer: &Syncer,
+    upload_pack: &mut dyn Transport,
+    receive_pack: &mut dyn Transport,
+    target_advert: &RefAdvertisement,
+    updates: &[RefUpdate],
+    requests: &PushRequests,
+    spooled: &mut Option<Spooled>,
+) -> Result<(Vec<RefUpdate>, ReportStatus, Option<Relayed>), Error> {
+    let PushRequests {
+        fetch_caps,
+        push_caps,
+        pack_format,
+        known,
+        commands_sent,
+    } = requests;
+    let opts = &syncer.options;
+    // Compute the set of things we want to fetch, and the set of things we already have
+    let wants = wanted_objects(target_advert, updates);
+    let haves: HashSet<_> = target_advert
+        .refs()
+        .values()
+        .chain(known)
+        .map(String::as_str)
+        .collect();
+    let owned = |set: &HashSet<&str>| set.iter().map(|sha| sha.to_string()).collect();
+    let (wants_owned, haves_owned): (BTreeSet<_>, BTreeSet<_>) = (owned(&wants), owned(&haves));
+    let reused = spooled.take().filter(|spooled| {
+        opts.spool.is_some() && spooled.wants == wants_owned && spooled.haves == haves_owned
+    });
+    let expecting_pack_data = !wants.is_empty() && reused.is_none();
+    // A push of nothing mustn't replace the last pack saved
+    let mut save = match &opts.save_pack {
+        Some(path) if !updates.is_empty() => {
+            Some(PackSaver::create(path, haves.iter().copied(), updates).await?)
+        }
+        _ => None,
+    };
+    if expecting_pack_data {
+        syncer.emit(SyncEvent::CapabilitiesRequested(
+            SyncSide::Source,
+            fetch_caps.clone(),
+        ));
+    }
+    // Finally send that out to the upload_pack service so it knows what to send to us.
+    // It has until the negotiation timeout to start sending the pack, and from then
+    // on the pack mustn't stop for longer than the idle timeout.
+    let negotiation = opts.negotiation_timeout.map(|limit| Instant::now() + limit);
+    {
+        let (reader, writer) = upload_pack.streams();
+        // A pack already spooled needn't be asked for again
+        let want_iter = wants.iter().copied().filter(|_| reused.is_none());
+        let have_iter = haves.iter().copied();
+        let request = request_pack(
+            reader,
+            writer,
+            want_iter,
+            have_iter,
+            borrowed_caps(fetch_caps),
+        );
+        by_deadline(negotiation, Timeout::Negotiation, request).await?;
+    }
+
+    let spool = match (&opts.spool, reused) {
+        (_, Some(reused)) => {
+            tracing::info!(repo = %syncer.target, phase = "push", bytes = reused.pack.size(), "Pushing the pack spooled by the last attempt");
+            // None of it was fetched this time
+            let fetched = Relayed {
+                took: Duration::ZERO,
+                ..reused.fetched
+            };
+            Some(Spooled { fetched, ..reused })
+        }
+        (Some(dir), None) if expecting_pack_data => {
+            let mut spool = PackSpool::create(dir, *pack_format, opts.spool_codec).await?;
+            let fetched = relay_pack(
+                syncer,
+                upload_pack,
+                &mut [],
+                Some(&mut spool),
+                None,
+                // The spool checks the pack itself
+                PackChecks {
+                    wanted: wants.len(),
+                    format: None,
+                },
+                negotiation,
+            )
+            .await?;
+            let pack = spool.finish().await?;
+            tracing::debug!(repo = %syncer.source, phase = "push", path = %pack.path().display(), "Spooled the pack");
+            Some(Spooled {
+                pack,
+                wants: wants_owned,
+                haves: haves_owned,
+                fetched,
+            })
+        }
+        _ => None,
+    };
+    if let Some(spool) = spool {
+        if let Some(mut save) = save {
+            save.write_spooled(&spool.pack).await?;
+            save.finish().await?;
+        }
+        // Until the target has the whole pack it can't have taken any of the
+        // updates, so a push which fails before then may be tried again
+        let pushed = async {
+            let not_yet = AtomicBool::new(false);
+            let sent = send_commands(
+                syncer,
+                receive_pack,
+                target_advert,
+                updates,
+                push_caps,
+                &not_yet,
+            )
+            .await?;
+            replay_pack(syncer, receive_pack, spool.pack.open().await?).await?;
+            Ok::<_, Error>(sent)
+        };
+        let sent = match pushed.await {
+            Ok(sent) => sent,
+            Err(err) => {
+                *spooled = Some(spool);
+                return Err(err);
+            }
+        };
+        commands_sent.store(true, Ordering::Relaxed);
+        let status = read_report(syncer, receive_pack, &SendActivity::for_updates(&sent)).await?;
+        return Ok((sent, status, Some(spool.fetched)));
+    }
+
+    // Now let's ensure that we're doing *something* to the target
+    let sent = send_commands(
+        syncer,
+        receive_pack,
+        target_advert,
+        updates,
+        push_caps,
+        commands_sent,
+    )
+    .await?;
+    let expecting_to_send = SendActivity::for_updates(&sent);
+
+    // Now relay the pack data, if there is any
+    let mut relayed = None;
+    if expecting_pack_data {
+        let mut sinks = [PackSink {
+            syncer,
+            receive_pack: &mut *receive_pack,
+            failed: None,
+        }];
+        let relay = relay_pack(
+            syncer,
+            upload_pack,
+            &mut sinks,
+            None,
+            save.as_mut(),
+            PackChecks {
+                wanted: wants.len(),
+                format: *pack_format,
+            },
+            negotiation,
+        );
+        relayed = Some(relay.await?);
+        if let Some(err) = sinks[0].failed.take() {
+            return Err(err);
+        }
+    } else if matches!(expecting_to_send, SendActivity::Sending) {
+        // We have no objects to send, but receive-pack still expects a pack
+        receive_pack.writer().write_all(EMPTY_PACK).await?;
+        if let Some(save) = &mut save {
+            save.write(EMPTY_PACK).await?;
+        }
+    }
+    if let Some(save) = save {
+        tracing::info!(repo = %syncer.target, phase = "push", path = %save.path().display(), "Saving a copy of the pack");
+        save.finish().await?;
+    }
+
+    let status = read_report(syncer, receive_pack, &expecting_to_send).await?;
+    Ok((sent, status, relayed))
+}
+
+/// The objects the source must send for the target to take `updates`, leaving out
+/// any it already has
+fn wanted_objects<'a>(
+    target_advert: &RefAdvertisement,
+    updates: &'a [RefUpdate],
+) -> HashSet<&'a str> {
+    updates
+        .iter()
+        .filter(|update| !update.is_delete())
+        .filter(|update| !target_advert.refs().values().any(|v| *v == update.newsha))
+        .map(|update| update.newsha.as_str())
+        .collect()
+}
+
+/// Send the ref updates to receive-pack, signed if asked, returning the commands
+/// sent
+async fn send_commands(
+    syncer: &Syncer,
+    receive_pack: &mut dyn Transport,
+    target_advert: &RefAdvertisement,
+    updates: &[RefUpdate],
+    push_caps: &[(Capability, Option<String>)],
+    commands_sent: &AtomicBool,
+) -> Result<Vec<RefUpdate>, Error> {
+    let sent = if let Some(signer) = &syncer.options.sign_with {
+        let nonce = match target_advert.caps().get(&Capability::PushCert) {
+            Some(Some(nonce)) => nonce,
+            _ => return Err(Error::MissingCapability(Capability::PushCert)),
+        };
+        let pusher = committer_ident().await?;
+        let pushee = syncer.target.to_string();
+        let cert = PushCert {
+            pusher: &pusher,
+            pushee: Some(&pushee),
+            nonce,
+            updates,
+        };
+        let cert = cert.sign(signer).await?;
+        commands_sent.store(true, Ordering::Relaxed);
+        send_push_cert(
+            receive_pack.writer(),
+            updates,
+            &cert,
+            borrowed_caps(push_caps),
+        )
+        .await?
+    } else {
+        commands_sent.store(true, Ordering::Relaxed);
+        send_ref_updates(receive_pack.writer(), updates, borrowed_caps(push_caps)).await?
+    };
+    syncer.emit(SyncEvent::CapabilitiesRequested(
+        SyncSide::Target,
+        push_caps.to_vec(),
+    ));
+    Ok(sent)
+}
+
+/// What a pack being relayed is checked against
+#[derive(Debug, Clone, Copy)]
+struct PackChecks {
+    /// How many tips were asked for, to say so if the pack has too few objects
+    wanted: usize,
+    /// The object format whose checksum the pack ends with, if it's to be
+    /// checked before the sinks are given the end of the pack
+    format: Option<ObjectFormat>,
+}
+
+/// A pack relayed from the source: how many bytes and objects it had, and how
+/// long it took to flow
+#[derive(Debug, Clone, Copy, Default)]
+struct Relayed {
+    bytes: u64,
+    objects: u64,
+    took: Duration,
+}
+
+/// A receive-pack which a pack is being relayed into, and what stopped it taking
+/// the rest, if anything did
+struct PackSink<'a> {
+    syncer: &'a Syncer,
+    receive_pack: &'a mut dyn Transport,
+    failed: Option<Error>,
+}
+
+/// How many frames of pack data may be read from upload-pack ahead of their
+/// being written out, about a megabyte of them at most
+const RELAY_DEPTH: usize = 16;
+
+/// The most pack data written out at once, from as many frames as have been
+/// read and are waiting
+const RELAY_WRITE: usize = 512 * 1024;
+
+/// Relay the pack upload-pack sends into each of the sinks, or into the spool if
+/// there is one, and into the saved copy if one is being kept, as fast as the
+/// syncer's options permit, returning what was relayed.  Reading the pack and
+/// writing it out go on at once, with a few frames between them, so that each
+/// side's delays needn't hold up the other.  A sink which can't keep up is left
+/// behind with the reason, and the relaying stops early if every sink has been.
+async fn relay_pack(
+    syncer: &Syncer,
+    upload_pack: &mut dyn Transport,
+    sinks: &mut [PackSink<'_>],
+    spool: Option<&mut PackSpool>,
+    save: Option<&mut PackSaver>,
+    checks: PackChecks,
+    negotiation: Option<Instant>,
+) -> Result<Relayed, Error> {
+    if spool.is_none() && sinks.iter().all(|sink| sink.failed.is_some()) {
+        return Ok(Relayed::default());
+    }
+    let (frames, received) = mpsc::channel(RELAY_DEPTH);
+    let read = read_pack(syncer, upload_pack, frames, checks.wanted, negotiation);
+    let write = write_pack(syncer, received, sinks, (spool, save), checks.format);
+    let ((flowing, objects), bytes) = tokio::try_join!(read, write)?;
+    let took = flowing.map_or(Duration::ZERO, |flowing: Instant| flowing.elapsed());
+    Ok(Relayed {
+        bytes,
+        objects,
+        took,
+    })
+}
+
+/// Read the pack upload-pack sends, passing on the data of each frame of it,
+/// sharing the buffer it was read into, until the pack ends or nothing is taking
+/// the frames any more, and returning when it started to flow, if it did, and
+/// how many objects its header said it has
+async fn read_pack(
+    syncer: &Syncer,
+    upload_pack: &mut dyn Transport,
+    frames: mpsc::Sender<Bytes>,
+    wanted: usize,
+    negotiation: Option<Instant>,
+) -> Result<(Option<Instant>, u64), Error> {
+    let opts = &syncer.options;
+    let mut scanner = PackHeaderScanner::new();
+    let mut throttle = opts
+        .max_bandwidth
+        .map(|rate| Throttle::new(rate, Instant::now()));
+    let mut packets = PacketReader::new();
+    let mut flowing = None;
+    let mut bytes = 0;
+    loop {
+        let (deadline, limit) = match flowing {
+            None => (negotiation, Timeout::Negotiation),
+            Some(_) => (
+                opts.idle_timeout.map(|limit| Instant::now() + limit),
+                Timeout::Idle,
+            ),
+        };
+        let packet = packets.read_from(upload_pack.reader());
+        match by_deadline(deadline, limit, packet).await? {
+            Packet::Data(frame) => match frame.first() {
+                // An empty packet carries nothing, not even a sideband channel
+                None => {}
+                Some(1) => {
+                    let data = frame.slice(1..);
+                    flowing.get_or_insert_with(Instant::now);
+                    if let Some(header) = scanner.feed(&data) {
+                        check_object_count(syncer, &header, wanted)?;
+                        syncer.emit(SyncEvent::PackObjects(header.objects.into()));
+                    }
+                    // Whatever would make the pack too big isn't passed on, so
+                    // the target never has the whole of it
+                    bytes += data.len() as u64;
+                    let objects = scanner.header().map(|header| header.objects.into());
+                    check_pack_limits(syncer, bytes, objects)?;
+                    // We need to send this content on to the receivers, as fast
+                    // as we're permitted to
+                    if let Some(throttle) = &mut throttle {
+                        throttle.take(data.len()).await;
+                    }
+                    if frames.send(data).await.is_err() {
+                        break;
+                    }
+                }
+                // Without multi-ack, upload-pack acknowledges again each have it
+                // already knew we had from an earlier one, before the pack starts
+                _ if flowing.is_none() && frame.starts_with(b"ACK ") => {}
+                Some(channel) => sideband(syncer, SyncSide::Source, *channel, &frame[1..]),
+            },
+            Packet::Flush => break,
+            packet => {
+                syncer.emit(SyncEvent::Warning(format!(
+                    "Unexpected {:?} from upload-pack",
+                    packet.as_line()
+                )));
+                break;
+            }
+        }
+    }
+    let objects = scanner.header().map_or(0, |header| header.objects.into());
+    Ok((flowing, objects))
+}
+
+/// Write out the pack data [`read_pack`] passes on, into the spool, saved copy
+/// and sinks, taking as many frames of it at once as are waiting, until there
+/// are no more or nothing is left to take them, returning how many bytes were
+/// written.  If the pack is to be checked against the checksum it ends with,
+/// the sinks aren't given that checksum until it has been, so that a pack
+/// which doesn't match it never reaches them whole.
+async fn write_pack(
+    syncer: &Syncer,
+    mut frames: mpsc::Receiver<Bytes>,
+    sinks: &mut [PackSink<'_>],
+    (mut spool, mut save): (Option<&mut PackSpool>, Option<&mut PackSaver>),
+    format: Option<ObjectFormat>,
+) -> Result<u64, Error> {
+    let format = format.filter(|_| !sinks.is_empty());
+    let mut checksum = format.map(PackChecksum::for_format);
+    let holding = format.map_or(0, ObjectFormat::checksum_len);
+    let mut held = Vec::new();
+    let mut bytes = 0;
+    let mut batch = Vec::with_capacity(RELAY_DEPTH);
+    while spool.is_some() || sinks.iter().any(|sink| sink.failed.is_none()) {
+        match frames.recv().await {
+            Some(frame) => batch.push(frame),
+            None => {
+                finish_sinks(syncer, sinks, checksum, &held, bytes).await?;
+                break;
+            }
+        }
+        let mut batched = batch[0].len();
+        while batched < RELAY_WRITE {
+            match frames.try_recv() {
+                Ok(frame) => {
+                    batched += frame.len();
+                    batch.push(frame);
+                }
+                Err(_) => break,
+            }
+        }
+        bytes += batched as u64;
+        let data: Vec<&[u8]> = batch.iter().map(|frame| &frame[..]).collect();
+        if let Some(spool) = &mut spool {
+            for data in &data {
+                spool.write(data).await?;
+            }
+            syncer.emit(SyncEvent::PackBytes(bytes));
+        }
+        if let Some(save) = &mut save {
+            for data in &data {
+                save.write(data).await?;
+            }
+        }
+        if let Some(checksum) = &mut checksum {
+            for data in &data {
+                checksum.feed(data);
+            }
+        }
+        held = {
+            let pieces: Vec<&[u8]> = std::iter::once(&held[..]).chain(data).collect();
+            let (before, rest) = hold_back(&pieces, holding);
+            write_to_sinks(syncer, sinks, &before, bytes).await;
+            rest
+        };
+        batch.clear();
+    }
+    Ok(bytes)
+}
+
+/// Once the whole pack has been read, check it against its checksum, if it's to
+/// be checked, and give the sinks the end of it which was `held` back until then
+async fn finish_sinks(
+    syncer: &Syncer,
+    sinks: &mut [PackSink<'_>],
+    checksum: Option<PackChecksum>,
+    held: &[u8],
+    bytes: u64,
+) -> Result<(), Error> {
+    if let Some(checksum) = checksum {
+        checksum
+            .check()
+            .map_err(|err| Error::Protocol(format!("The pack from the source {}", err)))?;
+    }
+    if !held.is_empty() {
+        write_to_sinks(syncer, sinks, &[held], bytes).await;
+    }
+    Ok(())
+}
+
+/// Write pieces of the pack into each sink which hasn't failed, within the idle
+/// timeout, leaving behind any which fail
+async fn write_to_sinks(syncer: &Syncer, sinks: &mut [PackSink<'_>], pieces: &[&[u8]], bytes: u64) {
+    let idle = syncer.options.idle_timeout;
+    for sink in sinks.iter_mut().filter(|sink| sink.failed.is_none()) {
+        let write = write_all_vectored(sink.receive_pack.writer(), pieces);
+        let deadline = idle.map(|limit| Instant::now() + limit);
+        match by_deadline(deadline, Timeout::Idle, write).await {
+            Ok(()) => sink.syncer.emit(SyncEvent::PackBytes(bytes)),
+            Err(err) => sink.failed = Some(err),
+        }
+    }
+}
+
+/// Split `pieces` of data into those before their last `len` bytes, and a copy
+/// of those bytes
+fn hold_back<'a>(pieces: &[&'a [u8]], len: usize) -> (Vec<&'a [u8]>, Vec<u8>) {
+    let total: usize = pieces.iter().map(|piece| piece.len()).sum();
+    let mut keep = total.saturating_sub(len);
+    let mut before = Vec::with_capacity(pieces.len());
+    let mut held = Vec::with_capacity(len);
+    for piece in pieces {
+        let split = keep.min(piece.len());
+        keep -= split;
+        if split > 0 {
+            before.push(&piece[..split]);
+        }
+        held.extend_from_slice(&piece[split..]);
+    }
+    (before, held)
+}
+
+/// Write all of `bufs` in turn, as few writes as the writer will take them in
+async fn write_all_vectored(
+    writer: &mut (dyn AsyncWrite + Unpin + Send),
+    bufs: &[&[u8]],
+) -> io::Result<()> {
+    let (mut buf, mut offset) = (0, 0);
+    while buf < bufs.len() {
+        let slices: Vec<IoSlice<'_>> = std::iter::once(&bufs[buf][offset..])
+            .chain(bufs[buf + 1..].iter().copied())
+            .map(IoSlice::new)
+            .collect();
+        let mut written =
+            poll_fn(|cx| Pin::new(&mut *writer).poll_write_vectored(cx, &slices)).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        // Move past what was written, which may end partway through a buffer
+        while buf < bufs.len() && written >= bufs[buf].len() - offset {
+            written -= bufs[buf].len() - offset;
+            buf += 1;
+            offset = 0;
+        }
+        offset += written;
+    }
+    Ok(())
+}
+
+/// Push a pack kept in a file into receive-pack, a piece at a time, each within
+/// the idle timeout
+async fn replay_pack(
+    syncer: &Syncer,
+    receive_pack: &mut dyn Transport,
+    mut file: impl AsyncRead + Unpin,
+) -> Result<(), Error> {
+    let idle = syncer.options.idle_timeout;
+    let mut buf = vec![0; SPOOL_CHUNK];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        let write = receive_pack.writer().write_all(&buf[..read]);
+        let deadline = idle.map(|limit| Instant::now() + limit);
+        by_deadline(deadline, Timeout::Idle, write).await?;
+    }
+}
+
+/// Read what receive-pack made of the ref updates sent to it, if it was sent any
+async fn read_report(
+    syncer: &Syncer,
+    receive_pack: &mut dyn Transport,
+    expecting_to_send: &SendActivity,
+) -> Result<ReportStatus, Error> {
+    if matches!(expecting_to_send, SendActivity::Nothing) {
+        return Ok(ReportStatus::default());
+    }
+    // We've now sent the pack to the other end, let's read the receive pack
+    // output, which is the report a line at a time
+    let mut report = ReportParser::new();
+    loop {
+        match ProtocolLine::read_from(receive_pack.reader(), false).await? {
+            ProtocolLine::Data(cow) => match cow.first() {
+                // An empty packet carries nothing, not even a sideband channel
+                None => {}
+                Some(1) => report.feed(&cow[1..])?,
+                Some(channel) => sideband(syncer, SyncSide::Target, *channel, &cow[1..]),
+            },
+            ProtocolLine::Flush => break,
+            l => {
+                syncer.emit(SyncEvent::Warning(format!(
+                    "Unexpected {:?} from receive-pack",
+                    l
+                )));
+                break;
+            }
+        }
+    }
+    report.finish()
+}
+
+/// Wait for a step of a sync, failing with the given timeout if it isn't done by
+/// the deadline, if there is one
+async fn by_deadline<T, E: Into<Error>>(
+    deadline: Option<Instant>,
+    limit: Timeout,
+    step: impl Future<Output = Result<T, E>>,
+) -> Result<T, Error> {
+    let result = match deadline {
+        Some(deadline) => timeout_at(deadline, step)
+            .await
+            .map_err(|_| Error::TimedOut(limit))?,
+        None => step.await,
+    };
+    result.map_err(Into::into)
+}
+
+/// Pass on what a service sent on a sideband channel other than the data channel
+fn sideband(syncer: &Syncer, side: SyncSide, channel: u8, data: &[u8]) {
+    let message = String::from_utf8_lossy(data).into_owned();
+    match channel {
+        2 if syncer.options.remote_progress => {
+            for update in ProgressUpdate::parse_all(&message) {
+                syncer.emit(SyncEvent::Progress(side, update));
+            }
+            syncer.emit(SyncEvent::RemoteProgress(side, message))
+        }
+        2 => {}
+        3 => syncer.emit(SyncEvent::RemoteError(side, message)),
+        _ => syncer.emit(SyncEvent::Warning(format!(
+            "Received {} bytes on channel {} from the {}",
+            data.len(),
+            channel,
+            side
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::{Context, Poll};
+
+    use tokio::io::DuplexStream;
+
+    use sha1::{Digest, Sha1};
+
+    use crate::{duplex_transport, DuplexTransport};
+
+    const ONE: &str = "1111111111111111111111111111111111111111";
+    const TWO: &str = "2222222222222222222222222222222222222222";
+
+    /// The tree git knows without its being stored, for commits of nothing
+    const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+    /// What a fake service was sent
+    #[derive(Debug, Default)]
+    struct Received {
+        /// The wants, haves or commands, without any capabilities after a NUL
+        lines: Vec<String>,
+        /// The pack data, as much of it as arrived
+        pack: Vec<u8>,
+    }
+
+    /// A pack whose header says it has `objects` objects, with `body` for them
+    /// and the checksum of it all at the end
+    fn fake_pack(objects: u32, body: &[u8]) -> Vec<u8> {
+        let mut pack = b"PACK\0\0\0\x02".to_vec();
+        pack.extend_from_slice(&objects.to_be_bytes());
+        pack.extend_from_slice(body);
+        let checksum = Sha1::digest(&pack);
+        pack.extend_from_slice(&checksum);
+        pack
+    }
+
+    /// Refs as a fake service is given them, by name
+    fn owned_refs(refs: &[(&str, &str)]) -> Vec<(String, String)> {
+        refs.iter()
+            .map(|(refname, sha)| (refname.to_string(), sha.to_string()))
+            .collect()
+    }
+
+    /// A line as a packet
+    fn pkt(line: &str) -> Vec<u8> {
+        format!("{:04x}{}", line.len() + 4, line).into_bytes()
+    }
+
+    /// Advertise `refs`, with `caps` on the first line
+    async fn advertise(
+        stream: &mut DuplexStream,
+        refs: &[(String, String)],
+        caps: &str,
+    ) -> Result<(), Error> {
+        if refs.is_empty() {
+            let line = format!("{} capabilities^{{}}\0{}\n", NULLSHA, caps);
+            ProtocolLine::write_str(stream, line).await?;
+        }
+        for (idx, (refname, sha)) in refs.iter().enumerate() {
+            let caps = match idx {
+                0 => format!("\0{}", caps),
+                _ => String::new(),
+            };
+            ProtocolLine::write_str(stream, format!("{} {}{}\n", sha, refname, caps)).await?;
+        }
+        ProtocolLine::Flush.write_to(stream).await
+    }
+
+    /// Read packets up to a flush, or up to `end` if there is one, as lines
+    async fn read_lines(
+        stream: &mut DuplexStream,
+        end: Option<&str>,
+    ) -> Result<Vec<String>, Error> {
+        let mut lines = Vec::new();
+        while let ProtocolLine::Data(data) = ProtocolLine::read_from(stream, true).await? {
+            let line = String::from_utf8_lossy(&data);
+            if Some(&*line) == end {
+                break;
+            }
+            lines.extend(line.split('\0').next().map(str::to_string));
+        }
+        Ok(lines)
+    }
+
+    /// A fake upload-pack advertising `refs`, which sends `frames` of pack data
+    /// once it's asked for a pack
+    async fn upload_pack(
+        mut stream: DuplexStream,
+        refs: Vec<(String, String)>,
+        frames: Vec<Vec<u8>>,
+    ) -> Result<Received, Error> {
+        advertise(
+            &mut stream,
+            &refs,
+            "side-band-64k ofs-delta thin-pack agent=fake",
+        )
+        .await?;
+        let mut received = Received {
+            lines: read_lines(&mut stream, None).await?,
+            ..Received::default()
+        };
+        if received.lines.is_empty() {
+            return Ok(received);
+        }
+        received
+            .lines
+            .extend(read_lines(&mut stream, Some("done")).await?);
+        ProtocolLine::write_str(&mut stream, "NAK\n").await?;
+        for frame in frames {
+            let data: Vec<u8> = std::iter::once(1).chain(frame).collect();
+            ProtocolLine::Data(data.into())
+                .write_to(&mut stream)
+                .await?;
+        }
+        ProtocolLine::Flush.write_to(&mut stream).await?;
+        Ok(received)
+    }
+
+    /// A fake receive-pack advertising `refs`, which reads the commands and any
+    /// pack sent to it, and then sends `report` back in frames of `frame` bytes
+    async fn receive_pack(
+        mut stream: DuplexStream,
+        refs: Vec<(String, String)>,
+        report: Vec<u8>,
+        frame: usize,
+    ) -> Result<Received, Error> {
+        let caps = "report-status delete-refs side-band-64k ofs-delta agent=fake";
+        advertise(&mut stream, &refs, caps).await?;
+        let mut received = Received {
+            lines: read_lines(&mut stream, None).await?,
+            ..Received::default()
+        };
+        let deletes_only = received
+            .lines
+            .iter()
+            .all(|line| line.split(' ').nth(1) == Some(NULLSHA));
+        if received.lines.is_empty() {
+            return Ok(received);
+        }
+        if !deletes_only {
+            // Nothing but the checksum marks where the pack ends
+            let mut checksum = PackChecksum::new();
+            let mut buf = [0; 1024];
+            while !checksum.is_whole() {
+                let read = stream.read(&mut buf).await?;
+                if read == 0 {
+                    return Ok(received);
+                }
+                checksum.feed(&buf[..read]);
+                received.pack.extend_from_slice(&buf[..read]);
+            }
+        }
+        for chunk in report.chunks(frame) {
+            let data: Vec<u8> = std::iter::once(1).chain(chunk.iter().copied()).collect();
+            ProtocolLine::Data(data.into())
+                .write_to(&mut stream)
+                .await?;
+        }
+        ProtocolLine::Flush.write_to(&mut stream).await?;
+        Ok(received)
+    }
+
+    /// A syncer between fake services, in which git commands can't be run
+    fn fake_syncer(options: SyncOptions) -> Syncer {
+        let endpoint = |name: &str| {
+            let url = RemoteUrl::Ext(format!("ext::fake-{} %s", name));
+            Endpoint::new(url, ConnectOptions::default()).unwrap()
+        };
+        Syncer::new(endpoint("source"), endpoint("target"), options).unwrap()
+    }
+
+    /// A session of the syncer with services at the other ends of the transports
+    async fn fake_session(
+        syncer: &Syncer,
+        upload_pack: DuplexTransport,
+        receive_pack: DuplexTransport,
+    ) -> Result<SyncSession<'_>, Error> {
+        let mut upload_pack: Box<dyn Transport> = Box::new(upload_pack);
+        let source_advert = RefAdvertisement::read_from(upload_pack.reader()).await?;
+        let mut receive_pack: Box<dyn Transport> = Box::new(receive_pack);
+        let target_advert = RefAdvertisement::read_from(receive_pack.reader()).await?;
+        let (source, target) = ((upload_pack, source_advert), (receive_pack, target_advert));
+        syncer.session(source, target, Vec::new()).await
+    }
+
+    /// Sync from a fake upload-pack advertising `source` and sending `pack` in
+    /// frames of `frame` bytes, into a fake receive-pack advertising `target`
+    /// which reports all went well, returning the outcome and what each was sent
+    async fn sync_fakes(
+        options: SyncOptions,
+        source: &[(&str, &str)],
+        target: &[(&str, &str)],
+        pack: &[u8],
+        frame: usize,
+    ) -> (Result<SyncOutcome, Error>, Received, Received) {
+        let mut report = pkt("unpack ok\n");
+        for (refname, _) in source.iter().chain(target) {
+            report.extend(pkt(&format!("ok {}\n", refname)));
+        }
+        report.extend_from_slice(b"0000");
+        let (up, up_stream) = duplex_transport(1 << 20);
+        let (rp, rp_stream) = duplex_transport(1 << 20);
+        let frames = pack.chunks(frame).map(<[u8]>::to_vec).collect();
+        let upload = tokio::spawn(upload_pack(up_stream, owned_refs(source), frames));
+        let receive = tokio::spawn(receive_pack(rp_stream, owned_refs(target), report, 5));
+        let syncer = fake_syncer(options);
+        let outcome = async {
+            let session = fake_session(&syncer, up, rp).await?;
+            let plan = session.plan().await?;
+            session.push(plan).await
+        }
+        .await;
+        let upload = upload.await.unwrap().unwrap_or_default();
+        let receive = receive.await.unwrap().unwrap_or_default();
+        (outcome, upload, receive)
+    }
+
+    /// Run git in `dir`, returning what it printed
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .env("GIT_AUTHOR_NAME", "git-sync")
+            .env("GIT_AUTHOR_EMAIL", "git-sync@example.com")
+            .env("GIT_COMMITTER_NAME", "git-sync")
+            .env("GIT_COMMITTER_EMAIL", "git-sync@example.com")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "git {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// A bare repository made afresh for a test
+    fn bare_repo(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("git-sync-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        git(&dir, &["init", "--bare", "-q"]);
+        dir
+    }
+
+    /// Commit nothing on top of `parents` in `dir`, and point `refname` at it
+    fn commit(dir: &Path, refname: &str, parents: &[&str], message: &str) -> String {
+        let mut args = vec!["commit-tree", EMPTY_TREE, "-m", message];
+        for parent in parents {
+            args.extend_from_slice(&["-p", parent]);
+        }
+        let sha = git(dir, &args);
+        git(dir, &["update-ref", refname, &sha]);
+        sha
+    }
+
+    /// A syncer between repositories on this machine
+    fn local_syncer(source: &Path, target: &Path, options: SyncOptions) -> Syncer {
+        let endpoint = |dir: &Path| {
+            Endpoint::new(
+                RemoteUrl::Local(dir.to_path_buf()),
+                ConnectOptions::default(),
+            )
+            .unwrap()
+        };
+        Syncer::new(endpoint(source), endpoint(target), options).unwrap()
+    }
+
+    #[test]
+    fn hold_back_splits_across_pieces() {
+        let pieces: &[&[u8]] = &[b"abc", b"de", b"f"];
+        assert_eq!(hold_back(pieces, 0), (pieces.to_vec(), Vec::new()));
+        let (before, held) = hold_back(pieces, 3);
+        assert_eq!((before, &held[..]), (vec![&b"abc"[..]], &b"def"[..]));
+        let (before, held) = hold_back(pieces, 4);
+        assert_eq!((before, &held[..]), (vec![&b"ab"[..]], &b"cdef"[..]));
+        let (before, held) = hold_back(pieces, 10);
+        assert_eq!((before, &held[..]), (Vec::<&[u8]>::new(), &b"abcdef"[..]));
+    }
+
+    /// A writer which takes no more than `limit` bytes at a time
+    struct Trickle {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let mut left = self.limit;
+            for buf in bufs {
+                let len = buf.len().min(left);
+                self.written.extend_from_slice(&buf[..len]);
+                left -= len;
+            }
+            Poll::Ready(Ok(self.limit - left))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn write_all_vectored_resumes_partial_writes() {
+        let bufs: &[&[u8]] = &[b"abc", b"", b"defgh", b"i"];
+        for limit in 1..=10 {
+            let mut writer = Trickle {
+                written: Vec::new(),
+                limit,
+            };
+            write_all_vectored(&mut writer, bufs).await.unwrap();
+            assert_eq!(writer.written, b"abcdefghi", "writing {} at a time", limit);
+        }
+        let mut stuck = Trickle {
+            written: Vec::new(),
+            limit: 0,
+        };
+        let err = write_all_vectored(&mut stuck, bufs).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[tokio::test]
+    async fn relays_the_pack_and_reads_a_report_split_across_frames() {
+        let pack = fake_pack(1, b"as good as an object");
+        let source = [("refs/heads/main", ONE)];
+        let target = [("refs/heads/gone", TWO)];
+        let (outcome, upload, receive) =
+            sync_fakes(SyncOptions::default(), &source, &target, &pack, 7).await;
+        let outcome = outcome.unwrap();
+        assert!(outcome.is_success());
+        assert_eq!(outcome.report.outcomes.len(), 2);
+        assert_eq!(outcome.pack_bytes, pack.len() as u64);
+        assert!(upload.lines[0].starts_with(&format!("want {} ", ONE)));
+        assert!(upload.lines.contains(&format!("have {}", TWO)));
+        let mut commands = receive.lines;
+        commands.sort();
+        assert_eq!(
+            commands,
+            vec![
+                format!("{} {} refs/heads/main", NULLSHA, ONE),
+                format!("{} {} refs/heads/gone", TWO, NULLSHA),
+            ]
+        );
+        assert_eq!(receive.pack, pack);
+    }
+
+    #[tokio::test]
+    async fn holds_back_the_checksum_of_a_corrupt_pack() {
+        let mut pack = fake_pack(1, b"as good as an object");
+        *pack.last_mut().unwrap() ^= 1;
+        let source = [("refs/heads/main", ONE)];
+        let (outcome, _, receive) =
+            sync_fakes(SyncOptions::default(), &source, &[], &pack, 3).await;
+        let err = outcome.unwrap_err();
+        assert!(err.to_string().contains("corrupt"), "{}", err);
+        // The target had all of the pack but the checksum, so never took it
+        assert_eq!(
+            receive.pack,
+            &pack[..pack.len() - ObjectFormat::Sha1.checksum_len()]
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_a_pack_with_too_many_objects() {
+        let options = SyncOptions::builder().max_objects(2).build().unwrap();
+        let pack = fake_pack(3, b"three objects, supposedly");
+        let source = [("refs/heads/main", ONE)];
+        let (outcome, _, receive) = sync_fakes(options, &source, &[], &pack, 8).await;
+        assert!(matches!(outcome, Err(Error::Refused(_))), "{:?}", outcome);
+        assert!(receive.pack.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_pack_which_grows_too_big() {
+        let options = SyncOptions::builder().max_pack_size(40).build().unwrap();
+        let pack = fake_pack(1, &[0; 64]);
+        let source = [("refs/heads/main", ONE)];
+        let (outcome, _, receive) = sync_fakes(options, &source, &[], &pack, 8).await;
+        assert!(matches!(outcome, Err(Error::Refused(_))), "{:?}", outcome);
+        assert!(receive.pack.len() <= 40 && pack.starts_with(&receive.pack));
+    }
+
+    #[test]
+    fn state_settings_are_stable_and_distinct() {
+        let options = |f: fn(SyncOptionsBuilder) -> SyncOptionsBuilder| {
+            state_settings(&f(SyncOptions::builder()).build().unwrap())
+        };
+        assert_eq!(
+            options(|builder| builder),
+            "mode mirror\nprune-tags true\nforce false\nprune-only false"
+        );
+        let include = options(|builder| builder.include("refs/heads/*".parse().unwrap()));
+        let exclude = options(|builder| builder.exclude("refs/heads/*".parse().unwrap()));
+        assert!(include.ends_with("\ninclude refs/heads/*"), "{}", include);
+        assert_ne!(include, exclude);
+        let refspec = options(|builder| builder.refspec("+refs/heads/*:refs/b/*".parse().unwrap()));
+        assert!(refspec.ends_with("\nrefspec +refs/heads/*:refs/b/*"));
+    }
+
+    #[tokio::test]
+    async fn delete_limit_permits_a_few_deletes() {
+        let target: Vec<_> = (0..12)
+            .map(|n| (format!("refs/heads/b{}", n), TWO.to_string()))
+            .collect();
+        for (kept, refused) in [(1, true), (2, false), (11, false)].iter() {
+            let (source, target) = (target[..*kept].to_vec(), target.clone());
+            let (up, up_stream) = duplex_transport(1 << 20);
+            let (rp, rp_stream) = duplex_transport(1 << 20);
+            tokio::spawn(upload_pack(up_stream, source, Vec::new()));
+            tokio::spawn(receive_pack(rp_stream, target, Vec::new(), 1));
+            let syncer = fake_syncer(SyncOptions::default());
+            let session = fake_session(&syncer, up, rp).await.unwrap();
+            let plan = session.plan().await;
+            session.abort().await;
+            match plan {
+                Err(Error::Refused(_)) => assert!(refused, "{} kept", kept),
+                Ok(plan) => {
+                    assert!(!refused, "{} kept", kept);
+                    assert_eq!(plan.updates.len(), 12 - kept);
+                }
+                Err(err) => panic!("{}", err),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn plan_refuses_non_fast_forwards() {
+        let (source, target) = (bare_repo("plan-source"), bare_repo("plan-target"));
+        let base = commit(&source, "refs/heads/main", &[], "base");
+        git(
+            &target,
+            &[
+                "fetch",
+                "-q",
+                source.to_str().unwrap(),
+                "refs/heads/main:refs/heads/main",
+            ],
+        );
+        git(&target, &["update-ref", "refs/heads/rewound", &base]);
+        let ahead = commit(&source, "refs/heads/main", &[&base], "ahead");
+        let rewound = commit(&source, "refs/heads/rewound", &[], "elsewhere");
+        let syncer = local_syncer(&source, &target, SyncOptions::default());
+        let session = syncer.connect().await.unwrap();
+        let plan = session.plan().await.unwrap();
+        session.abort().await;
+        assert_eq!(
+            plan.updates,
+            vec![RefUpdate {
+                refname: "refs/heads/main".into(),
+                oldsha: base.clone(),
+                newsha: ahead,
+            }]
+        );
+        assert_eq!(
+            plan.refused,
+            vec![RefUpdate {
+                refname: "refs/heads/rewound".into(),
+                oldsha: base,
+                newsha: rewound,
+            }]
+        );
+        for dir in [source, target].iter() {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    /// Two repositories sharing a commit on `main` and `feature`, where `main`
+    /// has moved on in the first and `feature` in the second, and `topic` has
+    /// moved in both; and a two-way sync between them with `policy`
+    fn two_way(name: &str, policy: ConflictPolicy) -> (PathBuf, PathBuf, TwoWay) {
+        let a = bare_repo(&format!("{}-a", name));
+        let b = bare_repo(&format!("{}-b", name));
+        let base = commit(&a, "refs/heads/main", &[], "base");
+        git(&a, &["update-ref", "refs/heads/feature", &base]);
+        git(&a, &["update-ref", "refs/heads/topic", &base]);
+        git(
+            &b,
+            &[
+                "fetch",
+                "-q",
+                a.to_str().unwrap(),
+                "refs/heads/*:refs/heads/*",
+            ],
+        );
+        commit(&a, "refs/heads/main", &[&base], "main in a");
+        commit(&a, "refs/heads/topic", &[&base], "topic in a");
+        commit(&b, "refs/heads/feature", &[&base], "feature in b");
+        commit(&b, "refs/heads/topic", &[&base], "topic in b");
+        let syncer = |from: &Path, to: &Path| local_syncer(from, to, SyncOptions::default());
+        let two_way = TwoWay::new(syncer(&a, &b), syncer(&b, &a), policy).unwrap();
+        (a, b, two_way)
+    }
+
+    /// The names of the refs the updates are for
+    fn refnames(updates: &[RefUpdate]) -> Vec<&str> {
+        updates
+            .iter()
+            .map(|update| update.refname.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn two_way_plan_sends_each_ref_the_way_it_moved() {
+        for policy in [
+            ConflictPolicy::Skip,
+            ConflictPolicy::PreferA,
+            ConflictPolicy::Fail,
+        ]
+        .iter()
+        {
+            let (a, b, two_way) = two_way(&format!("two-way-{}", policy.as_str()), *policy);
+            let to_b = two_way.a_to_b.connect().await.unwrap();
+            let to_a = two_way.b_to_a.connect().await.unwrap();
+            let plans = two_way.plan(&to_b, &to_a).await;
+            to_b.abort().await;
+            to_a.abort().await;
+            match (policy, plans) {
+                (ConflictPolicy::Skip, Ok((b_plan, a_plan))) => {
+                    assert_eq!(refnames(&b_plan.updates), vec!["refs/heads/main"]);
+                    assert_eq!(refnames(&a_plan.updates), vec!["refs/heads/feature"]);
+                    assert_eq!(refnames(&b_plan.refused), vec!["refs/heads/topic"]);
+                    assert_eq!(refnames(&a_plan.refused), vec!["refs/heads/topic"]);
+                }
+                (ConflictPolicy::PreferA, Ok((b_plan, a_plan))) => {
+                    let mut into_b = refnames(&b_plan.updates);
+                    into_b.sort_unstable();
+                    assert_eq!(into_b, vec!["refs/heads/main", "refs/heads/topic"]);
+                    assert_eq!(refnames(&a_plan.updates), vec!["refs/heads/feature"]);
+                    assert!(b_plan.refused.is_empty() && a_plan.refused.is_empty());
+                }
+                (ConflictPolicy::Fail, Err(Error::Refused(_))) => {}
+                (policy, plans) => panic!("{:?} planned {:?}", policy, plans),
+            }
+            for dir in [a, b].iter() {
+                std::fs::remove_dir_all(dir).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn two_way_plan_fails_when_ancestry_cannot_be_checked() {
+        let (a, b, two_way) = two_way("two-way-gone", ConflictPolicy::PreferA);
+        let to_b = two_way.a_to_b.connect().await.unwrap();
+        let to_a = two_way.b_to_a.connect().await.unwrap();
+        // With the second repository gone, none of its history can be checked
+        std::fs::remove_dir_all(&b).unwrap();
+        let plans = two_way.plan(&to_b, &to_a).await;
+        to_b.abort().await;
+        to_a.abort().await;
+        assert!(
+            matches!(plans, Err(Error::ChildFailed { .. })),
+            "{:?}",
+            plans
+        );
+        std::fs::remove_dir_all(&a).unwrap();
+    }
+}
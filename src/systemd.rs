@@ -0,0 +1,106 @@
+/// Telling systemd how a service is getting on, with the `sd_notify` protocol
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::time::Duration;
+
+use super::Error;
+
+/// Sends notifications to the socket systemd gives in `NOTIFY_SOCKET`, as for a
+/// service with `Type=notify`
+///
+/// ```
+/// # use git_sync::Notifier;
+/// # use std::os::unix::net::UnixDatagram;
+/// let path = std::env::temp_dir().join(format!("git-sync-notify-{}", std::process::id()));
+/// let systemd = UnixDatagram::bind(&path).unwrap();
+/// std::env::set_var("NOTIFY_SOCKET", &path);
+/// let notifier = Notifier::from_env().unwrap();
+/// notifier.ready("Syncing 3 pairs").unwrap();
+/// let mut buf = [0; 64];
+/// let len = systemd.recv(&mut buf).unwrap();
+/// assert_eq!(&buf[..len], b"READY=1\nSTATUS=Syncing 3 pairs");
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct Notifier {
+    socket: UnixDatagram,
+    path: String,
+}
+
+impl Notifier {
+    /// The notifier systemd has asked for, if any
+    pub fn from_env() -> Option<Notifier> {
+        let path = env::var("NOTIFY_SOCKET")
+            .ok()
+            .filter(|path| !path.is_empty())?;
+        let socket = UnixDatagram::unbound().ok()?;
+        Some(Notifier { socket, path })
+    }
+
+    /// How often systemd wants to hear that the service is still well: half as
+    /// often as the watchdog timeout, to allow for delays
+    pub fn watchdog_interval() -> Option<Duration> {
+        if let Ok(pid) = env::var("WATCHDOG_PID") {
+            if pid.parse() != Ok(process::id()) {
+                return None;
+            }
+        }
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+    }
+
+    /// Send some `VARIABLE=value` assignments, one per line
+    pub fn notify(&self, state: &str) -> Result<(), Error> {
+        let sent = match self.path.strip_prefix('@') {
+            Some(name) => send_abstract(&self.socket, name, state.as_bytes()),
+            None => self.socket.send_to(state.as_bytes(), &self.path),
+        };
+        sent.map_err(|err| {
+            Error::Transport(format!("Cannot notify systemd at {}: {}", self.path, err))
+        })?;
+        Ok(())
+    }
+
+    /// Say that the service has started, and what it's doing
+    pub fn ready(&self, status: &str) -> Result<(), Error> {
+        self.notify(&format!("READY=1\nSTATUS={}", status))
+    }
+
+    /// Say what the service is doing
+    pub fn status(&self, status: &str) -> Result<(), Error> {
+        self.notify(&format!("STATUS={}", status))
+    }
+
+    /// Say that the service is reloading its configuration, after which it will
+    /// say it's ready again
+    pub fn reloading(&self) -> Result<(), Error> {
+        self.notify("RELOADING=1")
+    }
+
+    /// Say that the service is stopping
+    pub fn stopping(&self) -> Result<(), Error> {
+        self.notify("STOPPING=1")
+    }
+
+    /// Tell the watchdog that the service is still well
+    pub fn watchdog(&self) -> Result<(), Error> {
+        self.notify("WATCHDOG=1")
+    }
+}
+
+/// Send to a socket in the abstract namespace, which only Linux has
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &str, data: &[u8]) -> io::Result<usize> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(data, &addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_socket: &UnixDatagram, _name: &str, _data: &[u8]) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Abstract sockets are only supported on Linux",
+    ))
+}
@@ -0,0 +1,113 @@
+/// Limiting how fast pack data is relayed, so that syncs can share a link politely
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::format_bytes;
+
+/// A rate of transfer, in bytes per second, written as a number of bytes with an
+/// optional binary unit and `/s`, e.g. `512K`, `10MiB/s` or `1G`
+///
+/// ```
+/// # use git_sync::Bandwidth;
+/// assert_eq!("512K".parse::<Bandwidth>().unwrap(), Bandwidth(512 * 1024));
+/// assert_eq!("10MiB/s".parse::<Bandwidth>().unwrap(), Bandwidth(10 * 1024 * 1024));
+/// assert_eq!("2000".parse::<Bandwidth>().unwrap().to_string(), "2.0 KiB/s");
+/// assert!("0".parse::<Bandwidth>().is_err());
+/// assert!("fast".parse::<Bandwidth>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bandwidth(pub u64);
+
+impl FromStr for Bandwidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rate = s.trim().trim_end_matches("/s");
+        let (number, unit) = rate.split_at(
+            rate.find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rate.len()),
+        );
+        let scale: u64 = match unit.trim().trim_end_matches("iB").trim_end_matches('B') {
+            "" => 1,
+            "k" | "K" => 1 << 10,
+            "M" => 1 << 20,
+            "G" => 1 << 30,
+            _ => return Err(format!("Unknown unit {} in rate {}", unit, s)),
+        };
+        match number.parse::<f64>() {
+            Ok(number) if number * scale as f64 >= 1.0 => {
+                Ok(Bandwidth((number * scale as f64) as u64))
+            }
+            _ => Err(format!(
+                "Expected a rate of at least one byte a second, such as 512K or 10M, not {}",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/s", format_bytes(self.0))
+    }
+}
+
+/// A token bucket which holds data back to a [`Bandwidth`], allowing bursts of up
+/// to a second's worth
+///
+/// ```
+/// # use git_sync::{Bandwidth, Throttle};
+/// # use std::time::Duration;
+/// # use tokio::time::Instant;
+/// let start = Instant::now();
+/// let mut throttle = Throttle::new(Bandwidth(1000), start);
+/// // A second's worth may go at once, but the next must wait for it to pass
+/// assert_eq!(throttle.delay_for(1000, start), Duration::ZERO);
+/// assert_eq!(throttle.delay_for(500, start), Duration::from_millis(500));
+/// let later = start + Duration::from_millis(500);
+/// assert_eq!(throttle.delay_for(250, later), Duration::from_millis(250));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    rate: Bandwidth,
+    /// How many bytes may be sent straight away, which is negative while waiting
+    /// to pay for some already sent
+    tokens: f64,
+    /// When the bucket was last topped up
+    filled: Instant,
+}
+
+impl Throttle {
+    /// Start limiting data to `rate`, with a full bucket as of `now`
+    pub fn new(rate: Bandwidth, now: Instant) -> Throttle {
+        Throttle {
+            rate,
+            tokens: rate.0 as f64,
+            filled: now,
+        }
+    }
+
+    /// Take `bytes` from the bucket as of `now`, returning how long to wait before
+    /// sending them to keep to the rate
+    pub fn delay_for(&mut self, bytes: usize, now: Instant) -> Duration {
+        let rate = self.rate.0 as f64;
+        let elapsed = now.saturating_duration_since(self.filled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        self.filled = now;
+        match self.tokens {
+            tokens if tokens < 0.0 => Duration::from_secs_f64(-tokens / rate),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Wait until `bytes` may be sent
+    pub async fn take(&mut self, bytes: usize) {
+        let delay = self.delay_for(bytes, Instant::now());
+        if delay > Duration::ZERO {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
@@ -0,0 +1,541 @@
+/// Transports carrying the git protocol to and from a remote service
+///
+/// A transport is anything which can give us a stream to read the service's output
+/// from and a stream to write its input to.  Connecting is the business of each
+/// transport's constructor; once connected, transports are used through the
+/// [`Transport`] trait, so embedders can supply their own.
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{
+    self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream, ReadHalf,
+    WriteHalf,
+};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use super::{shell_quote, Error, ProtocolLine, Proxy, SshOptions};
+
+/// The port a git daemon listens on unless told otherwise
+pub const DEFAULT_DAEMON_PORT: u16 = 9418;
+
+/// How much of a subprocess's output is read at a time, enough for several
+/// packets, rather than each packet's length and then its data being read alone
+const PROCESS_READ_BUFFER: usize = 256 * 1024;
+
+/// Settings which affect how transports make their connections
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// The proxy for transports which make their own network connections
+    pub proxy: Option<Proxy>,
+    /// How to run `ssh` for SSH remotes
+    pub ssh: SshOptions,
+    /// The command to run instead of `git-upload-pack` for local and SSH remotes
+    pub upload_pack: Option<String>,
+    /// The command to run instead of `git-receive-pack` for local and SSH remotes
+    pub receive_pack: Option<String>,
+    /// Configuration (`key=value`) to run upload-pack and receive-pack with, for
+    /// local and SSH remotes, as with `git -c`
+    pub service_config: Vec<String>,
+    /// What a bundle being synced into is based on, as a bundle or a list of
+    /// refs: the objects it names are left out of the bundle, for a receiver
+    /// which already has them
+    pub bundle_basis: Option<PathBuf>,
+}
+
+impl ConnectOptions {
+    /// The command to run for a service (e.g. `git-upload-pack`), if it's been
+    /// overridden or needs configuration.  Like git, we expect it to be a shell
+    /// command line.
+    ///
+    /// ```
+    /// # use git_sync::ConnectOptions;
+    /// let opts = ConnectOptions {
+    ///     service_config: vec!["uploadpack.allowFilter=true".into()],
+    ///     ..ConnectOptions::default()
+    /// };
+    /// assert_eq!(
+    ///     opts.service_command("git-upload-pack").as_deref(),
+    ///     Some("git -c 'uploadpack.allowFilter=true' upload-pack")
+    /// );
+    /// ```
+    pub fn service_command(&self, service: &str) -> Option<String> {
+        let command = match service {
+            "git-upload-pack" => self.upload_pack.as_ref(),
+            "git-receive-pack" => self.receive_pack.as_ref(),
+            _ => None,
+        };
+        if let Some(command) = command {
+            return Some(command.clone());
+        }
+        if self.service_config.is_empty() {
+            return None;
+        }
+        let mut command = "git".to_string();
+        for config in &self.service_config {
+            command.push_str(" -c ");
+            command.push_str(&shell_quote(config));
+        }
+        command.push(' ');
+        command.push_str(service.strip_prefix("git-").unwrap_or(service));
+        Some(command)
+    }
+}
+
+/// How much of the end of a service's stderr is kept, to report if it fails
+const STDERR_CAPTURE_LIMIT: usize = 64 * 1024;
+
+/// How long to wait for the rest of a service's stderr once it has exited, in case
+/// something it started still holds the pipe open
+const STDERR_GRACE: Duration = Duration::from_secs(1);
+
+/// Receives each line a service writes to its stderr
+pub type StderrHandler = Box<dyn FnMut(&str) + Send>;
+
+/// The future returned when shutting a transport down
+pub type ShutdownFuture = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+/// A connection to a git service such as upload-pack or receive-pack
+///
+/// Transports are `Sync` so that futures borrowing a session can be spawned.
+pub trait Transport: Send + Sync {
+    /// The streams carrying the service's output and input respectively
+    fn streams(
+        &mut self,
+    ) -> (
+        &mut (dyn AsyncRead + Unpin + Send),
+        &mut (dyn AsyncWrite + Unpin + Send),
+    );
+
+    /// Close the connection, waiting for the service to finish if that's meaningful
+    fn shutdown(self: Box<Self>) -> ShutdownFuture;
+
+    /// Stop the service straight away because something has gone wrong, waiting for
+    /// it to go if that's meaningful.  Fails if the service had already failed of
+    /// its own accord.  Dropping a transport stops its service too, but can't wait.
+    fn abort(self: Box<Self>) -> ShutdownFuture {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Pass the lines the service writes to its stderr to `handler`, rather than
+    /// writing them to our own stderr.  Transports whose services have no stderr
+    /// ignore this.
+    fn forward_stderr(&mut self, _handler: StderrHandler) {}
+
+    /// The stream carrying the service's output
+    fn reader(&mut self) -> &mut (dyn AsyncRead + Unpin + Send) {
+        self.streams().0
+    }
+
+    /// The stream carrying the service's input
+    fn writer(&mut self) -> &mut (dyn AsyncWrite + Unpin + Send) {
+        self.streams().1
+    }
+}
+
+/// What has become of a service's stderr
+struct StderrCapture {
+    /// The end of what the service has written, for reporting if it fails
+    captured: String,
+    /// Where each line is passed, if not to our own stderr
+    handler: Option<StderrHandler>,
+}
+
+impl StderrCapture {
+    fn push_line(&mut self, name: &str, line: &str) {
+        self.captured.push_str(line);
+        self.captured.push('\n');
+        if self.captured.len() > STDERR_CAPTURE_LIMIT {
+            let mut cut = self.captured.len() - STDERR_CAPTURE_LIMIT;
+            while !self.captured.is_char_boundary(cut) {
+                cut += 1;
+            }
+            self.captured.drain(..cut);
+        }
+        match &mut self.handler {
+            Some(handler) => handler(line),
+            None => tracing::info!(service = name, "{}", line),
+        }
+    }
+}
+
+/// Read a service's stderr a line at a time until it's closed
+async fn capture_stderr(name: String, stderr: ChildStderr, capture: Arc<Mutex<StderrCapture>>) {
+    let mut stderr = BufReader::new(stderr);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match stderr.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let text = String::from_utf8_lossy(&line);
+                let text = text.trim_end_matches(&['\r', '\n'][..]);
+                capture.lock().unwrap().push_line(&name, text);
+            }
+        }
+    }
+}
+
+/// Wait for the rest of a service's stderr once it has exited, and fail with the
+/// end of it if it exited unsuccessfully
+async fn exited(
+    name: String,
+    status: ExitStatus,
+    stderr: Arc<Mutex<StderrCapture>>,
+    mut stderr_task: JoinHandle<()>,
+) -> Result<(), Error> {
+    // Whatever the service wrote last is likely to say why it failed.  Anything
+    // else holding its stderr, such as a process it started, is given up on.
+    if timeout(STDERR_GRACE, &mut stderr_task).await.is_err() {
+        stderr_task.abort();
+    }
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::ChildFailed {
+            command: name,
+            status,
+            stderr: std::mem::take(&mut stderr.lock().unwrap().captured),
+        })
+    }
+}
+
+/// A service running as a subprocess, either locally or via `ssh`
+///
+/// The process is killed if the transport is dropped without being shut down, for
+/// instance when a sync is cancelled, and is killed and reaped if it's aborted.  What it writes to its stderr is
+/// passed on a line at a time, and shutting the transport down fails with the end
+/// of that output attached if the process exits unsuccessfully.
+pub struct ProcessTransport {
+    name: String,
+    child: Child,
+    reader: BufReader<ChildStdout>,
+    writer: ChildStdin,
+    stderr: Arc<Mutex<StderrCapture>>,
+    stderr_task: JoinHandle<()>,
+}
+
+impl ProcessTransport {
+    /// Spawn a command and talk to it over its stdin and stdout.  The name
+    /// (e.g. `git-upload-pack`) identifies the service in errors and in the lines
+    /// of its stderr.
+    pub fn spawn(name: &str, mut command: Command) -> Result<ProcessTransport, Error> {
+        tracing::debug!(service = name, "Running {:?}", command);
+        let mut child = command
+            .kill_on_drop(true)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| Error::Transport(format!("Unable to run {:?}: {}", command, err)))?;
+
+        let reader = BufReader::with_capacity(
+            PROCESS_READ_BUFFER,
+            child.stdout.take().expect("Did not get a stdout handle?"),
+        );
+        let writer = child.stdin.take().expect("Did not get a stdin handle?");
+        let stderr = Arc::new(Mutex::new(StderrCapture {
+            captured: String::new(),
+            handler: None,
+        }));
+        let stderr_task = tokio::spawn(capture_stderr(
+            name.to_string(),
+            child.stderr.take().expect("Did not get a stderr handle?"),
+            Arc::clone(&stderr),
+        ));
+
+        Ok(ProcessTransport {
+            name: name.to_string(),
+            child,
+            reader,
+            writer,
+            stderr,
+            stderr_task,
+        })
+    }
+
+    /// Run a service (e.g. `git-upload-pack`) for a local repository
+    pub fn local<P>(service: &str, path: P) -> Result<ProcessTransport, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let mut command = Command::new(service);
+        command.arg(path.as_ref());
+        Self::spawn(service, command)
+    }
+
+    /// Run a service through a user-specified command, as described by an `ext::` remote
+    pub async fn ext(spec: &str, service: &str) -> Result<ProcessTransport, Error> {
+        let ext = ExtCommand::parse(spec, service).map_err(Error::Config)?;
+        let mut command = Command::new(&ext.args[0]);
+        command.args(&ext.args[1..]);
+        let mut transport = Self::spawn(service, command)?;
+        if let Some(path) = &ext.git_path {
+            let mut request = format!("{} {}\0", service, path);
+            if let Some(host) = &ext.vhost {
+                request.push_str(&format!("host={}\0", host));
+            }
+            ProtocolLine::write_str(&mut transport.writer, request).await?;
+        }
+        Ok(transport)
+    }
+}
+
+impl Transport for ProcessTransport {
+    fn streams(
+        &mut self,
+    ) -> (
+        &mut (dyn AsyncRead + Unpin + Send),
+        &mut (dyn AsyncWrite + Unpin + Send),
+    ) {
+        (&mut self.reader, &mut self.writer)
+    }
+
+    fn shutdown(self: Box<Self>) -> ShutdownFuture {
+        let ProcessTransport {
+            name,
+            mut child,
+            reader,
+            writer,
+            stderr,
+            stderr_task,
+        } = *self;
+        // Closing our ends of the pipes lets the service know we're done with it
+        drop(reader);
+        drop(writer);
+        Box::pin(async move {
+            let status = child.wait().await?;
+            exited(name, status, stderr, stderr_task).await
+        })
+    }
+
+    fn abort(self: Box<Self>) -> ShutdownFuture {
+        let ProcessTransport {
+            name,
+            mut child,
+            stderr,
+            stderr_task,
+            ..
+        } = *self;
+        Box::pin(async move {
+            match child.try_wait()? {
+                Some(status) => exited(name, status, stderr, stderr_task).await,
+                None => {
+                    child.kill().await?;
+                    stderr_task.abort();
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    fn forward_stderr(&mut self, handler: StderrHandler) {
+        self.stderr.lock().unwrap().handler = Some(handler);
+    }
+}
+
+/// The command line described by an `ext::` remote, in the form git uses
+///
+/// The command and its arguments are separated by spaces, with `% ` standing for
+/// a literal space and `%%` for a literal `%`.  `%S` expands to the service name
+/// (e.g. `git-upload-pack`) and `%s` to the service name without its `git-` prefix.
+/// An argument of the form `%G<path>` is removed, and asks for a git daemon style
+/// request for `<path>` to be sent to the command before anything else, with the
+/// host given by a similarly removed `%V<host>` argument.
+///
+/// ```
+/// # use git_sync::ExtCommand;
+/// let ext = ExtCommand::parse("ext::kubectl exec -i git-0 -- %S /srv/repo.git", "git-upload-pack").unwrap();
+/// assert_eq!(ext.args, ["kubectl", "exec", "-i", "git-0", "--", "git-upload-pack", "/srv/repo.git"]);
+/// assert_eq!(ext.git_path, None);
+/// let ext = ExtCommand::parse("ext::nc gitbox 9418 %G/repo.git %Vgitbox", "git-receive-pack").unwrap();
+/// assert_eq!(ext.args, ["nc", "gitbox", "9418"]);
+/// assert_eq!(ext.git_path.as_deref(), Some("/repo.git"));
+/// assert_eq!(ext.vhost.as_deref(), Some("gitbox"));
+/// let ext = ExtCommand::parse("ext::sh -c %s% %%", "git-upload-pack").unwrap();
+/// assert_eq!(ext.args, ["sh", "-c", "upload-pack %"]);
+/// assert!(ExtCommand::parse("ext::nc %x", "git-upload-pack").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtCommand {
+    /// The command to run, followed by its arguments
+    pub args: Vec<String>,
+    /// The path to send in a git daemon style request, if any
+    pub git_path: Option<String>,
+    /// The host to name in that request, if any
+    pub vhost: Option<String>,
+}
+
+impl ExtCommand {
+    /// Parse an `ext::` remote for the given service
+    pub fn parse(spec: &str, service: &str) -> Result<ExtCommand, String> {
+        let command = spec
+            .strip_prefix("ext::")
+            .ok_or_else(|| format!("Not an ext:: remote: {}", spec))?;
+        let mut ret = ExtCommand {
+            args: Vec::new(),
+            git_path: None,
+            vhost: None,
+        };
+        let mut arg = String::new();
+        let mut special = None;
+        let mut chars = command.chars().peekable();
+        let mut in_arg = false;
+        while let Some(c) = chars.next() {
+            match c {
+                ' ' => {
+                    if in_arg {
+                        ret.finish_arg(special.take(), std::mem::take(&mut arg));
+                        in_arg = false;
+                    }
+                }
+                '%' => {
+                    let next = chars
+                        .next()
+                        .ok_or_else(|| format!("Trailing '%' in {}", spec))?;
+                    match next {
+                        ' ' | '%' => arg.push(next),
+                        'S' => arg.push_str(service),
+                        's' => arg.push_str(service.strip_prefix("git-").unwrap_or(service)),
+                        'G' | 'V' if !in_arg => special = Some(next),
+                        _ => return Err(format!("Unknown placeholder '%{}' in {}", next, spec)),
+                    }
+                    in_arg = true;
+                }
+                c => {
+                    arg.push(c);
+                    in_arg = true;
+                }
+            }
+        }
+        if in_arg {
+            ret.finish_arg(special, arg);
+        }
+        if ret.args.is_empty() {
+            return Err(format!("No command in {}", spec));
+        }
+        Ok(ret)
+    }
+
+    fn finish_arg(&mut self, special: Option<char>, arg: String) {
+        match special {
+            Some('G') => self.git_path = Some(arg),
+            Some('V') => self.vhost = Some(arg),
+            _ => self.args.push(arg),
+        }
+    }
+}
+
+/// A service reached over an arbitrary pair of streams
+pub struct StreamTransport<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> StreamTransport<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(reader: R, writer: W) -> StreamTransport<R, W> {
+        StreamTransport { reader, writer }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R, W> Transport for StreamTransport<R, W>
+where
+    R: AsyncRead + Unpin + Send + Sync + 'static,
+    W: AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    fn streams(
+        &mut self,
+    ) -> (
+        &mut (dyn AsyncRead + Unpin + Send),
+        &mut (dyn AsyncWrite + Unpin + Send),
+    ) {
+        (&mut self.reader, &mut self.writer)
+    }
+
+    fn shutdown(self: Box<Self>) -> ShutdownFuture {
+        let mut writer = self.writer;
+        Box::pin(async move {
+            match writer.shutdown().await {
+                // The other end has already gone away, which is fine by us
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+                res => Ok(res?),
+            }
+        })
+    }
+}
+
+/// A service reached over an in-memory pipe, for tests and embedding
+pub type DuplexTransport = StreamTransport<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+/// Create an in-memory transport, along with the stream a fake service should
+/// use for the other end of it.  Each direction buffers at most `max_buf_size` bytes.
+///
+/// ```
+/// # use git_sync::{duplex_transport, ProtocolLine, RefAdvertisement, Transport};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), git_sync::Error> {
+/// let (mut transport, mut service) = duplex_transport(4096);
+/// tokio::spawn(async move {
+///     let sha = "0123456789012345678901234567890123456789";
+///     ProtocolLine::write_str(&mut service, format!("{} HEAD\0agent=fake\n", sha)).await?;
+///     ProtocolLine::write_str(&mut service, format!("{} refs/heads/main\n", sha)).await?;
+///     ProtocolLine::Flush.write_to(&mut service).await
+/// });
+/// let advert = RefAdvertisement::read_from(transport.reader()).await?;
+/// assert!(advert.refs().contains_key("refs/heads/main"));
+/// Box::new(transport).shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn duplex_transport(max_buf_size: usize) -> (DuplexTransport, DuplexStream) {
+    let (ours, theirs) = io::duplex(max_buf_size);
+    let (reader, writer) = io::split(ours);
+    (StreamTransport::new(reader, writer), theirs)
+}
+
+/// A service provided by a git daemon over TCP
+pub type TcpTransport = StreamTransport<OwnedReadHalf, OwnedWriteHalf>;
+
+/// Connect to a git daemon, optionally by way of a proxy, and ask it to run the
+/// given service for the repository at `path`
+pub async fn connect_daemon(
+    host: &str,
+    port: u16,
+    proxy: Option<&Proxy>,
+    service: &str,
+    path: &str,
+) -> Result<TcpTransport, Error> {
+    let stream = match proxy {
+        Some(proxy) => proxy.connect(host, port).await?,
+        None => TcpStream::connect((host, port)).await.map_err(|err| {
+            Error::Transport(format!("Unable to connect to {}:{}: {}", host, port, err))
+        })?,
+    };
+    let (reader, mut writer) = stream.into_split();
+    let host_param = if port == DEFAULT_DAEMON_PORT {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, port)
+    };
+    ProtocolLine::write_str(
+        &mut writer,
+        format!("{} {}\0host={}\0", service, path, host_param),
+    )
+    .await?;
+    Ok(StreamTransport::new(reader, writer))
+}
@@ -0,0 +1,543 @@
+/// Remote repository locations, written the way git itself accepts them
+use std::borrow::Cow;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use tokio::process::Command;
+
+use super::{
+    connect_daemon, quote_remote_path, read_bundle_basis, Bundle, ConnectOptions, Error,
+    ProcessTransport, SshOptions, Transport, DEFAULT_DAEMON_PORT,
+};
+
+/// Where a repository lives, and so which transport reaches it
+///
+/// ```
+/// # use git_sync::RemoteUrl;
+/// let url: RemoteUrl = "ssh://git@example.com:2222/srv/repo.git".parse().unwrap();
+/// assert_eq!(
+///     url,
+///     RemoteUrl::Ssh {
+///         user: Some("git".into()),
+///         host: "example.com".into(),
+///         port: Some(2222),
+///         path: "/srv/repo.git".into(),
+///     }
+/// );
+/// let url: RemoteUrl = "git@example.com:repo.git".parse().unwrap();
+/// assert_eq!(url.to_string(), "git@example.com:repo.git");
+/// let url: RemoteUrl = "git://example.com/repo.git".parse().unwrap();
+/// assert_eq!(url.to_string(), "git://example.com/repo.git");
+/// let url: RemoteUrl = "quic://example.com:4433/repo.git".parse().unwrap();
+/// assert_eq!(url.host(), Some("example.com"));
+/// assert_eq!(url.to_string(), "quic://example.com:4433/repo.git");
+/// let url: RemoteUrl = "file:///srv/repo.git".parse().unwrap();
+/// assert_eq!(url, RemoteUrl::Local("/srv/repo.git".into()));
+/// let url: RemoteUrl = "./not:scp".parse().unwrap();
+/// assert_eq!(url, RemoteUrl::Local("./not:scp".into()));
+/// let url: RemoteUrl = "backups/repo.bundle".parse().unwrap();
+/// assert_eq!(url, RemoteUrl::Bundle("backups/repo.bundle".into()));
+/// assert_eq!(url.to_string(), "bundle:backups/repo.bundle");
+/// assert!("gopher://example.com/repo".parse::<RemoteUrl>().is_err());
+/// // An IPv6 address is bracketed, so its colons aren't taken for a port's
+/// let url: RemoteUrl = "ssh://git@[::1]:2222/srv/repo.git".parse().unwrap();
+/// assert_eq!(url.host(), Some("::1"));
+/// assert_eq!(url.to_string(), "ssh://git@[::1]:2222/srv/repo.git");
+/// let url: RemoteUrl = "git@[fe80::1]:repo.git".parse().unwrap();
+/// assert_eq!(url, RemoteUrl::ssh("git@fe80::1", "repo.git"));
+/// assert_eq!(url.to_string(), "git@[fe80::1]:repo.git");
+/// let url: RemoteUrl = "http://[::1]:8080/repo.git".parse().unwrap();
+/// assert_eq!(url.host(), Some("::1"));
+/// // As with git, a DOS drive is a local path rather than a host
+/// let url: RemoteUrl = "C:/repos/repo.git".parse().unwrap();
+/// assert_eq!(url, RemoteUrl::Local("C:/repos/repo.git".into()));
+/// let url: RemoteUrl = r"C:\repos\repo.git".parse().unwrap();
+/// assert_eq!(url, RemoteUrl::Local(r"C:\repos\repo.git".into()));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteUrl {
+    /// A repository on this machine, as a path or `file://` URL
+    Local(PathBuf),
+    /// A repository reached over SSH, as an `ssh://` URL or `[user@]host:path`
+    Ssh {
+        user: Option<String>,
+        host: String,
+        port: Option<u16>,
+        path: String,
+    },
+    /// A repository served by a git daemon, as a `git://` URL
+    Git {
+        host: String,
+        port: u16,
+        path: String,
+    },
+    /// A repository served over smart HTTP, as an `http://` or `https://` URL
+    Http(String),
+    /// A repository reached through a WebSocket gateway, as a `ws://` or `wss://` URL
+    WebSocket(String),
+    /// A repository reached through a QUIC gateway, as a `quic://` URL, whose port
+    /// defaults to the git daemon's
+    Quic {
+        host: String,
+        port: u16,
+        path: String,
+    },
+    /// A repository reached by running an arbitrary command, as `ext::<command>`
+    Ext(String),
+    /// A git bundle, as `bundle:<path>` or a path ending `.bundle` (or
+    /// `.bundle.gz` or `.bundle.zst` if compressed), which is read when syncing
+    /// from it and written when syncing into it
+    Bundle(PathBuf),
+}
+
+impl RemoteUrl {
+    /// Build the URL for a path on an SSH server named in the `[user@]host` form,
+    /// where the host may be a bracketed IPv6 address
+    pub fn ssh(server: &str, path: &str) -> RemoteUrl {
+        let (user, host) = split_user(server);
+        RemoteUrl::Ssh {
+            user: user.map(str::to_string),
+            host: unbracket(host).to_string(),
+            port: None,
+            path: path.to_string(),
+        }
+    }
+
+    /// The host this URL connects to, if it names one
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            RemoteUrl::Ssh { host, .. }
+            | RemoteUrl::Git { host, .. }
+            | RemoteUrl::Quic { host, .. } => Some(host),
+            RemoteUrl::Http(url) | RemoteUrl::WebSocket(url) => {
+                let authority = url.split('/').nth(2)?;
+                Some(split_host(split_user(authority).1).0)
+            }
+            RemoteUrl::Local(_) | RemoteUrl::Ext(_) | RemoteUrl::Bundle(_) => None,
+        }
+    }
+
+    /// Start the given service (e.g. `git-upload-pack`) for the repository
+    pub async fn connect(
+        &self,
+        service: &str,
+        opts: &ConnectOptions,
+    ) -> Result<Box<dyn Transport>, Error> {
+        let proxy = opts.proxy.as_ref();
+        Ok(match self {
+            RemoteUrl::Local(path) => match opts.service_command(service) {
+                Some(command) => {
+                    let mut cmd = Command::new("sh");
+                    cmd.arg("-c")
+                        .arg(format!("{} \"$@\"", command))
+                        .arg(command)
+                        .arg(path);
+                    Box::new(ProcessTransport::spawn(service, cmd)?)
+                }
+                None => Box::new(ProcessTransport::local(service, path)?),
+            },
+            RemoteUrl::Ssh { path, .. } => {
+                let mut cmd = self
+                    .ssh_command(&opts.ssh)
+                    .expect("SSH URL without SSH command?");
+                // The remote shell runs the command, so it may have arguments of its own
+                cmd.arg(
+                    opts.service_command(service)
+                        .unwrap_or_else(|| service.to_string()),
+                )
+                .arg(quote_remote_path(path));
+                Box::new(ProcessTransport::spawn(service, cmd)?)
+            }
+            RemoteUrl::Git { host, port, path } => {
+                Box::new(connect_daemon(host, *port, proxy, service, path).await?)
+            }
+            #[cfg(feature = "websocket")]
+            RemoteUrl::WebSocket(url) => {
+                Box::new(super::WebSocketTransport::connect(url, proxy, service).await?)
+            }
+            #[cfg(feature = "quic")]
+            RemoteUrl::Quic { host, port, path } => {
+                Box::new(super::QuicTransport::connect(host, *port, service, path).await?)
+            }
+            RemoteUrl::Ext(spec) => Box::new(ProcessTransport::ext(spec, service).await?),
+            RemoteUrl::Bundle(path) if service == "git-upload-pack" => {
+                Box::new(Bundle::open(path).await?.serve())
+            }
+            RemoteUrl::Bundle(path) => {
+                let basis = match &opts.bundle_basis {
+                    Some(basis) => read_bundle_basis(basis).await?,
+                    None => Vec::new(),
+                };
+                Box::new(Bundle::receive(path, basis))
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "No transport available for {}",
+                    self
+                )))
+            }
+        })
+    }
+
+    /// A command which runs `ssh` to the host of an SSH URL, to which the remote
+    /// command and its arguments should be added
+    pub fn ssh_command(&self, ssh: &SshOptions) -> Option<Command> {
+        match self {
+            RemoteUrl::Ssh {
+                user, host, port, ..
+            } => Some(ssh.command(user.as_deref(), host, *port)),
+            _ => None,
+        }
+    }
+}
+
+/// Split a `[user@]host` string into its parts
+fn split_user(s: &str) -> (Option<&str>, &str) {
+    match s.rfind('@') {
+        Some(idx) => (Some(&s[..idx]), &s[idx + 1..]),
+        None => (None, s),
+    }
+}
+
+/// Split a `host[:port]` string into its parts, as `http_send` does, taking the
+/// brackets off an IPv6 address such as `[::1]`
+fn split_host(s: &str) -> (&str, Option<&str>) {
+    match s.rsplit_once(':') {
+        // The colons inside a bracketed address don't come before a port
+        Some((host, port)) if !port.contains(']') => (unbracket(host), Some(port)),
+        _ => (unbracket(s), None),
+    }
+}
+
+/// Split a `host[:port]` string into its parts, parsing the port
+fn split_port(s: &str, url: &str) -> Result<(String, Option<u16>), String> {
+    let (host, port) = split_host(s);
+    let port = match port {
+        Some(port) => Some(port.parse().map_err(|_| format!("Bad port in {}", url))?),
+        None => None,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// A host without the brackets around it, if it's a bracketed IPv6 address
+fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// A host as a URL must give it, bracketed if it's an IPv6 address
+fn bracket(host: &str) -> Cow<'_, str> {
+    if host.contains(':') {
+        Cow::Owned(format!("[{}]", host))
+    } else {
+        Cow::Borrowed(host)
+    }
+}
+
+/// Where the colon ending the host of an scp-style `[user@]host:path` location
+/// is, if there is one, looking past the colons of a bracketed IPv6 address
+fn scp_colon(s: &str) -> Option<usize> {
+    let after = match s.find('[') {
+        Some(open) if open == 0 || s[..open].ends_with('@') => {
+            s[open..].find(']').map_or(0, |close| open + close)
+        }
+        _ => 0,
+    };
+    s[after..].find(':').map(|colon| after + colon)
+}
+
+/// Whether a location starts with a DOS drive, such as `C:/` or `C:\`, which
+/// git takes for a local path rather than an scp-style host
+fn has_dos_drive(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    bytes.len() > 2
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && (bytes[2] == b'/' || bytes[2] == b'\\')
+}
+
+impl FromStr for RemoteUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("Empty remote".to_string());
+        }
+        if s.starts_with("ext::") {
+            return Ok(RemoteUrl::Ext(s.to_string()));
+        }
+        if let Some(path) = s.strip_prefix("bundle:") {
+            return Ok(RemoteUrl::Bundle(PathBuf::from(path)));
+        }
+        if let Some(idx) = s.find("://") {
+            let scheme = &s[..idx];
+            let rest = &s[idx + 3..];
+            let (authority, path) = match rest.find('/') {
+                Some(slash) => rest.split_at(slash),
+                None => return Err(format!("No path in {}", s)),
+            };
+            return match scheme {
+                "file" => Ok(RemoteUrl::Local(PathBuf::from(path))),
+                "ssh" | "git+ssh" | "ssh+git" => {
+                    let (user, hostport) = split_user(authority);
+                    let (host, port) = split_port(hostport, s)?;
+                    // As with git, a path starting `/~` is relative to a home directory
+                    let path = path
+                        .strip_prefix("/~")
+                        .map_or_else(|| path.to_string(), |rest| format!("~{}", rest));
+                    Ok(RemoteUrl::Ssh {
+                        user: user.map(str::to_string),
+                        host,
+                        port,
+                        path,
+                    })
+                }
+                "git" => {
+                    let (host, port) = split_port(authority, s)?;
+                    Ok(RemoteUrl::Git {
+                        host,
+                        port: port.unwrap_or(DEFAULT_DAEMON_PORT),
+                        path: path.to_string(),
+                    })
+                }
+                "http" | "https" => Ok(RemoteUrl::Http(s.to_string())),
+                "ws" | "wss" => Ok(RemoteUrl::WebSocket(s.to_string())),
+                "quic" => {
+                    let (host, port) = split_port(authority, s)?;
+                    Ok(RemoteUrl::Quic {
+                        host,
+                        port: port.unwrap_or(DEFAULT_DAEMON_PORT),
+                        path: path.to_string(),
+                    })
+                }
+                _ => Err(format!("Unsupported URL scheme '{}' in {}", scheme, s)),
+            };
+        }
+        // As with git, `host:path` is an scp-style SSH location if the colon comes
+        // before any slash, and isn't a DOS drive's; anything else is a local path
+        match (scp_colon(s), s.find('/')) {
+            _ if has_dos_drive(s) => Ok(RemoteUrl::Local(PathBuf::from(s))),
+            (Some(colon), slash) if slash.is_none_or(|slash| colon < slash) => {
+                Ok(RemoteUrl::ssh(&s[..colon], &s[colon + 1..]))
+            }
+            _ if [".bundle", ".bundle.gz", ".bundle.zst"]
+                .iter()
+                .any(|suffix| s.ends_with(suffix)) =>
+            {
+                Ok(RemoteUrl::Bundle(PathBuf::from(s)))
+            }
+            _ => Ok(RemoteUrl::Local(PathBuf::from(s))),
+        }
+    }
+}
+
+impl fmt::Display for RemoteUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteUrl::Local(path) => write!(f, "{}", path.display()),
+            RemoteUrl::Ssh {
+                user,
+                host,
+                port,
+                path,
+            } => {
+                let user = user
+                    .as_deref()
+                    .map(|u| format!("{}@", u))
+                    .unwrap_or_default();
+                let host = bracket(host);
+                match port {
+                    Some(port) => write!(
+                        f,
+                        "ssh://{}{}:{}/{}",
+                        user,
+                        host,
+                        port,
+                        path.trim_start_matches('/')
+                    ),
+                    None => write!(f, "{}{}:{}", user, host, path),
+                }
+            }
+            RemoteUrl::Git { host, port, path } if *port == DEFAULT_DAEMON_PORT => {
+                write!(f, "git://{}{}", bracket(host), path)
+            }
+            RemoteUrl::Git { host, port, path } => {
+                write!(f, "git://{}:{}{}", bracket(host), port, path)
+            }
+            RemoteUrl::Quic { host, port, path } if *port == DEFAULT_DAEMON_PORT => {
+                write!(f, "quic://{}{}", bracket(host), path)
+            }
+            RemoteUrl::Quic { host, port, path } => {
+                write!(f, "quic://{}:{}{}", bracket(host), port, path)
+            }
+            RemoteUrl::Http(url) | RemoteUrl::WebSocket(url) | RemoteUrl::Ext(url) => {
+                f.write_str(url)
+            }
+            RemoteUrl::Bundle(path) => write!(f, "bundle:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> RemoteUrl {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn shows_urls_as_they_were_given() {
+        for url in &[
+            "ssh://git@example.com:2222/srv/repo.git",
+            "git@example.com:repo.git",
+            "example.com:~/repo.git",
+            "git://example.com/repo.git",
+            "git://example.com:9419/repo.git",
+            "git://[::1]:9419/repo.git",
+            "quic://example.com/repo.git",
+            "https://user@example.com/repo.git",
+            "ws://example.com/repo.git",
+            "ext::ssh -p 2222 example.com %S repo.git",
+            "/srv/repo.git",
+        ] {
+            assert_eq!(parse(url).to_string(), *url);
+        }
+    }
+
+    #[test]
+    fn ssh_urls() {
+        let url = parse("git+ssh://example.com/~user/repo.git");
+        assert_eq!(
+            url,
+            RemoteUrl::Ssh {
+                user: None,
+                host: "example.com".into(),
+                port: None,
+                path: "~user/repo.git".into(),
+            }
+        );
+        assert_eq!(
+            parse("ssh+git://example.com/srv/repo.git"),
+            parse("example.com:/srv/repo.git")
+        );
+        // The last @ ends the user, which may have one of its own
+        assert_eq!(
+            parse("ssh://me@corp@example.com/repo.git"),
+            RemoteUrl::Ssh {
+                user: Some("me@corp".into()),
+                host: "example.com".into(),
+                port: None,
+                path: "/repo.git".into(),
+            }
+        );
+        assert_eq!(
+            parse("ssh://example.com:22/repo.git").to_string(),
+            "ssh://example.com:22/repo.git"
+        );
+    }
+
+    #[test]
+    fn daemon_urls() {
+        assert_eq!(
+            parse("git://example.com/repo.git"),
+            RemoteUrl::Git {
+                host: "example.com".into(),
+                port: DEFAULT_DAEMON_PORT,
+                path: "/repo.git".into(),
+            }
+        );
+        // The default port is left out when it's shown
+        assert_eq!(
+            parse("git://example.com:9418/repo.git").to_string(),
+            "git://example.com/repo.git"
+        );
+        assert_eq!(
+            parse("quic://[fe80::1]:4433/repo.git"),
+            RemoteUrl::Quic {
+                host: "fe80::1".into(),
+                port: 4433,
+                path: "/repo.git".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn hosts() {
+        assert_eq!(
+            parse("https://user:pw@example.com:8443/repo.git").host(),
+            Some("example.com")
+        );
+        assert_eq!(parse("wss://[::1]/repo.git").host(), Some("::1"));
+        assert_eq!(
+            parse("git@example.com:repo.git").host(),
+            Some("example.com")
+        );
+        assert_eq!(parse("/srv/repo.git").host(), None);
+        assert_eq!(parse("ext::true").host(), None);
+        assert_eq!(parse("repo.bundle").host(), None);
+    }
+
+    #[test]
+    fn bundles() {
+        for path in &[
+            "repo.bundle",
+            "backups/repo.bundle.gz",
+            "/backups/repo.bundle.zst",
+        ] {
+            assert_eq!(parse(path), RemoteUrl::Bundle(PathBuf::from(path)));
+            assert_eq!(
+                parse(&format!("bundle:{}", path)),
+                RemoteUrl::Bundle(PathBuf::from(path))
+            );
+        }
+        // Only a whole suffix makes a bundle
+        assert_eq!(
+            parse("repo.bundle.xz"),
+            RemoteUrl::Local("repo.bundle.xz".into())
+        );
+        assert_eq!(parse("repo.gz"), RemoteUrl::Local("repo.gz".into()));
+        assert_eq!(
+            parse("bundle:other.git"),
+            RemoteUrl::Bundle("other.git".into())
+        );
+    }
+
+    #[test]
+    fn local_paths() {
+        assert_eq!(
+            parse("file:///srv/repo.git"),
+            RemoteUrl::Local("/srv/repo.git".into())
+        );
+        assert_eq!(parse("../repo.git"), RemoteUrl::Local("../repo.git".into()));
+        // A slash before the colon makes a path, not an scp-style location
+        assert_eq!(
+            parse("dir/host:repo"),
+            RemoteUrl::Local("dir/host:repo".into())
+        );
+        assert_eq!(parse(r"D:\repo"), RemoteUrl::Local(r"D:\repo".into()));
+        // A drive letter alone isn't a DOS path
+        assert_eq!(parse("D:repo"), RemoteUrl::ssh("D", "repo"));
+    }
+
+    #[test]
+    fn refuses_what_it_cannot_reach() {
+        let err = |s: &str| s.parse::<RemoteUrl>().unwrap_err();
+        assert_eq!(err(""), "Empty remote");
+        assert_eq!(err("git://example.com"), "No path in git://example.com");
+        assert_eq!(
+            err("git://example.com:port/repo.git"),
+            "Bad port in git://example.com:port/repo.git"
+        );
+        assert_eq!(
+            err("ssh://example.com:99999/repo.git"),
+            "Bad port in ssh://example.com:99999/repo.git"
+        );
+        assert_eq!(
+            err("gopher://example.com/repo"),
+            "Unsupported URL scheme 'gopher' in gopher://example.com/repo"
+        );
+    }
+}
@@ -0,0 +1,231 @@
+/// Noticing when the refs of a repository on this machine change
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use tokio::sync::mpsc;
+
+/// How often to look at the refs, when they can't be watched
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the refs must stay the same after changing before the change is
+/// reported, so that a push updating many refs is reported once
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// What's known of a repository's refs: the name, modification time and size of
+/// every file under `refs/`, and of `packed-refs`
+type RefsState = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+/// How a watcher learns of changes
+enum Watching {
+    /// The filesystem says when something under the git directory changes.
+    /// The debouncer stops watching once it's dropped.
+    Notified {
+        _debouncer: Debouncer<RecommendedWatcher>,
+        events: mpsc::UnboundedReceiver<DebounceEventResult>,
+    },
+    /// The refs are looked at every so often, for filesystems which can't be
+    /// watched
+    Polled(RefsState),
+}
+
+/// Watches the loose and packed refs of a local repository, being told of
+/// changes by the filesystem where it can, and looking at the refs every
+/// second where it can't
+///
+/// ```
+/// # use git_sync::RefWatcher;
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let dir = std::env::temp_dir().join(format!("git-sync-watch-{}", std::process::id()));
+/// std::fs::create_dir_all(dir.join("refs/heads")).unwrap();
+/// let mut watcher = RefWatcher::new(&dir).await;
+/// std::fs::write(dir.join("refs/heads/main"), "0".repeat(40)).unwrap();
+/// watcher.changed().await;
+/// std::fs::remove_dir_all(&dir).unwrap();
+/// # }
+/// ```
+pub struct RefWatcher {
+    git_dir: PathBuf,
+    watching: Watching,
+}
+
+impl RefWatcher {
+    /// Start watching the repository at `path`, which may be a bare repository
+    /// or a working tree with a `.git` directory
+    pub async fn new(path: &Path) -> RefWatcher {
+        let dot_git = path.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else {
+            path.to_path_buf()
+        };
+        let watching = match notified(&git_dir) {
+            Ok(watching) => watching,
+            Err(e) => {
+                tracing::warn!(
+                    "Cannot watch {} for changes, so looking at it every {}s instead: {}",
+                    git_dir.display(),
+                    POLL_INTERVAL.as_secs(),
+                    e
+                );
+                Watching::Polled(refs_state(git_dir.clone()).await)
+            }
+        };
+        RefWatcher { git_dir, watching }
+    }
+
+    /// Wait until the refs change, and then stop changing
+    pub async fn changed(&mut self) {
+        match &mut self.watching {
+            Watching::Notified { events, .. } => loop {
+                match events.recv().await {
+                    Some(Ok(events)) => {
+                        let refs = self.git_dir.join("refs");
+                        let packed_refs = self.git_dir.join("packed-refs");
+                        if events
+                            .iter()
+                            .any(|event| event.path.starts_with(&refs) || event.path == packed_refs)
+                        {
+                            return;
+                        }
+                    }
+                    // Events may have been missed, so there may have been changes
+                    Some(Err(e)) => {
+                        tracing::warn!("Trouble watching {}: {}", self.git_dir.display(), e);
+                        return;
+                    }
+                    // The debouncer has stopped, which it only does when dropped
+                    None => std::future::pending().await,
+                }
+            },
+            Watching::Polled(state) => {
+                loop {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    let now = refs_state(self.git_dir.clone()).await;
+                    if now != *state {
+                        *state = now;
+                        break;
+                    }
+                }
+                loop {
+                    tokio::time::sleep(DEBOUNCE).await;
+                    let now = refs_state(self.git_dir.clone()).await;
+                    if now == *state {
+                        return;
+                    }
+                    *state = now;
+                }
+            }
+        }
+    }
+}
+
+/// Ask the filesystem to say when the refs in a git directory change, which
+/// it says of everything directly in the directory and everything under
+/// `refs/`
+fn notified(git_dir: &Path) -> Result<Watching, notify::Error> {
+    let (sender, events) = mpsc::unbounded_channel();
+    let mut debouncer = new_debouncer(DEBOUNCE, move |result| {
+        let _ = sender.send(result);
+    })?;
+    let watcher = debouncer.watcher();
+    watcher.watch(git_dir, RecursiveMode::NonRecursive)?;
+    watcher.watch(&git_dir.join("refs"), RecursiveMode::Recursive)?;
+    Ok(Watching::Notified {
+        _debouncer: debouncer,
+        events,
+    })
+}
+
+/// Look at the refs in a git directory, without holding up other tasks
+async fn refs_state(git_dir: PathBuf) -> RefsState {
+    tokio::task::spawn_blocking(move || {
+        let mut state = Vec::new();
+        add_state(&mut state, &git_dir.join("packed-refs"));
+        let mut dirs = vec![git_dir.join("refs")];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                match entry.file_type() {
+                    Ok(kind) if kind.is_dir() => dirs.push(entry.path()),
+                    Ok(_) => add_state(&mut state, &entry.path()),
+                    Err(_) => {}
+                }
+            }
+        }
+        state.sort();
+        state
+    })
+    .await
+    .unwrap_or_default()
+}
+
+fn add_state(state: &mut RefsState, path: &Path) {
+    if let Ok(meta) = fs::metadata(path) {
+        state.push((path.to_path_buf(), meta.modified().ok(), meta.len()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A git directory of nothing but refs, to be removed when done with
+    fn git_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("git-sync-watch-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("refs/heads")).unwrap();
+        fs::create_dir_all(dir.join("objects")).unwrap();
+        dir
+    }
+
+    /// Whether the watcher says the refs changed within a while
+    async fn says_changed(watcher: &mut RefWatcher) -> bool {
+        tokio::time::timeout(DEBOUNCE * 3, watcher.changed())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn notices_refs_changing() {
+        let dir = git_dir("refs");
+        let mut watcher = RefWatcher::new(&dir).await;
+        assert!(matches!(watcher.watching, Watching::Notified { .. }));
+        fs::create_dir_all(dir.join("refs/tags/nested")).unwrap();
+        fs::write(dir.join("refs/tags/nested/v1"), "0".repeat(40)).unwrap();
+        assert!(says_changed(&mut watcher).await);
+        fs::write(dir.join("packed-refs"), "# pack-refs with: peeled\n").unwrap();
+        assert!(says_changed(&mut watcher).await);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_everything_but_refs() {
+        let dir = git_dir("objects");
+        let mut watcher = RefWatcher::new(&dir).await;
+        fs::write(dir.join("objects/pack"), "").unwrap();
+        fs::write(dir.join("FETCH_HEAD"), "").unwrap();
+        assert!(!says_changed(&mut watcher).await);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn polls_where_it_cannot_watch() {
+        let dir = git_dir("polled");
+        let mut watcher = RefWatcher {
+            watching: Watching::Polled(refs_state(dir.clone()).await),
+            git_dir: dir.clone(),
+        };
+        assert!(!says_changed(&mut watcher).await);
+        fs::write(dir.join("refs/heads/main"), "0".repeat(40)).unwrap();
+        assert!(says_changed(&mut watcher).await);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
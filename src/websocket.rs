@@ -0,0 +1,143 @@
+/// Tunnelling the git protocol over WebSocket connections
+///
+/// The gateway at the other end is expected to behave like a git daemon whose
+/// byte stream is carried in binary WebSocket messages: the first thing we send
+/// is the daemon's service request line, and from then on the usual protocol flows.
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{Error, ProtocolLine, Proxy, ShutdownFuture, Transport};
+
+/// A service reached through a WebSocket gateway, over `ws://` or `wss://`
+pub struct WebSocketTransport {
+    reader: io::ReadHalf<io::DuplexStream>,
+    writer: io::WriteHalf<io::DuplexStream>,
+    incoming: JoinHandle<Result<(), Error>>,
+    outgoing: Option<JoinHandle<Result<(), Error>>>,
+}
+
+fn ws_error(err: tokio_tungstenite::tungstenite::Error) -> Error {
+    Error::Transport(format!("WebSocket error: {}", err))
+}
+
+impl WebSocketTransport {
+    /// Connect to a WebSocket gateway, optionally by way of a proxy, and ask it to
+    /// run the given service for the repository named by the URL's path
+    pub async fn connect(
+        url: &str,
+        proxy: Option<&Proxy>,
+        service: &str,
+    ) -> Result<WebSocketTransport, Error> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("wss://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("ws://") {
+            (false, rest)
+        } else {
+            return Err(Error::Config(format!("Not a WebSocket URL: {}", url)));
+        };
+        let (hostport, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => return Err(Error::Config(format!("No path in {}", url))),
+        };
+        let (host, port) = match hostport.rfind(':') {
+            Some(idx) => (
+                &hostport[..idx],
+                hostport[idx + 1..]
+                    .parse()
+                    .map_err(|_| Error::Config(format!("Bad port in {}", url)))?,
+            ),
+            None => (hostport, if tls { 443 } else { 80 }),
+        };
+
+        let stream = match proxy {
+            Some(proxy) => proxy.connect(host, port).await?,
+            None => TcpStream::connect((host, port)).await.map_err(|err| {
+                Error::Transport(format!("Unable to connect to {}:{}: {}", host, port, err))
+            })?,
+        };
+        let (ws, _) = tokio_tungstenite::client_async_tls(url, stream)
+            .await
+            .map_err(ws_error)?;
+        let (mut sink, mut source) = ws.split();
+
+        // The transport's streams are one end of an in-memory pipe, with a pair of
+        // tasks shuttling data between the other end and the WebSocket
+        let (ours, theirs) = io::duplex(65536);
+        let (reader, mut writer) = io::split(ours);
+        let (mut from_transport, mut to_transport) = io::split(theirs);
+
+        ProtocolLine::write_str(
+            &mut writer,
+            format!("{} {}\0host={}\0", service, path, hostport),
+        )
+        .await?;
+
+        let incoming = tokio::spawn(async move {
+            while let Some(msg) = source.next().await {
+                match msg.map_err(ws_error)? {
+                    Message::Binary(data) => to_transport.write_all(&data).await?,
+                    Message::Text(text) => to_transport.write_all(text.as_bytes()).await?,
+                    Message::Close(_) => break,
+                    Message::Ping(_) | Message::Pong(_) => {}
+                }
+            }
+            Ok(to_transport.shutdown().await?)
+        });
+        let outgoing = tokio::spawn(async move {
+            let mut buf = vec![0; 65536];
+            loop {
+                let len = from_transport.read(&mut buf).await?;
+                if len == 0 {
+                    break;
+                }
+                sink.send(Message::binary(&buf[..len]))
+                    .await
+                    .map_err(ws_error)?;
+            }
+            sink.close().await.map_err(ws_error)
+        });
+
+        Ok(WebSocketTransport {
+            reader,
+            writer,
+            incoming,
+            outgoing: Some(outgoing),
+        })
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn streams(
+        &mut self,
+    ) -> (
+        &mut (dyn AsyncRead + Unpin + Send),
+        &mut (dyn AsyncWrite + Unpin + Send),
+    ) {
+        (&mut self.reader, &mut self.writer)
+    }
+
+    fn shutdown(mut self: Box<Self>) -> ShutdownFuture {
+        Box::pin(async move {
+            self.writer.shutdown().await?;
+            if let Some(outgoing) = self.outgoing.take() {
+                outgoing.await.map_err(io::Error::from)??;
+            }
+            // The gateway may already have gone away, and we've no more interest
+            // in anything it says, so dropping the transport stops listening
+            Ok(())
+        })
+    }
+}
+
+impl Drop for WebSocketTransport {
+    fn drop(&mut self) {
+        // Stop relaying, whether or not we were shut down cleanly
+        self.incoming.abort();
+        if let Some(outgoing) = &self.outgoing {
+            outgoing.abort();
+        }
+    }
+}
@@ -25,4 +25,4 @@ echo "Having a go at transferring"
 
 set -x
 
-cargo run -- $(cd source; pwd) $(cd target; pwd)
+cargo run -- sync $(cd source; pwd) $(cd target; pwd)